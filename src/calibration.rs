@@ -0,0 +1,709 @@
+use anyhow::{Context, Result};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use crate::cfa;
+use crate::isolate::{StateReader, StateWriter};
+use crate::libraw::{ColorLevels, LibRaw};
+use crate::term_colors::{blue, pink, red};
+
+/// A hot pixel sticks out of its same-color neighbors by this many times the noise.
+const HOT_SIGMA: f32 = 8.0;
+/// Flat cells darker than this fraction of their channel mean are dust or dead pixels;
+/// they get this gain floor instead of being amplified without bound.
+const FLAT_MIN: f32 = 0.05;
+
+/// Visible-area sensor values of a calibration frame, black level included.
+#[derive(Clone, Debug)]
+pub struct Mosaic {
+    pub width: usize,
+    pub height: usize,
+    pub filters: u32,
+    pub xtrans: [[c_char; 6]; 6],
+    /// `None` when the black levels could not be read from this libraw build.
+    pub levels: Option<ColorLevels>,
+    pub white: u32,
+    pub samples: Vec<u16>,
+}
+
+impl Mosaic {
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut lr = LibRaw::new()?;
+        let data = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        lr.open_buffer(data).context("libraw_open_buffer failed")?;
+        lr.unpack().context("libraw_unpack failed")?;
+        Self::from_libraw(&mut lr)
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        w.count(self.width);
+        w.count(self.height);
+        w.u32(self.filters);
+        for &c in self.xtrans.iter().flatten() {
+            w.u8(c as u8);
+        }
+        w.option(self.levels.as_ref(), |w, l| {
+            w.u32(l.black);
+            l.cblack.iter().for_each(|&v| w.u32(v));
+            w.option(l.black_pattern.as_ref(), |w, (rows, cols, v)| {
+                w.u32(*rows);
+                w.u32(*cols);
+                w.count(v.len());
+                v.iter().for_each(|&x| w.u32(x));
+            });
+            w.u32(l.maximum);
+            w.u32(l.data_maximum);
+            l.linear_max.iter().for_each(|&v| w.i64(v));
+            l.cam_xyz.iter().flatten().for_each(|&v| w.f32(v));
+        });
+        w.u32(self.white);
+        w.u16s(&self.samples);
+    }
+
+    fn read_state(r: &mut StateReader) -> Result<Self> {
+        let (width, height, filters) = (r.count()?, r.count()?, r.u32()?);
+        let mut xtrans = [[0; 6]; 6];
+        for c in xtrans.iter_mut().flatten() {
+            *c = r.u8()? as c_char;
+        }
+        let levels = r.option(|r| {
+            let black = r.u32()?;
+            let mut cblack = [0; 4];
+            for v in &mut cblack {
+                *v = r.u32()?;
+            }
+            let black_pattern = r.option(|r| {
+                let (rows, cols) = (r.u32()?, r.u32()?);
+                let v = (0..r.count()?).map(|_| r.u32()).collect::<Result<_>>()?;
+                Ok((rows, cols, v))
+            })?;
+            let (maximum, data_maximum) = (r.u32()?, r.u32()?);
+            let mut linear_max = [0; 4];
+            for v in &mut linear_max {
+                *v = r.i64()?;
+            }
+            let mut cam_xyz = [[0.0; 3]; 4];
+            for v in cam_xyz.iter_mut().flatten() {
+                *v = r.f32()?;
+            }
+            Ok(ColorLevels {
+                black,
+                cblack,
+                black_pattern,
+                maximum,
+                data_maximum,
+                linear_max,
+                cam_xyz,
+            })
+        })?;
+        let white = r.u32()?;
+        let samples = r.u16s()?;
+        if samples.len() != width * height {
+            anyhow::bail!("worker state has a malformed calibration frame");
+        }
+        Ok(Mosaic {
+            width,
+            height,
+            filters,
+            xtrans,
+            levels,
+            white,
+            samples,
+        })
+    }
+
+    pub fn from_libraw(lr: &mut LibRaw) -> Result<Self> {
+        let ip = lr.iparams();
+        let (filters, xtrans) = (ip.filters, ip.xtrans);
+        if filters == 0 {
+            anyhow::bail!("file has no color filter array (full color per pixel)");
+        }
+        let sizes = lr.sizes()?;
+        let levels = lr.color_levels().ok();
+        let white = lr.color_maximum().max(0) as u32;
+        let (w, h) = (sizes.width as usize, sizes.height as usize);
+        let (top, left) = (sizes.top_margin as usize, sizes.left_margin as usize);
+        let stride = sizes.raw_pitch as usize / 2;
+        let raw = lr.raw_image_mut()?;
+        let samples = (0..h)
+            .flat_map(|r| raw[(r + top) * stride + left..][..w].iter().copied())
+            .collect();
+        Ok(Mosaic {
+            width: w,
+            height: h,
+            filters,
+            xtrans,
+            levels,
+            white,
+            samples,
+        })
+    }
+
+    fn black_at(&self, row: usize, col: usize) -> u32 {
+        self.levels.as_ref().map_or(0, |l| {
+            cell_black(
+                l,
+                cfa::channel_at(self.filters, &self.xtrans, row, col),
+                row,
+                col,
+            )
+        })
+    }
+
+    fn check_size(&self, other: &Mosaic, what: &str, path: &Path) -> Result<()> {
+        if (other.width, other.height, other.filters) != (self.width, self.height, self.filters) {
+            anyhow::bail!(
+                "{} {} is {}x{}, the first one is {}x{}",
+                what,
+                red(path.display()),
+                other.width,
+                other.height,
+                self.width,
+                self.height
+            );
+        }
+        Ok(())
+    }
+
+    /// Distance to the nearest cells of the same color along rows and columns.
+    fn period(&self) -> usize {
+        if self.filters == 9 { 6 } else { 2 }
+    }
+}
+
+fn neighbors(
+    period: usize,
+    w: usize,
+    h: usize,
+    row: usize,
+    col: usize,
+) -> impl Iterator<Item = (usize, usize)> {
+    let p = period as i64;
+    [
+        (-p, -p),
+        (-p, 0),
+        (-p, p),
+        (0, -p),
+        (0, p),
+        (p, -p),
+        (p, 0),
+        (p, p),
+    ]
+    .into_iter()
+    .filter_map(move |(dr, dc)| {
+        let (r, c) = (row as i64 + dr, col as i64 + dc);
+        (r >= 0 && c >= 0 && r < h as i64 && c < w as i64).then_some((r as usize, c as usize))
+    })
+}
+
+/// Mean of several dark frames, which keeps fixed-pattern noise and averages the rest.
+pub fn master_dark(paths: &[PathBuf], debug: bool) -> Result<Mosaic> {
+    mean_frame(paths, debug, Mosaic::read)
+}
+
+fn mean_frame(
+    paths: &[PathBuf],
+    debug: bool,
+    read: impl Fn(&Path) -> Result<Mosaic>,
+) -> Result<Mosaic> {
+    let mut master: Option<Mosaic> = None;
+    let mut sum: Vec<u32> = Vec::new();
+    for path in paths {
+        if debug {
+            println!(
+                "{} reading dark frame {}",
+                blue("[dark]"),
+                pink(path.display())
+            );
+        }
+        let frame = read(path).with_context(|| format!("Dark frame {:?}", path))?;
+        match &master {
+            None => {
+                sum = frame.samples.iter().map(|&v| v as u32).collect();
+                master = Some(frame);
+            }
+            Some(m) => {
+                m.check_size(&frame, "Dark frame", path)?;
+                for (s, &v) in sum.iter_mut().zip(&frame.samples) {
+                    *s += v as u32;
+                }
+            }
+        }
+    }
+    let mut master = master.context("no dark frames given")?;
+    let n = paths.len() as u32;
+    master.samples = sum.iter().map(|&s| ((s + n / 2) / n) as u16).collect();
+    Ok(master)
+}
+
+/// Per-pixel median of several frames, which drops whatever shows up in only some of
+/// them: stars, moved dust, cosmic ray hits.
+pub fn median_frame(paths: &[PathBuf], what: &str, debug: bool) -> Result<Mosaic> {
+    let mut frames: Vec<Mosaic> = Vec::with_capacity(paths.len());
+    for path in paths {
+        if debug {
+            println!(
+                "{} reading {} frame {}",
+                blue("[calibration]"),
+                what.to_ascii_lowercase(),
+                pink(path.display())
+            );
+        }
+        let frame = Mosaic::read(path).with_context(|| format!("{} frame {:?}", what, path))?;
+        if let Some(first) = frames.first() {
+            first.check_size(&frame, &format!("{} frame", what), path)?;
+        }
+        frames.push(frame);
+    }
+    let mut master = frames
+        .first()
+        .cloned()
+        .with_context(|| format!("no {} frames given", what.to_ascii_lowercase()))?;
+    if frames.len() > 1 {
+        master.samples = (0..master.samples.len())
+            .into_par_iter()
+            .map(|i| {
+                let mut v: Vec<u16> = frames.iter().map(|f| f.samples[i]).collect();
+                let mid = v.len() / 2;
+                *v.select_nth_unstable(mid).1
+            })
+            .collect();
+    }
+    Ok(master)
+}
+
+/// Per-cell gains that undo vignetting and dust shadows, from a master flat.
+#[derive(Clone, Debug)]
+pub struct FlatField {
+    pub width: usize,
+    pub height: usize,
+    pub gain: Vec<f32>,
+}
+
+/// Median-combines the flats, removes the master bias (or the black level without one)
+/// and normalizes each CFA channel to its mean, so that only the falloff is corrected.
+pub fn master_flat(flats: &[PathBuf], biases: &[PathBuf], debug: bool) -> Result<FlatField> {
+    let flat = median_frame(flats, "Flat", debug)?;
+    let bias = if biases.is_empty() {
+        None
+    } else {
+        let bias = median_frame(biases, "Bias", debug)?;
+        flat.check_size(&bias, "Bias frame", &biases[0])?;
+        Some(bias)
+    };
+    if bias.is_none() && flat.levels.is_none() {
+        anyhow::bail!(
+            "flat-field correction without --bias needs the black levels, which this libraw build does not expose"
+        );
+    }
+    let (w, h) = (flat.width, flat.height);
+    let signal: Vec<f32> = (0..h)
+        .flat_map(|r| (0..w).map(move |c| (r, c)))
+        .map(|(r, c)| {
+            let i = r * w + c;
+            let zero = match &bias {
+                Some(b) => b.samples[i] as f32,
+                None => flat.black_at(r, c) as f32,
+            };
+            (flat.samples[i] as f32 - zero).max(0.0)
+        })
+        .collect();
+    let channel = |i: usize| cfa::channel_at(flat.filters, &flat.xtrans, i / w, i % w);
+    let mut sums = [0f64; 4];
+    let mut counts = [0u64; 4];
+    for (i, &v) in signal.iter().enumerate() {
+        let c = channel(i) & 3;
+        sums[c] += v as f64;
+        counts[c] += 1;
+    }
+    let means = [0, 1, 2, 3].map(|c| (sums[c] / counts[c].max(1) as f64) as f32);
+    if debug {
+        println!(
+            "{} flat channel means {}",
+            blue("[calibration]"),
+            pink(format!("{:?}", means))
+        );
+    }
+    if means.iter().zip(counts).any(|(&m, n)| n > 0 && m <= 0.0) {
+        anyhow::bail!("master flat has no signal above black in at least one channel");
+    }
+    let gain = signal
+        .iter()
+        .enumerate()
+        .map(|(i, &v)| 1.0 / (v / means[channel(i) & 3]).max(FLAT_MIN))
+        .collect();
+    Ok(FlatField {
+        width: w,
+        height: h,
+        gain,
+    })
+}
+
+/// Pixels of a dark frame that are far brighter than their same-color neighbors, as
+/// (column, row) in the visible area.
+pub fn find_hot_pixels(dark: &Mosaic) -> Vec<(usize, usize)> {
+    let (w, h, period) = (dark.width, dark.height, dark.period());
+    let excess = |row: usize, col: usize| -> Option<i32> {
+        let mut around: Vec<u16> = neighbors(period, w, h, row, col)
+            .map(|(r, c)| dark.samples[r * w + c])
+            .collect();
+        if around.len() < 3 {
+            return None;
+        }
+        let mid = around.len() / 2;
+        let median = *around.select_nth_unstable(mid).1;
+        Some(dark.samples[row * w + col] as i32 - median as i32)
+    };
+    // Robust spread of the excess over every seventh row.
+    let mut spread: Vec<u32> = (0..h)
+        .into_par_iter()
+        .step_by(7)
+        .flat_map_iter(|r| (0..w).filter_map(move |c| excess(r, c)))
+        .map(|d| d.unsigned_abs())
+        .collect();
+    let mad = if spread.is_empty() {
+        0
+    } else {
+        let mid = spread.len() / 2;
+        *spread.select_nth_unstable(mid).1
+    };
+    let black = dark.levels.as_ref().map_or(0, |l| l.black);
+    let floor = dark.white.saturating_sub(black) as f32 / 256.0;
+    let threshold = (HOT_SIGMA * 1.4826 * mad as f32).max(floor);
+    (0..h)
+        .into_par_iter()
+        .flat_map_iter(|r| {
+            (0..w)
+                .filter(move |&c| excess(r, c).is_some_and(|d| d as f32 > threshold))
+                .map(move |c| (c, r))
+        })
+        .collect()
+}
+
+/// Reads a dcraw-style bad pixel list: `column row [unix time]` per line, `#` comments.
+pub fn read_bad_pixels(path: &Path) -> Result<Vec<(usize, usize)>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let mut out = Vec::new();
+    for (n, line) in text.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let fields: Vec<&str> = line.split_whitespace().collect();
+        if fields.is_empty() {
+            continue;
+        }
+        let parsed = match fields.as_slice() {
+            [col, row, ..] => col.parse::<usize>().ok().zip(row.parse::<usize>().ok()),
+            _ => None,
+        };
+        match parsed {
+            Some(p) => out.push(p),
+            None => anyhow::bail!(
+                "Invalid bad pixel entry on line {} of {}: {}",
+                n + 1,
+                path.display(),
+                red(line.trim())
+            ),
+        }
+    }
+    Ok(out)
+}
+
+/// Writes pixels in dcraw's `.badpixels` format, with time 0 so that they always apply.
+pub fn write_bad_pixels(path: &Path, pixels: &[(usize, usize)]) -> Result<()> {
+    let mut text = String::from("# column row time, written by fempeg --find-hot-pixels\n");
+    for (col, row) in pixels {
+        text.push_str(&format!("{:5} {:5} 0\n", col, row));
+    }
+    std::fs::write(path, text).with_context(|| format!("Failed to write {:?}", path))
+}
+
+/// Black level of one visible-area cell, channel and pattern offsets included.
+fn cell_black(levels: &ColorLevels, channel: usize, row: usize, col: usize) -> u32 {
+    let pattern = levels.black_pattern.as_ref().map_or(0, |(rows, cols, v)| {
+        let i = (row as u32 % rows) * cols + col as u32 % cols;
+        v.get(i as usize).copied().unwrap_or(0)
+    });
+    levels.black + levels.cblack.get(channel).copied().unwrap_or(0) + pattern
+}
+
+/// Corrections applied to every raw file of a run, between unpacking and processing.
+pub struct Calibration {
+    pub dark: Option<Mosaic>,
+    pub flat: Option<FlatField>,
+    /// (column, row) in the visible area.
+    pub bad_pixels: Vec<(usize, usize)>,
+}
+
+impl Calibration {
+    /// Builds the master dark, master flat and bad pixel list; `bad_pixels` is a file or
+    /// `auto` to use the hot pixels of the master dark.
+    pub fn load(
+        darks: &[PathBuf],
+        flats: &[PathBuf],
+        biases: &[PathBuf],
+        bad_pixels: Option<&str>,
+        debug: bool,
+    ) -> Result<Option<Calibration>> {
+        if darks.is_empty() && flats.is_empty() && bad_pixels.is_none() {
+            if !biases.is_empty() {
+                anyhow::bail!("--bias frames are only used to correct --flat frames");
+            }
+            return Ok(None);
+        }
+        let dark = if darks.is_empty() {
+            None
+        } else {
+            println!(
+                "{} {} dark frame{}...",
+                blue("Averaging"),
+                pink(darks.len()),
+                if darks.len() == 1 { "" } else { "s" }
+            );
+            Some(master_dark(darks, debug)?)
+        };
+        let flat = if flats.is_empty() {
+            None
+        } else {
+            println!(
+                "{} {} flat frame{}...",
+                blue("Combining"),
+                pink(flats.len()),
+                if flats.len() == 1 { "" } else { "s" }
+            );
+            Some(master_flat(flats, biases, debug)?)
+        };
+        let bad_pixels = match bad_pixels {
+            None => Vec::new(),
+            Some(s) if s.trim().eq_ignore_ascii_case("auto") => {
+                let dark = dark
+                    .as_ref()
+                    .context("--bad-pixels auto detects hot pixels from --dark-frame")?;
+                find_hot_pixels(dark)
+            }
+            Some(path) => read_bad_pixels(Path::new(path))?,
+        };
+        if debug {
+            println!(
+                "{} {} bad pixels to repair",
+                blue("[calibration]"),
+                pink(bad_pixels.len())
+            );
+        }
+        Ok(Some(Calibration {
+            dark,
+            flat,
+            bad_pixels,
+        }))
+    }
+
+    /// Encodes the masters for `--isolate` workers, so they need not be built again.
+    pub fn write_state(&self, w: &mut StateWriter) {
+        w.option(self.dark.as_ref(), |w, d| d.write_state(w));
+        w.option(self.flat.as_ref(), |w, f| {
+            w.count(f.width);
+            w.count(f.height);
+            w.f32s(&f.gain);
+        });
+        w.count(self.bad_pixels.len());
+        for &(col, row) in &self.bad_pixels {
+            w.count(col);
+            w.count(row);
+        }
+    }
+
+    pub fn read_state(r: &mut StateReader) -> Result<Calibration> {
+        let dark = r.option(Mosaic::read_state)?;
+        let flat = r.option(|r| {
+            let (width, height, gain) = (r.count()?, r.count()?, r.f32s()?);
+            if gain.len() != width * height {
+                anyhow::bail!("worker state has a malformed flat field");
+            }
+            Ok(FlatField {
+                width,
+                height,
+                gain,
+            })
+        })?;
+        let bad_pixels = (0..r.count()?)
+            .map(|_| Ok((r.count()?, r.count()?)))
+            .collect::<Result<_>>()?;
+        Ok(Calibration {
+            dark,
+            flat,
+            bad_pixels,
+        })
+    }
+
+    /// Subtracts the dark frame's signal above black, divides by the flat and repairs the
+    /// listed pixels from their same-color neighbors, in the unpacked raw data.
+    pub fn apply(&self, lr: &mut LibRaw, debug: bool) -> Result<()> {
+        let ip = lr.iparams();
+        let (filters, xtrans) = (ip.filters, ip.xtrans);
+        if filters == 0 {
+            anyhow::bail!("file has no color filter array (full color per pixel)");
+        }
+        let sizes = lr.sizes()?;
+        let (w, h) = (sizes.width as usize, sizes.height as usize);
+        let (top, left) = (sizes.top_margin as usize, sizes.left_margin as usize);
+        let stride = sizes.raw_pitch as usize / 2;
+        let levels = if self.dark.is_some() || self.flat.is_some() {
+            Some(lr.color_levels().context(
+                "dark and flat correction need the black levels, which this libraw build does not expose",
+            )?)
+        } else {
+            None
+        };
+        let raw = lr.raw_image_mut()?;
+        let at = |row: usize, col: usize| (row + top) * stride + left + col;
+
+        if let (Some(dark), Some(levels)) = (&self.dark, &levels) {
+            if (dark.width, dark.height) != (w, h) {
+                anyhow::bail!(
+                    "Dark frame is {}x{} but this file is {}x{}",
+                    red(dark.width),
+                    red(dark.height),
+                    w,
+                    h
+                );
+            }
+            if debug {
+                println!("{} subtracting dark frame", blue("[calibration]"));
+            }
+            // The dark keeps the black level in, so only its signal above black is removed
+            // and libraw still finds the pedestal it subtracts later.
+            for row in 0..h {
+                for col in 0..w {
+                    let channel = cfa::channel_at(filters, &xtrans, row, col);
+                    let signal = dark.samples[row * w + col] as i32
+                        - cell_black(levels, channel, row, col) as i32;
+                    let i = at(row, col);
+                    raw[i] = (raw[i] as i32 - signal).clamp(0, u16::MAX as i32) as u16;
+                }
+            }
+        }
+
+        if let (Some(flat), Some(levels)) = (&self.flat, &levels) {
+            if (flat.width, flat.height) != (w, h) {
+                anyhow::bail!(
+                    "Flat frame is {}x{} but this file is {}x{}",
+                    red(flat.width),
+                    red(flat.height),
+                    w,
+                    h
+                );
+            }
+            if debug {
+                println!("{} dividing by the master flat", blue("[calibration]"));
+            }
+            for row in 0..h {
+                for col in 0..w {
+                    let channel = cfa::channel_at(filters, &xtrans, row, col);
+                    let black = cell_black(levels, channel, row, col) as f32;
+                    let i = at(row, col);
+                    let v = black + (raw[i] as f32 - black) * flat.gain[row * w + col];
+                    raw[i] = v.round().clamp(0.0, u16::MAX as f32) as u16;
+                }
+            }
+        }
+
+        if !self.bad_pixels.is_empty() {
+            let period = if filters == 9 { 6 } else { 2 };
+            let bad: HashSet<(usize, usize)> = self.bad_pixels.iter().copied().collect();
+            let mut repaired = 0usize;
+            for &(col, row) in &self.bad_pixels {
+                if col >= w || row >= h {
+                    continue;
+                }
+                let (sum, n) = neighbors(period, w, h, row, col)
+                    .filter(|&(r, c)| !bad.contains(&(c, r)))
+                    .fold((0u32, 0u32), |(s, n), (r, c)| {
+                        (s + raw[at(r, c)] as u32, n + 1)
+                    });
+                if let Some(mean) = sum.checked_div(n) {
+                    raw[at(row, col)] = mean as u16;
+                    repaired += 1;
+                }
+            }
+            if debug {
+                println!(
+                    "{} repaired {} bad pixels",
+                    blue("[calibration]"),
+                    pink(repaired)
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// RGGB frame with a little fixed texture, black 0 and a 12-bit white.
+    fn frame(width: usize, height: usize, value: impl Fn(usize, usize) -> u16) -> Mosaic {
+        Mosaic {
+            width,
+            height,
+            filters: 0x94949494,
+            xtrans: [[0; 6]; 6],
+            levels: None,
+            white: 4095,
+            samples: (0..height)
+                .flat_map(|r| (0..width).map(move |c| (r, c)))
+                .map(|(r, c)| value(r, c))
+                .collect(),
+        }
+    }
+
+    #[test]
+    fn master_dark_is_rounded_mean() {
+        let paths: Vec<PathBuf> = ["a", "b", "c"].iter().map(PathBuf::from).collect();
+        let read = |p: &Path| {
+            let v = match p.to_str() {
+                Some("a") => 10,
+                Some("b") => 11,
+                _ => 13,
+            };
+            Ok(frame(4, 2, |r, c| v + (r * 4 + c) as u16))
+        };
+        let master = mean_frame(&paths, false, read).unwrap();
+        assert_eq!(master.samples, (0..8).map(|i| 11 + i).collect::<Vec<u16>>());
+    }
+
+    #[test]
+    fn master_dark_rejects_mismatched_and_empty() {
+        let paths: Vec<PathBuf> = ["a", "b"].iter().map(PathBuf::from).collect();
+        let read = |p: &Path| {
+            let w = if p.to_str() == Some("a") { 4 } else { 6 };
+            Ok(frame(w, 2, |_, _| 0))
+        };
+        assert!(mean_frame(&paths, false, read).is_err());
+        assert!(mean_frame(&[], false, |_| Ok(frame(2, 2, |_, _| 0))).is_err());
+    }
+
+    #[test]
+    fn finds_isolated_hot_pixels() {
+        let mut dark = frame(24, 20, |r, c| 100 + ((r * 7 + c * 3) % 5) as u16);
+        dark.samples[5 * 24 + 7] = 1000;
+        dark.samples[12 * 24 + 20] = 400;
+        assert_eq!(find_hot_pixels(&dark), vec![(7, 5), (20, 12)]);
+
+        // A uniformly brighter channel is not hot.
+        let green = frame(24, 20, |r, c| if (r + c) % 2 == 1 { 300 } else { 100 });
+        assert!(find_hot_pixels(&green).is_empty());
+    }
+
+    #[test]
+    fn reads_bad_pixel_lists() {
+        let path = std::env::temp_dir().join(format!("fempeg-{}.badpixels", std::process::id()));
+        std::fs::write(&path, "# col row time\n 12 34 0\n\n7 8 # no time\n").unwrap();
+        assert_eq!(read_bad_pixels(&path).unwrap(), vec![(12, 34), (7, 8)]);
+        std::fs::write(&path, "12 34\n9\n").unwrap();
+        let err = read_bad_pixels(&path).unwrap_err().to_string();
+        std::fs::remove_file(&path).unwrap();
+        assert!(err.contains("line 2"), "{err}");
+    }
+}
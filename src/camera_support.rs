@@ -0,0 +1,251 @@
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::libraw::{self, LibRawError};
+use crate::raw_metadata::RawMetadata;
+use crate::term_colors::{blue, pink, red};
+
+/// Lowercase letters and digits only, so `Nikon Z 8` and `NIKON Z8` compare equal.
+fn normalize(s: &str) -> String {
+    s.chars()
+        .filter(|c| c.is_ascii_alphanumeric())
+        .map(|c| c.to_ascii_lowercase())
+        .collect()
+}
+
+/// Whether libraw's camera list has `make model`. Entries can add other names for the
+/// same body in parentheses, e.g. `Sony ILCE-7M4 (A7 IV)`.
+pub fn is_listed(list: &[String], make: &str, model: &str) -> bool {
+    let full = normalize(&format!("{} {}", make, model));
+    let model = normalize(model);
+    list.iter().any(|entry| {
+        let mut names = entry.split(['(', ')', ',']).map(normalize);
+        let Some(first) = names.next() else {
+            return false;
+        };
+        first == full || first == model || names.any(|alt| !alt.is_empty() && alt == model)
+    })
+}
+
+/// What converting a file showed about its camera, for `CameraCheck` in the process
+/// that reports progress.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub enum Seen {
+    #[default]
+    Unknown,
+    Camera {
+        make: String,
+        model: String,
+    },
+    /// libraw could not open the file; the camera name comes from EXIF when present.
+    Unsupported(Option<String>),
+}
+
+impl Seen {
+    /// The camera in the metadata of a decoded file.
+    pub fn decoded(meta: Option<&RawMetadata>) -> Seen {
+        match meta {
+            Some(m) if !m.model.is_empty() => Seen::Camera {
+                make: m.make.clone(),
+                model: m.model.clone(),
+            },
+            _ => Seen::Unknown,
+        }
+    }
+
+    /// For a failed decode: `Unsupported` when libraw rejected the format, the usual sign
+    /// of a body newer than the loaded libraw. Only then is the file read for its EXIF.
+    pub fn failed(path: &Path, err: &anyhow::Error) -> Seen {
+        let unsupported = err
+            .chain()
+            .any(|e| e.downcast_ref::<LibRawError>() == Some(&LibRawError::FileUnsupported));
+        if !unsupported {
+            return Seen::Unknown;
+        }
+        Seen::Unsupported(std::fs::read(path).ok().and_then(|d| exif_camera(&d)))
+    }
+}
+
+fn exif_camera(data: &[u8]) -> Option<String> {
+    let exif = rexif::parse_buffer_quiet(data).0.ok()?;
+    let field = |tag: u16| {
+        exif.entries
+            .iter()
+            .find(|e| e.ifd.tag == tag)
+            .map(|e| e.value_more_readable.trim().to_string())
+            .filter(|v| !v.is_empty())
+    };
+    let make = field(0x010f).unwrap_or_default();
+    let model = field(0x0110)?;
+    Some(format!("{} {}", make, model).trim().to_string())
+}
+
+/// Checks the cameras conversions report against libraw's camera list and warns once
+/// about each body it does not know, which usually means the loaded libraw predates it.
+pub struct CameraCheck {
+    list: Vec<String>,
+    version: String,
+    /// Normalized camera names already looked up; unsupported files without an EXIF
+    /// camera are keyed by extension.
+    seen: Mutex<HashSet<String>>,
+    debug: bool,
+}
+
+impl CameraCheck {
+    /// `None` when the loaded libraw cannot list its cameras.
+    pub fn new(debug: bool) -> Option<CameraCheck> {
+        Some(CameraCheck {
+            list: libraw::camera_list().ok()?,
+            version: libraw::version().ok()?,
+            seen: Mutex::new(HashSet::new()),
+            debug,
+        })
+    }
+
+    fn first_time(&self, key: String) -> bool {
+        self.seen
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .insert(key)
+    }
+
+    pub fn check(&self, path: &Path, seen: &Seen) {
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        match seen {
+            Seen::Camera { make, model } => {
+                if !self.first_time(normalize(&format!("{} {}", make, model))) {
+                    return;
+                }
+                let camera = RawMetadata {
+                    make: make.clone(),
+                    model: model.clone(),
+                    ..Default::default()
+                }
+                .camera();
+                if is_listed(&self.list, make, model) {
+                    if self.debug {
+                        println!(
+                            "{} {} ({}) is a supported camera",
+                            blue("[libraw]"),
+                            camera,
+                            name
+                        );
+                    }
+                } else {
+                    eprintln!(
+                        "{}",
+                        pink(format!(
+                            "Warning: {} ({}) is not in the camera list of libraw {}; it may fail or decode incorrectly. See --list-cameras, or update libraw.",
+                            red(camera),
+                            name,
+                            self.version
+                        ))
+                    );
+                }
+            }
+            Seen::Unsupported(camera) => {
+                let key = match camera {
+                    Some(c) => normalize(c),
+                    None => format!(
+                        ".{}",
+                        path.extension()
+                            .unwrap_or_default()
+                            .to_string_lossy()
+                            .to_ascii_lowercase()
+                    ),
+                };
+                if !self.first_time(key) {
+                    return;
+                }
+                eprintln!(
+                    "{}",
+                    pink(format!(
+                        "Warning: libraw {} cannot open {}{}; the camera is probably newer than this libraw. Update libraw to convert it.",
+                        self.version,
+                        name,
+                        camera
+                            .as_ref()
+                            .map(|c| format!(" ({})", red(c)))
+                            .unwrap_or_default()
+                    ))
+                );
+            }
+            Seen::Unknown => {}
+        }
+    }
+}
+
+/// Error for a failed `libraw_open_buffer`, naming the libraw version when the format is
+/// unsupported since that is the usual cause for new bodies.
+pub fn open_error(e: LibRawError) -> anyhow::Error {
+    let err = anyhow::Error::new(e).context("libraw_open_buffer failed");
+    match (e, libraw::version()) {
+        (LibRawError::FileUnsupported, Ok(v)) => err.context(format!(
+            "libraw {} does not support this file; newer cameras need a newer libraw",
+            v
+        )),
+        _ => err,
+    }
+}
+
+/// `--list-cameras`: libraw's supported cameras, optionally those matching `filter`.
+pub fn print_camera_list(filter: Option<&str>) -> anyhow::Result<()> {
+    let list = libraw::camera_list()?;
+    let version = libraw::version()?;
+    let filter = filter.map(normalize).unwrap_or_default();
+    let mut n = 0;
+    for camera in list.iter().filter(|c| normalize(c).contains(&filter)) {
+        println!("{}", camera);
+        n += 1;
+    }
+    println!(
+        "{}",
+        blue(format!(
+            "{} of {} cameras supported by libraw {}",
+            n,
+            list.len(),
+            version
+        ))
+    );
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn list() -> Vec<String> {
+        [
+            "Canon EOS R5",
+            "Nikon Z 8",
+            "Sony ILCE-7M4 (A7 IV)",
+            "Fujifilm X-T5",
+        ]
+        .map(String::from)
+        .to_vec()
+    }
+
+    #[test]
+    fn matches_make_and_model_loosely() {
+        let list = list();
+        assert!(is_listed(&list, "Canon", "EOS R5"));
+        assert!(is_listed(&list, "NIKON CORPORATION", "NIKON Z8"));
+        assert!(is_listed(&list, "FUJIFILM", "X-T5"));
+        assert!(is_listed(&list, "SONY", "ILCE-7M4"));
+    }
+
+    #[test]
+    fn matches_alternate_names() {
+        assert!(is_listed(&list(), "Sony", "A7 IV"));
+    }
+
+    #[test]
+    fn rejects_other_bodies() {
+        let list = list();
+        assert!(!is_listed(&list, "Canon", "EOS R6"));
+        assert!(!is_listed(&list, "Sony", "A7 III"));
+        assert!(!is_listed(&list, "Nikon", ""));
+        assert!(!is_listed(&[], "Canon", "EOS R5"));
+    }
+}
@@ -0,0 +1,112 @@
+use anyhow::{Context, Result};
+use image::{DynamicImage, ImageBuffer, Luma};
+use serde_json::{Value, json};
+use std::os::raw::c_char;
+use std::path::{Path, PathBuf};
+
+use crate::libraw::LibRaw;
+use crate::raw_info::RawInfo;
+use crate::term_colors::{blue, pink, red};
+
+/// Formats that can hold a single-channel 16-bit mosaic.
+pub const CFA_FORMATS: &[&str] = &["pgm", "tiff"];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CfaMode {
+    /// Sensor values as stored, black level included.
+    Raw,
+    /// Black level (per channel and pattern) subtracted.
+    SubtractBlack,
+}
+
+/// libraw channel of the CFA cell at a visible-area position, like libraw's `FC`.
+pub fn channel_at(filters: u32, xtrans: &[[c_char; 6]; 6], row: usize, col: usize) -> usize {
+    if filters == 9 {
+        xtrans[row % 6][col % 6] as usize
+    } else {
+        (filters >> ((((row << 1) & 14) | (col & 1)) << 1) & 3) as usize
+    }
+}
+
+/// Parses `--cfa`: no value or `raw` keeps the black level, `black` subtracts it.
+pub fn parse_cfa_mode(opt: &Option<Option<String>>) -> Result<Option<CfaMode>> {
+    let Some(v) = opt else {
+        return Ok(None);
+    };
+    match v
+        .as_deref()
+        .map(|s| s.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("raw") => Ok(Some(CfaMode::Raw)),
+        Some("black") | Some("subtract") => Ok(Some(CfaMode::SubtractBlack)),
+        Some(_) => anyhow::bail!(
+            "Unknown CFA mode {}. Valid: {}, {}",
+            red(v.as_deref().unwrap_or_default()),
+            blue("raw"),
+            blue("black")
+        ),
+    }
+}
+
+/// Undemosaiced sensor data of an unpacked file, cropped to the visible area, plus the
+/// sidecar describing its pattern and levels.
+pub fn extract_mosaic(
+    lr: &mut LibRaw,
+    mode: CfaMode,
+    debug: bool,
+) -> Result<(DynamicImage, Value)> {
+    let info = RawInfo::from_libraw(lr)?;
+    if info.filters == 0 {
+        anyhow::bail!("file has no color filter array (full color per pixel)");
+    }
+    if debug {
+        println!("{} calling libraw_raw2image...", blue("[cfa]"));
+    }
+    lr.raw2image().context("libraw_raw2image failed")?;
+    if mode == CfaMode::SubtractBlack {
+        if debug {
+            println!("{} subtracting black level", blue("[cfa]"));
+        }
+        lr.subtract_black();
+    }
+    let sizes = lr.sizes()?;
+    let (w, h) = (sizes.iwidth as u32, sizes.iheight as u32);
+    // raw2image leaves only the site's own channel set, so the sum is its value.
+    let samples: Vec<u16> = lr
+        .image_data()?
+        .iter()
+        .map(|px| px.iter().fold(0u16, |a, &v| a.saturating_add(v)))
+        .collect();
+    let buf: ImageBuffer<Luma<u16>, Vec<u16>> = ImageBuffer::from_raw(w, h, samples)
+        .context("libraw image buffer is smaller than its reported size")?;
+    if debug {
+        println!(
+            "{} {}x{} mosaic, pattern {}",
+            blue("[cfa]"),
+            pink(w),
+            pink(h),
+            pink(&info.cfa)
+        );
+    }
+    let sidecar = json!({
+        "width": w,
+        "height": h,
+        "cfa_pattern": info.cfa,
+        "black_subtracted": mode == CfaMode::SubtractBlack,
+        "white_level": lr.color_maximum(),
+        "raw": info.to_json(),
+    });
+    Ok((DynamicImage::ImageLuma16(buf), sidecar))
+}
+
+/// Sidecar path next to an output file: `name.pgm` gets `name.json`.
+pub fn sidecar_path(out_path: &Path) -> PathBuf {
+    out_path.with_extension("json")
+}
+
+pub fn write_sidecar(out_path: &Path, sidecar: &Value) -> Result<()> {
+    let path = sidecar_path(out_path);
+    let text = serde_json::to_string_pretty(sidecar)?;
+    std::fs::write(&path, text).with_context(|| format!("Failed to write {:?}", path))
+}
@@ -0,0 +1,777 @@
+use anyhow::{Context, Result};
+
+use crate::term_colors::{blue, red};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorSpace {
+    Raw,
+    Srgb,
+    Adobe,
+    Wide,
+    ProPhoto,
+    Xyz,
+    Aces,
+}
+
+pub const COLOR_SPACES: &[(&str, ColorSpace)] = &[
+    ("srgb", ColorSpace::Srgb),
+    ("adobe", ColorSpace::Adobe),
+    ("wide", ColorSpace::Wide),
+    ("prophoto", ColorSpace::ProPhoto),
+    ("xyz", ColorSpace::Xyz),
+    ("aces", ColorSpace::Aces),
+    ("raw", ColorSpace::Raw),
+];
+
+const D50: [f64; 3] = [0.9642, 1.0, 0.8249];
+
+impl ColorSpace {
+    pub fn parse(s: &str) -> Result<ColorSpace> {
+        let low = s.trim().to_ascii_lowercase();
+        let low = match low.as_str() {
+            "adobergb" | "adobe-rgb" => "adobe",
+            "widegamut" | "wide-gamut" => "wide",
+            "romm" => "prophoto",
+            other => other,
+        };
+        COLOR_SPACES
+            .iter()
+            .find(|(n, _)| *n == low)
+            .map(|(_, c)| *c)
+            .with_context(|| {
+                let names = COLOR_SPACES
+                    .iter()
+                    .map(|(n, _)| blue(n).to_string())
+                    .collect::<Vec<_>>()
+                    .join(", ");
+                format!("Unknown color space {}. Valid: {}", red(s), names)
+            })
+    }
+
+    /// libraw `output_color` value.
+    pub fn libraw_code(self) -> i32 {
+        match self {
+            ColorSpace::Raw => 0,
+            ColorSpace::Srgb => 1,
+            ColorSpace::Adobe => 2,
+            ColorSpace::Wide => 3,
+            ColorSpace::ProPhoto => 4,
+            ColorSpace::Xyz => 5,
+            ColorSpace::Aces => 6,
+        }
+    }
+
+    fn description(self) -> &'static str {
+        match self {
+            ColorSpace::Raw => "Camera native",
+            ColorSpace::Srgb => "sRGB",
+            ColorSpace::Adobe => "Adobe RGB (1998) compatible",
+            ColorSpace::Wide => "Wide Gamut RGB",
+            ColorSpace::ProPhoto => "ProPhoto RGB",
+            ColorSpace::Xyz => "CIE XYZ (D65)",
+            ColorSpace::Aces => "ACES AP0",
+        }
+    }
+
+    /// RGB to XYZ matrix relative to the space's own white point, or `None` for camera raw.
+    pub fn to_xyz(self) -> Option<([[f64; 3]; 3], [f64; 3])> {
+        let d65 = (0.3127, 0.3290);
+        let d50 = (0.3457, 0.3585);
+        let (prim, white) = match self {
+            ColorSpace::Raw => return None,
+            ColorSpace::Xyz => {
+                let w = xy_to_xyz(d65);
+                let id = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+                return Some((id, w));
+            }
+            ColorSpace::Srgb => ([(0.64, 0.33), (0.30, 0.60), (0.15, 0.06)], d65),
+            ColorSpace::Adobe => ([(0.64, 0.33), (0.21, 0.71), (0.15, 0.06)], d65),
+            ColorSpace::Wide => ([(0.7347, 0.2653), (0.1152, 0.8264), (0.1566, 0.0177)], d50),
+            ColorSpace::ProPhoto => ([(0.7347, 0.2653), (0.1596, 0.8404), (0.0366, 0.0001)], d50),
+            ColorSpace::Aces => (
+                [(0.7347, 0.2653), (0.0, 1.0), (0.0001, -0.0770)],
+                (0.32168, 0.33767),
+            ),
+        };
+        let w = xy_to_xyz(white);
+        let cols = prim.map(xy_to_xyz);
+        let m = [
+            [cols[0][0], cols[1][0], cols[2][0]],
+            [cols[0][1], cols[1][1], cols[2][1]],
+            [cols[0][2], cols[1][2], cols[2][2]],
+        ];
+        let s = mat_vec(invert3(m)?, w);
+        let mut out = m;
+        for row in out.iter_mut() {
+            for (j, c) in row.iter_mut().enumerate() {
+                *c *= s[j];
+            }
+        }
+        Some((out, w))
+    }
+}
+
+fn xy_to_xyz((x, y): (f64, f64)) -> [f64; 3] {
+    [x / y, 1.0, (1.0 - x - y) / y]
+}
+
+pub fn mat_vec(m: [[f64; 3]; 3], v: [f64; 3]) -> [f64; 3] {
+    [0, 1, 2].map(|i| m[i][0] * v[0] + m[i][1] * v[1] + m[i][2] * v[2])
+}
+
+pub fn mat_mul(a: [[f64; 3]; 3], b: [[f64; 3]; 3]) -> [[f64; 3]; 3] {
+    let mut out = [[0.0; 3]; 3];
+    for (i, row) in out.iter_mut().enumerate() {
+        for (j, c) in row.iter_mut().enumerate() {
+            *c = (0..3).map(|k| a[i][k] * b[k][j]).sum();
+        }
+    }
+    out
+}
+
+pub fn invert3(m: [[f64; 3]; 3]) -> Option<[[f64; 3]; 3]> {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    if det.abs() < 1e-12 {
+        return None;
+    }
+    let mut inv = [[0.0; 3]; 3];
+    for (i, row) in inv.iter_mut().enumerate() {
+        for (j, cell) in row.iter_mut().enumerate() {
+            let (r0, r1) = ((j + 1) % 3, (j + 2) % 3);
+            let (c0, c1) = ((i + 1) % 3, (i + 2) % 3);
+            *cell = (m[r0][c0] * m[r1][c1] - m[r0][c1] * m[r1][c0]) / det;
+        }
+    }
+    Some(inv)
+}
+
+/// Bradford chromatic adaptation from `src` white to D50.
+pub fn bradford_to_d50(src: [f64; 3]) -> [[f64; 3]; 3] {
+    const B: [[f64; 3]; 3] = [
+        [0.8951, 0.2664, -0.1614],
+        [-0.7502, 1.7135, 0.0367],
+        [0.0389, -0.0685, 1.0296],
+    ];
+    let s = mat_vec(B, src);
+    let d = mat_vec(B, D50);
+    let scale = [
+        [d[0] / s[0], 0.0, 0.0],
+        [0.0, d[1] / s[1], 0.0],
+        [0.0, 0.0, d[2] / s[2]],
+    ];
+    mat_mul(invert3(B).unwrap(), mat_mul(scale, B))
+}
+
+/// dcraw-style transfer curve: `power` is the exponent applied to linear values (0.45 for
+/// BT.709) and `slope` the gradient of the linear toe segment.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ToneCurve {
+    pub power: f64,
+    pub slope: f64,
+}
+
+impl ToneCurve {
+    /// libraw's default output curve.
+    pub const BT709: ToneCurve = ToneCurve {
+        power: 0.45,
+        slope: 4.5,
+    };
+    pub const SRGB: ToneCurve = ToneCurve {
+        power: 1.0 / 2.4,
+        slope: 12.92,
+    };
+    pub const LINEAR: ToneCurve = ToneCurve {
+        power: 1.0,
+        slope: 1.0,
+    };
+
+    /// Parses a named curve or `P[,TS]`, where `P` is the gamma as given to dcraw's `-g`
+    /// (2.222 for BT.709) and `TS` the toe slope (0 for a pure power curve).
+    pub fn parse(s: &str) -> Result<ToneCurve> {
+        let low = s.trim().to_ascii_lowercase();
+        match low.as_str() {
+            "srgb" => return Ok(ToneCurve::SRGB),
+            "bt709" | "rec709" => return Ok(ToneCurve::BT709),
+            "linear" => return Ok(ToneCurve::LINEAR),
+            _ => {}
+        }
+        let (p, ts) = match low.split_once(',') {
+            Some((p, ts)) => (p, ts),
+            None => (low.as_str(), "0"),
+        };
+        let parse = |t: &str| {
+            t.trim()
+                .parse::<f64>()
+                .ok()
+                .filter(|v| v.is_finite())
+                .with_context(|| format!("Invalid gamma value {}", red(t.trim())))
+        };
+        let (p, ts) = (parse(p)?, parse(ts)?);
+        if p <= 0.0 || ts < 0.0 {
+            anyhow::bail!(
+                "Gamma must be positive and the toe slope non-negative. Use {}, {}, {} or P[,TS]",
+                blue("srgb"),
+                blue("bt709"),
+                blue("linear")
+            );
+        }
+        Ok(ToneCurve {
+            power: 1.0 / p,
+            slope: ts,
+        })
+    }
+
+    /// Breakpoint and offset of the power segment, solved like dcraw's `gamma_curve`.
+    fn segments(&self) -> (f64, f64) {
+        let (p, ts) = (self.power, self.slope);
+        if self.is_linear() || ts == 0.0 || (ts - 1.0) * (p - 1.0) > 0.0 {
+            return (0.0, 0.0);
+        }
+        let mut bnd = [0.0f64; 2];
+        bnd[(ts >= 1.0) as usize] = 1.0;
+        let mut g2 = 0.0;
+        for _ in 0..48 {
+            g2 = (bnd[0] + bnd[1]) / 2.0;
+            let above = if p != 0.0 {
+                ((g2 / ts).powf(-p) - 1.0) / p - 1.0 / g2 > -1.0
+            } else {
+                g2 / (1.0 - 1.0 / g2).exp() < ts
+            };
+            bnd[above as usize] = g2;
+        }
+        let g3 = g2 / ts;
+        let g4 = if p != 0.0 { g2 * (1.0 / p - 1.0) } else { 0.0 };
+        (g3, g4)
+    }
+
+    pub fn is_linear(&self) -> bool {
+        self.power == 1.0 && (self.slope == 1.0 || self.slope == 0.0)
+    }
+
+    /// Linear [0, 1] to encoded [0, 1].
+    pub fn encode(&self, v: f64) -> f64 {
+        let v = v.clamp(0.0, 1.0);
+        let (g3, g4) = self.segments();
+        if v < g3 {
+            v * self.slope
+        } else {
+            v.powf(self.power) * (1.0 + g4) - g4
+        }
+    }
+
+    /// Encoded [0, 1] to linear [0, 1].
+    pub fn decode(&self, v: f64) -> f64 {
+        let v = v.clamp(0.0, 1.0);
+        let (g3, g4) = self.segments();
+        if v < g3 * self.slope {
+            v / self.slope
+        } else {
+            ((v + g4) / (1.0 + g4)).powf(1.0 / self.power)
+        }
+    }
+
+    /// Encoding lookup table for 8 and 16-bit outputs.
+    pub fn encode_table(&self, size: usize) -> Vec<f32> {
+        (0..size)
+            .map(|i| self.encode(i as f64 / (size - 1) as f64) as f32)
+            .collect()
+    }
+}
+
+/// Color encoding of integer outputs: primaries plus transfer curve.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct ColorProfile {
+    pub space: ColorSpace,
+    pub curve: ToneCurve,
+}
+
+impl ColorProfile {
+    pub const SRGB: ColorProfile = ColorProfile {
+        space: ColorSpace::Srgb,
+        curve: ToneCurve::SRGB,
+    };
+
+    /// Builds an ICC v2 matrix/TRC display profile, or `None` for camera-native color.
+    pub fn icc(&self) -> Option<Vec<u8>> {
+        let (to_xyz, white) = self.space.to_xyz()?;
+        let to_pcs = mat_mul(bradford_to_d50(white), to_xyz);
+
+        let desc = format!("fempeg {}", self.space.description());
+        let mut tags: Vec<([u8; 4], Vec<u8>)> = vec![
+            (*b"desc", desc_tag(&desc)),
+            (*b"cprt", text_tag("No copyright, use freely")),
+            (*b"wtpt", xyz_tag(D50)),
+            (*b"chad", sf32_tag(bradford_to_d50(white))),
+        ];
+        for (i, sig) in [b"rXYZ", b"gXYZ", b"bXYZ"].iter().enumerate() {
+            tags.push((**sig, xyz_tag([to_pcs[0][i], to_pcs[1][i], to_pcs[2][i]])));
+        }
+        let trc = curv_tag(&self.curve);
+        for sig in [b"rTRC", b"gTRC", b"bTRC"] {
+            tags.push((*sig, trc.clone()));
+        }
+
+        let table_len = 4 + 12 * tags.len();
+        let mut offset = 128 + table_len;
+        let mut table = Vec::with_capacity(table_len);
+        let mut data = Vec::new();
+        table.extend_from_slice(&(tags.len() as u32).to_be_bytes());
+        for (sig, body) in &tags {
+            table.extend_from_slice(sig);
+            table.extend_from_slice(&(offset as u32).to_be_bytes());
+            table.extend_from_slice(&(body.len() as u32).to_be_bytes());
+            data.extend_from_slice(body);
+            while data.len() % 4 != 0 {
+                data.push(0);
+            }
+            offset = 128 + table_len + data.len();
+        }
+
+        let size = 128 + table.len() + data.len();
+        let mut out = Vec::with_capacity(size);
+        out.extend_from_slice(&(size as u32).to_be_bytes());
+        out.extend_from_slice(&[0; 4]);
+        out.extend_from_slice(&0x0210_0000u32.to_be_bytes());
+        out.extend_from_slice(b"mntrRGB XYZ ");
+        for v in [2024u16, 1, 1, 0, 0, 0] {
+            out.extend_from_slice(&v.to_be_bytes());
+        }
+        out.extend_from_slice(b"acsp");
+        out.extend_from_slice(&[0; 24]);
+        out.extend_from_slice(&0u32.to_be_bytes());
+        for v in D50 {
+            out.extend_from_slice(&s15f16(v));
+        }
+        out.resize(128, 0);
+        out.extend_from_slice(&table);
+        out.extend_from_slice(&data);
+        Some(out)
+    }
+}
+
+fn s15f16(v: f64) -> [u8; 4] {
+    ((v * 65536.0).round() as i32).to_be_bytes()
+}
+
+fn xyz_tag(v: [f64; 3]) -> Vec<u8> {
+    let mut out = b"XYZ \0\0\0\0".to_vec();
+    for c in v {
+        out.extend_from_slice(&s15f16(c));
+    }
+    out
+}
+
+fn sf32_tag(m: [[f64; 3]; 3]) -> Vec<u8> {
+    let mut out = b"sf32\0\0\0\0".to_vec();
+    for row in m {
+        for c in row {
+            out.extend_from_slice(&s15f16(c));
+        }
+    }
+    out
+}
+
+fn text_tag(s: &str) -> Vec<u8> {
+    let mut out = b"text\0\0\0\0".to_vec();
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+    out
+}
+
+fn desc_tag(s: &str) -> Vec<u8> {
+    let mut out = b"desc\0\0\0\0".to_vec();
+    out.extend_from_slice(&(s.len() as u32 + 1).to_be_bytes());
+    out.extend_from_slice(s.as_bytes());
+    out.push(0);
+    // Empty Unicode and ScriptCode records.
+    out.extend_from_slice(&[0; 8]);
+    out.extend_from_slice(&[0; 3]);
+    out.extend_from_slice(&[0; 67]);
+    out
+}
+
+fn curv_tag(curve: &ToneCurve) -> Vec<u8> {
+    let mut out = b"curv\0\0\0\0".to_vec();
+    if curve.is_linear() {
+        out.extend_from_slice(&0u32.to_be_bytes());
+        return out;
+    }
+    const POINTS: usize = 1024;
+    out.extend_from_slice(&(POINTS as u32).to_be_bytes());
+    // The TRC maps device (encoded) values to linear light.
+    for i in 0..POINTS {
+        let lin = curve.decode(i as f64 / (POINTS - 1) as f64);
+        out.extend_from_slice(&((lin * 65535.0).round() as u16).to_be_bytes());
+    }
+    out
+}
+
+fn read_u32(b: &[u8], at: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(b.get(at..at + 4)?.try_into().ok()?))
+}
+
+fn read_uint(b: &[u8], at: usize, size: usize) -> Option<u64> {
+    let bytes = b.get(at..at + size)?;
+    Some(bytes.iter().fold(0u64, |acc, &x| (acc << 8) | x as u64))
+}
+
+fn write_uint(b: &mut [u8], at: usize, size: usize, v: u64) -> Option<()> {
+    let bytes = b.get_mut(at..at + size)?;
+    for (i, x) in bytes.iter_mut().enumerate() {
+        *x = (v >> (8 * (size - 1 - i))) as u8;
+    }
+    Some(())
+}
+
+fn read_u8(b: &[u8], at: usize) -> Option<u8> {
+    b.get(at).copied()
+}
+
+/// Size and header length of the box at `pos`, which must end by `end`. Size 0 ("to the
+/// end of the file") is rejected along with sizes smaller than the header.
+fn box_size(b: &[u8], pos: usize, end: usize) -> Option<(usize, usize)> {
+    let (size, header) = match read_u32(b, pos)? {
+        1 => (usize::try_from(read_uint(b, pos + 8, 8)?).ok()?, 16),
+        s => (s as usize, 8),
+    };
+    if size < header.max(8) || size > end.checked_sub(pos)? {
+        return None;
+    }
+    Some((size, header))
+}
+
+/// Finds the direct child box `kind` within `range` and returns (start, size, header_len).
+fn find_box(b: &[u8], range: (usize, usize), kind: &[u8; 4]) -> Option<(usize, usize, usize)> {
+    let (mut pos, end) = range;
+    while pos + 8 <= end {
+        let (size, header) = box_size(b, pos, end)?;
+        if b.get(pos + 4..pos + 8)? == kind {
+            return Some((pos, size, header));
+        }
+        pos += size;
+    }
+    None
+}
+
+/// Adds an ICC `colr` property to the primary item of an AVIF file.
+///
+/// The image encoder only writes an nclx `colr` box, so the profile is spliced into
+/// `meta/iprp/ipco`, linked in `ipma`, and `iloc` offsets behind the insertion are shifted.
+pub fn embed_icc_in_avif(avif: &[u8], icc: &[u8]) -> Result<Vec<u8>> {
+    let bad = || anyhow::anyhow!("unexpected AVIF box layout");
+    let meta = find_box(avif, (0, avif.len()), b"meta").ok_or_else(bad)?;
+    // meta is a full box: 4 bytes of version/flags precede its children.
+    let meta_children = (meta.0 + meta.2 + 4, meta.0 + meta.1);
+    let pitm = find_box(avif, meta_children, b"pitm").ok_or_else(bad)?;
+    let pitm_version = read_u8(avif, pitm.0 + pitm.2).ok_or_else(bad)?;
+    let primary = if pitm_version == 0 {
+        read_uint(avif, pitm.0 + pitm.2 + 4, 2)
+    } else {
+        read_uint(avif, pitm.0 + pitm.2 + 4, 4)
+    }
+    .ok_or_else(bad)? as u32;
+    let iprp = find_box(avif, meta_children, b"iprp").ok_or_else(bad)?;
+    let iprp_children = (iprp.0 + iprp.2, iprp.0 + iprp.1);
+    let ipco = find_box(avif, iprp_children, b"ipco").ok_or_else(bad)?;
+    let ipma = find_box(avif, iprp_children, b"ipma").ok_or_else(bad)?;
+    let iloc = find_box(avif, meta_children, b"iloc").ok_or_else(bad)?;
+    if [meta.2, iprp.2, ipco.2, ipma.2].iter().any(|h| *h != 8) {
+        anyhow::bail!("large AVIF boxes are not supported");
+    }
+
+    // Count existing properties to get the new 1-based property index.
+    let mut count = 0usize;
+    let mut pos = ipco.0 + ipco.2;
+    while pos + 8 <= ipco.0 + ipco.1 {
+        pos += box_size(avif, pos, ipco.0 + ipco.1).ok_or_else(bad)?.0;
+        count += 1;
+    }
+    let prop_index = count + 1;
+
+    let mut colr = Vec::with_capacity(12 + icc.len());
+    colr.extend_from_slice(&(12 + icc.len() as u32).to_be_bytes());
+    colr.extend_from_slice(b"colrprof");
+    colr.extend_from_slice(icc);
+
+    // Rebuild ipma with the extra association for the primary item.
+    let ipma_version = read_u8(avif, ipma.0 + 8).ok_or_else(bad)?;
+    let ipma_flags = read_uint(avif, ipma.0 + 9, 3).ok_or_else(bad)?;
+    let wide_ids = ipma_version >= 1;
+    let wide_props = ipma_flags & 1 == 1;
+    if !wide_props && prop_index > 0x7f {
+        anyhow::bail!("too many AVIF properties");
+    }
+    let mut p = ipma.0 + 12;
+    let entries = read_u32(avif, p).ok_or_else(bad)?;
+    p += 4;
+    let mut new_ipma_body = Vec::new();
+    new_ipma_body.extend_from_slice(avif.get(ipma.0 + 8..ipma.0 + 16).ok_or_else(bad)?);
+    for _ in 0..entries {
+        let id_len = if wide_ids { 4 } else { 2 };
+        let id = read_uint(avif, p, id_len).ok_or_else(bad)? as u32;
+        let n = read_u8(avif, p + id_len).ok_or_else(bad)? as usize;
+        let assoc_len = if wide_props { 2 } else { 1 };
+        let assoc_end = p + id_len + 1 + n * assoc_len;
+        if assoc_end > ipma.0 + ipma.1 {
+            return Err(bad());
+        }
+        if id == primary {
+            if n == 255 {
+                anyhow::bail!("too many AVIF property associations");
+            }
+            new_ipma_body.extend_from_slice(&avif[p..p + id_len]);
+            new_ipma_body.push(n as u8 + 1);
+            new_ipma_body.extend_from_slice(&avif[p + id_len + 1..assoc_end]);
+            if wide_props {
+                new_ipma_body.extend_from_slice(&(prop_index as u16).to_be_bytes());
+            } else {
+                new_ipma_body.push(prop_index as u8);
+            }
+        } else {
+            new_ipma_body.extend_from_slice(&avif[p..assoc_end]);
+        }
+        p = assoc_end;
+    }
+    let grow = colr.len() + (new_ipma_body.len() + 8 - ipma.1);
+
+    let mut out = Vec::with_capacity(avif.len() + grow);
+    let ipco_end = ipco.0 + ipco.1;
+    let (first, second) = if ipco_end <= ipma.0 {
+        (ipco_end, ipma.0)
+    } else {
+        anyhow::bail!("ipma before ipco is not supported");
+    };
+    out.extend_from_slice(&avif[..first]);
+    out.extend_from_slice(&colr);
+    out.extend_from_slice(&avif[first..second]);
+    out.extend_from_slice(&((new_ipma_body.len() + 8) as u32).to_be_bytes());
+    out.extend_from_slice(b"ipma");
+    out.extend_from_slice(&new_ipma_body);
+    out.extend_from_slice(&avif[ipma.0 + ipma.1..]);
+
+    for (start, size) in [(meta.0, meta.1), (iprp.0, iprp.1), (ipco.0, ipco.1)] {
+        let grown = if start == ipco.0 { colr.len() } else { grow };
+        let size =
+            u32::try_from(size + grown).map_err(|_| anyhow::anyhow!("AVIF metadata too large"))?;
+        write_uint(&mut out, start, 4, size as u64).ok_or_else(bad)?;
+    }
+
+    // iloc sits before iprp in files written by the encoder, so its own position is stable;
+    // extents pointing past meta move by `grow`.
+    if iloc.0 > ipco.0 {
+        anyhow::bail!("iloc after iprp is not supported");
+    }
+    let meta_end = meta.0 + meta.1;
+    let mut q = iloc.0 + iloc.2;
+    let version = read_u8(&out, q).ok_or_else(bad)?;
+    q += 4;
+    let sizes = read_u8(&out, q).ok_or_else(bad)?;
+    let offset_size = (sizes >> 4) as usize;
+    let length_size = (sizes & 0xf) as usize;
+    let sizes = read_u8(&out, q + 1).ok_or_else(bad)?;
+    let base_offset_size = (sizes >> 4) as usize;
+    let index_size = if version >= 1 {
+        (sizes & 0xf) as usize
+    } else {
+        0
+    };
+    if [offset_size, length_size, base_offset_size, index_size]
+        .iter()
+        .any(|s| ![0, 4, 8].contains(s))
+    {
+        return Err(bad());
+    }
+    q += 2;
+    let (item_count, id_len) = if version < 2 {
+        (read_uint(&out, q, 2).ok_or_else(bad)?, 2)
+    } else {
+        (read_uint(&out, q, 4).ok_or_else(bad)?, 4)
+    };
+    q += id_len;
+    for _ in 0..item_count {
+        q += id_len;
+        if version >= 1 {
+            let method = read_uint(&out, q, 2).ok_or_else(bad)? & 0xf;
+            if method != 0 {
+                anyhow::bail!("AVIF items stored outside the file are not supported");
+            }
+            q += 2;
+        }
+        q += 2;
+        let base = read_uint(&out, q, base_offset_size).ok_or_else(bad)?;
+        let base_shifts = base_offset_size > 0 && base as usize >= meta_end;
+        if base_shifts {
+            let base = base.checked_add(grow as u64).ok_or_else(bad)?;
+            write_uint(&mut out, q, base_offset_size, base).ok_or_else(bad)?;
+        }
+        q += base_offset_size;
+        let extents = read_uint(&out, q, 2).ok_or_else(bad)?;
+        q += 2;
+        for _ in 0..extents {
+            q += index_size;
+            let off = read_uint(&out, q, offset_size).ok_or_else(bad)?;
+            if !base_shifts && offset_size > 0 && base.saturating_add(off) >= meta_end as u64 {
+                let off = off.checked_add(grow as u64).ok_or_else(bad)?;
+                write_uint(&mut out, q, offset_size, off).ok_or_else(bad)?;
+            }
+            q += offset_size + length_size;
+        }
+    }
+    Ok(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn boxed(kind: &[u8; 4], body: &[u8]) -> Vec<u8> {
+        let mut out = ((8 + body.len()) as u32).to_be_bytes().to_vec();
+        out.extend_from_slice(kind);
+        out.extend_from_slice(body);
+        out
+    }
+
+    /// Minimal still AVIF layout: ftyp, meta(pitm, iloc, iprp(ipco(ispe), ipma)), mdat.
+    fn sample_avif(payload: &[u8]) -> (Vec<u8>, usize) {
+        let ftyp = boxed(b"ftyp", b"avif\0\0\0\0");
+        let pitm = boxed(b"pitm", &[0, 0, 0, 0, 0, 1]);
+        let ispe = boxed(b"ispe", &[0, 0, 0, 0, 0, 0, 0, 4, 0, 0, 0, 4]);
+        let ipco = boxed(b"ipco", &ispe);
+        let ipma = boxed(b"ipma", &[0, 0, 0, 0, 0, 0, 0, 1, 0, 1, 1, 0x81]);
+        let iprp = boxed(b"iprp", &[ipco, ipma].concat());
+        let iloc_len = 8 + 4 + 2 + 2 + 2 + 2 + 2 + 8;
+        let meta_len = 8 + 4 + pitm.len() + iloc_len + iprp.len();
+        let data_at = ftyp.len() + meta_len + 8;
+
+        let mut iloc = vec![0, 0, 0, 0, 0x44, 0x00, 0, 1, 0, 1, 0, 0, 0, 1];
+        iloc.extend_from_slice(&(data_at as u32).to_be_bytes());
+        iloc.extend_from_slice(&(payload.len() as u32).to_be_bytes());
+        let iloc = boxed(b"iloc", &iloc);
+        let meta = boxed(b"meta", &[&[0, 0, 0, 0][..], &pitm, &iloc, &iprp].concat());
+        assert_eq!(meta.len(), meta_len);
+        let file = [ftyp, meta, boxed(b"mdat", payload)].concat();
+        (file, data_at)
+    }
+
+    fn tag<'a>(icc: &'a [u8], sig: &[u8; 4]) -> &'a [u8] {
+        let count = read_u32(icc, 128).unwrap() as usize;
+        let entry = (0..count)
+            .map(|i| 132 + 12 * i)
+            .find(|&at| &icc[at..at + 4] == sig)
+            .unwrap();
+        let offset = read_u32(icc, entry + 4).unwrap() as usize;
+        let len = read_u32(icc, entry + 8).unwrap() as usize;
+        &icc[offset..offset + len]
+    }
+
+    fn xyz(tag: &[u8]) -> [f64; 3] {
+        let v =
+            |at: usize| i32::from_be_bytes(tag[at..at + 4].try_into().unwrap()) as f64 / 65536.0;
+        [v(8), v(12), v(16)]
+    }
+
+    #[test]
+    fn icc_header_and_tags() {
+        let icc = ColorProfile::SRGB.icc().unwrap();
+        assert_eq!(read_u32(&icc, 0).unwrap() as usize, icc.len());
+        assert_eq!(&icc[36..40], b"acsp");
+        assert_eq!(read_u32(&icc, 128), Some(10));
+        assert_eq!(&tag(&icc, b"rTRC")[..4], b"curv");
+        assert!(String::from_utf8_lossy(tag(&icc, b"desc")).contains("fempeg"));
+    }
+
+    #[test]
+    fn icc_primaries_add_up_to_d50() {
+        for &(name, space) in COLOR_SPACES {
+            let profile = ColorProfile {
+                space,
+                curve: ToneCurve::BT709,
+            };
+            // XYZ's white is D65 itself rather than RGB (1, 1, 1).
+            if space == ColorSpace::Xyz {
+                continue;
+            }
+            let Some(icc) = profile.icc() else {
+                assert_eq!(space, ColorSpace::Raw);
+                continue;
+            };
+            let [r, g, b] = [b"rXYZ", b"gXYZ", b"bXYZ"].map(|s| xyz(tag(&icc, s)));
+            for c in 0..3 {
+                let white = r[c] + g[c] + b[c];
+                assert!(
+                    (white - D50[c]).abs() < 2e-3,
+                    "{name}: {white} vs {}",
+                    D50[c]
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn avif_gets_colr_and_shifted_extents() {
+        let payload = b"AV1 payload";
+        let (avif, data_at) = sample_avif(payload);
+        let icc = ColorProfile::SRGB.icc().unwrap();
+        let out = embed_icc_in_avif(&avif, &icc).unwrap();
+
+        let grow = 12 + icc.len() + 1;
+        assert_eq!(out.len(), avif.len() + grow);
+        let meta = find_box(&out, (0, out.len()), b"meta").unwrap();
+        assert_eq!(
+            meta.1,
+            find_box(&avif, (0, avif.len()), b"meta").unwrap().1 + grow
+        );
+
+        let iprp = find_box(&out, (meta.0 + 12, meta.0 + meta.1), b"iprp").unwrap();
+        let ipco = find_box(&out, (iprp.0 + 8, iprp.0 + iprp.1), b"ipco").unwrap();
+        let colr = find_box(&out, (ipco.0 + 8, ipco.0 + ipco.1), b"colr").unwrap();
+        assert_eq!(&out[colr.0 + 8..colr.0 + 12], b"prof");
+        assert_eq!(&out[colr.0 + 12..colr.0 + colr.1], &icc[..]);
+
+        let ipma = find_box(&out, (iprp.0 + 8, iprp.0 + iprp.1), b"ipma").unwrap();
+        assert_eq!(&out[ipma.0 + 16..ipma.0 + ipma.1], &[0, 1, 2, 0x81, 2]);
+
+        let iloc = find_box(&out, (meta.0 + 12, meta.0 + meta.1), b"iloc").unwrap();
+        let offset = read_u32(&out, iloc.0 + 22).unwrap() as usize;
+        assert_eq!(offset, data_at + grow);
+        assert_eq!(&out[offset..offset + payload.len()], payload);
+    }
+
+    #[test]
+    fn avif_zero_sized_boxes_are_rejected() {
+        let (mut avif, _) = sample_avif(b"AV1");
+        let icc = ColorProfile::SRGB.icc().unwrap();
+        let ispe = avif.windows(4).position(|w| w == b"ispe").unwrap() - 4;
+        avif[ispe..ispe + 4].copy_from_slice(&0u32.to_be_bytes());
+        assert!(embed_icc_in_avif(&avif, &icc).is_err());
+    }
+
+    #[test]
+    fn avif_truncated_or_inflated_boxes_are_errors() {
+        let (avif, _) = sample_avif(b"AV1");
+        let icc = ColorProfile::SRGB.icc().unwrap();
+        let mdat = avif.windows(4).position(|w| w == b"mdat").unwrap() - 4;
+        for len in 0..mdat {
+            assert!(
+                embed_icc_in_avif(&avif[..len], &icc).is_err(),
+                "truncated at {len}"
+            );
+        }
+        // An ipma entry count far past the end of the box.
+        let mut inflated = avif.clone();
+        let ipma = avif.windows(4).position(|w| w == b"ipma").unwrap() + 8;
+        inflated[ipma..ipma + 4].copy_from_slice(&0xffffu32.to_be_bytes());
+        assert!(embed_icc_in_avif(&inflated, &icc).is_err());
+    }
+
+    #[test]
+    fn raw_space_has_no_profile() {
+        let profile = ColorProfile {
+            space: ColorSpace::Raw,
+            curve: ToneCurve::SRGB,
+        };
+        assert!(profile.icc().is_none());
+    }
+}
@@ -0,0 +1,180 @@
+use anyhow::Result;
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+
+use crate::term_colors::{blue, pink, red};
+use crate::{DecodeOptions, Decoded};
+use crate::{init_libraw, libraw_ffi};
+
+/// A backend that turns a raw file into a decoded image.
+pub trait RawDecoder: Sync {
+    fn name(&self) -> &'static str;
+    fn decode(&self, path: &Path, opts: &DecodeOptions, debug: bool) -> Result<Decoded>;
+}
+
+pub struct LibRawDecoder {
+    /// Held around the libraw calls of each decode when the library is not reentrant.
+    lock: Option<Mutex<()>>,
+}
+
+static LIBRAW: LibRawDecoder = LibRawDecoder { lock: None };
+static LIBRAW_SERIAL: LibRawDecoder = LibRawDecoder {
+    lock: Some(Mutex::new(())),
+};
+
+impl RawDecoder for LibRawDecoder {
+    fn name(&self) -> &'static str {
+        "libraw"
+    }
+
+    fn decode(&self, path: &Path, opts: &DecodeOptions, debug: bool) -> Result<Decoded> {
+        crate::load_with_libraw(path, opts, self.lock.as_ref(), debug)
+    }
+}
+
+/// libraw for `threads` concurrent decodes: serialized unless the loaded build is
+/// thread-safe, so that the rest of the pipeline can stay parallel.
+fn libraw(threads: usize, debug: bool) -> &'static dyn RawDecoder {
+    if threads <= 1 || init_libraw::thread_safe() {
+        if debug && threads > 1 {
+            println!(
+                "{} thread-safe libraw, decoding in parallel",
+                blue("[decoder]")
+            );
+        }
+        return &LIBRAW;
+    }
+    eprintln!(
+        "{}",
+        pink(format!(
+            "{} is not the thread-safe libraw_r build; decoding one file at a time (install libraw_r or pass {} to parallelize decoding)",
+            init_libraw::loaded_path().map_or("libraw".into(), |p| p.display().to_string()),
+            blue("--libraw")
+        ))
+    );
+    &LIBRAW_SERIAL
+}
+
+/// Pure-Rust DNG decoder, built with the `native` feature.
+#[cfg(feature = "native")]
+pub struct NativeDecoder;
+
+#[cfg(feature = "native")]
+impl RawDecoder for NativeDecoder {
+    fn name(&self) -> &'static str {
+        "native"
+    }
+
+    fn decode(&self, path: &Path, opts: &DecodeOptions, debug: bool) -> Result<Decoded> {
+        crate::native::decode(path, opts, debug)
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DecoderChoice {
+    LibRaw,
+    Native,
+    /// libraw when it loads, the native decoder otherwise.
+    Auto,
+}
+
+pub fn parse_decoder(s: &str) -> Result<DecoderChoice> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "libraw" => Ok(DecoderChoice::LibRaw),
+        "native" => Ok(DecoderChoice::Native),
+        "auto" => Ok(DecoderChoice::Auto),
+        _ => anyhow::bail!(
+            "Unknown decoder {}. Valid: {}, {}, {}",
+            red(s),
+            blue("libraw"),
+            blue("native"),
+            blue("auto")
+        ),
+    }
+}
+
+#[cfg(feature = "native")]
+fn native() -> Result<&'static dyn RawDecoder> {
+    Ok(&NativeDecoder)
+}
+
+#[cfg(not(feature = "native"))]
+fn native() -> Result<&'static dyn RawDecoder> {
+    anyhow::bail!(
+        "this fempeg was built without the native decoder (cargo feature {})",
+        blue("native")
+    )
+}
+
+/// Resolves `--decoder` for `threads` concurrent decodes; `auto` only falls back when libraw
+/// or one of its symbols is missing.
+pub fn select(
+    choice: DecoderChoice,
+    threads: usize,
+    debug: bool,
+) -> Result<&'static dyn RawDecoder> {
+    let decoder = match choice {
+        DecoderChoice::LibRaw => {
+            libraw_ffi::get_api()?;
+            libraw(threads, debug)
+        }
+        DecoderChoice::Native => native()?,
+        DecoderChoice::Auto => match libraw_ffi::get_api() {
+            Ok(_) => libraw(threads, debug),
+            Err(e) => match native() {
+                Ok(d) => {
+                    eprintln!(
+                        "{}",
+                        pink("libraw is unavailable, using the native decoder (DNG only)")
+                    );
+                    if debug {
+                        eprintln!("{} {}", blue("[decoder]"), e);
+                    }
+                    d
+                }
+                Err(_) => return Err(e),
+            },
+        },
+    };
+    if debug {
+        println!("{} using {}", blue("[decoder]"), pink(decoder.name()));
+    }
+    Ok(decoder)
+}
+
+/// Fails once before the batch when the native decoder would have to read inputs that are
+/// not DNG, rather than reporting each of them as failed.
+pub fn check_inputs(decoder: &dyn RawDecoder, inputs: &[PathBuf]) -> Result<()> {
+    if decoder.name() != "native" {
+        return Ok(());
+    }
+    let is_dng = |p: &&PathBuf| p.extension().is_some_and(|e| e.eq_ignore_ascii_case("dng"));
+    let others: Vec<&PathBuf> = inputs.iter().filter(|p| !is_dng(p)).collect();
+    let Some(first) = others.first() else {
+        return Ok(());
+    };
+    anyhow::bail!(
+        "The native decoder only reads DNG, but {} of {} inputs {} not (e.g. {}). Install libraw or point {} at it to convert them",
+        red(others.len()),
+        inputs.len(),
+        if others.len() == 1 { "is" } else { "are" },
+        red(first.display()),
+        blue("--libraw")
+    )
+}
+
+#[cfg(all(test, feature = "native"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn native_rejects_other_raw_inputs_up_front() {
+        let dngs = [PathBuf::from("a.DNG"), PathBuf::from("b.dng")];
+        assert!(check_inputs(&NativeDecoder, &dngs).is_ok());
+        let mixed = [PathBuf::from("a.dng"), PathBuf::from("b.NEF")];
+        let err = check_inputs(&NativeDecoder, &mixed)
+            .unwrap_err()
+            .to_string();
+        assert!(err.contains("b.NEF"), "{err}");
+    }
+}
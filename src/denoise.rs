@@ -0,0 +1,248 @@
+use anyhow::Result;
+use image::{DynamicImage, Rgb32FImage};
+use rayon::prelude::*;
+
+use crate::term_colors::{blue, pink, red};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DenoisePreset {
+    Off,
+    Low,
+    Medium,
+    High,
+    /// Picked from the file's ISO.
+    Auto,
+}
+
+/// What a preset turns on in libraw and in the Rust filter.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DenoiseSettings {
+    /// libraw wavelet threshold, in raw units.
+    pub wavelet: f32,
+    /// libraw FBDD noise reduction: 0 off, 1 light, 2 full.
+    pub fbdd: i32,
+    /// Luma filter strength, in multiples of the estimated noise.
+    pub luma: f32,
+    /// Chroma filter strength, in multiples of the estimated noise.
+    pub chroma: f32,
+}
+
+pub fn parse_denoise(opt: &Option<Option<String>>) -> Result<DenoisePreset> {
+    let Some(v) = opt else {
+        return Ok(DenoisePreset::Off);
+    };
+    match v
+        .as_deref()
+        .map(|s| s.trim().to_ascii_lowercase())
+        .as_deref()
+    {
+        None | Some("auto") => Ok(DenoisePreset::Auto),
+        Some("off") | Some("none") => Ok(DenoisePreset::Off),
+        Some("low") => Ok(DenoisePreset::Low),
+        Some("medium") => Ok(DenoisePreset::Medium),
+        Some("high") => Ok(DenoisePreset::High),
+        Some(_) => anyhow::bail!(
+            "Unknown denoise preset {}. Valid: {}, {}, {}, {}, {}",
+            red(v.as_deref().unwrap_or_default()),
+            blue("auto"),
+            blue("off"),
+            blue("low"),
+            blue("medium"),
+            blue("high")
+        ),
+    }
+}
+
+impl DenoisePreset {
+    /// Resolves `Auto` from the ISO; files without one get `Low`.
+    pub fn resolve(self, iso: Option<f32>) -> DenoisePreset {
+        match (self, iso) {
+            (DenoisePreset::Auto, Some(iso)) if iso <= 400.0 => DenoisePreset::Off,
+            (DenoisePreset::Auto, Some(iso)) if iso <= 1600.0 => DenoisePreset::Low,
+            (DenoisePreset::Auto, Some(iso)) if iso <= 6400.0 => DenoisePreset::Medium,
+            (DenoisePreset::Auto, Some(_)) => DenoisePreset::High,
+            (DenoisePreset::Auto, None) => DenoisePreset::Low,
+            (preset, _) => preset,
+        }
+    }
+
+    /// Settings of a resolved preset, `None` when it does nothing.
+    pub fn settings(self) -> Option<DenoiseSettings> {
+        let (wavelet, fbdd, luma, chroma) = match self {
+            DenoisePreset::Off | DenoisePreset::Auto => return None,
+            DenoisePreset::Low => (50.0, 0, 0.0, 1.0),
+            DenoisePreset::Medium => (150.0, 1, 0.7, 2.0),
+            DenoisePreset::High => (400.0, 2, 1.2, 3.0),
+        };
+        Some(DenoiseSettings {
+            wavelet,
+            fbdd,
+            luma,
+            chroma,
+        })
+    }
+}
+
+const LUMA_RADIUS: i64 = 2;
+/// Chroma noise is coarser, so its window is larger but sampled every other pixel.
+const CHROMA_RADIUS: i64 = 4;
+
+/// Fast noise estimate (Immerkær 1996) of a single channel, from every fourth row.
+fn estimate_noise(plane: &[f32], w: usize, h: usize) -> f32 {
+    if w < 3 || h < 3 {
+        return 0.0;
+    }
+    let mut sum = 0f64;
+    let mut n = 0u64;
+    for y in (1..h - 1).step_by(4) {
+        for x in 1..w - 1 {
+            let p = |dx: usize, dy: usize| plane[(y + dy - 1) * w + x + dx - 1] as f64;
+            let v = p(0, 0) - 2.0 * p(1, 0) + p(2, 0) - 2.0 * p(0, 1) + 4.0 * p(1, 1)
+                - 2.0 * p(2, 1)
+                + p(0, 2)
+                - 2.0 * p(1, 2)
+                + p(2, 2);
+            sum += v.abs();
+            n += 1;
+        }
+    }
+    ((std::f64::consts::PI / 2.0).sqrt() * sum / (6.0 * n.max(1) as f64)) as f32
+}
+
+/// Joint bilateral filter of `planes[targets]`, with range weights over every plane whose
+/// `sigma_r` is non-zero.
+fn bilateral(
+    planes: &[Vec<f32>; 3],
+    w: usize,
+    h: usize,
+    radius: i64,
+    step: usize,
+    sigma_r: [f32; 3],
+    targets: std::ops::Range<usize>,
+) -> Vec<Vec<f32>> {
+    let sigma_s = radius as f32 / 2.0;
+    let inv_s = -0.5 / (sigma_s * sigma_s);
+    let inv_r = sigma_r.map(|s| if s > 0.0 { -0.5 / (s * s) } else { 0.0 });
+    let mut out: Vec<Vec<f32>> = targets.clone().map(|_| vec![0f32; w * h]).collect();
+    let rows: Vec<Vec<Vec<f32>>> = (0..h)
+        .into_par_iter()
+        .map(|y| {
+            let mut row: Vec<Vec<f32>> = targets.clone().map(|_| vec![0f32; w]).collect();
+            for x in 0..w {
+                let center = y * w + x;
+                let mut acc = [0f32; 3];
+                let mut total = 0f32;
+                for dy in (-radius..=radius).step_by(step) {
+                    let yy = (y as i64 + dy).clamp(0, h as i64 - 1) as usize;
+                    for dx in (-radius..=radius).step_by(step) {
+                        let xx = (x as i64 + dx).clamp(0, w as i64 - 1) as usize;
+                        let i = yy * w + xx;
+                        let mut e = (dx * dx + dy * dy) as f32 * inv_s;
+                        for c in 0..3 {
+                            let d = planes[c][i] - planes[c][center];
+                            e += d * d * inv_r[c];
+                        }
+                        let weight = e.exp();
+                        total += weight;
+                        for (a, c) in acc.iter_mut().zip(targets.clone()) {
+                            *a += weight * planes[c][i];
+                        }
+                    }
+                }
+                for (r, a) in row.iter_mut().zip(acc) {
+                    r[x] = a / total;
+                }
+            }
+            row
+        })
+        .collect();
+    for (y, row) in rows.into_iter().enumerate() {
+        for (o, r) in out.iter_mut().zip(row) {
+            o[y * w..(y + 1) * w].copy_from_slice(&r);
+        }
+    }
+    out
+}
+
+/// Bilateral luma and chroma denoising in an opponent color space. Range sigmas scale
+/// with the noise measured in the image, so a preset behaves alike on any data.
+pub fn denoise_rgb(buf: &mut Rgb32FImage, settings: &DenoiseSettings, debug: bool) {
+    if settings.luma <= 0.0 && settings.chroma <= 0.0 {
+        return;
+    }
+    let (w, h) = (buf.width() as usize, buf.height() as usize);
+    let mut planes = [vec![0f32; w * h], vec![0f32; w * h], vec![0f32; w * h]];
+    for (i, p) in buf.pixels().enumerate() {
+        let [r, g, b] = p.0;
+        let y = 0.299 * r + 0.587 * g + 0.114 * b;
+        planes[0][i] = y;
+        planes[1][i] = b - y;
+        planes[2][i] = r - y;
+    }
+    let noise = [0, 1, 2].map(|c| estimate_noise(&planes[c], w, h));
+    if debug {
+        println!(
+            "{} estimated noise {}",
+            blue("[denoise]"),
+            pink(format!(
+                "luma {:.5}, chroma {:.5}/{:.5}",
+                noise[0], noise[1], noise[2]
+            ))
+        );
+    }
+    if settings.chroma > 0.0 {
+        // Luma edges stop chroma from bleeding across object boundaries.
+        let sigma = [
+            2.0 * noise[0].max(1e-4),
+            settings.chroma * noise[1].max(1e-4),
+            settings.chroma * noise[2].max(1e-4),
+        ];
+        let mut chroma = bilateral(&planes, w, h, CHROMA_RADIUS, 2, sigma, 1..3).into_iter();
+        planes[1] = chroma.next().unwrap_or_default();
+        planes[2] = chroma.next().unwrap_or_default();
+    }
+    if settings.luma > 0.0 {
+        let sigma = [settings.luma * noise[0].max(1e-4), 0.0, 0.0];
+        if let Some(y) = bilateral(&planes, w, h, LUMA_RADIUS, 1, sigma, 0..1)
+            .into_iter()
+            .next()
+        {
+            planes[0] = y;
+        }
+    }
+    for (i, p) in buf.pixels_mut().enumerate() {
+        let (y, cb, cr) = (planes[0][i], planes[1][i], planes[2][i]);
+        let r = y + cr;
+        let b = y + cb;
+        let g = (y - 0.299 * r - 0.114 * b) / 0.587;
+        p.0 = [r, g, b];
+    }
+}
+
+/// Denoises any decoded image in place of its own type; integer images are filtered as
+/// their encoded values.
+pub fn denoise_image(img: DynamicImage, settings: &DenoiseSettings, debug: bool) -> DynamicImage {
+    if settings.luma <= 0.0 && settings.chroma <= 0.0 {
+        return img;
+    }
+    match img {
+        DynamicImage::ImageRgb32F(mut buf) => {
+            denoise_rgb(&mut buf, settings, debug);
+            DynamicImage::ImageRgb32F(buf)
+        }
+        img => {
+            let sixteen = matches!(
+                img,
+                DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_)
+            );
+            let mut buf = img.to_rgb32f();
+            denoise_rgb(&mut buf, settings, debug);
+            let out = DynamicImage::ImageRgb32F(buf);
+            if sixteen {
+                DynamicImage::ImageRgb16(out.to_rgb16())
+            } else {
+                DynamicImage::ImageRgb8(out.to_rgb8())
+            }
+        }
+    }
+}
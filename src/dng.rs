@@ -0,0 +1,657 @@
+use anyhow::{Context, Result};
+use image::{DynamicImage, RgbImage};
+use std::path::Path;
+
+use crate::color_profile::{self, ColorSpace};
+use crate::libraw::LibRaw;
+use crate::raw_info::RawInfo;
+use crate::raw_metadata::RawMetadata;
+use crate::term_colors::{blue, pink, red};
+
+/// Long side of the RGB preview stored in IFD0.
+const PREVIEW_SIZE: u32 = 1024;
+
+const BYTE: u16 = 1;
+const ASCII: u16 = 2;
+const SHORT: u16 = 3;
+const LONG: u16 = 4;
+const RATIONAL: u16 = 5;
+const SRATIONAL: u16 = 10;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DngKind {
+    /// The sensor mosaic, demosaiced by the reading application.
+    Cfa,
+    /// Demosaiced camera RGB (`LinearRaw`).
+    Linear,
+}
+
+pub fn parse_dng_kind(s: &str) -> Result<DngKind> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "cfa" | "raw" | "mosaic" => Ok(DngKind::Cfa),
+        "linear" => Ok(DngKind::Linear),
+        _ => anyhow::bail!(
+            "Unknown DNG kind {}. Valid: {}, {}",
+            red(s),
+            blue("cfa"),
+            blue("linear")
+        ),
+    }
+}
+
+/// A TIFF directory entry; `data` is already little-endian.
+#[derive(Clone)]
+struct Tag {
+    id: u16,
+    typ: u16,
+    count: u32,
+    data: Vec<u8>,
+}
+
+impl Tag {
+    fn bytes(id: u16, v: &[u8]) -> Self {
+        Tag {
+            id,
+            typ: BYTE,
+            count: v.len() as u32,
+            data: v.to_vec(),
+        }
+    }
+
+    fn ascii(id: u16, s: &str) -> Self {
+        let mut data: Vec<u8> = s.bytes().filter(|&b| b != 0).collect();
+        data.push(0);
+        Tag {
+            id,
+            typ: ASCII,
+            count: data.len() as u32,
+            data,
+        }
+    }
+
+    fn shorts(id: u16, v: &[u16]) -> Self {
+        Tag {
+            id,
+            typ: SHORT,
+            count: v.len() as u32,
+            data: v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        }
+    }
+
+    fn longs(id: u16, v: &[u32]) -> Self {
+        Tag {
+            id,
+            typ: LONG,
+            count: v.len() as u32,
+            data: v.iter().flat_map(|x| x.to_le_bytes()).collect(),
+        }
+    }
+
+    fn rationals(id: u16, v: &[(u32, u32)]) -> Self {
+        Tag {
+            id,
+            typ: RATIONAL,
+            count: v.len() as u32,
+            data: v
+                .iter()
+                .flat_map(|(n, d)| [n.to_le_bytes(), d.to_le_bytes()])
+                .flatten()
+                .collect(),
+        }
+    }
+
+    fn srationals(id: u16, v: &[(i32, i32)]) -> Self {
+        Tag {
+            id,
+            typ: SRATIONAL,
+            count: v.len() as u32,
+            data: v
+                .iter()
+                .flat_map(|(n, d)| [n.to_le_bytes(), d.to_le_bytes()])
+                .flatten()
+                .collect(),
+        }
+    }
+}
+
+/// Little-endian TIFF assembled front to back; IFD0 is written last and linked from the
+/// header by `finish`.
+struct TiffWriter {
+    buf: Vec<u8>,
+}
+
+impl TiffWriter {
+    fn new() -> Self {
+        TiffWriter {
+            buf: b"II*\0\0\0\0\0".to_vec(),
+        }
+    }
+
+    fn offset(&self) -> Result<u32> {
+        u32::try_from(self.buf.len()).context("DNG would exceed 4 GiB")
+    }
+
+    fn align(&mut self) {
+        if self.buf.len() % 2 == 1 {
+            self.buf.push(0);
+        }
+    }
+
+    fn write_data(&mut self, data: &[u8]) -> Result<u32> {
+        self.align();
+        let at = self.offset()?;
+        self.buf.extend_from_slice(data);
+        Ok(at)
+    }
+
+    fn write_ifd(&mut self, mut tags: Vec<Tag>) -> Result<u32> {
+        tags.sort_by_key(|t| t.id);
+        self.align();
+        let start = self.offset()?;
+        let overflow_start = start as usize + 2 + 12 * tags.len() + 4;
+        let mut overflow = Vec::new();
+        self.buf
+            .extend_from_slice(&(tags.len() as u16).to_le_bytes());
+        for t in &tags {
+            self.buf.extend_from_slice(&t.id.to_le_bytes());
+            self.buf.extend_from_slice(&t.typ.to_le_bytes());
+            self.buf.extend_from_slice(&t.count.to_le_bytes());
+            if t.data.len() <= 4 {
+                let mut inline = [0u8; 4];
+                inline[..t.data.len()].copy_from_slice(&t.data);
+                self.buf.extend_from_slice(&inline);
+            } else {
+                let at = u32::try_from(overflow_start + overflow.len())
+                    .context("DNG would exceed 4 GiB")?;
+                self.buf.extend_from_slice(&at.to_le_bytes());
+                overflow.extend_from_slice(&t.data);
+                if overflow.len() % 2 == 1 {
+                    overflow.push(0);
+                }
+            }
+        }
+        self.buf.extend_from_slice(&0u32.to_le_bytes());
+        self.buf.extend_from_slice(&overflow);
+        Ok(start)
+    }
+
+    fn finish(mut self, ifd0: u32) -> Vec<u8> {
+        self.buf[4..8].copy_from_slice(&ifd0.to_le_bytes());
+        self.buf
+    }
+}
+
+/// EXIF entries copied from the source file.
+#[derive(Default)]
+struct ExifTags {
+    ifd0: Vec<Tag>,
+    exif: Vec<Tag>,
+    gps: Vec<Tag>,
+}
+
+/// Re-encodes a rexif entry's raw bytes as little-endian.
+fn exif_tag(e: &rexif::IfdEntry) -> Tag {
+    use rexif::IfdFormat as F;
+    let width = match e.format {
+        F::U16 | F::I16 => 2,
+        F::U32 | F::I32 | F::F32 | F::URational | F::IRational => 4,
+        F::F64 => 8,
+        _ => 1,
+    };
+    let mut data = e.data.clone();
+    if !e.le && width > 1 {
+        for chunk in data.chunks_mut(width) {
+            chunk.reverse();
+        }
+    }
+    Tag {
+        id: e.tag,
+        typ: e.format as u16,
+        count: e.count,
+        data,
+    }
+}
+
+fn copy_exif(file: &[u8], debug: bool) -> ExifTags {
+    // Copied from IFD0 as well; Make, Model and the image structure are written fresh.
+    const IFD0_KEEP: &[u16] = &[0x010e, 0x0132, 0x013b, 0x8298];
+    // Pointers into the source file would dangle in the copy.
+    const EXIF_SKIP: &[u16] = &[0x927c, 0xa005, 0x8769, 0x8825];
+    let exif = match rexif::parse_buffer_quiet(file).0 {
+        Ok(exif) => exif,
+        Err(e) => {
+            if debug {
+                eprintln!("{} no EXIF copied: {}", blue("[dng]"), e);
+            }
+            return ExifTags::default();
+        }
+    };
+    let mut out = ExifTags::default();
+    for entry in &exif.entries {
+        let e = &entry.ifd;
+        if e.format as u16 == 0 || e.data.is_empty() {
+            continue;
+        }
+        match entry.kind {
+            rexif::IfdKind::Ifd0 if IFD0_KEEP.contains(&e.tag) => out.ifd0.push(exif_tag(e)),
+            rexif::IfdKind::Exif if !EXIF_SKIP.contains(&e.tag) => out.exif.push(exif_tag(e)),
+            rexif::IfdKind::Gps => out.gps.push(exif_tag(e)),
+            _ => {}
+        }
+    }
+    if debug {
+        println!(
+            "{} copying {} EXIF and {} GPS entries",
+            blue("[dng]"),
+            pink(out.exif.len()),
+            pink(out.gps.len())
+        );
+    }
+    out
+}
+
+/// TIFF orientation for a libraw flip code.
+fn flip_to_orientation(flip: i32) -> u16 {
+    match flip {
+        1 => 2,
+        2 => 4,
+        3 => 3,
+        4 => 5,
+        5 => 8,
+        6 => 6,
+        7 => 7,
+        _ => 1,
+    }
+}
+
+/// Undoes the rotation libraw applied to its processed output, so images line up with
+/// the sensor data and the Orientation tag.
+fn unorient(img: DynamicImage, orientation: u16) -> DynamicImage {
+    match orientation {
+        2 => img.fliph(),
+        3 => img.rotate180(),
+        4 => img.flipv(),
+        5 => img.rotate90().fliph(),
+        6 => img.rotate270(),
+        7 => img.rotate270().fliph(),
+        8 => img.rotate90(),
+        _ => img,
+    }
+}
+
+fn gcd(a: u16, b: u16) -> u16 {
+    if b == 0 { a } else { gcd(b, a % b) }
+}
+
+fn lcm(a: u16, b: u16) -> u16 {
+    a / gcd(a, b) * b
+}
+
+/// Everything a DNG needs from the unpacked raw, captured before processing.
+pub struct DngSource {
+    kind: DngKind,
+    width: u32,
+    height: u32,
+    /// One sample per pixel for CFA data, three for linear.
+    samples: Vec<u16>,
+    /// Repeat rows, columns and color (0 red, 1 green, 2 blue) of each cell.
+    cfa: Option<(u16, u16, Vec<u8>)>,
+    /// Repeat rows and columns of `black`.
+    black_dim: (u16, u16),
+    black: Vec<u32>,
+    white: u32,
+    /// Camera from XYZ (D65).
+    color_matrix: [[f64; 3]; 3],
+    as_shot_neutral: [f64; 3],
+    orientation: u16,
+    meta: RawMetadata,
+    exif: ExifTags,
+    preview: Option<RgbImage>,
+}
+
+impl DngSource {
+    /// Reads the mosaic, or renders linear camera RGB, from a freshly unpacked handle.
+    /// Processing parameters changed for the linear render are reset to libraw defaults.
+    pub fn capture(
+        lr: &mut LibRaw,
+        kind: DngKind,
+        demosaic: Option<i32>,
+        meta: Option<&RawMetadata>,
+        debug: bool,
+    ) -> Result<Self> {
+        let info = RawInfo::from_libraw(lr)?;
+        if info.colors != 3 || info.cdesc.get(..3) != Some("RGB") {
+            anyhow::bail!(
+                "DNG output needs an RGB sensor, this one has {} colors ({})",
+                info.colors,
+                info.cdesc
+            );
+        }
+        let cdesc = info.cdesc.as_bytes().to_vec();
+        let channel_color = |idx: usize| match cdesc.get(idx) {
+            Some(b'R') => 0u8,
+            Some(b'B') => 2,
+            _ => 1,
+        };
+        let color_matrix = match &info.levels {
+            Some(l) => [0, 1, 2].map(|i| l.cam_xyz[i].map(|v| v as f64)),
+            None => {
+                // rgb_cam maps camera to linear sRGB; chain it with sRGB from XYZ.
+                let rgb_cam = info.rgb_cam.map(|r| r.map(|v| v as f64));
+                let (srgb_to_xyz, _) = ColorSpace::Srgb.to_xyz().context("sRGB has no matrix")?;
+                let cam_rgb = color_profile::invert3(rgb_cam).context("singular rgb_cam")?;
+                let xyz_to_srgb =
+                    color_profile::invert3(srgb_to_xyz).context("singular sRGB matrix")?;
+                color_profile::mat_mul(cam_rgb, xyz_to_srgb)
+            }
+        };
+        let mul = if info.cam_mul[0] > 0.0 && info.cam_mul[1] > 0.0 && info.cam_mul[2] > 0.0 {
+            info.cam_mul
+        } else {
+            info.pre_mul
+        };
+        let as_shot_neutral = [0, 1, 2].map(|c| {
+            if mul[c] > 0.0 {
+                mul[1] as f64 / mul[c] as f64
+            } else {
+                1.0
+            }
+        });
+        let orientation = flip_to_orientation(info.sizes.flip);
+        let exif = copy_exif(lr.file_data(), debug);
+        let meta = meta.cloned().unwrap_or_default();
+
+        match kind {
+            DngKind::Cfa => {
+                let levels = info.levels.as_ref().context(
+                    "DNG output needs the black levels, which this libraw build does not expose",
+                )?;
+                let (rows, cols): (u16, u16) = match info.filters {
+                    9 => (6, 6),
+                    f if f > 1000 => {
+                        let fc = |r: u32, c: u32| (f >> ((((r << 1) & 14) | (c & 1)) << 1)) & 3;
+                        if (0..8).all(|r| (0..2).all(|c| fc(r, c) == fc(r % 2, c))) {
+                            (2, 2)
+                        } else {
+                            (8, 2)
+                        }
+                    }
+                    f => anyhow::bail!("DNG output does not support CFA layout {}", f),
+                };
+                let ip = lr.iparams();
+                let channel = |r: u16, c: u16| -> usize {
+                    if info.filters == 9 {
+                        ip.xtrans[r as usize % 6][c as usize % 6] as usize
+                    } else {
+                        let (r, c) = (r as u32, c as u32);
+                        ((info.filters >> ((((r << 1) & 14) | (c & 1)) << 1)) & 3) as usize
+                    }
+                };
+                let pattern: Vec<u8> = (0..rows)
+                    .flat_map(|r| (0..cols).map(move |c| (r, c)))
+                    .map(|(r, c)| channel_color(channel(r, c)))
+                    .collect();
+                let pattern_dim = levels
+                    .black_pattern
+                    .as_ref()
+                    .map_or((1, 1), |(r, c, _)| (*r as u16, *c as u16));
+                let black_dim = (lcm(rows, pattern_dim.0), lcm(cols, pattern_dim.1));
+                let black: Vec<u32> = (0..black_dim.0)
+                    .flat_map(|r| (0..black_dim.1).map(move |c| (r, c)))
+                    .map(|(r, c)| {
+                        let idx = channel(r, c);
+                        let extra = levels.black_pattern.as_ref().map_or(0, |(pr, pc, v)| {
+                            let i = (r as u32 % pr) * pc + c as u32 % pc;
+                            v.get(i as usize).copied().unwrap_or(0)
+                        });
+                        levels.black + levels.cblack.get(idx).copied().unwrap_or(0) + extra
+                    })
+                    .collect();
+                if debug {
+                    println!("{} calling libraw_raw2image...", blue("[dng]"));
+                }
+                lr.raw2image().context("libraw_raw2image failed")?;
+                let sizes = lr.sizes()?;
+                let samples: Vec<u16> = lr
+                    .image_data()?
+                    .iter()
+                    .map(|px| px.iter().fold(0u16, |a, &v| a.saturating_add(v)))
+                    .collect();
+                Ok(DngSource {
+                    kind,
+                    width: sizes.iwidth as u32,
+                    height: sizes.iheight as u32,
+                    samples,
+                    cfa: Some((rows, cols, pattern)),
+                    black_dim,
+                    black,
+                    white: levels.maximum,
+                    color_matrix,
+                    as_shot_neutral,
+                    orientation,
+                    meta,
+                    exif,
+                    preview: None,
+                })
+            }
+            DngKind::Linear => {
+                if debug {
+                    println!(
+                        "{} rendering linear camera RGB for the DNG...",
+                        blue("[dng]")
+                    );
+                }
+                // Unit multipliers keep the data in camera space without white balance;
+                // AsShotNeutral carries it instead.
+                lr.set_output_bps(16);
+                lr.set_output_color(ColorSpace::Raw.libraw_code());
+                lr.set_no_auto_bright(true);
+                lr.set_gamma(1.0, 1.0);
+                lr.set_user_mul([1.0; 4]);
+                lr.set_highlight(0);
+                if let Some(qual) = demosaic {
+                    lr.set_demosaic(qual);
+                }
+                let processed = lr
+                    .dcraw_process()
+                    .context("libraw_dcraw_process failed")
+                    .and_then(|()| {
+                        lr.make_mem_image()
+                            .context("libraw_dcraw_make_mem_image failed")
+                    })
+                    .and_then(|pimg| crate::processed_bitmap_to_image(&pimg, "linear DNG"));
+                lr.set_user_mul([0.0; 4]);
+                let img = unorient(processed?, orientation).to_rgb16();
+                Ok(DngSource {
+                    kind,
+                    width: img.width(),
+                    height: img.height(),
+                    samples: img.into_raw(),
+                    cfa: None,
+                    black_dim: (1, 1),
+                    black: vec![0],
+                    white: 65535,
+                    color_matrix,
+                    as_shot_neutral,
+                    orientation,
+                    meta,
+                    exif,
+                    preview: None,
+                })
+            }
+        }
+    }
+
+    /// Stores a reduced copy of libraw's rendering as the IFD0 preview.
+    pub fn set_preview(&mut self, rendered: &DynamicImage) {
+        let small = rendered.thumbnail(PREVIEW_SIZE, PREVIEW_SIZE);
+        self.preview = Some(unorient(small, self.orientation).to_rgb8());
+    }
+
+    /// A bare CFA source for tests, without color data, EXIF or preview.
+    #[cfg(all(test, feature = "native"))]
+    pub(crate) fn mosaic(
+        width: u32,
+        height: u32,
+        samples: Vec<u16>,
+        pattern: [u8; 4],
+        black: u32,
+        white: u32,
+    ) -> Self {
+        DngSource {
+            kind: DngKind::Cfa,
+            width,
+            height,
+            samples,
+            cfa: Some((2, 2, pattern.to_vec())),
+            black_dim: (1, 1),
+            black: vec![black],
+            white,
+            color_matrix: [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+            as_shot_neutral: [1.0; 3],
+            orientation: 1,
+            meta: RawMetadata::default(),
+            exif: ExifTags::default(),
+            preview: None,
+        }
+    }
+}
+
+/// Writes a DNG 1.4 with the raw data in a SubIFD and an RGB preview in IFD0.
+pub fn write_dng(out_path: &Path, src: &DngSource, debug: bool) -> Result<()> {
+    let mut w = TiffWriter::new();
+
+    let raw_bytes: Vec<u8> = src.samples.iter().flat_map(|s| s.to_le_bytes()).collect();
+    let raw_offset = w.write_data(&raw_bytes)?;
+    let spp: u16 = if src.kind == DngKind::Linear { 3 } else { 1 };
+    let mut raw_tags = vec![
+        Tag::longs(254, &[0]),
+        Tag::longs(256, &[src.width]),
+        Tag::longs(257, &[src.height]),
+        Tag::shorts(258, &vec![16; spp as usize]),
+        Tag::shorts(259, &[1]),
+        Tag::longs(273, &[raw_offset]),
+        Tag::shorts(277, &[spp]),
+        Tag::longs(278, &[src.height]),
+        Tag::longs(279, &[raw_bytes.len() as u32]),
+        Tag::shorts(284, &[1]),
+        Tag::longs(50714, &src.black),
+        Tag::longs(50717, &[src.white]),
+    ];
+    if src.black.len() > 1 {
+        raw_tags.push(Tag::shorts(50713, &[src.black_dim.0, src.black_dim.1]));
+    }
+    match &src.cfa {
+        Some((rows, cols, pattern)) => {
+            raw_tags.push(Tag::shorts(262, &[32803]));
+            raw_tags.push(Tag::shorts(33421, &[*rows, *cols]));
+            raw_tags.push(Tag::bytes(33422, pattern));
+            raw_tags.push(Tag::bytes(50710, &[0, 1, 2]));
+            raw_tags.push(Tag::shorts(50711, &[1]));
+        }
+        None => raw_tags.push(Tag::shorts(262, &[34892])),
+    }
+    let raw_ifd = w.write_ifd(raw_tags)?;
+
+    let exif_ifd = if src.exif.exif.is_empty() {
+        None
+    } else {
+        Some(w.write_ifd(src.exif.exif.to_vec())?)
+    };
+    let gps_ifd = if src.exif.gps.is_empty() {
+        None
+    } else {
+        Some(w.write_ifd(src.exif.gps.to_vec())?)
+    };
+
+    let preview = match &src.preview {
+        Some(p) => p.clone(),
+        None => RgbImage::new(1, 1),
+    };
+    let preview_offset = w.write_data(preview.as_raw())?;
+
+    let meta = &src.meta;
+    let camera = meta.camera();
+    let unique = if camera.is_empty() {
+        "Unknown camera".to_string()
+    } else {
+        camera
+    };
+    let matrix: Vec<(i32, i32)> = src
+        .color_matrix
+        .iter()
+        .flatten()
+        .map(|v| ((v * 10000.0).round() as i32, 10000))
+        .collect();
+    let neutral: Vec<(u32, u32)> = src
+        .as_shot_neutral
+        .iter()
+        .map(|v| ((v * 1_000_000.0).round().max(1.0) as u32, 1_000_000))
+        .collect();
+    let mut tags = vec![
+        Tag::longs(254, &[1]),
+        Tag::longs(256, &[preview.width()]),
+        Tag::longs(257, &[preview.height()]),
+        Tag::shorts(258, &[8, 8, 8]),
+        Tag::shorts(259, &[1]),
+        Tag::shorts(262, &[2]),
+        Tag::longs(273, &[preview_offset]),
+        Tag::shorts(274, &[src.orientation]),
+        Tag::shorts(277, &[3]),
+        Tag::longs(278, &[preview.height()]),
+        Tag::longs(279, &[preview.as_raw().len() as u32]),
+        Tag::shorts(284, &[1]),
+        Tag::ascii(305, &format!("fempeg {}", crate::VERSION)),
+        Tag::longs(330, &[raw_ifd]),
+        Tag::bytes(50706, &[1, 4, 0, 0]),
+        Tag::bytes(50707, &[1, 1, 0, 0]),
+        Tag::ascii(50708, &unique),
+        Tag::srationals(50721, &matrix),
+        Tag::rationals(50728, &neutral),
+        Tag::shorts(50778, &[21]),
+    ];
+    if !meta.make.is_empty() {
+        tags.push(Tag::ascii(271, &meta.make));
+    }
+    if !meta.model.is_empty() {
+        tags.push(Tag::ascii(272, &meta.model));
+    }
+    if let Some(t) = exif_ifd {
+        tags.push(Tag::longs(34665, &[t]));
+    }
+    if let Some(t) = gps_ifd {
+        tags.push(Tag::longs(34853, &[t]));
+    }
+    let have: Vec<u16> = tags.iter().map(|t| t.id).collect();
+    tags.extend(
+        src.exif
+            .ifd0
+            .iter()
+            .filter(|t| !have.contains(&t.id))
+            .cloned(),
+    );
+    if !have.contains(&306)
+        && !src.exif.ifd0.iter().any(|t| t.id == 306)
+        && let Some(dt) = meta.datetime()
+    {
+        tags.push(Tag::ascii(306, &dt.format("%Y:%m:%d %H:%M:%S").to_string()));
+    }
+    let ifd0 = w.write_ifd(tags)?;
+    let bytes = w.finish(ifd0);
+
+    if debug {
+        println!(
+            "{} {} DNG {}x{}, {} bytes",
+            blue("[dng]"),
+            pink(if src.kind == DngKind::Linear {
+                "linear"
+            } else {
+                "CFA"
+            }),
+            src.width,
+            src.height,
+            bytes.len()
+        );
+    }
+    std::fs::write(out_path, bytes).with_context(|| format!("Failed to write {:?}", out_path))
+}
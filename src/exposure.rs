@@ -0,0 +1,121 @@
+use anyhow::{Context, Result};
+use image::DynamicImage;
+#[cfg(feature = "native")]
+use image::Rgb32FImage;
+
+use crate::libraw::LibRaw;
+use crate::term_colors::{blue, pink};
+
+/// Range libraw accepts for `exp_shift`.
+pub const SHIFT_MIN: f32 = 0.25;
+pub const SHIFT_MAX: f32 = 8.0;
+
+/// Middle grey three stops under raw clipping, about where cameras meter it.
+const RAW_KEY: f32 = 0.125;
+/// Middle grey of display-referred (sRGB) data.
+const DISPLAY_KEY: f32 = 0.18;
+const BINS: usize = 4096;
+/// Raw sites sampled for auto exposure; larger sensors are strided.
+const MAX_SAMPLES: usize = 2_000_000;
+
+/// Exposure factor that brings the log-average of `samples` (linear, 1.0 = clipping) to
+/// `key`, while keeping the brightest 0.5% at most `preserve` stops over white.
+fn auto_factor(samples: impl Iterator<Item = f32>, key: f32, preserve: f32) -> Option<f32> {
+    let mut hist = vec![0u64; BINS];
+    let mut log_sum = 0f64;
+    let mut n = 0u64;
+    for v in samples {
+        let v = v.clamp(0.0, 1.0);
+        hist[(v * (BINS - 1) as f32) as usize] += 1;
+        log_sum += (v.max(1.0 / BINS as f32) as f64).ln();
+        n += 1;
+    }
+    if n == 0 {
+        return None;
+    }
+    let average = (log_sum / n as f64).exp() as f32;
+    let limit = n / 200;
+    let mut total = 0u64;
+    let mut bin = BINS - 1;
+    while bin > 0 {
+        total += hist[bin];
+        if total > limit {
+            break;
+        }
+        bin -= 1;
+    }
+    let high = (bin + 1) as f32 / BINS as f32;
+    let factor = (key / average).min(2f32.powf(preserve.clamp(0.0, 1.0)) / high);
+    Some(factor.clamp(SHIFT_MIN, SHIFT_MAX))
+}
+
+/// Auto exposure measured on the unpacked raw data, black subtracted and balanced with the
+/// camera multipliers so that a strong color cast does not read as under exposure.
+pub fn auto_exposure(lr: &mut LibRaw, preserve: f32, debug: bool) -> Result<f32> {
+    let filters = lr.iparams().filters;
+    let maximum = lr.color_maximum().max(1) as f32;
+    let (black, cblack) = match lr.color_levels() {
+        Ok(l) => (l.black, l.cblack),
+        Err(_) => (0, [0; 4]),
+    };
+    let cam_mul = lr.cam_mul();
+    let wb = if cam_mul[0] > 0.0 && cam_mul[1] > 0.0 {
+        cam_mul
+    } else {
+        lr.pre_mul()
+    };
+    let green = if wb[1] > 0.0 { wb[1] } else { 1.0 };
+    let mul = [0, 1, 2, 3].map(|c| match wb[c] {
+        m if m > 0.0 => m / green,
+        _ => 1.0,
+    });
+
+    if debug {
+        println!("{} calling libraw_raw2image...", blue("[exposure]"));
+    }
+    lr.raw2image().context("libraw_raw2image failed")?;
+    let image = lr.image_data()?;
+    let step = (image.len() / MAX_SAMPLES).max(1);
+    let samples = image.iter().step_by(step).map(|px| {
+        // raw2image leaves one channel per site; full-color data is measured on green.
+        let c = if filters == 0 {
+            1
+        } else {
+            (0..4).max_by_key(|&c| px[c]).unwrap_or(0)
+        };
+        let b = (black + cblack[c]) as f32;
+        (px[c] as f32 - b).max(0.0) * mul[c] / (maximum - b).max(1.0)
+    });
+    let factor = auto_factor(samples, RAW_KEY, preserve).context("raw data is empty")?;
+    if debug {
+        println!(
+            "{} auto exposure {}",
+            blue("[exposure]"),
+            pink(format!("x{:.3} ({:+.2} EV)", factor, factor.log2()))
+        );
+    }
+    Ok(factor)
+}
+
+/// Auto exposure for gamma-encoded images such as embedded previews, measured on their
+/// approximately linearized luminance.
+pub fn display_auto_factor(img: &DynamicImage, preserve: f32) -> Option<f32> {
+    let buf = img.to_rgb8();
+    let step = (buf.pixels().len() / MAX_SAMPLES).max(1);
+    let samples = buf.pixels().step_by(step).map(|p| {
+        let lin = |v: u8| (v as f32 / 255.0).powf(2.2);
+        0.2126 * lin(p[0]) + 0.7152 * lin(p[1]) + 0.0722 * lin(p[2])
+    });
+    auto_factor(samples, DISPLAY_KEY, preserve)
+}
+
+/// Auto exposure for scene-linear RGB, measured on its luminance.
+#[cfg(feature = "native")]
+pub fn linear_auto_factor(buf: &Rgb32FImage, preserve: f32) -> Option<f32> {
+    let step = (buf.pixels().len() / MAX_SAMPLES).max(1);
+    let samples = buf
+        .pixels()
+        .step_by(step)
+        .map(|p| 0.2126 * p[0] + 0.7152 * p[1] + 0.0722 * p[2]);
+    auto_factor(samples, DISPLAY_KEY, preserve)
+}
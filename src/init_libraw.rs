@@ -3,90 +3,216 @@ use anyhow::Context;
 use anyhow::Result;
 use libloading::Library;
 
-use std::path::PathBuf;
+use std::os::raw::c_int;
+use std::path::{Path, PathBuf};
 use std::sync::OnceLock;
 #[cfg(target_os = "windows")]
 use std::{env, fs};
 
-use crate::term_colors::{blue, white};
+use crate::libraw_ffi::make_version;
 #[cfg(target_os = "linux")]
-use crate::term_colors::{green, pink};
+use crate::term_colors::green;
+use crate::term_colors::{blue, pink, red, white};
 
-static LIB: OnceLock<Result<Library>> = OnceLock::new();
+static LIB: OnceLock<Result<(Library, PathBuf)>> = OnceLock::new();
+/// Set from `--libraw` before anything loads the library.
+static LIB_PATH: OnceLock<PathBuf> = OnceLock::new();
+
+/// Oldest libraw whose structs fempeg reads.
+const MIN_VERSION: (c_int, c_int) = (0, 20);
 
 #[cfg(target_os = "windows")]
 const LIBRAW_DLL: &[u8] = include_bytes!("../assets/libraw.dll");
 
-pub fn init_libraw() -> Result<PathBuf> {
+/// File names tried in system library paths, thread-safe builds and newer sonames first.
+/// Distros often ship only the versioned files without the `-dev` symlink.
+#[cfg(target_os = "linux")]
+const LIB_NAMES: &[&str] = &[
+    "libraw_r.so.24",
+    "libraw_r.so.23",
+    "libraw_r.so.22",
+    "libraw_r.so.21",
+    "libraw_r.so.20",
+    "libraw_r.so",
+    "libraw.so.24",
+    "libraw.so.23",
+    "libraw.so.22",
+    "libraw.so.21",
+    "libraw.so.20",
+    "libraw.so",
+];
+
+#[cfg(target_os = "macos")]
+const LIB_NAMES: &[&str] = &[
+    "libraw_r.dylib",
+    "/opt/homebrew/lib/libraw_r.dylib",
+    "/usr/local/lib/libraw_r.dylib",
+    "libraw.dylib",
+    "/opt/homebrew/lib/libraw.dylib",
+    "/usr/local/lib/libraw.dylib",
+];
+
+#[cfg(target_os = "windows")]
+const LIB_NAMES: &[&str] = &["libraw_r.dll", "libraw.dll"];
+
+#[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+const LIB_NAMES: &[&str] = &["libraw_r.so", "libraw.so"];
+
+/// Directory the bundled DLL is unpacked to.
+#[cfg(target_os = "windows")]
+fn bundled_dir() -> PathBuf {
+    env::temp_dir().join("fempeg_libraw")
+}
+
+#[cfg(target_os = "windows")]
+fn bundled_dll() -> PathBuf {
+    bundled_dir().join("libraw_c.dll")
+}
+
+/// Uses `path` (a library file or a directory holding one) instead of searching.
+pub fn set_library_path(path: PathBuf) {
+    LIB_PATH.set(path).ok();
+}
+
+/// Paths to try in order: `--libraw`, else `LIBRAW_PATH`, else the bundled DLL on Windows
+/// and the known library names elsewhere.
+fn candidates() -> Result<Vec<PathBuf>> {
+    let explicit = LIB_PATH
+        .get()
+        .cloned()
+        .or_else(|| std::env::var_os("LIBRAW_PATH").map(PathBuf::from));
+    if let Some(path) = explicit {
+        if path.is_dir() {
+            let mut paths: Vec<PathBuf> = Vec::new();
+            for name in LIB_NAMES.iter().filter_map(|n| Path::new(n).file_name()) {
+                let p = path.join(name);
+                if !paths.contains(&p) {
+                    paths.push(p);
+                }
+            }
+            return Ok(paths);
+        }
+        return Ok(vec![path]);
+    }
+
     #[cfg(target_os = "windows")]
     {
-        let tmp_dir = env::temp_dir().join("fempeg_libraw");
+        let tmp_dir = bundled_dir();
         fs::create_dir_all(&tmp_dir)
             .with_context(|| format!("Failed to create temp directory {:?}", tmp_dir))?;
 
-        let dll_path = tmp_dir.join("libraw_c.dll");
+        let dll_path = bundled_dll();
         if !dll_path.exists() {
             fs::write(&dll_path, LIBRAW_DLL)
                 .with_context(|| format!("Failed to write libraw DLL to {:?}", dll_path))?;
         }
 
-        Ok(dll_path)
+        Ok(vec![dll_path])
     }
 
-    #[cfg(target_os = "linux")]
+    #[cfg(not(target_os = "windows"))]
     {
-        Ok(PathBuf::from("libraw.so"))
+        Ok(LIB_NAMES.iter().map(PathBuf::from).collect())
     }
+}
 
-    #[cfg(target_os = "macos")]
-    {
-        Ok(PathBuf::from("libraw.dylib"))
+/// Loads one candidate and checks that it is a libraw with the ABI fempeg expects.
+fn open(path: &Path) -> Result<Library> {
+    let lib = unsafe { Library::new(path) }?;
+    let version: c_int = unsafe {
+        let f = lib.get::<unsafe extern "C" fn() -> c_int>(b"libraw_versionNumber\0")?;
+        f()
+    };
+    let (major, minor) = MIN_VERSION;
+    if version < make_version(major, minor, 0) {
+        anyhow::bail!(
+            "libraw {}.{}.{} is older than {}.{}, the oldest ABI fempeg supports",
+            version >> 16,
+            (version >> 8) & 0xff,
+            version & 0xff,
+            major,
+            minor
+        );
     }
+    Ok(lib)
+}
 
-    #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
-    {
-        let lib_path = std::env::var("LIBRAW_PATH").unwrap_or_else(|_| {
-            eprintln!(
-                "Unsupported OS for libraw, defaulting to libraw.so (env {} not set)",
-                blue("LIBRAW_PATH")
-            );
-            "libraw.so".to_string()
-        });
-
-        Ok(PathBuf::from(lib_path))
+fn load() -> Result<(Library, PathBuf)> {
+    let mut tried = Vec::new();
+    for path in candidates()? {
+        match open(&path) {
+            Ok(lib) => return Ok((lib, path)),
+            Err(e) => tried.push(format!("  {}: {}", pink(path.display()), e)),
+        }
     }
+
+    #[cfg(target_os = "windows")]
+    let (head, hint) = (
+        blue("Failed to load internal libraw DLL."),
+        white(format!(
+            "Set {} or pass {} to use another libraw.dll",
+            blue("LIBRAW_PATH"),
+            blue("--libraw")
+        )),
+    );
+
+    #[cfg(target_os = "linux")]
+    let (head, hint) = (
+        blue("Failed to load system libraw."),
+        white(format!(
+            "Please install it using your package manager:\n  sudo {} install libraw-dev   {}\n  sudo {} install libraw       {}\n  sudo {} -S libraw         {}\nor point {} or {} at the library.",
+            pink("apt"),
+            green("# Ubuntu/Debian"),
+            pink("dnf"),
+            green("# Fedora"),
+            pink("pacman"),
+            green("# Arch Linux"),
+            blue("LIBRAW_PATH"),
+            blue("--libraw")
+        )),
+    );
+
+    #[cfg(target_os = "macos")]
+    let (head, hint) = (
+        blue("Failed to load system libraw."),
+        white("Please install it using Homebrew:\n  brew install libraw"),
+    );
+
+    #[cfg(not(any(target_os = "windows", target_os = "linux", target_os = "macos")))]
+    let (head, hint) = (
+        blue("Failed to load libraw."),
+        white(format!(
+            "Point {} or {} at the library.",
+            blue("LIBRAW_PATH"),
+            blue("--libraw")
+        )),
+    );
+
+    anyhow::bail!("{} {}\n{}\n{}", head, red("Tried:"), tried.join("\n"), hint)
 }
 
 pub fn get_lib() -> Result<&'static Library> {
-    LIB.get_or_init(|| {
-        let lib_path = init_libraw().unwrap();
-
-        unsafe { Library::new(&lib_path) }.map_err(|e| {
-            #[cfg(target_os = "windows")]
-            {
-                let head = blue("Failed to load internal libraw DLL:");
-                let body = white(format!(" {}", e));
-                anyhow::anyhow!(format!("{}{}", head, body))
-            }
+    LIB.get_or_init(load)
+        .as_ref()
+        .map(|(lib, _)| lib)
+        .map_err(|e| anyhow::anyhow!(e.to_string()))
+}
 
-            #[cfg(target_os = "linux")]
-            {
-                let head = blue("Failed to load system libraw:");
-                let hint = white(format!(
-                    " Please install it using your package manager:\n  sudo {} install libraw-dev   {}\n  sudo {} install libraw       {}\n  sudo {} -S libraw         {} ",
-                    pink("apt"), green("# Ubuntu/Debian"), pink("dnf"), green("# Fedora"), pink("pacman"), green("# Arch Linux")
-                ));
-                anyhow::anyhow!(format!("{}{} {}", head, white(format!(" {}", e)), hint))
-            }
+/// Whether the loaded library may decode several files at once. Builds are reentrant only
+/// as `libraw_r`; libraw has no capability bit for it, so the file name decides. The bundled
+/// Windows DLL has no `_r` name but is built with thread support; other DLLs given through
+/// `--libraw` or `LIBRAW_PATH` get no such assumption.
+pub fn thread_safe() -> bool {
+    #[cfg(target_os = "windows")]
+    if loaded_path() == Some(bundled_dll().as_path()) {
+        return true;
+    }
+    loaded_path()
+        .and_then(|p| p.file_name())
+        .is_some_and(|n| n.to_string_lossy().starts_with("libraw_r"))
+}
 
-            #[cfg(target_os = "macos")]
-            {
-                let head = blue("Failed to load system libraw:");
-                let hint = white(" Please install it using Homebrew:\n  brew install libraw");
-                anyhow::anyhow!(format!("{}{} {}", head, white(format!(" {}", e)), hint))
-            }
-        })
-    })
-    .as_ref()
-    .map_err(|e| anyhow::anyhow!(e))
+/// Path of the loaded library, once `get_lib` succeeded.
+pub fn loaded_path() -> Option<&'static Path> {
+    LIB.get()?.as_ref().ok().map(|(_, path)| path.as_path())
 }
@@ -0,0 +1,666 @@
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+use std::collections::VecDeque;
+use std::env;
+use std::io::{BufRead, BufReader, Read, Write};
+use std::path::{Path, PathBuf};
+use std::process::{Child, ChildStdin, ChildStdout, Command, Stdio};
+use std::sync::Mutex;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::thread::{self, JoinHandle};
+
+use crate::calibration::Calibration;
+use crate::camera_support::Seen;
+use crate::lens::LensCorrection;
+use crate::term_colors::{blue, red};
+use crate::{Converted, Outcome};
+
+/// Starts reply lines on a worker's stdout; anything else there is the worker's own output.
+const REPLY: &str = "fempeg-worker-reply ";
+/// Written by a worker to stderr once it is set up. What it prints before that, every
+/// worker repeats, so it is only shown with `--debug` or when the worker dies early.
+const READY: &str = "fempeg-worker-ready";
+
+/// Little-endian encoder for the state a parent prepares once and hands to its workers.
+#[derive(Default)]
+pub struct StateWriter(Vec<u8>);
+
+impl StateWriter {
+    pub fn u8(&mut self, v: u8) {
+        self.0.push(v);
+    }
+
+    pub fn u32(&mut self, v: u32) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    pub fn i64(&mut self, v: i64) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    pub fn f32(&mut self, v: f32) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    pub fn f64(&mut self, v: f64) {
+        self.0.extend(v.to_le_bytes());
+    }
+
+    pub fn count(&mut self, n: usize) {
+        self.0.extend((n as u64).to_le_bytes());
+    }
+
+    pub fn str(&mut self, s: &str) {
+        self.count(s.len());
+        self.0.extend(s.as_bytes());
+    }
+
+    pub fn u16s(&mut self, v: &[u16]) {
+        self.count(v.len());
+        self.0.extend(v.iter().flat_map(|x| x.to_le_bytes()));
+    }
+
+    pub fn f32s(&mut self, v: &[f32]) {
+        self.count(v.len());
+        self.0.extend(v.iter().flat_map(|x| x.to_le_bytes()));
+    }
+
+    /// A presence byte, then `v` through `put` when it is set.
+    pub fn option<T: ?Sized>(&mut self, v: Option<&T>, put: impl FnOnce(&mut Self, &T)) {
+        self.u8(v.is_some() as u8);
+        if let Some(v) = v {
+            put(self, v);
+        }
+    }
+}
+
+/// Reads what a `StateWriter` wrote, failing instead of panicking on a short buffer.
+pub struct StateReader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+}
+
+impl<'a> StateReader<'a> {
+    fn take<const N: usize>(&mut self) -> Result<[u8; N]> {
+        let b = self
+            .buf
+            .get(self.pos..self.pos + N)
+            .context("worker state is truncated")?;
+        self.pos += N;
+        Ok(b.try_into()?)
+    }
+
+    pub fn u8(&mut self) -> Result<u8> {
+        Ok(self.take::<1>()?[0])
+    }
+
+    pub fn u32(&mut self) -> Result<u32> {
+        Ok(u32::from_le_bytes(self.take()?))
+    }
+
+    pub fn i64(&mut self) -> Result<i64> {
+        Ok(i64::from_le_bytes(self.take()?))
+    }
+
+    pub fn f32(&mut self) -> Result<f32> {
+        Ok(f32::from_le_bytes(self.take()?))
+    }
+
+    pub fn f64(&mut self) -> Result<f64> {
+        Ok(f64::from_le_bytes(self.take()?))
+    }
+
+    /// A length, checked against what is left so a bad one cannot allocate without bound.
+    pub fn count(&mut self) -> Result<usize> {
+        let n = u64::from_le_bytes(self.take()?) as usize;
+        if n > self.buf.len() - self.pos {
+            anyhow::bail!("worker state is truncated");
+        }
+        Ok(n)
+    }
+
+    pub fn str(&mut self) -> Result<String> {
+        let n = self.count()?;
+        let b = &self.buf[self.pos..self.pos + n];
+        self.pos += n;
+        Ok(String::from_utf8(b.to_vec())?)
+    }
+
+    pub fn u16s(&mut self) -> Result<Vec<u16>> {
+        (0..self.count()?)
+            .map(|_| Ok(u16::from_le_bytes(self.take()?)))
+            .collect()
+    }
+
+    pub fn f32s(&mut self) -> Result<Vec<f32>> {
+        (0..self.count()?).map(|_| self.f32()).collect()
+    }
+
+    pub fn option<T>(&mut self, get: impl FnOnce(&mut Self) -> Result<T>) -> Result<Option<T>> {
+        match self.u8()? {
+            0 => Ok(None),
+            _ => get(self).map(Some),
+        }
+    }
+}
+
+/// What a worker takes from its parent instead of loading it again: the calibration
+/// masters, the lens table and where outputs go.
+pub struct Prepared {
+    pub calibration: Option<Calibration>,
+    pub lens: Option<LensCorrection>,
+    /// One directory per output format, for a directory input.
+    pub out_dirs: Vec<PathBuf>,
+    /// Output files of a single input.
+    pub single_outs: Option<Vec<PathBuf>>,
+}
+
+/// Encodes the parent's prepared state for `run`.
+pub fn encode_state(
+    calibration: Option<&Calibration>,
+    lens: Option<&LensCorrection>,
+    out_dirs: &[PathBuf],
+    single_outs: Option<&[PathBuf]>,
+) -> Vec<u8> {
+    let mut w = StateWriter::default();
+    w.option(calibration, |w, c| c.write_state(w));
+    w.option(lens, |w, l| l.write_state(w));
+    let paths = |w: &mut StateWriter, paths: &[PathBuf]| {
+        w.count(paths.len());
+        for p in paths {
+            w.str(&p.to_string_lossy());
+        }
+    };
+    paths(&mut w, out_dirs);
+    w.option(single_outs, paths);
+    w.0
+}
+
+/// Sends `state` from `encode_state` to a worker ahead of its first job, length first.
+fn send_state(out: &mut impl Write, state: &[u8]) -> std::io::Result<()> {
+    out.write_all(&(state.len() as u64).to_le_bytes())?;
+    out.write_all(state)?;
+    out.flush()
+}
+
+/// Reads the state a `--worker` process gets on stdin before its first job.
+pub fn read_state(input: &mut impl Read) -> Result<Prepared> {
+    let mut len = [0u8; 8];
+    input
+        .read_exact(&mut len)
+        .context("Failed to read worker state")?;
+    let len = u64::from_le_bytes(len);
+    let mut buf = Vec::new();
+    input
+        .take(len)
+        .read_to_end(&mut buf)
+        .context("Failed to read worker state")?;
+    if buf.len() as u64 != len {
+        anyhow::bail!("worker state is truncated");
+    }
+    let mut r = StateReader { buf: &buf, pos: 0 };
+    let calibration = r.option(Calibration::read_state)?;
+    let lens = r.option(LensCorrection::read_state)?;
+    let paths = |r: &mut StateReader| -> Result<Vec<PathBuf>> {
+        (0..r.count()?)
+            .map(|_| Ok(PathBuf::from(r.str()?)))
+            .collect()
+    };
+    let out_dirs = paths(&mut r)?;
+    let single_outs = r.option(paths)?;
+    Ok(Prepared {
+        calibration,
+        lens,
+        out_dirs,
+        single_outs,
+    })
+}
+
+fn encode_reply(seq: usize, converted: &Converted) -> String {
+    let mut reply = match &converted.outcome {
+        Outcome::Done { raw_fmt, elapsed } => json!({
+            "seq": seq,
+            "bytes": converted.bytes,
+            "raw_fmt": raw_fmt,
+            "elapsed": elapsed,
+        }),
+        Outcome::Failed(msg) => json!({
+            "seq": seq,
+            "bytes": converted.bytes,
+            "failed": msg,
+        }),
+    };
+    match &converted.camera {
+        Seen::Camera { make, model } => reply["camera"] = json!([make, model]),
+        // An empty name stands for an unsupported file without an EXIF camera.
+        Seen::Unsupported(camera) => reply["unsupported"] = json!(camera.as_deref().unwrap_or("")),
+        Seen::Unknown => {}
+    }
+    reply.to_string()
+}
+
+fn decode_reply(v: &Value) -> Converted {
+    let outcome = match v["failed"].as_str() {
+        Some(msg) => Outcome::Failed(msg.to_string()),
+        None => Outcome::Done {
+            raw_fmt: v["raw_fmt"].as_str().unwrap_or_default().to_string(),
+            elapsed: v["elapsed"].as_f64().unwrap_or(0.0),
+        },
+    };
+    let camera = match (&v["camera"][0], &v["camera"][1], v["unsupported"].as_str()) {
+        (Value::String(make), Value::String(model), _) => Seen::Camera {
+            make: make.clone(),
+            model: model.clone(),
+        },
+        (_, _, Some(camera)) => {
+            Seen::Unsupported(Some(camera.to_string()).filter(|c| !c.is_empty()))
+        }
+        _ => Seen::Unknown,
+    };
+    Converted {
+        outcome,
+        bytes: v["bytes"].as_u64().unwrap_or(0),
+        camera,
+    }
+}
+
+/// What the parent needs from a file's metadata before converting, read by a worker so
+/// that the parent never parses an input itself.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct Probed {
+    /// Capture time, for `--sort capture`.
+    pub timestamp: Option<i64>,
+    /// Output stem of the file as a single input, `--name` expanded.
+    pub stem: String,
+}
+
+fn encode_probed(seq: usize, probed: &Probed) -> String {
+    json!({
+        "seq": seq,
+        "timestamp": probed.timestamp,
+        "stem": probed.stem,
+    })
+    .to_string()
+}
+
+fn decode_probed(v: &Value) -> Probed {
+    Probed {
+        timestamp: v["timestamp"].as_i64(),
+        stem: v["stem"].as_str().unwrap_or_default().to_string(),
+    }
+}
+
+/// Body of a `--worker` process: converts or probes the files sent as job lines on stdin
+/// one at a time and answers each with a reply line on stdout, until the parent closes
+/// stdin.
+pub fn serve(
+    convert: impl Fn(usize, &Path) -> Converted,
+    probe: impl Fn(&Path) -> Probed,
+) -> Result<()> {
+    // Ctrl-C reaches the whole process group; the parent decides when to stop.
+    ctrlc::set_handler(|| {})?;
+    eprintln!("{}", READY);
+    let mut stdout = std::io::stdout();
+    for line in std::io::stdin().lock().lines() {
+        let line = line?;
+        let job: Value = serde_json::from_str(&line).context("Invalid worker job")?;
+        let seq = job["seq"].as_u64().map(|s| s as usize);
+        let reply = match (seq, job["path"].as_str(), job["probe"].as_str()) {
+            (Some(seq), Some(path), _) => encode_reply(seq, &convert(seq, Path::new(path))),
+            (Some(seq), _, Some(path)) => encode_probed(seq, &probe(Path::new(path))),
+            _ => anyhow::bail!("Invalid worker job: {}", red(line)),
+        };
+        writeln!(stdout, "{}{}", REPLY, reply)?;
+        stdout.flush()?;
+    }
+    Ok(())
+}
+
+/// A running `fempeg --worker` with the same options as this process.
+struct Worker {
+    child: Child,
+    stdin: ChildStdin,
+    stdout: BufReader<ChildStdout>,
+    stderr: JoinHandle<()>,
+}
+
+impl Worker {
+    fn spawn(state: &[u8], debug: bool) -> Result<Worker> {
+        let mut child = Command::new(env::current_exe()?)
+            .arg("--worker")
+            .args(env::args_os().skip(1))
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Failed to start a worker process")?;
+        let mut stdin = child.stdin.take().context("worker stdin")?;
+        let stdout = BufReader::new(child.stdout.take().context("worker stdout")?);
+        let stderr = child.stderr.take().context("worker stderr")?;
+        let stderr = thread::spawn(move || {
+            let mut ready = debug;
+            let mut early = Vec::new();
+            for line in BufReader::new(stderr).lines().map_while(|l| l.ok()) {
+                if line == READY {
+                    ready = true;
+                } else if ready {
+                    eprintln!("{}", line);
+                } else {
+                    early.push(line);
+                }
+            }
+            if !ready {
+                for line in early {
+                    eprintln!("{}", line);
+                }
+            }
+        });
+        if debug {
+            println!("{} started worker {}", blue("[isolate]"), child.id());
+        }
+        let sent = send_state(&mut stdin, state);
+        let worker = Worker {
+            child,
+            stdin,
+            stdout,
+            stderr,
+        };
+        match sent {
+            Ok(()) => Ok(worker),
+            Err(e) => Err(anyhow::anyhow!(
+                "Failed to send state to a worker: {} ({})",
+                e,
+                worker.reap()
+            )),
+        }
+    }
+
+    /// Sends one file as a `kind` job and waits for the reply; an error means the worker
+    /// died or broke the protocol.
+    fn send(&mut self, kind: &str, seq: usize, path: &Path, debug: bool) -> Result<Value> {
+        let job = json!({ "seq": seq, kind: path.to_string_lossy() });
+        writeln!(self.stdin, "{}", job)?;
+        self.stdin.flush()?;
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.stdout.read_line(&mut line)? == 0 {
+                anyhow::bail!("worker closed its output");
+            }
+            match line.trim_end().strip_prefix(REPLY) {
+                Some(reply) => {
+                    let v: Value = serde_json::from_str(reply).context("Invalid worker reply")?;
+                    let got = v["seq"].as_u64().context("Worker reply without seq")?;
+                    if got != seq as u64 {
+                        anyhow::bail!("worker answered job {} instead of {}", got, seq);
+                    }
+                    return Ok(v);
+                }
+                None if debug => print!("{}", line),
+                None => {}
+            }
+        }
+    }
+
+    /// Reaps a worker that failed mid-file and describes how it ended.
+    fn reap(mut self) -> String {
+        self.child.kill().ok();
+        drop(self.stdin);
+        let status = self.child.wait();
+        self.stderr.join().ok();
+        match status {
+            Ok(s) => s.to_string(),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    /// Closes stdin so that the worker exits, and waits for it.
+    fn finish(mut self) {
+        drop(self.stdin);
+        self.child.wait().ok();
+        self.stderr.join().ok();
+    }
+}
+
+/// Sends every input as a `kind` job to a pool of `workers` subprocesses, each sent `state`
+/// from `encode_state` over its stdin, and passes `done` each reply or why the worker
+/// failed on that file. A worker that crashes is replaced for the next file.
+fn pool(
+    inputs: Vec<PathBuf>,
+    state: &[u8],
+    workers: usize,
+    stop: &AtomicBool,
+    debug: bool,
+    kind: &str,
+    done: impl Fn(usize, &Path, Result<Value, String>) + Sync,
+) {
+    let queue = Mutex::new(inputs.into_iter().enumerate().collect::<VecDeque<_>>());
+    thread::scope(|s| {
+        for _ in 0..workers.max(1) {
+            s.spawn(|| {
+                let mut worker: Option<Worker> = None;
+                while !stop.load(Ordering::SeqCst) {
+                    let Some((seq, path)) =
+                        queue.lock().unwrap_or_else(|e| e.into_inner()).pop_front()
+                    else {
+                        break;
+                    };
+                    let mut w = match worker.take() {
+                        Some(w) => w,
+                        None => match Worker::spawn(state, debug) {
+                            Ok(w) => w,
+                            Err(e) => {
+                                done(seq, &path, Err(format!("{}: {:#}", red("Error"), e)));
+                                continue;
+                            }
+                        },
+                    };
+                    match w.send(kind, seq, &path, debug) {
+                        Ok(reply) => {
+                            worker = Some(w);
+                            done(seq, &path, Ok(reply));
+                        }
+                        Err(e) => {
+                            let status = w.reap();
+                            if debug {
+                                println!(
+                                    "{} worker failed on {}: {:#}",
+                                    blue("[isolate]"),
+                                    path.file_name().unwrap_or_default().to_string_lossy(),
+                                    e
+                                );
+                            }
+                            done(
+                                seq,
+                                &path,
+                                Err(format!("{} ({})", red("Worker crashed"), status)),
+                            );
+                        }
+                    }
+                }
+                if let Some(w) = worker {
+                    w.finish();
+                }
+            });
+        }
+    });
+}
+
+/// `--isolate`: converts `inputs` in `workers` subprocesses. A worker that crashes fails
+/// only the file it was on.
+pub fn run(
+    inputs: Vec<PathBuf>,
+    state: &[u8],
+    workers: usize,
+    stop: &AtomicBool,
+    debug: bool,
+    report: impl Fn(&Path, Converted) + Sync,
+) {
+    pool(
+        inputs,
+        state,
+        workers,
+        stop,
+        debug,
+        "path",
+        |_, path, reply| {
+            let converted = match reply {
+                Ok(v) => decode_reply(&v),
+                Err(msg) => Converted {
+                    outcome: Outcome::Failed(format!(
+                        "{}... {}",
+                        path.file_name().unwrap_or_default().to_string_lossy(),
+                        msg
+                    )),
+                    bytes: 0,
+                    camera: Seen::Unknown,
+                },
+            };
+            report(path, converted);
+        },
+    );
+}
+
+/// Reads what the parent needs from the metadata of `inputs` in `workers` subprocesses, so
+/// that a file that crashes the parser cannot end the run before it starts. `None` for a
+/// file its worker failed on.
+pub fn probe(inputs: &[PathBuf], workers: usize, debug: bool) -> Vec<Option<Probed>> {
+    let state = encode_state(None, None, &[], None);
+    let probed = Mutex::new(vec![None; inputs.len()]);
+    let stop = AtomicBool::new(false);
+    pool(
+        inputs.to_vec(),
+        &state,
+        workers,
+        &stop,
+        debug,
+        "probe",
+        |seq, _, reply| {
+            if let Ok(v) = reply {
+                probed.lock().unwrap_or_else(|e| e.into_inner())[seq] = Some(decode_probed(&v));
+            }
+        },
+    );
+    probed.into_inner().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::calibration::{FlatField, Mosaic};
+    use crate::libraw::ColorLevels;
+
+    #[test]
+    fn state_round_trip() {
+        let dark = Mosaic {
+            width: 3,
+            height: 2,
+            filters: 0x94949494,
+            xtrans: [[1; 6]; 6],
+            levels: Some(ColorLevels {
+                black: 512,
+                cblack: [1, 2, 3, 4],
+                black_pattern: Some((2, 1, vec![5, 6])),
+                maximum: 16383,
+                data_maximum: 15000,
+                linear_max: [-1, 0, 1, 2],
+                cam_xyz: [[0.5; 3]; 4],
+            }),
+            white: 16383,
+            samples: vec![510, 511, 512, 513, 514, 4000],
+        };
+        let calibration = Calibration {
+            dark: Some(dark),
+            flat: Some(FlatField {
+                width: 1,
+                height: 2,
+                gain: vec![1.5, 0.75],
+            }),
+            bad_pixels: vec![(2, 1)],
+        };
+        let outs = vec![PathBuf::from("out/a.png"), PathBuf::from("out/a.jpeg")];
+        let state = encode_state(Some(&calibration), None, &[], Some(&outs));
+        let mut sent = Vec::new();
+        send_state(&mut sent, &state).unwrap();
+        sent.extend(b"{\"seq\":0}\n");
+        let mut input = &sent[..];
+        let prepared = read_state(&mut input).unwrap();
+        assert_eq!(input, b"{\"seq\":0}\n");
+
+        let c = prepared.calibration.unwrap();
+        let d = c.dark.unwrap();
+        assert_eq!(
+            (d.width, d.height, d.filters, d.white),
+            (3, 2, 0x94949494, 16383)
+        );
+        assert_eq!(d.xtrans, [[1; 6]; 6]);
+        assert_eq!(d.samples, [510, 511, 512, 513, 514, 4000]);
+        let l = d.levels.unwrap();
+        assert_eq!((l.black, l.cblack), (512, [1, 2, 3, 4]));
+        assert_eq!(l.black_pattern, Some((2, 1, vec![5, 6])));
+        assert_eq!((l.maximum, l.data_maximum), (16383, 15000));
+        assert_eq!((l.linear_max, l.cam_xyz), ([-1, 0, 1, 2], [[0.5; 3]; 4]));
+        let f = c.flat.unwrap();
+        assert_eq!((f.width, f.height, f.gain), (1, 2, vec![1.5, 0.75]));
+        assert_eq!(c.bad_pixels, [(2, 1)]);
+        assert!(prepared.lens.is_none());
+        assert!(prepared.out_dirs.is_empty());
+        assert_eq!(prepared.single_outs, Some(outs));
+    }
+
+    #[test]
+    fn replies_carry_the_camera() {
+        for camera in [
+            Seen::Unknown,
+            Seen::Camera {
+                make: "Nikon".into(),
+                model: "Z 8".into(),
+            },
+            Seen::Unsupported(None),
+            Seen::Unsupported(Some("Canon EOS R1".into())),
+        ] {
+            let converted = Converted {
+                outcome: Outcome::Failed("a.nef... Error".into()),
+                bytes: 3,
+                camera: camera.clone(),
+            };
+            let reply: Value = serde_json::from_str(&encode_reply(7, &converted)).unwrap();
+            let back = decode_reply(&reply);
+            assert_eq!((reply["seq"].as_u64(), back.bytes), (Some(7), 3));
+            assert!(matches!(back.outcome, Outcome::Failed(m) if m == "a.nef... Error"));
+            assert_eq!(back.camera, camera);
+        }
+    }
+
+    #[test]
+    fn probe_replies_round_trip() {
+        for probed in [
+            Probed::default(),
+            Probed {
+                timestamp: Some(1_700_000_000),
+                stem: "2024-05-01_Z 8".into(),
+            },
+        ] {
+            let reply: Value = serde_json::from_str(&encode_probed(3, &probed)).unwrap();
+            assert_eq!(reply["seq"].as_u64(), Some(3));
+            assert_eq!(decode_probed(&reply), probed);
+        }
+    }
+
+    #[test]
+    fn truncated_state_is_an_error() {
+        let state = encode_state(None, None, &[PathBuf::from("out/png")], None);
+        let mut r = StateReader {
+            buf: &state[..state.len() - 2],
+            pos: 0,
+        };
+        assert!(r.option(Calibration::read_state).unwrap().is_none());
+        assert!(r.option(LensCorrection::read_state).unwrap().is_none());
+        assert!(r.count().and_then(|_| r.str()).is_err());
+
+        let mut sent = Vec::new();
+        send_state(&mut sent, &state).unwrap();
+        assert!(read_state(&mut &sent[..sent.len() - 1]).is_err());
+        assert!(read_state(&mut &sent[..4]).is_err());
+    }
+}
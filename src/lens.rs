@@ -0,0 +1,559 @@
+use anyhow::{Context, Result};
+use image::{DynamicImage, Rgb32FImage};
+use rayon::prelude::*;
+use std::collections::HashSet;
+use std::path::Path;
+use std::sync::Mutex;
+
+use crate::color_profile::ToneCurve;
+use crate::isolate::{StateReader, StateWriter};
+use crate::raw_metadata::RawMetadata;
+use crate::term_colors::{blue, pink, red};
+
+/// Lens models already reported as missing from the table, so a batch warns once per lens.
+static MISSING: Mutex<Option<HashSet<String>>> = Mutex::new(None);
+
+/// Corrections for one lens; each part is optional.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct LensProfile {
+    /// Red and blue magnification around the center, as dcraw's `-C`; 1.0 is none.
+    pub ca: Option<[f64; 2]>,
+    /// Radial distortion k1, k2, k3: a point at radius r of the corrected image (1.0 is
+    /// half the diagonal) was recorded at r * (1 + k1 r² + k2 r⁴ + k3 r⁶).
+    pub distortion: Option<[f32; 3]>,
+    /// Vignetting v1, v2, v3: brightness at radius r is 1 + v1 r² + v2 r⁴ + v3 r⁶ times
+    /// the center's.
+    pub vignetting: Option<[f32; 3]>,
+}
+
+impl LensProfile {
+    /// Parts set in `over` replace these.
+    fn merge(self, over: LensProfile) -> LensProfile {
+        LensProfile {
+            ca: over.ca.or(self.ca),
+            distortion: over.distortion.or(self.distortion),
+            vignetting: over.vignetting.or(self.vignetting),
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.ca.is_none() && self.distortion.is_none() && self.vignetting.is_none()
+    }
+
+    fn write_state(&self, w: &mut StateWriter) {
+        w.option(self.ca.as_ref(), |w, v| v.iter().for_each(|&x| w.f64(x)));
+        w.option(self.distortion.as_ref(), |w, v| w.f32s(v));
+        w.option(self.vignetting.as_ref(), |w, v| w.f32s(v));
+    }
+
+    fn read_state(r: &mut StateReader) -> Result<LensProfile> {
+        let coefficients = |r: &mut StateReader| -> Result<[f32; 3]> {
+            r.f32s()?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("worker state has malformed lens coefficients"))
+        };
+        Ok(LensProfile {
+            ca: r.option(|r| Ok([r.f64()?, r.f64()?]))?,
+            distortion: r.option(coefficients)?,
+            vignetting: r.option(coefficients)?,
+        })
+    }
+
+    fn check(&self) -> Result<()> {
+        if let Some(ca) = self.ca
+            && ca.iter().any(|&s| !(0.9..=1.1).contains(&s))
+        {
+            anyhow::bail!(
+                "Chromatic aberration scales {} must be between 0.9 and 1.1",
+                red(format!("{:?}", ca))
+            );
+        }
+        for (what, k) in [
+            ("Distortion", self.distortion),
+            ("Vignetting", self.vignetting),
+        ] {
+            if let Some(k) = k
+                && (0..=100).any(|i| radial(k, i as f32 / 100.0) < 0.1)
+            {
+                anyhow::bail!(
+                    "{} coefficients {} fold the image over inside the frame",
+                    what,
+                    red(format!("{:?}", k))
+                );
+            }
+        }
+        Ok(())
+    }
+}
+
+/// 1 + k1 r² + k2 r⁴ + k3 r⁶.
+fn radial(k: [f32; 3], r: f32) -> f32 {
+    let r2 = r * r;
+    1.0 + r2 * (k[0] + r2 * (k[1] + r2 * k[2]))
+}
+
+/// `min` to `N` values, missing trailing ones as 0.
+fn coefficients<const N: usize>(values: &[f64], min: usize, what: &str) -> Result<[f64; N]> {
+    if values.len() < min || values.len() > N {
+        anyhow::bail!(
+            "{} takes {} values, got {}",
+            what,
+            if min == N {
+                N.to_string()
+            } else {
+                format!("{} to {}", min, N)
+            },
+            red(values.len())
+        );
+    }
+    let mut out = [0.0; N];
+    out[..values.len()].copy_from_slice(values);
+    Ok(out)
+}
+
+/// Parses comma-separated coefficients, e.g. `-0.05,0.01`.
+fn parse_coefficients<const N: usize>(s: &str, min: usize, what: &str) -> Result<[f64; N]> {
+    let values = s
+        .split(',')
+        .map(|t| {
+            t.trim()
+                .parse::<f64>()
+                .with_context(|| format!("Invalid number {}", red(t.trim())))
+        })
+        .collect::<Result<Vec<f64>>>()?;
+    coefficients(&values, min, what)
+}
+
+fn to_f32(v: [f64; 3]) -> [f32; 3] {
+    v.map(|x| x as f32)
+}
+
+fn toml_numbers(value: &toml::Value, key: &str) -> Result<Vec<f64>> {
+    value
+        .as_array()
+        .with_context(|| format!("{} must be an array of numbers", red(key)))?
+        .iter()
+        .map(|v| {
+            v.as_float()
+                .or_else(|| v.as_integer().map(|i| i as f64))
+                .with_context(|| format!("{} must be an array of numbers", red(key)))
+        })
+        .collect()
+}
+
+fn toml_profile(table: &toml::Table) -> Result<LensProfile> {
+    let mut profile = LensProfile::default();
+    for (key, value) in table {
+        match key.as_str() {
+            "ca" => profile.ca = Some(coefficients(&toml_numbers(value, key)?, 2, key)?),
+            "distortion" => {
+                profile.distortion = Some(to_f32(coefficients(&toml_numbers(value, key)?, 1, key)?))
+            }
+            "vignetting" => {
+                profile.vignetting = Some(to_f32(coefficients(&toml_numbers(value, key)?, 1, key)?))
+            }
+            "focal" => {}
+            _ => anyhow::bail!(
+                "Unknown key {}. Valid: {}, {}, {}, {}",
+                red(key),
+                blue("ca"),
+                blue("distortion"),
+                blue("vignetting"),
+                blue("focal")
+            ),
+        }
+    }
+    profile.check()?;
+    Ok(profile)
+}
+
+/// Lower case with single spaces, so table keys need not match the maker's spacing.
+fn normalize(s: &str) -> String {
+    s.split_whitespace()
+        .collect::<Vec<_>>()
+        .join(" ")
+        .to_lowercase()
+}
+
+struct LensEntry {
+    name: String,
+    profile: LensProfile,
+    /// Overrides at given focal lengths (mm), for zooms.
+    focal: Vec<(f32, LensProfile)>,
+}
+
+impl LensEntry {
+    /// The lens-wide profile with the overrides of the nearest listed focal length.
+    fn at(&self, focal: Option<f32>) -> LensProfile {
+        let nearest = focal.and_then(|f| {
+            self.focal
+                .iter()
+                .min_by(|a, b| (a.0 - f).abs().total_cmp(&(b.0 - f).abs()))
+        });
+        match nearest {
+            Some((_, over)) => self.profile.merge(*over),
+            None => self.profile,
+        }
+    }
+}
+
+/// Lens table and command line overrides for a run.
+pub struct LensCorrection {
+    table: Vec<LensEntry>,
+    manual: LensProfile,
+}
+
+impl LensCorrection {
+    /// `None` when no correction was asked for.
+    pub fn load(
+        table: Option<&Path>,
+        ca: Option<&str>,
+        distortion: Option<&str>,
+        vignetting: Option<&str>,
+    ) -> Result<Option<LensCorrection>> {
+        let manual = LensProfile {
+            ca: ca.map(|s| parse_coefficients(s, 2, "--ca")).transpose()?,
+            distortion: distortion
+                .map(|s| parse_coefficients(s, 1, "--distortion").map(to_f32))
+                .transpose()?,
+            vignetting: vignetting
+                .map(|s| parse_coefficients(s, 1, "--vignetting").map(to_f32))
+                .transpose()?,
+        };
+        manual.check()?;
+        let table = match table {
+            Some(path) => read_table(path)?,
+            None => Vec::new(),
+        };
+        if table.is_empty() && manual.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(LensCorrection { table, manual }))
+    }
+
+    /// Encodes the table and overrides for `--isolate` workers.
+    pub fn write_state(&self, w: &mut StateWriter) {
+        self.manual.write_state(w);
+        w.count(self.table.len());
+        for e in &self.table {
+            w.str(&e.name);
+            e.profile.write_state(w);
+            w.count(e.focal.len());
+            for (mm, p) in &e.focal {
+                w.f32(*mm);
+                p.write_state(w);
+            }
+        }
+    }
+
+    pub fn read_state(r: &mut StateReader) -> Result<LensCorrection> {
+        let manual = LensProfile::read_state(r)?;
+        let table = (0..r.count()?)
+            .map(|_| {
+                let name = r.str()?;
+                let profile = LensProfile::read_state(r)?;
+                let focal = (0..r.count()?)
+                    .map(|_| Ok((r.f32()?, LensProfile::read_state(r)?)))
+                    .collect::<Result<_>>()?;
+                Ok(LensEntry {
+                    name,
+                    profile,
+                    focal,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok(LensCorrection { table, manual })
+    }
+
+    /// Profile for a file: its lens's table entry (by `LensModel`, with or without the lens
+    /// make) under the command line values.
+    pub fn profile_for(&self, meta: Option<&RawMetadata>, debug: bool) -> LensProfile {
+        let entry = meta.filter(|m| !m.lens.is_empty()).and_then(|m| {
+            let lens = normalize(&m.lens);
+            let full = normalize(&format!("{} {}", m.lens_make, m.lens));
+            self.table.iter().find(|e| e.name == lens || e.name == full)
+        });
+        let from_table = match entry {
+            Some(e) => {
+                let profile = e.at(meta.and_then(|m| m.focal_len));
+                if debug {
+                    println!(
+                        "{} table entry {} {}",
+                        blue("[lens]"),
+                        pink(&e.name),
+                        pink(format!("{:?}", profile))
+                    );
+                }
+                profile
+            }
+            None => {
+                if !self.table.is_empty() {
+                    let lens = meta.map(|m| m.lens.clone()).unwrap_or_default();
+                    let mut missing = MISSING.lock().unwrap_or_else(|e| e.into_inner());
+                    if missing
+                        .get_or_insert_with(HashSet::new)
+                        .insert(lens.clone())
+                    {
+                        eprintln!(
+                            "{}",
+                            pink(if lens.is_empty() {
+                                "Warning: no lens model in the metadata, lens table not applied"
+                                    .to_string()
+                            } else {
+                                format!("Warning: lens {} is not in the lens table", red(&lens))
+                            })
+                        );
+                    }
+                }
+                LensProfile::default()
+            }
+        };
+        from_table.merge(self.manual)
+    }
+}
+
+/// Reads a table of `["Lens model"]` sections with `ca`, `distortion` and `vignetting`
+/// arrays, and `["Lens model".focal.24]` sub-sections overriding them at 24 mm.
+fn read_table(path: &Path) -> Result<Vec<LensEntry>> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let doc: toml::Table = text
+        .parse()
+        .with_context(|| format!("Invalid lens table {:?}", path))?;
+    let mut out = Vec::new();
+    for (name, value) in &doc {
+        let table = value
+            .as_table()
+            .with_context(|| format!("Lens {} in {:?} is not a table", red(name), path))?;
+        let profile = toml_profile(table).with_context(|| format!("Lens {:?}", name))?;
+        let mut focal = Vec::new();
+        if let Some(by_focal) = table.get("focal") {
+            let by_focal = by_focal
+                .as_table()
+                .with_context(|| format!("{}.focal in {:?} is not a table", red(name), path))?;
+            for (mm, value) in by_focal {
+                let f = mm
+                    .parse::<f32>()
+                    .ok()
+                    .filter(|&f| f > 0.0)
+                    .with_context(|| format!("Invalid focal length {} for {:?}", red(mm), name))?;
+                let over = value
+                    .as_table()
+                    .context("not a table")
+                    .and_then(toml_profile)
+                    .with_context(|| format!("Lens {:?} at {} mm", name, mm))?;
+                focal.push((f, over));
+            }
+        }
+        out.push(LensEntry {
+            name: normalize(name),
+            profile,
+            focal,
+        });
+    }
+    Ok(out)
+}
+
+fn sample(buf: &Rgb32FImage, x: f32, y: f32) -> [f32; 3] {
+    let (w, h) = (buf.width() as i64, buf.height() as i64);
+    let (x0, y0) = (x.floor(), y.floor());
+    let (fx, fy) = (x - x0, y - y0);
+    let px = |xx: i64, yy: i64| {
+        buf.get_pixel(xx.clamp(0, w - 1) as u32, yy.clamp(0, h - 1) as u32)
+            .0
+    };
+    let (x0, y0) = (x0 as i64, y0 as i64);
+    let (a, b, c, d) = (
+        px(x0, y0),
+        px(x0 + 1, y0),
+        px(x0, y0 + 1),
+        px(x0 + 1, y0 + 1),
+    );
+    [0, 1, 2].map(|i| {
+        let top = a[i] + (b[i] - a[i]) * fx;
+        let bottom = c[i] + (d[i] - c[i]) * fx;
+        top + (bottom - top) * fy
+    })
+}
+
+/// Undoes distortion and vignetting. The corrected image is scaled to fill the frame, so
+/// no empty corners are left; vignetting is divided out in linear light through `curve`.
+pub fn correct_rgb(
+    buf: &Rgb32FImage,
+    profile: &LensProfile,
+    curve: &ToneCurve,
+    debug: bool,
+) -> Rgb32FImage {
+    let (w, h) = (buf.width(), buf.height());
+    let (cx, cy) = ((w as f32 - 1.0) / 2.0, (h as f32 - 1.0) / 2.0);
+    let half_diag = (cx * cx + cy * cy).sqrt().max(1.0);
+    let zoom = profile.distortion.map_or(1.0, |k| {
+        (0..=100)
+            .map(|i| radial(k, i as f32 / 100.0))
+            .fold(1.0f32, f32::max)
+    });
+    if debug {
+        println!(
+            "{} distortion {} vignetting {} zoom {}",
+            blue("[lens]"),
+            pink(format!("{:?}", profile.distortion)),
+            pink(format!("{:?}", profile.vignetting)),
+            pink(format!("{:.4}", zoom))
+        );
+    }
+    let linear = curve.is_linear();
+    let rows: Vec<Vec<f32>> = (0..h)
+        .into_par_iter()
+        .map(|y| {
+            let mut row = Vec::with_capacity(w as usize * 3);
+            for x in 0..w {
+                let (dx, dy) = (x as f32 - cx, y as f32 - cy);
+                let r = (dx * dx + dy * dy).sqrt() / half_diag;
+                let scale = profile.distortion.map_or(1.0, |k| radial(k, r) / zoom);
+                let (sx, sy) = (cx + dx * scale, cy + dy * scale);
+                let mut px = sample(buf, sx, sy);
+                if let Some(v) = profile.vignetting {
+                    let gain = 1.0 / radial(v, r * scale);
+                    for c in px.iter_mut() {
+                        *c = if linear {
+                            *c * gain
+                        } else {
+                            curve.encode(curve.decode(*c as f64) * gain as f64) as f32
+                        };
+                    }
+                }
+                row.extend_from_slice(&px);
+            }
+            row
+        })
+        .collect();
+    Rgb32FImage::from_raw(w, h, rows.concat()).unwrap_or_else(|| buf.clone())
+}
+
+/// Lens correction of any decoded image, returned in its own sample type.
+pub fn correct_image(
+    img: DynamicImage,
+    profile: &LensProfile,
+    curve: &ToneCurve,
+    debug: bool,
+) -> DynamicImage {
+    if profile.distortion.is_none() && profile.vignetting.is_none() {
+        return img;
+    }
+    match img {
+        DynamicImage::ImageRgb32F(buf) => {
+            DynamicImage::ImageRgb32F(correct_rgb(&buf, profile, curve, debug))
+        }
+        img => {
+            let sixteen = matches!(
+                img,
+                DynamicImage::ImageRgb16(_) | DynamicImage::ImageRgba16(_)
+            );
+            let out =
+                DynamicImage::ImageRgb32F(correct_rgb(&img.to_rgb32f(), profile, curve, debug));
+            if sixteen {
+                DynamicImage::ImageRgb16(out.to_rgb16())
+            } else {
+                DynamicImage::ImageRgb8(out.to_rgb8())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    const TABLE: &str = r#"
+["Canon RF 24-105mm F4 L IS USM"]
+ca = [1.0004, 0.9997]
+distortion = [-0.05]
+vignetting = [-0.3, 0.1]
+
+["Canon RF 24-105mm F4 L IS USM".focal.24]
+distortion = [-0.08, 0.02]
+
+["Canon RF 24-105mm F4 L IS USM".focal.105]
+distortion = [0.02]
+"#;
+
+    /// Loads `text` as a lens table, with the given command line values.
+    fn load_table(
+        text: &str,
+        ca: Option<&str>,
+        distortion: Option<&str>,
+    ) -> Result<Option<LensCorrection>> {
+        static NEXT: AtomicUsize = AtomicUsize::new(0);
+        let n = NEXT.fetch_add(1, Ordering::Relaxed);
+        let path = std::env::temp_dir().join(format!("fempeg-{}-{}.toml", std::process::id(), n));
+        std::fs::write(&path, text).unwrap();
+        let lenses = LensCorrection::load(Some(&path), ca, distortion, None);
+        std::fs::remove_file(&path).unwrap();
+        lenses
+    }
+
+    fn meta(lens: &str, focal: f32) -> RawMetadata {
+        RawMetadata {
+            lens: lens.into(),
+            lens_make: "Canon".into(),
+            focal_len: Some(focal),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn table_picks_nearest_focal_length() {
+        let lenses = load_table(TABLE, None, None).unwrap().unwrap();
+        let wide = lenses.profile_for(Some(&meta("RF 24-105mm  F4 L IS USM", 28.0)), false);
+        assert_eq!(wide.distortion, Some([-0.08, 0.02, 0.0]));
+        assert_eq!(wide.ca, Some([1.0004, 0.9997]));
+        assert_eq!(wide.vignetting, Some([-0.3, 0.1, 0.0]));
+        let tele = lenses.profile_for(Some(&meta("canon rf 24-105mm f4 l is usm", 90.0)), false);
+        assert_eq!(tele.distortion, Some([0.02, 0.0, 0.0]));
+        assert!(
+            lenses
+                .profile_for(Some(&meta("RF 50mm F1.8 STM", 50.0)), false)
+                .is_empty()
+        );
+    }
+
+    #[test]
+    fn manual_values_override_the_table() {
+        let lenses = load_table(TABLE, Some("1.001,0.999"), Some("0.01"));
+        let profile = lenses
+            .unwrap()
+            .unwrap()
+            .profile_for(Some(&meta("RF 24-105mm F4 L IS USM", 24.0)), false);
+        assert_eq!(profile.ca, Some([1.001, 0.999]));
+        assert_eq!(profile.distortion, Some([0.01, 0.0, 0.0]));
+        assert_eq!(profile.vignetting, Some([-0.3, 0.1, 0.0]));
+    }
+
+    #[test]
+    fn rejects_bad_tables() {
+        assert!(load_table("[\"Lens\"]\nsharpness = [1.0]\n", None, None).is_err());
+        assert!(load_table("[\"Lens\"]\nca = [1.0]\n", None, None).is_err());
+        assert!(load_table("[\"Lens\"]\ndistortion = \"-0.05\"\n", None, None).is_err());
+        assert!(load_table("[\"Lens\".focal.wide]\ndistortion = [0.01]\n", None, None).is_err());
+    }
+
+    #[test]
+    fn coefficients_are_checked() {
+        assert_eq!(
+            parse_coefficients::<3>("-0.05, 0.01", 1, "k").unwrap(),
+            [-0.05, 0.01, 0.0]
+        );
+        assert!(parse_coefficients::<3>("", 1, "k").is_err());
+        assert!(parse_coefficients::<2>("1,1,1", 2, "k").is_err());
+        assert!(
+            LensCorrection::load(None, None, None, None)
+                .unwrap()
+                .is_none()
+        );
+        assert!(LensCorrection::load(None, Some("1.2,1.0"), None, None).is_err());
+        // Pulls the corners past the center.
+        assert!(LensCorrection::load(None, None, Some("-1.5"), None).is_err());
+        assert!(LensCorrection::load(None, None, None, Some("-0.5")).is_ok());
+    }
+}
@@ -0,0 +1,561 @@
+use anyhow::Context;
+use std::ffi::CStr;
+use std::fmt;
+use std::os::raw::c_int;
+use std::ptr::NonNull;
+
+use crate::libraw_ffi::{
+    self, DataLayout, LibRawApi, LibRawIParams, LibRawImageSizesHead, LibRawImgOther,
+    LibRawLensInfoHead, LibRawProcessedImage, libraw_data_t,
+};
+
+/// `LibRaw_errors` from libraw_const.h. Positive codes are `errno` values from file I/O.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LibRawError {
+    /// `libraw_init` returned null.
+    InitFailed,
+    Unspecified,
+    FileUnsupported,
+    RequestForNonexistentImage,
+    OutOfOrderCall,
+    NoThumbnail,
+    UnsupportedThumbnail,
+    InputClosed,
+    NotImplemented,
+    RequestForNonexistentThumbnail,
+    UnsufficientMemory,
+    DataError,
+    IoError,
+    CancelledByCallback,
+    BadCrop,
+    TooBig,
+    MempoolOverflow,
+    Other(c_int),
+}
+
+impl LibRawError {
+    pub fn from_code(code: c_int) -> LibRawError {
+        match code {
+            -1 => LibRawError::Unspecified,
+            -2 => LibRawError::FileUnsupported,
+            -3 => LibRawError::RequestForNonexistentImage,
+            -4 => LibRawError::OutOfOrderCall,
+            -5 => LibRawError::NoThumbnail,
+            -6 => LibRawError::UnsupportedThumbnail,
+            -7 => LibRawError::InputClosed,
+            -8 => LibRawError::NotImplemented,
+            -9 => LibRawError::RequestForNonexistentThumbnail,
+            -100007 => LibRawError::UnsufficientMemory,
+            -100008 => LibRawError::DataError,
+            -100009 => LibRawError::IoError,
+            -100010 => LibRawError::CancelledByCallback,
+            -100011 => LibRawError::BadCrop,
+            -100012 => LibRawError::TooBig,
+            -100013 => LibRawError::MempoolOverflow,
+            other => LibRawError::Other(other),
+        }
+    }
+
+    pub fn code(self) -> Option<c_int> {
+        Some(match self {
+            LibRawError::InitFailed => return None,
+            LibRawError::Unspecified => -1,
+            LibRawError::FileUnsupported => -2,
+            LibRawError::RequestForNonexistentImage => -3,
+            LibRawError::OutOfOrderCall => -4,
+            LibRawError::NoThumbnail => -5,
+            LibRawError::UnsupportedThumbnail => -6,
+            LibRawError::InputClosed => -7,
+            LibRawError::NotImplemented => -8,
+            LibRawError::RequestForNonexistentThumbnail => -9,
+            LibRawError::UnsufficientMemory => -100007,
+            LibRawError::DataError => -100008,
+            LibRawError::IoError => -100009,
+            LibRawError::CancelledByCallback => -100010,
+            LibRawError::BadCrop => -100011,
+            LibRawError::TooBig => -100012,
+            LibRawError::MempoolOverflow => -100013,
+            LibRawError::Other(code) => code,
+        })
+    }
+
+    fn check(code: c_int) -> Result<(), LibRawError> {
+        if code == 0 {
+            Ok(())
+        } else {
+            Err(LibRawError::from_code(code))
+        }
+    }
+}
+
+impl fmt::Display for LibRawError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let Some(code) = self.code() else {
+            return write!(f, "libraw_init returned null");
+        };
+        let message = libraw_ffi::get_api().ok().and_then(|api| unsafe {
+            let p = (api.libraw_strerror)(code);
+            (!p.is_null()).then(|| std::ffi::CStr::from_ptr(p).to_string_lossy().into_owned())
+        });
+        match message {
+            Some(m) => write!(f, "{} ({})", m, code),
+            None => write!(f, "libraw error {}", code),
+        }
+    }
+}
+
+impl std::error::Error for LibRawError {}
+
+/// `LIBRAW_IMAGE_JPEG` / `LIBRAW_IMAGE_BITMAP`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ImageKind {
+    Jpeg,
+    Bitmap,
+}
+
+/// An image returned by `libraw_dcraw_make_mem_image` or `libraw_dcraw_make_mem_thumb`,
+/// freed with `libraw_dcraw_clear_mem` on drop.
+pub struct ProcessedImage {
+    api: &'static LibRawApi,
+    ptr: NonNull<LibRawProcessedImage>,
+}
+
+impl ProcessedImage {
+    fn header(&self) -> &LibRawProcessedImage {
+        unsafe { self.ptr.as_ref() }
+    }
+
+    pub fn kind(&self) -> ImageKind {
+        if self.header().type_ == 1 {
+            ImageKind::Jpeg
+        } else {
+            ImageKind::Bitmap
+        }
+    }
+
+    pub fn width(&self) -> u32 {
+        self.header().width as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        self.header().height as u32
+    }
+
+    pub fn colors(&self) -> usize {
+        self.header().colors as usize
+    }
+
+    pub fn bits(&self) -> u16 {
+        self.header().bits
+    }
+
+    /// Encoded JPEG bytes or interleaved samples, depending on `kind`.
+    pub fn data(&self) -> &[u8] {
+        let header_size = std::mem::size_of::<LibRawProcessedImage>();
+        let len = self.header().data_size as usize;
+        unsafe {
+            std::slice::from_raw_parts((self.ptr.as_ptr() as *const u8).add(header_size), len)
+        }
+    }
+}
+
+impl Drop for ProcessedImage {
+    fn drop(&mut self) {
+        unsafe { (self.api.libraw_dcraw_clear_mem)(self.ptr.as_ptr()) };
+    }
+}
+
+/// An owned libraw handle, closed on drop so that no error path can leak it.
+pub struct LibRaw {
+    api: &'static LibRawApi,
+    raw: NonNull<libraw_data_t>,
+    /// libraw reads from this buffer without copying it, so it lives as long as the handle.
+    data: Vec<u8>,
+}
+
+impl LibRaw {
+    pub fn new() -> anyhow::Result<LibRaw> {
+        let api = libraw_ffi::get_api().context("Failed to load libraw symbols")?;
+        let raw = NonNull::new(unsafe { (api.libraw_init)(0) }).ok_or(LibRawError::InitFailed)?;
+        Ok(LibRaw {
+            api,
+            raw,
+            data: Vec::new(),
+        })
+    }
+
+    fn ptr(&self) -> *mut libraw_data_t {
+        self.raw.as_ptr()
+    }
+
+    pub fn open_buffer(&mut self, data: Vec<u8>) -> Result<(), LibRawError> {
+        self.data = data;
+        let r = unsafe {
+            (self.api.libraw_open_buffer)(self.ptr(), self.data.as_ptr(), self.data.len())
+        };
+        LibRawError::check(r)
+    }
+
+    /// The file contents passed to `open_buffer`.
+    pub fn file_data(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn unpack(&mut self) -> Result<(), LibRawError> {
+        LibRawError::check(unsafe { (self.api.libraw_unpack)(self.ptr()) })
+    }
+
+    pub fn unpack_thumb(&mut self) -> Result<(), LibRawError> {
+        LibRawError::check(unsafe { (self.api.libraw_unpack_thumb)(self.ptr()) })
+    }
+
+    /// Unpacks entry `index` of the thumbnail list, or `None` on libraw older than 0.21.
+    pub fn unpack_thumb_ex(&mut self, index: c_int) -> Option<Result<(), LibRawError>> {
+        let unpack_ex = self.api.libraw_unpack_thumb_ex?;
+        Some(LibRawError::check(unsafe { unpack_ex(self.ptr(), index) }))
+    }
+
+    pub fn dcraw_process(&mut self) -> Result<(), LibRawError> {
+        LibRawError::check(unsafe { (self.api.libraw_dcraw_process)(self.ptr()) })
+    }
+
+    /// Copies the unpacked raw data into `image`, one pixel per visible sensor site with
+    /// the value in that site's color channel.
+    pub fn raw2image(&mut self) -> Result<(), LibRawError> {
+        LibRawError::check(unsafe { (self.api.libraw_raw2image)(self.ptr()) })
+    }
+
+    /// Subtracts the black levels from `image` and lowers the white level to match.
+    pub fn subtract_black(&mut self) {
+        unsafe { (self.api.libraw_subtract_black)(self.ptr()) }
+    }
+
+    /// The `image` buffer filled by `raw2image` or processing, `iwidth * iheight` pixels.
+    pub fn image_data(&self) -> anyhow::Result<&[[u16; 4]]> {
+        let sizes = self.sizes()?;
+        let image: *const [u16; 4] = unsafe { DataLayout::read(self.ptr(), 0) };
+        if image.is_null() {
+            anyhow::bail!("libraw has no image data");
+        }
+        let len = sizes.iwidth as usize * sizes.iheight as usize;
+        Ok(unsafe { std::slice::from_raw_parts(image, len) })
+    }
+
+    /// Unpacked Bayer or X-Trans data over the full raw size, margins included, with
+    /// `raw_pitch / 2` values per row. `dcraw_process` reads it again on every call, so
+    /// changes made here reach every later stage.
+    pub fn raw_image_mut(&mut self) -> anyhow::Result<&mut [u16]> {
+        let sizes = self.sizes()?;
+        let rawdata = unsafe { libraw_ffi::data_layout()?.rawdata(self.ptr()) };
+        // `libraw_unpack` copies idata and sizes into rawdata; check them before trusting
+        // the pointer next to them.
+        let idata_len = std::mem::size_of::<LibRawIParams>();
+        let idata = unsafe {
+            std::slice::from_raw_parts(
+                (self.api.libraw_get_iparams)(self.ptr()) as *const u8,
+                idata_len,
+            )
+        };
+        let copy = unsafe {
+            std::slice::from_raw_parts(
+                &rawdata.iparams as *const LibRawIParams as *const u8,
+                idata_len,
+            )
+        };
+        let s = rawdata.sizes;
+        if copy != idata
+            || (s.raw_width, s.raw_height, s.raw_pitch)
+                != (sizes.raw_width, sizes.raw_height, sizes.raw_pitch)
+        {
+            anyhow::bail!("libraw raw data does not match the expected layout");
+        }
+        let image = rawdata.raw_image;
+        if image.is_null() {
+            anyhow::bail!("file has no single-channel raw data (not a Bayer or X-Trans sensor)");
+        }
+        let len = sizes.raw_pitch as usize / 2 * sizes.raw_height as usize;
+        Ok(unsafe { std::slice::from_raw_parts_mut(image, len) })
+    }
+
+    pub fn make_mem_image(&mut self) -> Result<ProcessedImage, LibRawError> {
+        let mut err: c_int = 0;
+        let p = unsafe { (self.api.libraw_dcraw_make_mem_image)(self.ptr(), &mut err) };
+        self.wrap_image(p, err)
+    }
+
+    pub fn make_mem_thumb(&mut self) -> Result<ProcessedImage, LibRawError> {
+        let mut err: c_int = 0;
+        let p = unsafe { (self.api.libraw_dcraw_make_mem_thumb)(self.ptr(), &mut err) };
+        self.wrap_image(p, err)
+    }
+
+    fn wrap_image(
+        &self,
+        p: *mut LibRawProcessedImage,
+        err: c_int,
+    ) -> Result<ProcessedImage, LibRawError> {
+        match NonNull::new(p) {
+            Some(ptr) => Ok(ProcessedImage { api: self.api, ptr }),
+            None if err != 0 => Err(LibRawError::from_code(err)),
+            None => Err(LibRawError::Unspecified),
+        }
+    }
+
+    pub fn set_output_bps(&mut self, bps: c_int) {
+        unsafe { (self.api.libraw_set_output_bps)(self.ptr(), bps) };
+    }
+
+    pub fn set_output_color(&mut self, color: c_int) {
+        unsafe { (self.api.libraw_set_output_color)(self.ptr(), color) };
+    }
+
+    pub fn set_no_auto_bright(&mut self, value: bool) {
+        unsafe { (self.api.libraw_set_no_auto_bright)(self.ptr(), value as c_int) };
+    }
+
+    /// Sets `gamm[0]` (power) and `gamm[1]` (toe slope).
+    pub fn set_gamma(&mut self, power: f32, slope: f32) {
+        unsafe {
+            (self.api.libraw_set_gamma)(self.ptr(), 0, power);
+            (self.api.libraw_set_gamma)(self.ptr(), 1, slope);
+        }
+    }
+
+    /// User white balance multipliers in libraw order: R, G, B, G2.
+    pub fn set_user_mul(&mut self, mul: [f32; 4]) {
+        for (i, m) in mul.iter().enumerate() {
+            unsafe { (self.api.libraw_set_user_mul)(self.ptr(), i as c_int, *m) };
+        }
+    }
+
+    pub fn set_demosaic(&mut self, quality: c_int) {
+        unsafe { (self.api.libraw_set_demosaic)(self.ptr(), quality) };
+    }
+
+    pub fn set_highlight(&mut self, mode: c_int) {
+        unsafe { (self.api.libraw_set_highlight)(self.ptr(), mode) };
+    }
+
+    pub fn cam_mul(&self) -> [f32; 4] {
+        [0, 1, 2, 3].map(|i| unsafe { (self.api.libraw_get_cam_mul)(self.ptr(), i) })
+    }
+
+    pub fn pre_mul(&self) -> [f32; 4] {
+        [0, 1, 2, 3].map(|i| unsafe { (self.api.libraw_get_pre_mul)(self.ptr(), i) })
+    }
+
+    pub fn rgb_cam(&self) -> [[f32; 3]; 3] {
+        [0, 1, 2]
+            .map(|i| [0, 1, 2].map(|j| unsafe { (self.api.libraw_get_rgb_cam)(self.ptr(), i, j) }))
+    }
+
+    /// Output size before rotation; the full sensor size until `half_size` takes effect.
+    pub fn image_size(&self) -> (u32, u32) {
+        unsafe {
+            (
+                (self.api.libraw_get_iwidth)(self.ptr()).max(0) as u32,
+                (self.api.libraw_get_iheight)(self.ptr()).max(0) as u32,
+            )
+        }
+    }
+
+    /// Camera identification; fields past `software` need libraw 0.20 or newer.
+    pub fn iparams(&self) -> &LibRawIParams {
+        unsafe { &*(self.api.libraw_get_iparams)(self.ptr()) }
+    }
+
+    /// Exposure, timestamp and GPS data; the layout needs libraw 0.20 or newer.
+    pub fn imgother(&self) -> &LibRawImgOther {
+        unsafe { &*(self.api.libraw_get_imgother)(self.ptr()) }
+    }
+
+    pub fn lensinfo(&self) -> &LibRawLensInfoHead {
+        unsafe { &*(self.api.libraw_get_lensinfo)(self.ptr()) }
+    }
+
+    /// Raw and visible dimensions, margins and flip.
+    pub fn sizes(&self) -> anyhow::Result<LibRawImageSizesHead> {
+        let offset = std::mem::size_of::<*mut u16>();
+        let sizes: LibRawImageSizesHead = unsafe { DataLayout::read(self.ptr(), offset) };
+        let (raw_w, raw_h) = unsafe {
+            (
+                (self.api.libraw_get_raw_width)(self.ptr()),
+                (self.api.libraw_get_raw_height)(self.ptr()),
+            )
+        };
+        if sizes.raw_width as c_int != raw_w || sizes.raw_height as c_int != raw_h {
+            anyhow::bail!("libraw image sizes do not match the expected layout");
+        }
+        Ok(sizes)
+    }
+
+    pub fn color_maximum(&self) -> c_int {
+        unsafe { (self.api.libraw_get_color_maximum)(self.ptr()) }
+    }
+
+    /// Name of the raw decoder libraw picked, which identifies the compression.
+    pub fn unpack_function_name(&self) -> Option<String> {
+        let f = self.api.libraw_unpack_function_name?;
+        let name = unsafe { f(self.ptr()) };
+        if name.is_null() {
+            return None;
+        }
+        Some(
+            unsafe { CStr::from_ptr(name) }
+                .to_string_lossy()
+                .into_owned(),
+        )
+    }
+
+    /// Black and white levels; most formats fill them at open, some only at `unpack`.
+    pub fn color_levels(&self) -> anyhow::Result<ColorLevels> {
+        let color = unsafe { libraw_ffi::data_layout()?.color(self.ptr()) };
+        if color.maximum as c_int != self.color_maximum()
+            || color.cam_mul != self.cam_mul()
+            || color.pre_mul != self.pre_mul()
+            || color.rgb_cam[0][..3] != self.rgb_cam()[0]
+        {
+            anyhow::bail!("libraw color data does not match the expected layout");
+        }
+        let cblack = &color.cblack;
+        let pattern = (cblack[4], cblack[5]);
+        let count = (pattern.0 * pattern.1) as usize;
+        let black_pattern = (count > 0 && count <= libraw_ffi::CBLACK_SIZE - 6)
+            .then(|| (pattern.0, pattern.1, cblack[6..6 + count].to_vec()));
+        Ok(ColorLevels {
+            black: color.black,
+            cblack: [cblack[0], cblack[1], cblack[2], cblack[3]],
+            black_pattern,
+            maximum: color.maximum,
+            data_maximum: color.data_maximum,
+            linear_max: color.linear_max.map(i64::from),
+            cam_xyz: color.cam_xyz,
+        })
+    }
+
+    /// Writes a parameter the C API has no setter for, at an offset from `data_layout`.
+    fn write_param<T: Copy>(
+        &mut self,
+        field: impl Fn(&DataLayout) -> usize,
+        value: T,
+    ) -> anyhow::Result<()> {
+        let layout = libraw_ffi::data_layout()?;
+        unsafe { DataLayout::write::<T>(self.ptr(), field(layout), value) };
+        Ok(())
+    }
+
+    pub fn set_half_size(&mut self, value: bool) -> anyhow::Result<()> {
+        self.write_param(|l| l.half_size, value as c_int)
+    }
+
+    pub fn set_use_camera_wb(&mut self, value: bool) -> anyhow::Result<()> {
+        self.write_param(|l| l.use_camera_wb, value as c_int)
+    }
+
+    pub fn set_use_auto_wb(&mut self, value: bool) -> anyhow::Result<()> {
+        self.write_param(|l| l.use_auto_wb, value as c_int)
+    }
+
+    /// Raw-domain exposure before demosaicing: `shift` is a linear factor (0.25 to 8) and
+    /// `preserve` (0 to 1) rolls off highlights instead of clipping them when brightening.
+    pub fn set_exposure(&mut self, shift: f32, preserve: f32) -> anyhow::Result<()> {
+        self.write_param(|l| l.exp_correc, 1 as c_int)?;
+        self.write_param(|l| l.exp_shift, shift)?;
+        self.write_param(|l| l.exp_preser, preserve)
+    }
+
+    /// Wavelet denoising threshold in raw units; 0 turns it off.
+    pub fn set_wavelet_threshold(&mut self, threshold: f32) -> anyhow::Result<()> {
+        self.write_param(|l| l.threshold, threshold)
+    }
+
+    /// FBDD noise reduction before demosaicing: 0 off, 1 light, 2 full.
+    pub fn set_fbdd_noiserd(&mut self, value: c_int) -> anyhow::Result<()> {
+        let set = self
+            .api
+            .libraw_set_fbdd_noiserd
+            .context("libraw_set_fbdd_noiserd is missing from this libraw build")?;
+        unsafe { set(self.ptr(), value) };
+        Ok(())
+    }
+
+    /// Chromatic aberration correction: red and blue are magnified by these factors around
+    /// the center before demosaicing, like dcraw's `-C`.
+    pub fn set_aberration(&mut self, red: f64, blue: f64) -> anyhow::Result<()> {
+        self.write_param(|l| l.aber, 1.0 / red)?;
+        self.write_param(|l| l.aber + 16, 1.0 / blue)
+    }
+
+    /// Gray box for auto white balance: x, y, width, height in sensor pixels.
+    pub fn set_greybox(&mut self, rect: [u32; 4]) -> anyhow::Result<()> {
+        self.write_param(|l| l.greybox, rect)
+    }
+}
+
+/// Black and white levels and the camera matrix from `libraw_colordata_t`.
+#[derive(Clone, Debug)]
+pub struct ColorLevels {
+    pub black: u32,
+    /// Per-channel black offsets, added to `black`.
+    pub cblack: [u32; 4],
+    /// Repeating black pattern (rows, columns, values) on top of the channel levels.
+    pub black_pattern: Option<(u32, u32, Vec<u32>)>,
+    pub maximum: u32,
+    /// Largest value actually found in the raw data; zero until `unpack`.
+    pub data_maximum: u32,
+    /// Per-channel white levels from the maker notes, zero when not recorded.
+    pub linear_max: [i64; 4],
+    /// Camera-from-XYZ matrix, one row per camera channel.
+    pub cam_xyz: [[f32; 3]; 4],
+}
+
+impl Drop for LibRaw {
+    fn drop(&mut self) {
+        unsafe { (self.api.libraw_close)(self.ptr()) };
+    }
+}
+
+/// `libraw_version()` of the loaded library, e.g. `0.21.2-Release`.
+pub fn version() -> anyhow::Result<String> {
+    let api = libraw_ffi::get_api().context("Failed to load libraw symbols")?;
+    let p = unsafe { (api.libraw_version)() };
+    if p.is_null() {
+        anyhow::bail!("libraw_version returned null");
+    }
+    Ok(unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned())
+}
+
+/// Names of the `LIBRAW_CAPS_*` features the loaded library was built with.
+pub fn capabilities() -> anyhow::Result<Vec<&'static str>> {
+    let api = libraw_ffi::get_api().context("Failed to load libraw symbols")?;
+    let caps = unsafe { (api.libraw_capabilities)() };
+    Ok(libraw_ffi::CAPABILITIES
+        .iter()
+        .filter(|(bit, _)| caps & bit != 0)
+        .map(|(_, name)| *name)
+        .collect())
+}
+
+/// Cameras the loaded library lists as supported, as `Make Model` strings.
+pub fn camera_list() -> anyhow::Result<Vec<String>> {
+    let api = libraw_ffi::get_api().context("Failed to load libraw symbols")?;
+    let list = unsafe { (api.libraw_camera_list)() };
+    let mut out = Vec::new();
+    if list.is_null() {
+        return Ok(out);
+    }
+    for i in 0.. {
+        let p = unsafe { *list.add(i) };
+        if p.is_null() {
+            break;
+        }
+        out.push(unsafe { CStr::from_ptr(p) }.to_string_lossy().into_owned());
+    }
+    Ok(out)
+}
+
+/// `libraw_versionNumber()` of the loaded library.
+pub fn version_number() -> anyhow::Result<c_int> {
+    let api = libraw_ffi::get_api().context("Failed to load libraw symbols")?;
+    Ok(unsafe { (api.libraw_version_number)() })
+}
@@ -1,6 +1,29 @@
-use std::os::raw::{c_char, c_int};
+use std::os::raw::{c_char, c_int, c_long};
 use std::sync::OnceLock;
 
+/// `LIBRAW_CAPS_*` bits of `libraw_capabilities()`, with the names `--version` prints.
+pub const CAPABILITIES: &[(u32, &str)] = &[
+    (1 << 0, "rawspeed"),
+    (1 << 1, "dng-sdk"),
+    (1 << 2, "gpr-sdk"),
+    (1 << 3, "unicode-paths"),
+    (1 << 4, "x3f-tools"),
+    (1 << 5, "rpi6by9"),
+    (1 << 6, "zlib"),
+    (1 << 7, "jpeg"),
+    (1 << 8, "rawspeed3"),
+    (1 << 9, "rawspeed-bits"),
+];
+
+/// `LIBRAW_MAKE_VERSION` from libraw_version.h.
+pub const fn make_version(major: c_int, minor: c_int, patch: c_int) -> c_int {
+    (major << 16) | (minor << 8) | patch
+}
+
+pub fn format_version(v: c_int) -> String {
+    format!("{}.{}.{}", (v >> 16) & 0xff, (v >> 8) & 0xff, v & 0xff)
+}
+
 #[repr(C)]
 pub struct libraw_data_t {
     _private: [u8; 0],
@@ -16,11 +39,99 @@ pub struct LibRawProcessedImage {
     pub data_size: u32,
 }
 
+/// `libraw_iparams_t` as laid out since libraw 0.20.
+#[repr(C)]
+pub struct LibRawIParams {
+    pub guard: [c_char; 4],
+    pub make: [c_char; 64],
+    pub model: [c_char; 64],
+    pub software: [c_char; 64],
+    pub normalized_make: [c_char; 64],
+    pub normalized_model: [c_char; 64],
+    pub maker_index: u32,
+    pub raw_count: u32,
+    pub dng_version: u32,
+    pub is_foveon: u32,
+    pub colors: c_int,
+    pub filters: u32,
+    pub xtrans: [[c_char; 6]; 6],
+    pub xtrans_abs: [[c_char; 6]; 6],
+    pub cdesc: [c_char; 5],
+    pub xmplen: u32,
+    pub xmpdata: *mut c_char,
+}
+
+#[repr(C)]
+pub struct LibRawGpsInfo {
+    /// Degrees, minutes, seconds.
+    pub latitude: [f32; 3],
+    pub longitude: [f32; 3],
+    pub gpstimestamp: [f32; 3],
+    pub altitude: f32,
+    pub altref: c_char,
+    pub latref: c_char,
+    pub longref: c_char,
+    pub gpsstatus: c_char,
+    pub gpsparsed: c_char,
+}
+
+/// `libraw_imgother_t` as laid out since libraw 0.20.
+#[repr(C)]
+pub struct LibRawImgOther {
+    pub iso_speed: f32,
+    pub shutter: f32,
+    pub aperture: f32,
+    pub focal_len: f32,
+    pub timestamp: i64,
+    pub shot_order: u32,
+    pub gpsdata: [u32; 32],
+    pub parsed_gps: LibRawGpsInfo,
+    pub desc: [c_char; 512],
+    pub artist: [c_char; 64],
+    pub analogbalance: [f32; 4],
+}
+
+/// Leading fields of `libraw_lensinfo_t`, unchanged since libraw 0.18. The vendor
+/// sub-structs that follow vary between releases and are not mirrored.
+#[repr(C)]
+pub struct LibRawLensInfoHead {
+    pub min_focal: f32,
+    pub max_focal: f32,
+    pub max_ap4_min_focal: f32,
+    pub max_ap4_max_focal: f32,
+    pub exif_max_ap: f32,
+    pub lens_make: [c_char; 128],
+    pub lens: [c_char; 128],
+    pub lens_serial: [c_char; 128],
+    pub internal_lens_serial: [c_char; 128],
+    pub focal_length_in_35mm_format: u16,
+}
+
+/// Leading fields of `libraw_image_sizes_t`, which directly follows the `image` pointer
+/// at the start of `libraw_data_t`.
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct LibRawImageSizesHead {
+    pub raw_height: u16,
+    pub raw_width: u16,
+    pub height: u16,
+    pub width: u16,
+    pub top_margin: u16,
+    pub left_margin: u16,
+    pub iheight: u16,
+    pub iwidth: u16,
+    pub raw_pitch: u32,
+    pub pixel_aspect: f64,
+    pub flip: c_int,
+}
+
 pub struct LibRawApi {
     pub libraw_init: unsafe extern "C" fn(c_int) -> *mut libraw_data_t,
     pub libraw_open_buffer: unsafe extern "C" fn(*mut libraw_data_t, *const u8, usize) -> c_int,
     pub libraw_unpack: unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
     pub libraw_dcraw_process: unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+    pub libraw_raw2image: unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+    pub libraw_subtract_black: unsafe extern "C" fn(*mut libraw_data_t),
     pub libraw_dcraw_make_mem_image:
         unsafe extern "C" fn(*mut libraw_data_t, *mut c_int) -> *mut LibRawProcessedImage,
     pub libraw_dcraw_clear_mem: unsafe extern "C" fn(*mut LibRawProcessedImage),
@@ -29,6 +140,35 @@ pub struct LibRawApi {
     pub libraw_set_output_bps: unsafe extern "C" fn(*mut libraw_data_t, c_int) -> c_int,
     pub libraw_set_output_color: unsafe extern "C" fn(*mut libraw_data_t, c_int) -> c_int,
     pub libraw_set_no_auto_bright: unsafe extern "C" fn(*mut libraw_data_t, c_int) -> c_int,
+    pub libraw_set_gamma: unsafe extern "C" fn(*mut libraw_data_t, c_int, f32),
+    pub libraw_set_user_mul: unsafe extern "C" fn(*mut libraw_data_t, c_int, f32),
+    pub libraw_set_bright: unsafe extern "C" fn(*mut libraw_data_t, f32),
+    pub libraw_set_adjust_maximum_thr: unsafe extern "C" fn(*mut libraw_data_t, f32),
+    pub libraw_get_cam_mul: unsafe extern "C" fn(*mut libraw_data_t, c_int) -> f32,
+    pub libraw_get_pre_mul: unsafe extern "C" fn(*mut libraw_data_t, c_int) -> f32,
+    pub libraw_get_rgb_cam: unsafe extern "C" fn(*mut libraw_data_t, c_int, c_int) -> f32,
+    pub libraw_set_demosaic: unsafe extern "C" fn(*mut libraw_data_t, c_int),
+    pub libraw_set_highlight: unsafe extern "C" fn(*mut libraw_data_t, c_int),
+    pub libraw_unpack_thumb: unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+    /// Selects one entry of the thumbnail list; libraw 0.21 and newer only.
+    pub libraw_unpack_thumb_ex: Option<unsafe extern "C" fn(*mut libraw_data_t, c_int) -> c_int>,
+    pub libraw_dcraw_make_mem_thumb:
+        unsafe extern "C" fn(*mut libraw_data_t, *mut c_int) -> *mut LibRawProcessedImage,
+    pub libraw_get_iwidth: unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+    pub libraw_get_iheight: unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+    pub libraw_get_raw_width: unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+    pub libraw_get_raw_height: unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+    pub libraw_get_color_maximum: unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+    pub libraw_unpack_function_name:
+        Option<unsafe extern "C" fn(*mut libraw_data_t) -> *const c_char>,
+    pub libraw_get_iparams: unsafe extern "C" fn(*mut libraw_data_t) -> *mut LibRawIParams,
+    pub libraw_get_imgother: unsafe extern "C" fn(*mut libraw_data_t) -> *mut LibRawImgOther,
+    pub libraw_get_lensinfo: unsafe extern "C" fn(*mut libraw_data_t) -> *mut LibRawLensInfoHead,
+    pub libraw_version_number: unsafe extern "C" fn() -> c_int,
+    pub libraw_version: unsafe extern "C" fn() -> *const c_char,
+    pub libraw_capabilities: unsafe extern "C" fn() -> u32,
+    pub libraw_camera_list: unsafe extern "C" fn() -> *const *const c_char,
+    pub libraw_set_fbdd_noiserd: Option<unsafe extern "C" fn(*mut libraw_data_t, c_int)>,
 }
 
 static API: OnceLock<Result<LibRawApi, anyhow::Error>> = OnceLock::new();
@@ -50,6 +190,12 @@ pub fn get_api() -> anyhow::Result<&'static LibRawApi> {
             let s_process: libloading::Symbol<unsafe extern "C" fn(*mut libraw_data_t) -> c_int> =
                 lib.get(b"libraw_dcraw_process\0")
                     .map_err(|e| anyhow::anyhow!(e))?;
+            let s_raw2image: libloading::Symbol<unsafe extern "C" fn(*mut libraw_data_t) -> c_int> =
+                lib.get(b"libraw_raw2image\0")
+                    .map_err(|e| anyhow::anyhow!(e))?;
+            let s_subtract_black: libloading::Symbol<unsafe extern "C" fn(*mut libraw_data_t)> =
+                lib.get(b"libraw_subtract_black\0")
+                    .map_err(|e| anyhow::anyhow!(e))?;
             let s_make_mem: libloading::Symbol<
                 unsafe extern "C" fn(*mut libraw_data_t, *mut c_int) -> *mut LibRawProcessedImage,
             > = lib
@@ -78,12 +224,128 @@ pub fn get_api() -> anyhow::Result<&'static LibRawApi> {
             > = lib
                 .get(b"libraw_set_no_auto_bright\0")
                 .map_err(|e| anyhow::anyhow!(e))?;
+            let s_set_gamma: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t, c_int, f32),
+            > = lib
+                .get(b"libraw_set_gamma\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_set_user_mul: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t, c_int, f32),
+            > = lib
+                .get(b"libraw_set_user_mul\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_set_bright: libloading::Symbol<unsafe extern "C" fn(*mut libraw_data_t, f32)> =
+                lib.get(b"libraw_set_bright\0")
+                    .map_err(|e| anyhow::anyhow!(e))?;
+            let s_set_adjust_maximum_thr: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t, f32),
+            > = lib
+                .get(b"libraw_set_adjust_maximum_thr\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_get_cam_mul: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t, c_int) -> f32,
+            > = lib
+                .get(b"libraw_get_cam_mul\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_get_pre_mul: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t, c_int) -> f32,
+            > = lib
+                .get(b"libraw_get_pre_mul\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_get_rgb_cam: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t, c_int, c_int) -> f32,
+            > = lib
+                .get(b"libraw_get_rgb_cam\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_set_demosaic: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t, c_int),
+            > = lib
+                .get(b"libraw_set_demosaic\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_set_highlight: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t, c_int),
+            > = lib
+                .get(b"libraw_set_highlight\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_unpack_thumb: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+            > = lib
+                .get(b"libraw_unpack_thumb\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_unpack_thumb_ex: Option<
+                libloading::Symbol<unsafe extern "C" fn(*mut libraw_data_t, c_int) -> c_int>,
+            > = lib.get(b"libraw_unpack_thumb_ex\0").ok();
+            let s_make_mem_thumb: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t, *mut c_int) -> *mut LibRawProcessedImage,
+            > = lib
+                .get(b"libraw_dcraw_make_mem_thumb\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_get_iwidth: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+            > = lib
+                .get(b"libraw_get_iwidth\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_get_iheight: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+            > = lib
+                .get(b"libraw_get_iheight\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_get_raw_width: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+            > = lib
+                .get(b"libraw_get_raw_width\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_get_raw_height: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+            > = lib
+                .get(b"libraw_get_raw_height\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_get_color_maximum: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t) -> c_int,
+            > = lib
+                .get(b"libraw_get_color_maximum\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_unpack_function_name: Option<
+                libloading::Symbol<unsafe extern "C" fn(*mut libraw_data_t) -> *const c_char>,
+            > = lib.get(b"libraw_unpack_function_name\0").ok();
+            let s_get_iparams: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t) -> *mut LibRawIParams,
+            > = lib
+                .get(b"libraw_get_iparams\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_get_imgother: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t) -> *mut LibRawImgOther,
+            > = lib
+                .get(b"libraw_get_imgother\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_get_lensinfo: libloading::Symbol<
+                unsafe extern "C" fn(*mut libraw_data_t) -> *mut LibRawLensInfoHead,
+            > = lib
+                .get(b"libraw_get_lensinfo\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_version_number: libloading::Symbol<unsafe extern "C" fn() -> c_int> = lib
+                .get(b"libraw_versionNumber\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_version: libloading::Symbol<unsafe extern "C" fn() -> *const c_char> = lib
+                .get(b"libraw_version\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_capabilities: libloading::Symbol<unsafe extern "C" fn() -> u32> = lib
+                .get(b"libraw_capabilities\0")
+                .map_err(|e| anyhow::anyhow!(e))?;
+            let s_camera_list: libloading::Symbol<unsafe extern "C" fn() -> *const *const c_char> =
+                lib.get(b"libraw_cameraList\0")
+                    .map_err(|e| anyhow::anyhow!(e))?;
+            let s_set_fbdd_noiserd: Option<
+                libloading::Symbol<unsafe extern "C" fn(*mut libraw_data_t, c_int)>,
+            > = lib.get(b"libraw_set_fbdd_noiserd\0").ok();
 
             let api = LibRawApi {
                 libraw_init: *s_init,
                 libraw_open_buffer: *s_open_buf,
                 libraw_unpack: *s_unpack,
                 libraw_dcraw_process: *s_process,
+                libraw_raw2image: *s_raw2image,
+                libraw_subtract_black: *s_subtract_black,
                 libraw_dcraw_make_mem_image: *s_make_mem,
                 libraw_dcraw_clear_mem: *s_clear_mem,
                 libraw_close: *s_close,
@@ -91,6 +353,32 @@ pub fn get_api() -> anyhow::Result<&'static LibRawApi> {
                 libraw_set_output_bps: *s_set_bps,
                 libraw_set_output_color: *s_set_color,
                 libraw_set_no_auto_bright: *s_set_no_auto,
+                libraw_set_gamma: *s_set_gamma,
+                libraw_set_user_mul: *s_set_user_mul,
+                libraw_set_bright: *s_set_bright,
+                libraw_set_adjust_maximum_thr: *s_set_adjust_maximum_thr,
+                libraw_get_cam_mul: *s_get_cam_mul,
+                libraw_get_pre_mul: *s_get_pre_mul,
+                libraw_get_rgb_cam: *s_get_rgb_cam,
+                libraw_set_demosaic: *s_set_demosaic,
+                libraw_set_highlight: *s_set_highlight,
+                libraw_unpack_thumb: *s_unpack_thumb,
+                libraw_unpack_thumb_ex: s_unpack_thumb_ex.map(|s| *s),
+                libraw_dcraw_make_mem_thumb: *s_make_mem_thumb,
+                libraw_get_iwidth: *s_get_iwidth,
+                libraw_get_iheight: *s_get_iheight,
+                libraw_get_raw_width: *s_get_raw_width,
+                libraw_get_raw_height: *s_get_raw_height,
+                libraw_get_color_maximum: *s_get_color_maximum,
+                libraw_unpack_function_name: s_unpack_function_name.map(|s| *s),
+                libraw_get_iparams: *s_get_iparams,
+                libraw_get_imgother: *s_get_imgother,
+                libraw_get_lensinfo: *s_get_lensinfo,
+                libraw_version_number: *s_version_number,
+                libraw_version: *s_version,
+                libraw_capabilities: *s_capabilities,
+                libraw_camera_list: *s_camera_list,
+                libraw_set_fbdd_noiserd: s_set_fbdd_noiserd.map(|s| *s),
             };
             Ok(api)
         }
@@ -98,3 +386,431 @@ pub fn get_api() -> anyhow::Result<&'static LibRawApi> {
     .as_ref()
     .map_err(|e| anyhow::anyhow!(e))
 }
+
+/// `LIBRAW_CBLACK_SIZE` since libraw 0.20.
+pub const CBLACK_SIZE: usize = 4104;
+
+/// `LIBRAW_THUMBNAIL_MAXCOUNT` since libraw 0.21.
+pub const THUMBNAIL_MAXCOUNT: usize = 8;
+
+/// `libraw_output_params_t` in libraw 0.20, which still holds the unpacking options that
+/// 0.21 moved to `libraw_raw_unpack_params_t`.
+#[repr(C)]
+pub struct LibRawOutputParams020 {
+    pub greybox: [u32; 4],
+    pub cropbox: [u32; 4],
+    pub aber: [f64; 4],
+    pub gamm: [f64; 6],
+    pub user_mul: [f32; 4],
+    pub shot_select: u32,
+    pub bright: f32,
+    pub threshold: f32,
+    pub half_size: c_int,
+    pub four_color_rgb: c_int,
+    pub highlight: c_int,
+    pub use_auto_wb: c_int,
+    pub use_camera_wb: c_int,
+    pub use_camera_matrix: c_int,
+    pub output_color: c_int,
+    pub output_profile: *mut c_char,
+    pub camera_profile: *mut c_char,
+    pub bad_pixels: *mut c_char,
+    pub dark_frame: *mut c_char,
+    pub output_bps: c_int,
+    pub output_tiff: c_int,
+    pub output_flags: c_int,
+    pub user_flip: c_int,
+    pub user_qual: c_int,
+    pub user_black: c_int,
+    pub user_cblack: [c_int; 4],
+    pub user_sat: c_int,
+    pub med_passes: c_int,
+    pub auto_bright_thr: f32,
+    pub adjust_maximum_thr: f32,
+    pub no_auto_bright: c_int,
+    pub use_fuji_rotate: c_int,
+    pub green_matching: c_int,
+    pub dcb_iterations: c_int,
+    pub dcb_enhance_fl: c_int,
+    pub fbdd_noiserd: c_int,
+    pub exp_correc: c_int,
+    pub exp_shift: f32,
+    pub exp_preser: f32,
+    pub use_rawspeed: c_int,
+    pub use_dngsdk: c_int,
+    pub no_auto_scale: c_int,
+    pub no_interpolation: c_int,
+    pub raw_processing_options: u32,
+    pub max_raw_memory_mb: u32,
+    pub sony_arw2_posterization_thr: c_int,
+    pub coolscan_nef_gamma: f32,
+    pub p4shot_order: [c_char; 5],
+    pub custom_camera_strings: *mut *mut c_char,
+}
+
+/// `libraw_output_params_t` since libraw 0.21.
+#[repr(C)]
+pub struct LibRawOutputParams021 {
+    pub greybox: [u32; 4],
+    pub cropbox: [u32; 4],
+    pub aber: [f64; 4],
+    pub gamm: [f64; 6],
+    pub user_mul: [f32; 4],
+    pub bright: f32,
+    pub threshold: f32,
+    pub half_size: c_int,
+    pub four_color_rgb: c_int,
+    pub highlight: c_int,
+    pub use_auto_wb: c_int,
+    pub use_camera_wb: c_int,
+    pub use_camera_matrix: c_int,
+    pub output_color: c_int,
+    pub output_profile: *mut c_char,
+    pub camera_profile: *mut c_char,
+    pub bad_pixels: *mut c_char,
+    pub dark_frame: *mut c_char,
+    pub output_bps: c_int,
+    pub output_tiff: c_int,
+    pub output_flags: c_int,
+    pub user_flip: c_int,
+    pub user_qual: c_int,
+    pub user_black: c_int,
+    pub user_cblack: [c_int; 4],
+    pub user_sat: c_int,
+    pub med_passes: c_int,
+    pub auto_bright_thr: f32,
+    pub adjust_maximum_thr: f32,
+    pub no_auto_bright: c_int,
+    pub use_fuji_rotate: c_int,
+    pub green_matching: c_int,
+    pub dcb_iterations: c_int,
+    pub dcb_enhance_fl: c_int,
+    pub fbdd_noiserd: c_int,
+    pub exp_correc: c_int,
+    pub exp_shift: f32,
+    pub exp_preser: f32,
+    pub no_auto_scale: c_int,
+    pub no_interpolation: c_int,
+}
+
+/// `libraw_raw_unpack_params_t`, added in libraw 0.21.
+#[repr(C)]
+pub struct LibRawRawUnpackParams {
+    pub use_rawspeed: c_int,
+    pub use_dngsdk: c_int,
+    pub options: u32,
+    pub shot_select: u32,
+    pub specials: u32,
+    pub max_raw_memory_mb: u32,
+    pub sony_arw2_posterization_thr: c_int,
+    pub coolscan_nef_gamma: f32,
+    pub p4shot_order: [c_char; 5],
+    pub custom_camera_strings: *mut *mut c_char,
+}
+
+/// `struct ph1_t`.
+#[repr(C)]
+pub struct LibRawPh1 {
+    pub format: c_int,
+    pub key_off: c_int,
+    pub tag_21a: c_int,
+    pub t_black: c_int,
+    pub split_col: c_int,
+    pub black_col: c_int,
+    pub split_row: c_int,
+    pub black_row: c_int,
+    pub tag_210: f32,
+}
+
+#[repr(C)]
+pub struct LibRawDngColor {
+    pub parsedfields: u32,
+    pub illuminant: u16,
+    pub calibration: [[f32; 4]; 4],
+    pub colormatrix: [[f32; 3]; 4],
+    pub forwardmatrix: [[f32; 4]; 3],
+}
+
+#[repr(C)]
+pub struct LibRawDngLevels {
+    pub parsedfields: u32,
+    pub dng_cblack: [u32; CBLACK_SIZE],
+    pub dng_black: u32,
+    pub dng_fcblack: [f32; CBLACK_SIZE],
+    pub dng_fblack: f32,
+    pub dng_whitelevel: [u32; CBLACK_SIZE],
+    pub default_crop: [u16; 4],
+    pub user_crop: [f32; 4],
+    pub preview_colorspace: u32,
+    pub analogbalance: [f32; 4],
+    pub asshotneutral: [f32; 4],
+    pub baseline_exposure: f32,
+    pub linear_response_limit: f32,
+}
+
+/// `libraw_colordata_t` as laid out since libraw 0.20.
+#[repr(C)]
+pub struct LibRawColorData {
+    pub curve: [u16; 0x10000],
+    pub cblack: [u32; CBLACK_SIZE],
+    pub black: u32,
+    pub data_maximum: u32,
+    pub maximum: u32,
+    pub linear_max: [c_long; 4],
+    pub fmaximum: f32,
+    pub fnorm: f32,
+    pub white: [[u16; 8]; 8],
+    pub cam_mul: [f32; 4],
+    pub pre_mul: [f32; 4],
+    pub cmatrix: [[f32; 4]; 3],
+    pub ccm: [[f32; 4]; 3],
+    pub rgb_cam: [[f32; 4]; 3],
+    pub cam_xyz: [[f32; 3]; 4],
+    pub phase_one_data: LibRawPh1,
+    pub flash_used: f32,
+    pub canon_ev: f32,
+    pub model2: [c_char; 64],
+    pub unique_camera_model: [c_char; 64],
+    pub localized_camera_model: [c_char; 64],
+    pub image_unique_id: [c_char; 64],
+    pub raw_data_unique_id: [c_char; 17],
+    pub original_raw_file_name: [c_char; 64],
+    pub profile: *mut std::ffi::c_void,
+    pub profile_length: u32,
+    pub black_stat: [u32; 8],
+    pub dng_color: [LibRawDngColor; 2],
+    pub dng_levels: LibRawDngLevels,
+    pub wb_coeffs: [[c_int; 4]; 256],
+    pub wbct_coeffs: [[f32; 5]; 64],
+    pub as_shot_wb_applied: c_int,
+    pub p1_color: [[f32; 9]; 2],
+    pub raw_bps: u32,
+    pub exif_color_space: c_int,
+}
+
+#[repr(C)]
+pub struct LibRawThumbnail {
+    pub tformat: c_int,
+    pub twidth: u16,
+    pub theight: u16,
+    pub tlength: u32,
+    pub tcolors: c_int,
+    pub thumb: *mut c_char,
+}
+
+#[repr(C)]
+pub struct LibRawThumbnailItem {
+    pub tformat: c_int,
+    pub twidth: u16,
+    pub theight: u16,
+    pub tflip: u16,
+    pub tlength: u32,
+    pub tmisc: u32,
+    pub toffset: i64,
+}
+
+/// `libraw_thumbnail_list_t`, added in libraw 0.21.
+#[repr(C)]
+pub struct LibRawThumbnailList {
+    pub thumbcount: c_int,
+    pub thumblist: [LibRawThumbnailItem; THUMBNAIL_MAXCOUNT],
+}
+
+/// Leading fields of `libraw_rawdata_t`, through the copies of `idata` and `sizes` that
+/// `libraw_unpack` stores.
+#[repr(C)]
+pub struct LibRawRawDataHead {
+    pub raw_alloc: *mut std::ffi::c_void,
+    pub raw_image: *mut u16,
+    pub color4_image: *mut [u16; 4],
+    pub color3_image: *mut [u16; 3],
+    pub float_image: *mut f32,
+    pub float3_image: *mut [f32; 3],
+    pub float4_image: *mut [f32; 4],
+    pub ph1_cblack: *mut [i16; 2],
+    pub ph1_rblack: *mut [i16; 2],
+    pub iparams: LibRawIParams,
+    pub sizes: LibRawImageSizesHead,
+}
+
+/// `libraw_data_t` from `params` on, for libraw 0.20.
+#[repr(C)]
+pub struct LibRawDataTail020 {
+    pub params: LibRawOutputParams020,
+    pub progress_flags: u32,
+    pub process_warnings: u32,
+    pub color: LibRawColorData,
+    pub other: LibRawImgOther,
+    pub thumbnail: LibRawThumbnail,
+    pub rawdata: LibRawRawDataHead,
+}
+
+/// `libraw_data_t` from `params` on, for libraw 0.21 and 0.22.
+#[repr(C)]
+pub struct LibRawDataTail021 {
+    pub params: LibRawOutputParams021,
+    pub rawparams: LibRawRawUnpackParams,
+    pub progress_flags: u32,
+    pub process_warnings: u32,
+    pub color: LibRawColorData,
+    pub other: LibRawImgOther,
+    pub thumbnail: LibRawThumbnail,
+    pub thumbs_list: LibRawThumbnailList,
+    pub rawdata: LibRawRawDataHead,
+}
+
+/// Byte offsets into `libraw_data_t` of the fields the C API has no accessor for.
+///
+/// The makernote structs ahead of `params` change with every release and are not
+/// mirrored. Everything from `params` to `rawdata` is, per version, and placed relative
+/// to `other`, whose address `libraw_get_imgother` returns.
+#[derive(Clone, Copy, Debug)]
+pub struct DataLayout {
+    pub greybox: usize,
+    /// `double aber[4]`.
+    pub aber: usize,
+    pub gamm: usize,
+    pub user_mul: usize,
+    pub bright: usize,
+    /// Wavelet denoising threshold, a float.
+    pub threshold: usize,
+    pub half_size: usize,
+    pub use_auto_wb: usize,
+    pub use_camera_wb: usize,
+    pub output_color: usize,
+    pub output_bps: usize,
+    pub user_flip: usize,
+    pub adjust_maximum_thr: usize,
+    pub no_auto_bright: usize,
+    pub exp_correc: usize,
+    pub exp_shift: usize,
+    pub exp_preser: usize,
+    /// `libraw_colordata_t color`.
+    pub color: usize,
+    /// `libraw_rawdata_t rawdata`.
+    pub rawdata: usize,
+}
+
+macro_rules! data_layout {
+    ($tail:ty, $other:expr) => {{
+        use std::mem::offset_of;
+        let base = $other - offset_of!($tail, other);
+        DataLayout {
+            greybox: base + offset_of!($tail, params.greybox),
+            aber: base + offset_of!($tail, params.aber),
+            gamm: base + offset_of!($tail, params.gamm),
+            user_mul: base + offset_of!($tail, params.user_mul),
+            bright: base + offset_of!($tail, params.bright),
+            threshold: base + offset_of!($tail, params.threshold),
+            half_size: base + offset_of!($tail, params.half_size),
+            use_auto_wb: base + offset_of!($tail, params.use_auto_wb),
+            use_camera_wb: base + offset_of!($tail, params.use_camera_wb),
+            output_color: base + offset_of!($tail, params.output_color),
+            output_bps: base + offset_of!($tail, params.output_bps),
+            user_flip: base + offset_of!($tail, params.user_flip),
+            adjust_maximum_thr: base + offset_of!($tail, params.adjust_maximum_thr),
+            no_auto_bright: base + offset_of!($tail, params.no_auto_bright),
+            exp_correc: base + offset_of!($tail, params.exp_correc),
+            exp_shift: base + offset_of!($tail, params.exp_shift),
+            exp_preser: base + offset_of!($tail, params.exp_preser),
+            color: base + offset_of!($tail, color),
+            rawdata: base + offset_of!($tail, rawdata),
+        }
+    }};
+}
+
+static DATA_LAYOUT: OnceLock<Result<DataLayout, String>> = OnceLock::new();
+
+impl DataLayout {
+    /// # Safety
+    /// `raw` must be a live handle and `T` must match the field at `offset`.
+    pub unsafe fn write<T: Copy>(raw: *mut libraw_data_t, offset: usize, value: T) {
+        unsafe { std::ptr::write_unaligned((raw as *mut u8).add(offset) as *mut T, value) }
+    }
+
+    /// # Safety
+    /// `raw` must be a live handle and `T` must match the field at `offset`.
+    pub unsafe fn read<T: Copy>(raw: *const libraw_data_t, offset: usize) -> T {
+        unsafe { std::ptr::read_unaligned((raw as *const u8).add(offset) as *const T) }
+    }
+
+    /// # Safety
+    /// `raw` must be a live handle.
+    pub unsafe fn color<'a>(&self, raw: *const libraw_data_t) -> &'a LibRawColorData {
+        unsafe { &*((raw as *const u8).add(self.color) as *const LibRawColorData) }
+    }
+
+    /// # Safety
+    /// `raw` must be a live handle.
+    pub unsafe fn rawdata<'a>(&self, raw: *const libraw_data_t) -> &'a LibRawRawDataHead {
+        unsafe { &*((raw as *const u8).add(self.rawdata) as *const LibRawRawDataHead) }
+    }
+}
+
+/// Picks the mirror for the loaded version and checks it on a fresh handle: values set
+/// through the C setters, and libraw's defaults, must read back at the mirrored offsets.
+unsafe fn load_data_layout(api: &LibRawApi) -> Result<DataLayout, String> {
+    let version = unsafe { (api.libraw_version_number)() };
+    let raw = unsafe { (api.libraw_init)(0) };
+    if raw.is_null() {
+        return Err("libraw_init returned null".to_string());
+    }
+    let other = unsafe { (api.libraw_get_imgother)(raw) } as usize - raw as usize;
+    let layout = if version < make_version(0, 21, 0) {
+        data_layout!(LibRawDataTail020, other)
+    } else if version < make_version(0, 23, 0) {
+        data_layout!(LibRawDataTail021, other)
+    } else {
+        unsafe { (api.libraw_close)(raw) };
+        return Err(format!(
+            "libraw {} is newer than the struct layouts fempeg knows",
+            format_version(version)
+        ));
+    };
+
+    const GAMMA: [f32; 2] = [0.390_625, 7.75];
+    const MUL: f32 = 1.171_875;
+    const BRIGHT: f32 = 1.375;
+    const MAXIMUM_THR: f32 = 0.625;
+    const COLOR: c_int = 5;
+    const BPS: c_int = 12;
+    const NO_AUTO_BRIGHT: c_int = 3;
+    let ok = unsafe {
+        (api.libraw_set_gamma)(raw, 0, GAMMA[0]);
+        (api.libraw_set_gamma)(raw, 1, GAMMA[1]);
+        (api.libraw_set_user_mul)(raw, 0, MUL);
+        (api.libraw_set_bright)(raw, BRIGHT);
+        (api.libraw_set_adjust_maximum_thr)(raw, MAXIMUM_THR);
+        (api.libraw_set_output_color)(raw, COLOR);
+        (api.libraw_set_output_bps)(raw, BPS);
+        (api.libraw_set_no_auto_bright)(raw, NO_AUTO_BRIGHT);
+        let read_int = |offset: usize| DataLayout::read::<c_int>(raw, offset);
+        let read_float = |offset: usize| DataLayout::read::<f32>(raw, offset);
+        DataLayout::read::<[f64; 2]>(raw, layout.gamm) == GAMMA.map(f64::from)
+            && DataLayout::read::<[f64; 4]>(raw, layout.aber) == [1.0; 4]
+            && read_float(layout.user_mul) == MUL
+            && read_float(layout.bright) == BRIGHT
+            && read_float(layout.adjust_maximum_thr) == MAXIMUM_THR
+            && read_int(layout.output_color) == COLOR
+            && read_int(layout.output_bps) == BPS
+            && read_int(layout.no_auto_bright) == NO_AUTO_BRIGHT
+            && read_int(layout.user_flip) == -1
+    };
+    unsafe { (api.libraw_close)(raw) };
+    if ok {
+        Ok(layout)
+    } else {
+        Err(format!(
+            "libraw {} does not match fempeg's struct layout for that version",
+            format_version(version)
+        ))
+    }
+}
+
+pub fn data_layout() -> anyhow::Result<&'static DataLayout> {
+    let api = get_api()?;
+    DATA_LAYOUT
+        .get_or_init(|| unsafe { load_data_layout(api) })
+        .as_ref()
+        .map_err(|e| anyhow::anyhow!("{}", e))
+}
@@ -2,7 +2,6 @@ use lazy_static::lazy_static;
 use std::collections::HashMap;
 use std::env;
 use std::fs::{self, File};
-use std::io::Read;
 use std::io::Write;
 use std::path::{Path, PathBuf};
 use std::sync::mpsc;
@@ -15,19 +14,32 @@ use std::time::Instant;
 
 use crate::term_colors::{blue, dark, green, pink, red, white};
 use anyhow::{Context, Result};
+use calibration::Calibration;
+use camera_support::{CameraCheck, Seen};
+use cfa::CfaMode;
 use clap::CommandFactory;
 use clap::Parser;
+use color_profile::{ColorProfile, ColorSpace, ToneCurve};
+use denoise::DenoisePreset;
+use dng::{DngKind, DngSource};
 use image::ImageEncoder;
 use image::codecs::farbfeld::FarbfeldEncoder;
 use image::codecs::hdr::HdrEncoder;
 use image::codecs::jpeg::JpegEncoder;
 use image::codecs::openexr::OpenExrEncoder;
+use image::codecs::png::PngEncoder;
 use image::codecs::pnm::{PnmEncoder, PnmSubtype, SampleEncoding};
+use image::codecs::tiff::TiffEncoder;
+use image::codecs::webp::WebPEncoder;
 use image::{ColorType, DynamicImage, ImageFormat, imageops::FilterType};
-use num_cpus;
+use lens::LensCorrection;
+use libraw::{ImageKind, LibRaw, ProcessedImage};
+use preview::PreviewPick;
+use raw_info::RawInfo;
+use raw_metadata::RawMetadata;
 use rayon::ThreadPoolBuilder;
 use rayon::prelude::*;
-use std::convert::TryInto;
+use white_balance::WhiteBalance;
 
 #[cfg(feature = "include_exiftool")]
 use crossterm::{
@@ -47,9 +59,27 @@ use ratatui::{
 #[cfg(feature = "include_exiftool")]
 use std::{collections::HashSet, io::stdout};
 
+mod calibration;
+mod camera_support;
+mod cfa;
+mod color_profile;
+mod decoder;
+mod denoise;
+mod dng;
+mod exposure;
 mod init_libraw;
+mod isolate;
+mod lens;
+mod libraw;
 mod libraw_ffi;
+#[cfg(feature = "native")]
+mod native;
+mod preview;
+mod raw_format;
+mod raw_info;
+mod raw_metadata;
 mod term_colors;
+mod white_balance;
 
 #[cfg(feature = "include_exiftool")]
 mod exiftool;
@@ -109,6 +139,9 @@ lazy_static! {
         // QOI
         m.insert("qoi", ("qoi", ImageFormat::Qoi));
 
+        // DNG (TIFF based, written from the raw data by dng::write_dng)
+        m.insert("dng", ("dng", ImageFormat::Tiff));
+
         m
     };
 }
@@ -119,7 +152,7 @@ const VERSION: &str = env!("CARGO_PKG_VERSION");
 #[command(
     author,
     version,
-    about = "Convert NEF images (via libraw) to common formats",
+    about = "Convert camera raw images (via libraw) to common formats",
     disable_help_flag = true,
     disable_version_flag = true
 )]
@@ -153,6 +186,70 @@ struct Args {
         help = "Number of threads to use (default: number of CPU cores)"
     )]
     threads: Option<usize>,
+    #[arg(
+        long = "isolate",
+        help = "Convert in a pool of fempeg worker processes, so a file that crashes the decoder fails alone and its worker is restarted"
+    )]
+    isolate: bool,
+    /// Internal: runs as an `--isolate` worker, taking the parent's prepared state and
+    /// then files on stdin.
+    #[arg(long = "worker", hide = true)]
+    worker: bool,
+    #[arg(
+        long = "bits",
+        value_name = "BITS",
+        default_value_t = 8,
+        help = "Output bit depth per channel: 8 or 16. With 16, PNG, TIFF, PPM/PGM/PAM and Farbfeld are written with 16-bit samples; 8-bit-only formats are reduced when saving"
+    )]
+    bits: u8,
+    #[arg(
+        long = "wb",
+        value_name = "MODE",
+        default_value = "camera",
+        help = "White balance: `camera` (as shot), `auto`, a preset (daylight, cloudy, shade, tungsten, fluorescent, flash), a color temperature with optional tint (e.g. 5200K or 5200K:10), raw multipliers `mul:R,G,G,B`, or a gray-card rectangle in full-size sensor pixels `spot:X,Y,W,H`"
+    )]
+    wb: String,
+    #[arg(
+        long = "demosaic",
+        value_name = "ALGO",
+        help = "Demosaicing algorithm, fastest to slowest: linear (soft, for proofs), vng, ppg (fast, decent), ahd (libraw default), dcb (sharper, fewer artifacts), dht, aahd (best detail, slowest)"
+    )]
+    demosaic: Option<String>,
+    #[arg(
+        long = "decoder",
+        value_name = "BACKEND",
+        default_value = "auto",
+        help = "Raw decoder: libraw, native (pure Rust, DNG only, needs the `native` cargo feature) or auto (native only when libraw cannot be loaded, which helps only DNG inputs)"
+    )]
+    decoder: String,
+    #[arg(
+        long = "colorspace",
+        value_name = "SPACE",
+        default_value = "srgb",
+        help = "Output color space: srgb, adobe, wide, prophoto, xyz, aces or raw (camera native). A matching ICC profile is embedded in PNG, JPEG, TIFF, WebP and AVIF outputs"
+    )]
+    colorspace: String,
+    #[arg(
+        long = "highlights",
+        value_name = "MODE",
+        default_value = "clip",
+        help = "Highlight handling: clip (neutral white, libraw default), unclip (keeps clipped channels, may tint), blend (blends clipped and unclipped values to soften magenta skies), or rebuild[=3..9] (reconstructs highlights, higher is more aggressive; default 5)"
+    )]
+    highlights: String,
+    #[arg(
+        long = "gamma",
+        value_name = "CURVE",
+        default_value = "bt709",
+        help = "Output tone curve applied by libraw: bt709 (libraw default), srgb, linear, or a custom gamma and toe slope `P[,TS]` as in dcraw's -g (e.g. 2.222,4.5; 1.8,0 for a pure power curve). Lower gammas give flatter, log-like output for grading"
+    )]
+    gamma: String,
+    #[arg(
+        long = "decode-size",
+        value_name = "SIZE",
+        default_value = "auto",
+        help = "Sensor decode size: full, half (libraw half_size, 2x2 binned, no demosaicing needed), or auto (half whenever --ratio keeps at most half the width and height)"
+    )]
+    decode_size: String,
     #[arg(
         short = 'p',
         long = "preview",
@@ -160,13 +257,47 @@ struct Args {
         help = "Use embedded preview image if available"
     )]
     preview: bool,
+    #[arg(
+        long = "preview-select",
+        value_name = "PICK",
+        default_value = "fit",
+        help = "Which embedded preview --preview uses: fit (smallest one covering the --ratio output size, else the largest) or largest"
+    )]
+    preview_select: String,
+    #[arg(
+        long = "extensions",
+        value_name = "LIST",
+        help = "File extensions picked up when the input is a directory, separated by , or + (e.g. nef+arw+cr3). Defaults to every raw format libraw reads"
+    )]
+    extensions: Option<String>,
+    #[arg(
+        long = "cfa",
+        value_name = "MODE",
+        num_args = 0..=1,
+        help = "Write the undemosaiced sensor mosaic instead of a rendered image, as 16-bit PGM or TIFF with a JSON sidecar (CFA pattern, black and white levels). `--cfa` or `--cfa raw` keeps the black level, `--cfa black` subtracts it. Output is cropped to the visible area and never resized"
+    )]
+    cfa: Option<Option<String>>,
+    #[arg(
+        long = "dng",
+        value_name = "KIND",
+        default_value = "cfa",
+        help = "What `-f dng` stores: cfa (the sensor mosaic, demosaiced later by the raw editor) or linear (demosaiced camera RGB, LinearRaw)"
+    )]
+    dng: String,
     #[arg(
         short = 'b',
         long = "brightness",
         num_args = 0..=1,
-        help = "Brightness control. No flag = leave as-is. `-b` (no value) => auto. Accepts: `auto`/`true`, `none`/`false`, a float factor (e.g. 0.58), an integer literal (e.g. 5 => factor 5.0), or a percent suffix (e.g. 120% => 1.2, -20% => 0.8)."
+        help = "Exposure, applied to the raw data before demosaicing. No flag = leave as-is. `-b` (no value) => auto exposure from the raw histogram. Accepts: `auto`/`true`, `none`/`false`, a float factor (e.g. 0.58), an integer literal (e.g. 5 => factor 5.0), a percent (e.g. 120% => 1.2, -20% => 0.8) or stops (e.g. +1ev => 2.0, -0.5ev => 0.71)."
     )]
     brightness: Option<Option<String>>,
+    #[arg(
+        long = "preserve-highlights",
+        value_name = "AMOUNT",
+        default_value_t = 0.8,
+        help = "How much of the highlights to roll off instead of clipping when -b brightens, from 0 (none) to 1 (full)"
+    )]
+    preserve_highlights: f32,
     #[arg(
         short = 'R',
         long = "rotation",
@@ -180,6 +311,67 @@ struct Args {
         help = "Automatically enhance the image (simple unsharpen + slight contrast)"
     )]
     enhance: bool,
+    #[arg(
+        long = "denoise",
+        value_name = "PRESET",
+        num_args = 0..=1,
+        help = "Noise reduction: libraw wavelet and FBDD denoising plus a bilateral luma/chroma filter. Presets: low, medium, high, off; `--denoise` alone or `auto` picks one from the ISO"
+    )]
+    denoise: Option<Option<String>>,
+    #[arg(
+        long = "dark-frame",
+        value_name = "RAW",
+        help = "Dark frame subtracted from every file before demosaicing. Repeat to average several into a master dark"
+    )]
+    dark_frame: Vec<PathBuf>,
+    #[arg(
+        long = "flat",
+        value_name = "RAW",
+        help = "Flat frame to correct vignetting and dust before demosaicing. Repeat to median-combine several into a master flat"
+    )]
+    flat: Vec<PathBuf>,
+    #[arg(
+        long = "bias",
+        value_name = "RAW",
+        help = "Bias frame subtracted from the --flat frames instead of the black level. Repeat to median-combine several"
+    )]
+    bias: Vec<PathBuf>,
+    #[arg(
+        long = "bad-pixels",
+        value_name = "FILE",
+        help = "Repair the pixels listed in a dcraw-style file (`column row [time]` per line), or `auto` to detect hot pixels from --dark-frame"
+    )]
+    bad_pixels: Option<String>,
+    #[arg(
+        long = "find-hot-pixels",
+        value_name = "OUT",
+        help = "Detect hot pixels in the input dark frames (or --dark-frame) and write them to OUT as a --bad-pixels map, then exit"
+    )]
+    find_hot_pixels: Option<PathBuf>,
+    #[arg(
+        long = "lens-table",
+        value_name = "TOML",
+        help = "Per-lens corrections (ca, distortion, vignetting) keyed by the LensModel of each file"
+    )]
+    lens_table: Option<PathBuf>,
+    #[arg(
+        long = "ca",
+        value_name = "R,B",
+        help = "Chromatic aberration correction: scale the red and blue channels by R and B (e.g. 1.0004,0.9997) before demosaicing"
+    )]
+    ca: Option<String>,
+    #[arg(
+        long = "distortion",
+        value_name = "K1[,K2,K3]",
+        help = "Correct radial distortion with these coefficients (radius 1 = half the diagonal); negative K1 for barrel"
+    )]
+    distortion: Option<String>,
+    #[arg(
+        long = "vignetting",
+        value_name = "V1[,V2,V3]",
+        help = "Correct vignetting, brightness falling off as 1 + V1 r^2 + V2 r^4 + V3 r^6 (negative V1 for darker corners)"
+    )]
+    vignetting: Option<String>,
     #[arg(
         short = 'q',
         long = "quality",
@@ -200,14 +392,39 @@ struct Args {
         help = "Print metadata/info for the input file(s) and exit"
     )]
     info: bool,
+    #[arg(
+        long = "json",
+        default_value_t = false,
+        help = "With --info, print the camera and raw sections as JSON instead"
+    )]
+    json: bool,
     #[arg(short = 'v', long = "version", help = "Print version information")]
     version: bool,
+    #[arg(
+        long = "list-cameras",
+        value_name = "FILTER",
+        num_args = 0..=1,
+        help = "List the cameras the loaded libraw supports, optionally only those matching FILTER (e.g. \"nikon z\"), and exit"
+    )]
+    list_cameras: Option<Option<String>>,
+    #[arg(
+        long = "libraw",
+        value_name = "PATH",
+        help = "libraw library to load, or a directory to search, instead of the system one (overrides LIBRAW_PATH)"
+    )]
+    libraw: Option<PathBuf>,
     #[arg(
         long = "sort",
         value_name = "METHOD",
-        help = "Sort input files before processing. Methods: name, mtime, size, numeric"
+        help = "Sort input files before processing. Methods: name, mtime, size, numeric, capture (camera timestamp)"
     )]
     sort: Option<String>,
+    #[arg(
+        long = "name",
+        value_name = "TEMPLATE",
+        help = "Output file name template (without extension) built from raw metadata, e.g. {date}_{model}_{stem}. Placeholders: {stem}, {make}, {model}, {lens}, {iso}, {shutter}, {aperture}, {focal}, {date}, {time}, {datetime}, {seq}. With several inputs it must contain {stem} or {seq}"
+    )]
+    name: Option<String>,
 }
 
 #[derive(Clone, Copy, Debug)]
@@ -229,16 +446,24 @@ fn parse_brightness(opt: &Option<Option<String>>) -> BrightnessMode {
             } else if low == "false" || low == "none" {
                 BrightnessMode::None
             } else {
-                if s_trim.ends_with('%') {
-                    let num = s_trim[..s_trim.len() - 1].trim();
-                    if let Ok(v) = num.parse::<f32>() {
-                        return BrightnessMode::Factor(v / 100.0);
-                    }
+                if let Some(num) = low.strip_suffix("ev")
+                    && let Ok(v) = num.trim().parse::<f32>()
+                {
+                    return BrightnessMode::Factor(2f32.powf(v));
                 }
-                if s_trim.contains('.') || s_trim.contains('e') || s_trim.contains('E') {
-                    if let Ok(v) = s_trim.parse::<f32>() {
-                        return BrightnessMode::Factor(v);
+                if let Some(num) = s_trim.strip_suffix('%')
+                    && let Ok(v) = num.trim().parse::<f32>()
+                {
+                    // A sign makes the percentage relative: -20% => 0.8.
+                    if num.trim_start().starts_with(['+', '-']) {
+                        return BrightnessMode::Factor(1.0 + v / 100.0);
                     }
+                    return BrightnessMode::Factor(v / 100.0);
+                }
+                if (s_trim.contains('.') || s_trim.contains('e') || s_trim.contains('E'))
+                    && let Ok(v) = s_trim.parse::<f32>()
+                {
+                    return BrightnessMode::Factor(v);
                 }
                 if let Ok(i) = s_trim.parse::<i32>() {
                     return BrightnessMode::Factor(i as f32);
@@ -252,6 +477,87 @@ fn parse_brightness(opt: &Option<Option<String>>) -> BrightnessMode {
     }
 }
 
+/// libraw `user_qual` values and the first libraw release that ships each algorithm.
+const DEMOSAIC_ALGOS: &[(&str, i32, (i32, i32))] = &[
+    ("linear", 0, (0, 0)),
+    ("vng", 1, (0, 0)),
+    ("ppg", 2, (0, 0)),
+    ("ahd", 3, (0, 0)),
+    ("dcb", 4, (0, 14)),
+    ("dht", 11, (0, 16)),
+    ("aahd", 12, (0, 16)),
+];
+
+fn parse_demosaic(name: &str) -> Result<i32> {
+    let low = name.trim().to_ascii_lowercase();
+    let low = if low == "bilinear" { "linear" } else { &low };
+    let Some(&(_, qual, (major, minor))) = DEMOSAIC_ALGOS.iter().find(|(n, _, _)| *n == low) else {
+        let names = DEMOSAIC_ALGOS
+            .iter()
+            .map(|(n, _, _)| blue(n).to_string())
+            .collect::<Vec<_>>()
+            .join(", ");
+        anyhow::bail!("Unknown demosaic algorithm {}. Valid: {}", red(name), names);
+    };
+    let have = libraw::version_number()?;
+    if have < libraw_ffi::make_version(major, minor, 0) {
+        anyhow::bail!(
+            "Demosaic {} needs libraw {}.{} or newer, loaded libraw is {}",
+            pink(low),
+            major,
+            minor,
+            red(libraw_ffi::format_version(have))
+        );
+    }
+    Ok(qual)
+}
+
+/// Maps `--highlights` to libraw's `highlight` mode: 0 clip, 1 unclip, 2 blend, 3-9 rebuild.
+fn parse_highlights(s: &str) -> Result<i32> {
+    let low = s.trim().to_ascii_lowercase();
+    match low.as_str() {
+        "clip" => return Ok(0),
+        "unclip" => return Ok(1),
+        "blend" => return Ok(2),
+        "rebuild" => return Ok(5),
+        _ => {}
+    }
+    if let Some(level) = low.strip_prefix("rebuild=") {
+        return match level.parse::<i32>() {
+            Ok(v) if (3..=9).contains(&v) => Ok(v),
+            _ => anyhow::bail!(
+                "Highlight rebuild level must be between 3 and 9, got {}",
+                red(level)
+            ),
+        };
+    }
+    anyhow::bail!(
+        "Unknown highlight mode {}. Valid: {}, {}, {}, {}",
+        red(s),
+        blue("clip"),
+        blue("unclip"),
+        blue("blend"),
+        blue("rebuild[=3..9]")
+    )
+}
+
+/// Resolves `--decode-size` to whether libraw should decode at half size.
+fn parse_decode_size(s: &str, ratio: f64) -> Result<bool> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "full" => Ok(false),
+        "half" => Ok(true),
+        // `ratio` scales the area, so half the width and height is a quarter of it.
+        "auto" => Ok(ratio <= 0.25),
+        _ => anyhow::bail!(
+            "Unknown decode size {}. Valid: {}, {}, {}",
+            red(s),
+            blue("auto"),
+            blue("full"),
+            blue("half")
+        ),
+    }
+}
+
 pub fn normalize_format(fmt: &str) -> Option<(&'static str, ImageFormat)> {
     FORMAT_MAP.get(&fmt.to_lowercase() as &str).copied()
 }
@@ -286,13 +592,35 @@ fn print_metadata(path: &Path) -> Result<()> {
         }
     }
 
-    if is_nef_file(path) {
-        println!("\n{}", blue("Format hint: NEF (Nikon RAW) detected"));
-    } else {
-        println!(
+    match raw_format::detect_file(path) {
+        Some(f) => println!(
             "\n{}",
-            blue("Format hint: NEF not detected by header heuristics")
-        );
+            blue(format!(
+                "Format: {} ({} raw, {:?} container)",
+                f.name, f.vendor, f.container
+            ))
+        ),
+        None => println!("\n{}", blue("Format hint: no known raw container detected")),
+    }
+
+    match RawMetadata::read(path) {
+        Ok(meta) => {
+            println!("\n{}", blue("Camera (libraw):"));
+            for (k, v) in meta.fields() {
+                println!("  {}: {}", pink(k), white(v));
+            }
+        }
+        Err(e) => eprintln!("{}", pink(format!("Failed to read libraw metadata: {}", e))),
+    }
+
+    match RawInfo::read(path) {
+        Ok(info) => {
+            println!("\n{}", blue("Raw:"));
+            for (k, v) in info.fields() {
+                println!("  {}: {}", pink(k), white(v));
+            }
+        }
+        Err(e) => eprintln!("{}", pink(format!("Failed to read raw data: {}", e))),
     }
 
     Ok(())
@@ -314,6 +642,23 @@ fn print_metadata(path: &Path) -> Result<()> {
         }
     }
 
+    match RawMetadata::read(path) {
+        Ok(meta) => {
+            for (k, v) in meta.fields() {
+                entries.push(("LibRaw".to_string(), k.to_string(), v));
+            }
+        }
+        Err(e) => entries.push(("LibRaw".to_string(), "Error".to_string(), e.to_string())),
+    }
+    match RawInfo::read(path) {
+        Ok(info) => {
+            for (k, v) in info.fields() {
+                entries.push(("Raw".to_string(), k.to_string(), v));
+            }
+        }
+        Err(e) => entries.push(("Raw".to_string(), "Error".to_string(), e.to_string())),
+    }
+
     entries.sort_by(|a, b| a.0.cmp(&b.0).then(a.1.cmp(&b.1)));
 
     use std::collections::HashMap;
@@ -409,7 +754,7 @@ fn print_metadata(path: &Path) -> Result<()> {
                             }
                         }
 
-                        let pri = ["nikon", "composite", "exifidf"];
+                        let pri = ["libraw", "raw", "nikon", "composite", "exifidf"];
                         for &pfx in &pri {
                             let mut bucket: Vec<(usize, &(String, String, String))> = entries
                                 .iter()
@@ -613,22 +958,160 @@ fn print_metadata(path: &Path) -> Result<()> {
     res
 }
 
-fn apply_brightness(img: DynamicImage, mode: BrightnessMode) -> DynamicImage {
-    match mode {
-        BrightnessMode::None => img,
-        BrightnessMode::Auto => img,
-        BrightnessMode::Factor(f) => {
+/// `--info --json`: format, camera and raw sections as one JSON object.
+fn print_metadata_json(path: &Path) -> Result<()> {
+    let format = raw_format::detect_file(path).map(|f| {
+        serde_json::json!({
+            "name": f.name,
+            "vendor": f.vendor,
+            "container": format!("{:?}", f.container),
+        })
+    });
+    let camera = RawMetadata::read(path)?;
+    let raw = RawInfo::read(path)?;
+    let out = serde_json::json!({
+        "file": path.to_string_lossy(),
+        "format": format,
+        "camera": camera.to_json(),
+        "raw": raw.to_json(),
+    });
+    println!("{}", serde_json::to_string_pretty(&out)?);
+    Ok(())
+}
+
+/// Multiplies the color channels, for images that are already display encoded.
+fn scale_brightness(img: DynamicImage, f: f32) -> DynamicImage {
+    match img {
+        DynamicImage::ImageRgb16(mut buf) => {
+            for p in buf.pixels_mut() {
+                for c in p.0.iter_mut() {
+                    *c = (*c as f32 * f).clamp(0.0, 65535.0) as u16;
+                }
+            }
+            DynamicImage::ImageRgb16(buf)
+        }
+        DynamicImage::ImageRgba16(mut buf) => {
+            for p in buf.pixels_mut() {
+                for c in p.0[..3].iter_mut() {
+                    *c = (*c as f32 * f).clamp(0.0, 65535.0) as u16;
+                }
+            }
+            DynamicImage::ImageRgba16(buf)
+        }
+        DynamicImage::ImageRgb32F(mut buf) => {
+            for p in buf.pixels_mut() {
+                for c in p.0.iter_mut() {
+                    *c *= f;
+                }
+            }
+            DynamicImage::ImageRgb32F(buf)
+        }
+        img => {
             let mut buf = img.to_rgba8();
             for p in buf.pixels_mut() {
-                p[0] = ((p[0] as f32 * f).min(255.0).max(0.0)) as u8;
-                p[1] = ((p[1] as f32 * f).min(255.0).max(0.0)) as u8;
-                p[2] = ((p[2] as f32 * f).min(255.0).max(0.0)) as u8;
+                p[0] = (p[0] as f32 * f).clamp(0.0, 255.0) as u8;
+                p[1] = (p[1] as f32 * f).clamp(0.0, 255.0) as u8;
+                p[2] = (p[2] as f32 * f).clamp(0.0, 255.0) as u8;
             }
             DynamicImage::ImageRgba8(buf)
         }
     }
 }
 
+/// Approximates an exposure factor on gamma-encoded data, where the raw is not available.
+fn display_exposure(img: DynamicImage, f: f32) -> DynamicImage {
+    scale_brightness(img, f.powf(1.0 / 2.2))
+}
+
+fn is_16bit(img: &DynamicImage) -> bool {
+    matches!(
+        img.color(),
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16
+    )
+}
+
+/// Returns scene-linear float RGB. `ImageRgb32F` images are always linear in fempeg,
+/// everything else is display-referred and gets `curve` removed.
+fn to_linear_rgb32f(img: &DynamicImage, curve: &ToneCurve) -> image::Rgb32FImage {
+    match img {
+        DynamicImage::ImageRgb32F(buf) => buf.clone(),
+        other => {
+            let mut buf = other.to_rgb32f();
+            for p in buf.pixels_mut() {
+                for c in p.0.iter_mut() {
+                    *c = curve.decode(*c as f64) as f32;
+                }
+            }
+            buf
+        }
+    }
+}
+
+/// Encodes a scene-linear float image with `curve` for integer output formats.
+fn linear_to_display(buf: &image::Rgb32FImage, bits: u8, curve: &ToneCurve) -> DynamicImage {
+    let (w, h) = buf.dimensions();
+    let lut = curve.encode_table(1 << 16);
+    let encode = |v: f32| lut[(v.clamp(0.0, 1.0) * 65535.0).round() as usize];
+    if bits == 16 {
+        let data = buf
+            .as_raw()
+            .iter()
+            .map(|&v| (encode(v) * 65535.0).round() as u16)
+            .collect();
+        DynamicImage::ImageRgb16(image::ImageBuffer::from_raw(w, h, data).unwrap())
+    } else {
+        let data = buf
+            .as_raw()
+            .iter()
+            .map(|&v| (encode(v) * 255.0).round() as u8)
+            .collect();
+        DynamicImage::ImageRgb8(image::RgbImage::from_raw(w, h, data).unwrap())
+    }
+}
+
+fn orient_image(img: DynamicImage, code: u32) -> DynamicImage {
+    match code {
+        3 => img.rotate180(),
+        6 => img.rotate90(),
+        8 => img.rotate270(),
+        _ => img,
+    }
+}
+
+/// EXIF orientation code of a JPEG or TIFF-based file, if it has one.
+fn exif_orientation(buf: &[u8]) -> Option<u32> {
+    let exif = rexif::parse_buffer(buf).ok()?;
+    let entry = exif
+        .entries
+        .iter()
+        .find(|e| format!("{}", e.tag).to_lowercase().contains("orientation"))?;
+    format!("{}", entry.value)
+        .split_whitespace()
+        .next()
+        .and_then(|tok| tok.parse::<u32>().ok())
+}
+
+fn apply_rotation(img: DynamicImage, rot: &str, in_path: &Path) -> DynamicImage {
+    if rot == "auto" {
+        let Ok(buf) = std::fs::read(in_path) else {
+            return img;
+        };
+        match exif_orientation(&buf) {
+            Some(code) => orient_image(img, code),
+            None => img,
+        }
+    } else if let Ok(deg) = rot.parse::<i32>() {
+        match deg.rem_euclid(360) {
+            90 => img.rotate90(),
+            180 => img.rotate180(),
+            270 => img.rotate270(),
+            _ => img,
+        }
+    } else {
+        img
+    }
+}
+
 fn format_time(secs: f64) -> String {
     let s = secs as u64;
     if s < 60 {
@@ -659,42 +1142,14 @@ fn resize_image(img: DynamicImage, ratio: f64) -> DynamicImage {
     img.resize_exact(new_w, new_h, FilterType::Lanczos3)
 }
 
-fn is_nef_file(path: &Path) -> bool {
-    let f = std::fs::File::open(path);
-    let mut f = match f {
-        Ok(x) => x,
-        Err(_) => return false,
-    };
-    let mut buf = Vec::new();
-    let _ = std::io::Read::by_ref(&mut f)
-        .take(131072)
-        .read_to_end(&mut buf);
-    if buf.len() < 4 {
-        return false;
-    }
-    if !(buf.starts_with(b"II*\0") || buf.starts_with(b"MM\0*")) {
-        return false;
-    }
-    let mut found_nikon = false;
-    let lower: Vec<u8> = buf.iter().map(|b| b.to_ascii_lowercase()).collect();
-    if lower.windows(5).any(|w| w == b"nikon") {
-        found_nikon = true;
-    }
-    if found_nikon {
-        return true;
-    }
-    if let Ok(exif) = rexif::parse_buffer(&buf) {
-        for entry in exif.entries.iter() {
-            let val = format!("{}", entry.value).to_ascii_lowercase();
-            if val.contains("nikon") {
-                return true;
-            }
-        }
-    }
-    false
-}
-
-fn sort_inputs(inputs: &mut Vec<PathBuf>, method: &str, debug: bool) {
+/// Sorts `inputs` by `method`; `capture_times` reads the capture time of each of them for
+/// the `capture` method.
+fn sort_inputs(
+    inputs: &mut [PathBuf],
+    method: &str,
+    debug: bool,
+    capture_times: impl FnOnce(&[PathBuf]) -> Vec<Option<i64>>,
+) {
     match method.to_ascii_lowercase().as_str() {
         "name" => inputs.sort_by_key(|p| p.file_name().map(|s| s.to_os_string())),
         "numeric" => inputs.sort_by(|a, b| {
@@ -724,6 +1179,21 @@ fn sort_inputs(inputs: &mut Vec<PathBuf>, method: &str, debug: bool) {
             let sb = b.metadata().map(|m| m.len()).unwrap_or(0);
             sa.cmp(&sb)
         }),
+        "capture" | "shot" => {
+            let mut keyed: Vec<_> = capture_times(inputs)
+                .into_iter()
+                .zip(inputs.iter().cloned())
+                .collect();
+            for (ts, p) in &keyed {
+                if debug && ts.is_none() {
+                    eprintln!("No capture time in {}, sorting it last", p.display());
+                }
+            }
+            keyed.sort_by_key(|(ts, p)| (ts.is_none(), *ts, p.clone()));
+            for (slot, (_, p)) in inputs.iter_mut().zip(keyed) {
+                *slot = p;
+            }
+        }
         "mtime" | "time" | "date" => inputs.sort_by(|a, b| {
             let ta = a
                 .metadata()
@@ -750,28 +1220,205 @@ fn sort_inputs(inputs: &mut Vec<PathBuf>, method: &str, debug: bool) {
     }
 }
 
-unsafe fn load_with_libraw(
-    path: &Path,
-    use_preview: bool,
+/// Copies a libraw processed bitmap (8 or 16 bits per sample) into an owned image.
+fn processed_bitmap_to_image(pimg: &ProcessedImage, what: &str) -> Result<DynamicImage> {
+    let (width, height, colors, bits) = (pimg.width(), pimg.height(), pimg.colors(), pimg.bits());
+    let slice = pimg.data();
+    let data_size = slice.len();
+    let sample_size = match bits {
+        8 => 1,
+        16 => 2,
+        _ => anyhow::bail!("libraw {} bitmap has unsupported bit depth: {}", what, bits),
+    };
+    let expected = (width as usize) * (height as usize) * colors * sample_size;
+    if data_size < expected {
+        anyhow::bail!(
+            "libraw {} bitmap too small: {} < {}",
+            what,
+            data_size,
+            expected
+        );
+    }
+    let img = match (bits, colors) {
+        (8, 3) => DynamicImage::ImageRgb8(
+            image::RgbImage::from_raw(width, height, slice[..expected].to_vec())
+                .with_context(|| format!("Failed to construct RGB image from libraw {}", what))?,
+        ),
+        (8, 4) => DynamicImage::ImageRgba8(
+            image::RgbaImage::from_raw(width, height, slice[..expected].to_vec())
+                .with_context(|| format!("Failed to construct RGBA image from libraw {}", what))?,
+        ),
+        (16, 3) | (16, 4) => {
+            let samples: Vec<u16> = slice[..expected]
+                .chunks_exact(2)
+                .map(|b| u16::from_ne_bytes([b[0], b[1]]))
+                .collect();
+            if colors == 3 {
+                DynamicImage::ImageRgb16(
+                    image::ImageBuffer::from_raw(width, height, samples).with_context(|| {
+                        format!("Failed to construct 16-bit RGB image from libraw {}", what)
+                    })?,
+                )
+            } else {
+                DynamicImage::ImageRgba16(
+                    image::ImageBuffer::from_raw(width, height, samples).with_context(|| {
+                        format!("Failed to construct 16-bit RGBA image from libraw {}", what)
+                    })?,
+                )
+            }
+        }
+        _ => anyhow::bail!("Unsupported {} colors: {}", what, colors),
+    };
+    Ok(img)
+}
+
+/// libraw settings shared by every file of a run.
+#[derive(Clone, Copy)]
+struct DecodeOptions<'a> {
+    preview: Option<PreviewPick>,
+    exposure: BrightnessMode,
+    /// `exp_preser` for raw-domain exposure, 0 to 1.
+    preserve_highlights: f32,
+    output_bps: u8,
+    linear: bool,
+    white_balance: WhiteBalance,
+    demosaic: Option<i32>,
+    color_space: ColorSpace,
+    highlight: i32,
+    curve: ToneCurve,
+    half_size: bool,
+    denoise: DenoisePreset,
+    calibration: Option<&'a Calibration>,
+    lens: Option<&'a LensCorrection>,
+    cfa: Option<CfaMode>,
+    dng: Option<DngKind>,
+}
+
+struct Decoded {
+    image: DynamicImage,
+    /// Color encoding of the integer form; for linear float images the one applied when saving.
+    profile: ColorProfile,
+    /// Area of the decoded image relative to the full sensor output.
+    area: f64,
+    meta: Option<RawMetadata>,
+    /// CFA exports: the JSON written next to each output. Set only for mosaics.
+    sidecar: Option<serde_json::Value>,
+    /// Raw data for `dng` outputs, when one was requested.
+    dng: Option<DngSource>,
+    /// Already turned upright by its EXIF orientation, so `-R auto` must not rotate it again.
+    oriented: bool,
+}
+
+/// What writing a decoded file needs besides the finished image.
+struct OutputData {
+    profile: ColorProfile,
+    sidecar: Option<serde_json::Value>,
+    dng: Option<DngSource>,
+}
+
+/// How converting one file of a batch went; also what `--isolate` workers send back.
+enum Outcome {
+    /// Written in `elapsed` seconds.
+    Done { raw_fmt: String, elapsed: f64 },
+    /// Log line for a skipped or failed file.
+    Failed(String),
+}
+
+struct Converted {
+    outcome: Outcome,
+    /// Size of the outputs written.
+    bytes: u64,
+    camera: Seen,
+}
+
+/// Writes one output: DNGs from the raw data, everything else through `save_image`.
+fn save_output(
+    img: &DynamicImage,
+    data: &OutputData,
+    out_path: &Path,
+    fmt: &str,
+    quality: u8,
+    bits: u8,
     debug: bool,
-    auto_brightness: bool,
-) -> Result<DynamicImage> {
-    let api = libraw_ffi::get_api().context("Failed to load libraw symbols")?;
-    if debug {
-        println!("{} calling libraw_init...", blue("[init]"));
+) -> Result<()> {
+    if normalize_format(fmt).map(|(ext, _)| ext) == Some("dng") {
+        let src = data
+            .dng
+            .as_ref()
+            .context("DNG output needs the raw data, which was not decoded")?;
+        return dng::write_dng(out_path, src, debug);
     }
-    let raw = unsafe { (api.libraw_init)(0) };
-    if debug {
-        println!("{} libraw_init -> {:p}", blue("[init]"), raw);
+    save_image(img, out_path, fmt, quality, bits, &data.profile, debug)?;
+    if let Some(sidecar) = &data.sidecar {
+        cfa::write_sidecar(out_path, sidecar)?;
+    }
+    Ok(())
+}
+
+/// File stem for the outputs of `in_path`, expanded from `--name` when given.
+fn output_stem(
+    in_path: &Path,
+    template: Option<&str>,
+    meta: Option<&RawMetadata>,
+    seq: usize,
+) -> String {
+    let stem = in_path.file_stem().unwrap().to_string_lossy();
+    match template {
+        Some(t) => meta
+            .cloned()
+            .unwrap_or_default()
+            .expand_template(t, &stem, seq),
+        None => stem.to_string(),
     }
-    if raw.is_null() {
-        anyhow::bail!("libraw_init returned null");
+}
+
+impl Decoded {
+    /// Remaining area ratio for `resize_image` to reach `ratio` of the full sensor size.
+    fn resize_ratio(&self, ratio: f64) -> f64 {
+        // Resampling would mix sensor sites of different colors.
+        if self.sidecar.is_some() {
+            return 1.0;
+        }
+        (ratio / self.area).min(1.0)
     }
+}
 
+/// Decodes `path` with libraw. `lock`, when given, is held only while libraw is in use, so
+/// the denoising and lens correction that follow can overlap with other decodes.
+fn load_with_libraw(
+    path: &Path,
+    opts: &DecodeOptions,
+    lock: Option<&Mutex<()>>,
+    debug: bool,
+) -> Result<Decoded> {
+    let DecodeOptions {
+        preview,
+        exposure,
+        preserve_highlights,
+        output_bps,
+        linear,
+        white_balance,
+        demosaic,
+        color_space,
+        highlight,
+        curve,
+        half_size,
+        denoise,
+        calibration,
+        lens,
+        cfa,
+        dng,
+    } = *opts;
     if debug {
         println!("{} reading file into memory...", blue("[read]"));
     }
     let data = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+
+    let guard = lock.map(|l| l.lock().unwrap_or_else(|e| e.into_inner()));
+    if debug {
+        println!("{} calling libraw_init...", blue("[init]"));
+    }
+    let mut lr = LibRaw::new()?;
     if debug {
         println!(
             "{} calling libraw_open_buffer (len={})...",
@@ -779,227 +1426,360 @@ unsafe fn load_with_libraw(
             data.len()
         );
     }
-    let r = unsafe { (api.libraw_open_buffer)(raw, data.as_ptr(), data.len()) };
-    if debug {
-        println!("{} libraw_open_buffer -> {}", blue("[buffer]"), r);
-    }
-    if r != 0 {
-        unsafe { (api.libraw_close)(raw) };
-        anyhow::bail!("libraw_open_buffer failed: {}", r);
+    lr.open_buffer(data).map_err(camera_support::open_error)?;
+    let meta = match RawMetadata::from_libraw(&lr) {
+        Ok(m) => Some(m),
+        Err(e) => {
+            if debug {
+                eprintln!("{} {}", blue("[meta]"), e);
+            }
+            None
+        }
+    };
+
+    // Previews only need the thumbnail data, so they are tried before unpacking the raw.
+    if let Some(pick) = preview {
+        match preview::extract_preview(&mut lr, pick, debug) {
+            Ok(Some((image, area))) => {
+                drop(lr);
+                drop(guard);
+                let factor = match exposure {
+                    BrightnessMode::None => None,
+                    BrightnessMode::Factor(f) => Some(f),
+                    BrightnessMode::Auto => {
+                        exposure::display_auto_factor(&image, preserve_highlights)
+                    }
+                };
+                let image = match factor {
+                    Some(f) => display_exposure(image, f),
+                    None => image,
+                };
+                // Camera previews are sRGB JPEGs regardless of the requested color space.
+                return Ok(Decoded {
+                    image,
+                    profile: ColorProfile::SRGB,
+                    area,
+                    meta,
+                    sidecar: None,
+                    dng: None,
+                    oriented: true,
+                });
+            }
+            Ok(None) => {
+                if debug {
+                    eprintln!(
+                        "{} no embedded preview, continuing to full processing",
+                        blue("[preview]")
+                    );
+                }
+            }
+            Err(e) => {
+                if debug {
+                    eprintln!(
+                        "{} preview failed ({}), continuing to full processing",
+                        blue("[preview]"),
+                        e
+                    );
+                }
+            }
+        }
     }
 
     if debug {
         println!("{} calling libraw_unpack...", blue("[unpack]"));
     }
-    let r = unsafe { (api.libraw_unpack)(raw) };
-    if debug {
-        println!("{} libraw_unpack -> {}", blue("[unpack]"), r);
+    lr.unpack().context("libraw_unpack failed")?;
+    if let Some(cal) = calibration {
+        cal.apply(&mut lr, debug).context("Calibration failed")?;
     }
-    if r != 0 {
-        unsafe { (api.libraw_close)(raw) };
-        anyhow::bail!("libraw_unpack failed: {}", r);
+
+    if let Some(mode) = cfa {
+        let (image, sidecar) = cfa::extract_mosaic(&mut lr, mode, debug)?;
+        return Ok(Decoded {
+            image,
+            profile: ColorProfile {
+                space: ColorSpace::Raw,
+                curve: ToneCurve::LINEAR,
+            },
+            area: 1.0,
+            meta,
+            sidecar: Some(sidecar),
+            dng: None,
+            oriented: false,
+        });
     }
 
-    let _ = unsafe { (api.libraw_set_output_bps)(raw, 8) };
-    let _ = unsafe { (api.libraw_set_output_color)(raw, 1) };
-    let no_auto_val = if auto_brightness { 0 } else { 1 };
-    let _ = unsafe { (api.libraw_set_no_auto_bright)(raw, no_auto_val) };
+    let mut dng = match dng {
+        Some(kind) => Some(
+            DngSource::capture(&mut lr, kind, demosaic, meta.as_ref(), debug)
+                .context("Failed to read raw data for DNG")?,
+        ),
+        None => None,
+    };
 
-    if use_preview {
-        let mut err_code: std::os::raw::c_int = 0;
-        let pimg = unsafe {
-            (api.libraw_dcraw_make_mem_image)(raw, &mut err_code as *mut std::os::raw::c_int)
-        };
-        if !pimg.is_null() {
-            let ty = unsafe { (*pimg).type_ };
-            let data_size = unsafe { (*pimg).data_size as usize };
-            if data_size > 0 {
-                let header_size = std::mem::size_of::<libraw_ffi::LibRawProcessedImage>();
-                let data_ptr = (pimg as *const u8).wrapping_add(header_size) as *const u8;
-                let slice = unsafe { std::slice::from_raw_parts(data_ptr, data_size) };
-                if ty == 1 {
-                    let img = image::load_from_memory(slice)
-                        .context("Failed to decode preview JPEG from libraw")?;
-                    unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
-                    unsafe { (api.libraw_close)(raw) };
-                    return Ok(img);
-                } else {
-                    let colors = unsafe { (*pimg).colors as usize };
-                    let width = unsafe { (*pimg).width as u32 };
-                    let height = unsafe { (*pimg).height as u32 };
-                    let bits = unsafe { (*pimg).bits };
-                    if bits != 8 {
-                        unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
-                        unsafe { (api.libraw_close)(raw) };
-                        anyhow::bail!("libraw preview bitmap has unsupported bit depth: {}", bits);
-                    }
-                    let expected = (width as usize) * (height as usize) * colors;
-                    if data_size < expected {
-                        unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
-                        unsafe { (api.libraw_close)(raw) };
-                        anyhow::bail!(
-                            "libraw preview bitmap too small: {} < {}",
-                            data_size,
-                            expected
-                        );
+    // Exposure is applied to the raw data by libraw, or to the floats for linear outputs,
+    // before anything is clipped or gamma encoded.
+    let exposure_factor = match exposure {
+        BrightnessMode::None => None,
+        BrightnessMode::Factor(f) => Some(f),
+        BrightnessMode::Auto => {
+            match exposure::auto_exposure(&mut lr, preserve_highlights, debug) {
+                Ok(f) => Some(f),
+                Err(e) => {
+                    if debug {
+                        eprintln!("{} auto exposure unavailable: {}", blue("[exposure]"), e);
                     }
-                    let vec = slice[..expected].to_vec();
-                    let result_img = match colors {
-                        3 => {
-                            let imgbuf = image::RgbImage::from_raw(width, height, vec)
-                                .context("Failed to construct RGB image from libraw preview")?;
-                            DynamicImage::ImageRgb8(imgbuf)
-                        }
-                        4 => {
-                            let imgbuf = image::RgbaImage::from_raw(width, height, vec)
-                                .context("Failed to construct RGBA image from libraw.preview")?;
-                            DynamicImage::ImageRgba8(imgbuf)
-                        }
-                        _ => {
-                            unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
-                            unsafe { (api.libraw_close)(raw) };
-                            anyhow::bail!("Unsupported preview colors: {}", colors);
-                        }
-                    };
-                    unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
-                    unsafe { (api.libraw_close)(raw) };
-                    return Ok(result_img);
+                    None
                 }
             }
-            unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
         }
-        let err_msg = unsafe {
-            let p = (api.libraw_strerror)(err_code);
-            if p.is_null() {
-                "(unknown)".into()
-            } else {
-                std::ffi::CStr::from_ptr(p).to_string_lossy().into_owned()
-            }
-        };
-        if debug {
+    };
+    let mut fallback_factor = None;
+    if let Some(f) = exposure_factor.filter(|_| !linear) {
+        let shift = f.clamp(exposure::SHIFT_MIN, exposure::SHIFT_MAX);
+        if shift != f {
             eprintln!(
-                "libraw dcraw_make_mem_image preview returned null or empty (err={} msg={}), continuing to full processing",
-                err_code, err_msg
+                "{}",
+                pink(format!(
+                    "Exposure x{} is outside libraw's range, using x{}",
+                    f, shift
+                ))
             );
         }
+        if debug {
+            println!(
+                "{} exposure {} preserving {} of the highlights",
+                blue("[params]"),
+                pink(format!("x{:.3} ({:+.2} EV)", shift, shift.log2())),
+                pink(preserve_highlights)
+            );
+        }
+        if let Err(e) = lr.set_exposure(shift, preserve_highlights) {
+            if debug {
+                eprintln!(
+                    "{} raw exposure unavailable, scaling the output: {}",
+                    blue("[params]"),
+                    e
+                );
+            }
+            fallback_factor = Some(shift);
+        }
     }
-
-    if debug {
-        println!("{} calling libraw_dcraw_process...", blue("[process]"));
-    }
-    let r = unsafe { (api.libraw_dcraw_process)(raw) };
+
+    // The linear path always decodes at 16 bits with a unit gamma and no auto-brightening,
+    // so the float result stays proportional to the sensor data.
+    let output_bps = if linear { 16 } else { output_bps };
     if debug {
-        println!("{} libraw_dcraw_process -> {}", blue("[process]"), r);
-    }
-    if r != 0 {
-        unsafe { (api.libraw_close)(raw) };
-        anyhow::bail!("libraw_dcraw_process failed: {}", r);
+        println!(
+            "{} requesting {} bits per sample{}",
+            blue("[params]"),
+            pink(output_bps),
+            if linear { ", linear gamma" } else { "" }
+        );
     }
-
+    lr.set_output_bps(output_bps as i32);
     if debug {
-        println!("{} calling libraw_dcraw_make_mem_image...", blue("[image]"));
+        println!(
+            "{} output color {}",
+            blue("[params]"),
+            pink(format!("{:?}", color_space))
+        );
     }
-    let mut err_code: std::os::raw::c_int = 0;
-    let pimg = unsafe {
-        (api.libraw_dcraw_make_mem_image)(raw, &mut err_code as *mut std::os::raw::c_int)
-    };
+    lr.set_output_color(color_space.libraw_code());
+    // libraw's auto-bright clips at its histogram white point; -b auto is done by
+    // `exposure::auto_exposure` instead.
+    lr.set_no_auto_bright(true);
+    let libraw_curve = if linear { ToneCurve::LINEAR } else { curve };
     if debug {
         println!(
-            "{} libraw_dcraw_make_mem_image -> {:p}, err={}",
-            blue("[image]"),
-            pimg,
-            err_code
+            "{} gamma power {} toe slope {}",
+            blue("[params]"),
+            pink(libraw_curve.power),
+            pink(libraw_curve.slope)
         );
     }
-    if pimg.is_null() {
-        let err_msg = unsafe {
-            let p = (api.libraw_strerror)(err_code);
-            if p.is_null() {
-                "(unknown)".into()
-            } else {
-                std::ffi::CStr::from_ptr(p).to_string_lossy().into_owned()
+    lr.set_gamma(libraw_curve.power as f32, libraw_curve.slope as f32);
+    if debug {
+        println!("{} highlight mode {}", blue("[params]"), pink(highlight));
+    }
+    lr.set_highlight(highlight);
+    let mut area = 1.0;
+    if half_size {
+        match lr.set_half_size(true) {
+            Ok(()) => {
+                area = 0.25;
+                if debug {
+                    println!("{} decoding at half size", blue("[params]"));
+                }
             }
-        };
-        unsafe { (api.libraw_close)(raw) };
-        anyhow::bail!(
-            "libraw dcraw_make_mem_image returned null: {} ({})",
-            err_code,
-            err_msg
-        );
+            Err(e) => {
+                if debug {
+                    eprintln!(
+                        "{} half size unavailable, decoding full: {}",
+                        blue("[params]"),
+                        e
+                    );
+                }
+            }
+        }
+    }
+    if let Some(qual) = demosaic {
+        if debug {
+            println!("{} demosaic quality {}", blue("[params]"), pink(qual));
+        }
+        lr.set_demosaic(qual);
+    }
+    white_balance::apply_white_balance(&mut lr, white_balance, debug)?;
+    let lens = lens
+        .map(|l| l.profile_for(meta.as_ref(), debug))
+        .unwrap_or_default();
+    if let Some([r, b]) = lens.ca {
+        if debug {
+            println!(
+                "{} chromatic aberration red x{} blue x{}",
+                blue("[params]"),
+                pink(r),
+                pink(b)
+            );
+        }
+        if let Err(e) = lr.set_aberration(r, b) {
+            eprintln!(
+                "{}",
+                pink(format!(
+                    "Chromatic aberration correction unavailable: {}",
+                    e
+                ))
+            );
+        }
+    }
+    let preset = denoise.resolve(meta.as_ref().and_then(|m| m.iso));
+    let denoise = preset.settings();
+    if let Some(d) = &denoise {
+        if debug {
+            println!(
+                "{} denoise {} (wavelet {}, fbdd {})",
+                blue("[params]"),
+                pink(format!("{:?}", preset).to_ascii_lowercase()),
+                pink(d.wavelet),
+                pink(d.fbdd)
+            );
+        }
+        let set = lr
+            .set_wavelet_threshold(d.wavelet)
+            .and_then(|()| lr.set_fbdd_noiserd(d.fbdd));
+        if let Err(e) = set
+            && debug
+        {
+            eprintln!("{} libraw denoising unavailable: {}", blue("[params]"), e);
+        }
     }
 
-    let ty = unsafe { (*pimg).type_ };
-    let data_size = unsafe { (*pimg).data_size as usize };
-    let header_size = std::mem::size_of::<libraw_ffi::LibRawProcessedImage>();
-    let data_ptr = (pimg as *const u8).wrapping_add(header_size) as *const u8;
-    if data_ptr.is_null() || data_size == 0 {
-        unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
-        unsafe { (api.libraw_close)(raw) };
-        anyhow::bail!("libraw processed image has no data (size={})", data_size);
+    if debug {
+        println!("{} calling libraw_dcraw_process...", blue("[process]"));
+    }
+    lr.dcraw_process().context("libraw_dcraw_process failed")?;
+
+    if debug {
+        println!("{} calling libraw_dcraw_make_mem_image...", blue("[image]"));
+    }
+    let pimg = lr
+        .make_mem_image()
+        .context("libraw_dcraw_make_mem_image failed")?;
+    if pimg.data().is_empty() {
+        anyhow::bail!("libraw processed image has no data");
     }
     if debug {
         println!(
-            "{} constructing slice for data_size={}",
+            "{} converting data_size={} ({} bits)",
             blue("[image]"),
-            data_size
+            pimg.data().len(),
+            pimg.bits()
         );
     }
-    let slice = unsafe { std::slice::from_raw_parts(data_ptr, data_size) };
-    if debug {
-        if slice.len() > 0 {
-            let b = slice[0];
-            println!("{} first byte = {}", blue("[image]"), b);
+    let jpeg = pimg.kind() == ImageKind::Jpeg;
+    let img = if jpeg {
+        image::load_from_memory(pimg.data())
+            .context("Failed to decode processed JPEG from libraw")?
+    } else {
+        processed_bitmap_to_image(&pimg, "processed")?
+    };
+    drop(pimg);
+    drop(lr);
+    drop(guard);
+    let img = match &denoise {
+        Some(d) if !jpeg => denoise::denoise_image(img, d, debug),
+        _ => img,
+    };
+    // Geometry is corrected after demosaicing; libraw's output curve tells the vignetting
+    // gain how to reach linear light.
+    let img = lens::correct_image(
+        img,
+        &lens,
+        if jpeg {
+            &ToneCurve::SRGB
         } else {
-            println!("{} slice has no bytes", blue("[image]"));
+            &libraw_curve
+        },
+        debug,
+    );
+    let profile = ColorProfile {
+        space: color_space,
+        curve,
+    };
+    if let Some(d) = dng.as_mut() {
+        if linear && !jpeg {
+            d.set_preview(&linear_to_display(&img.to_rgb32f(), 8, &ToneCurve::SRGB));
+        } else {
+            d.set_preview(&img);
         }
     }
-    let img = if ty == 1 {
-        image::load_from_memory(slice).context("Failed to decode processed JPEG from libraw")?
-    } else {
-        let colors = unsafe { (*pimg).colors as usize };
-        let width = unsafe { (*pimg).width as u32 };
-        let height = unsafe { (*pimg).height as u32 };
-        let bits = unsafe { (*pimg).bits };
-        if bits != 8 {
-            unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
-            unsafe { (api.libraw_close)(raw) };
-            anyhow::bail!(
-                "libraw processed bitmap has unsupported bit depth: {}",
-                bits
-            );
-        }
-        let expected = (width as usize) * (height as usize) * colors;
-        if data_size < expected {
-            unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
-            unsafe { (api.libraw_close)(raw) };
-            anyhow::bail!(
-                "libraw processed bitmap too small: {} < {}",
-                data_size,
-                expected
-            );
-        }
-        let vec = slice[..expected].to_vec();
-        match colors {
-            3 => {
-                let imgbuf = image::RgbImage::from_raw(width, height, vec)
-                    .context("Failed to construct RGB image from libraw processed data")?;
-                DynamicImage::ImageRgb8(imgbuf)
-            }
-            4 => {
-                let imgbuf = image::RgbaImage::from_raw(width, height, vec)
-                    .context("Failed to construct RGBA image from libraw processed data")?;
-                DynamicImage::ImageRgba8(imgbuf)
+    if linear && !jpeg {
+        let mut buf = img.to_rgb32f();
+        if let Some(f) = exposure_factor {
+            if debug {
+                println!("{} scaling linear data x{:.3}", blue("[exposure]"), f);
             }
-            _ => {
-                unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
-                unsafe { (api.libraw_close)(raw) };
-                anyhow::bail!("Unsupported processed colors: {}", colors);
+            for c in buf.iter_mut() {
+                *c *= f;
             }
         }
+        return Ok(Decoded {
+            image: DynamicImage::ImageRgb32F(buf),
+            profile,
+            area,
+            meta,
+            sidecar: None,
+            dng,
+            oriented: false,
+        });
+    }
+    let img = match fallback_factor {
+        Some(f) => display_exposure(img, f),
+        None => img,
     };
-    unsafe { (api.libraw_dcraw_clear_mem)(pimg) };
-    unsafe { (api.libraw_close)(raw) };
-    Ok(img)
+    Ok(Decoded {
+        image: img,
+        profile,
+        area,
+        meta,
+        sidecar: None,
+        dng,
+        oriented: false,
+    })
+}
+
+/// Encodes with `encoder`, attaching `icc` when the format can carry a profile.
+fn encode_with_icc(
+    img: &DynamicImage,
+    mut encoder: impl ImageEncoder,
+    icc: Option<&[u8]>,
+    ext: &str,
+) -> Result<()> {
+    if let Some(icc) = icc {
+        encoder.set_icc_profile(icc.to_vec()).ok();
+    }
+    img.write_with_encoder(encoder)
+        .with_context(|| format!("Failed to encode {}", ext))
 }
 
 fn save_image(
@@ -1007,14 +1787,43 @@ fn save_image(
     out_path: &Path,
     fmt: &str,
     quality: u8,
+    bits: u8,
+    profile: &ColorProfile,
     debug: bool,
 ) -> Result<()> {
     let (ext, imgfmt) = match normalize_format(fmt) {
         Some((e, f)) => (e, f),
         None => anyhow::bail!("Unsupported output format: {}", red(fmt)),
     };
+    let display;
+    let img = match img {
+        DynamicImage::ImageRgb32F(buf)
+            if !matches!(imgfmt, ImageFormat::Hdr | ImageFormat::OpenExr) =>
+        {
+            if debug {
+                println!(
+                    "{} encoding linear data with gamma {}/{}",
+                    blue("[save]"),
+                    pink(profile.curve.power),
+                    pink(profile.curve.slope)
+                );
+            }
+            display = linear_to_display(buf, bits, &profile.curve);
+            &display
+        }
+        img => img,
+    };
     if debug {
-        println!("{} saving image as {}", blue("[save]"), pink(&ext));
+        println!("{} saving image as {}", blue("[save]"), pink(ext));
+    }
+    let icc = profile.icc();
+    if debug && let Some(icc) = icc.as_ref() {
+        println!(
+            "{} ICC profile {} ({} bytes)",
+            blue("[save]"),
+            pink(format!("{:?}", profile.space)),
+            icc.len()
+        );
     }
     let mut f =
         File::create(out_path).with_context(|| format!("Failed to create {:?}", out_path))?;
@@ -1028,24 +1837,51 @@ fn save_image(
                 );
             }
             let mut buf = Vec::new();
-            let mut encoder = JpegEncoder::new_with_quality(&mut buf, quality);
-            encoder.encode_image(img).context("Failed to encode JPEG")?;
+            encode_with_icc(
+                img,
+                JpegEncoder::new_with_quality(&mut buf, quality),
+                icc.as_deref(),
+                ext,
+            )?;
             buf
         }
-        ImageFormat::Hdr => {
-            let rgb8 = img.to_rgb8();
-            let (w, h) = (rgb8.width(), rgb8.height());
-            let mut data: Vec<image::Rgb<f32>> = Vec::with_capacity((w as usize) * (h as usize));
-            for p in rgb8.pixels() {
-                data.push(image::Rgb([
-                    p[0] as f32 / 255.0,
-                    p[1] as f32 / 255.0,
-                    p[2] as f32 / 255.0,
-                ]));
+        ImageFormat::Png => {
+            let mut buf = Vec::new();
+            encode_with_icc(img, PngEncoder::new(&mut buf), icc.as_deref(), ext)?;
+            buf
+        }
+        ImageFormat::Tiff => {
+            let mut buf = std::io::Cursor::new(Vec::new());
+            encode_with_icc(img, TiffEncoder::new(&mut buf), icc.as_deref(), ext)?;
+            buf.into_inner()
+        }
+        ImageFormat::WebP => {
+            let mut buf = Vec::new();
+            encode_with_icc(
+                img,
+                WebPEncoder::new_lossless(&mut buf),
+                icc.as_deref(),
+                ext,
+            )?;
+            buf
+        }
+        ImageFormat::Avif => {
+            let mut buf = Vec::new();
+            img.write_to(&mut std::io::Cursor::new(&mut buf), ImageFormat::Avif)
+                .context("Failed to encode AVIF")?;
+            match icc.as_ref() {
+                Some(icc) => color_profile::embed_icc_in_avif(&buf, icc)
+                    .context("Failed to embed ICC profile in AVIF")?,
+                None => buf,
             }
+        }
+        ImageFormat::Hdr => {
+            let rgb = to_linear_rgb32f(img, &profile.curve);
+            let (w, h) = (rgb.width(), rgb.height());
+            let data: Vec<image::Rgb<f32>> = rgb.pixels().copied().collect();
             let mut cursor = std::io::Cursor::new(Vec::new());
             let enc = HdrEncoder::new(&mut cursor);
-            enc.encode(&data, w.try_into().unwrap(), h.try_into().unwrap())
+            enc.encode(&data, w as usize, h as usize)
                 .context("Failed to encode HDR")?;
             cursor.into_inner()
         }
@@ -1061,59 +1897,29 @@ fn save_image(
             buf
         }
         ImageFormat::OpenExr => {
-            let rgb8 = img.to_rgb8();
-            let (w, h) = (rgb8.width(), rgb8.height());
-            let mut data: Vec<image::Rgb<f32>> = Vec::with_capacity((w as usize) * (h as usize));
-            for p in rgb8.pixels() {
-                data.push(image::Rgb([
-                    p[0] as f32 / 255.0,
-                    p[1] as f32 / 255.0,
-                    p[2] as f32 / 255.0,
-                ]));
-            }
+            let rgb = to_linear_rgb32f(img, &profile.curve);
+            let (w, h) = (rgb.width(), rgb.height());
             let mut cursor = std::io::Cursor::new(Vec::new());
             let mut bytes: Vec<u8> = Vec::with_capacity((w as usize) * (h as usize) * 3 * 4);
-            for pix in &data {
-                for &c in &pix.0 {
-                    bytes.extend_from_slice(&c.to_ne_bytes());
-                }
+            for &c in rgb.as_raw() {
+                bytes.extend_from_slice(&c.to_ne_bytes());
             }
             let enc = OpenExrEncoder::new(&mut cursor);
-            enc.write_image(
-                bytes.as_slice(),
-                w.try_into().unwrap(),
-                h.try_into().unwrap(),
-                image::ExtendedColorType::Rgb32F,
-            )
-            .context("Failed to encode OpenEXR")?;
+            enc.write_image(bytes.as_slice(), w, h, image::ExtendedColorType::Rgb32F)
+                .context("Failed to encode OpenEXR")?;
             cursor.into_inner()
         }
         ImageFormat::Farbfeld => {
-            let rgba8 = img.to_rgba8();
-            let (w, h) = (rgba8.width(), rgba8.height());
-            let mut data: Vec<image::Rgba<u16>> = Vec::with_capacity((w as usize) * (h as usize));
-            for p in rgba8.pixels() {
-                data.push(image::Rgba([
-                    (p[0] as u16).wrapping_mul(257),
-                    (p[1] as u16).wrapping_mul(257),
-                    (p[2] as u16).wrapping_mul(257),
-                    (p[3] as u16).wrapping_mul(257),
-                ]));
-            }
+            let rgba16 = img.to_rgba16();
+            let (w, h) = (rgba16.width(), rgba16.height());
             let mut cursor = std::io::Cursor::new(Vec::new());
             let mut bytes: Vec<u8> = Vec::with_capacity((w as usize) * (h as usize) * 8);
-            for pix in &data {
-                for &c in &pix.0 {
-                    bytes.extend_from_slice(&c.to_ne_bytes());
-                }
+            for &c in rgba16.as_raw() {
+                bytes.extend_from_slice(&c.to_ne_bytes());
             }
             let enc = FarbfeldEncoder::new(&mut cursor);
-            enc.encode(
-                bytes.as_slice(),
-                w.try_into().unwrap(),
-                h.try_into().unwrap(),
-            )
-            .context("Failed to encode Farbfeld")?;
+            enc.encode(bytes.as_slice(), w, h)
+                .context("Failed to encode Farbfeld")?;
             cursor.into_inner()
         }
         ImageFormat::Pnm if ext != "pnm" => {
@@ -1155,36 +1961,56 @@ fn save_image(
             }
 
             let mut bytes = Vec::new();
-            let mut encoder = PnmEncoder::new(&mut bytes).with_subtype(subtype);
+            if is_16bit(img) && !matches!(subtype, PnmSubtype::Bitmap(_)) {
+                if debug {
+                    println!("{} writing 16-bit samples", blue("[save]"));
+                }
+                let (samples, color_type) = match subtype {
+                    PnmSubtype::Graymap(_) => {
+                        (img.to_luma16().into_raw(), image::ExtendedColorType::L16)
+                    }
+                    PnmSubtype::Pixmap(_) => {
+                        (img.to_rgb16().into_raw(), image::ExtendedColorType::Rgb16)
+                    }
+                    _ => (img.to_rgba16().into_raw(), image::ExtendedColorType::Rgba16),
+                };
+                PnmEncoder::new(&mut bytes)
+                    .with_subtype(subtype)
+                    .encode(samples.as_slice(), img.width(), img.height(), color_type)
+                    .context("Failed to encode PNM")?;
+                bytes
+            } else {
+                let mut encoder = PnmEncoder::new(&mut bytes).with_subtype(subtype);
 
-            let color_type = match subtype {
-                PnmSubtype::Bitmap(_) | PnmSubtype::Graymap(_) => ColorType::L8,
-                PnmSubtype::Pixmap(_) => ColorType::Rgb8,
-                PnmSubtype::ArbitraryMap => ColorType::Rgba8,
-            };
+                let color_type = match subtype {
+                    PnmSubtype::Bitmap(_) | PnmSubtype::Graymap(_) => ColorType::L8,
+                    PnmSubtype::Pixmap(_) => ColorType::Rgb8,
+                    PnmSubtype::ArbitraryMap => ColorType::Rgba8,
+                };
 
-            let buffer = match subtype {
-                PnmSubtype::Bitmap(_) => img
-                    .to_luma8()
-                    .into_raw()
-                    .into_iter()
-                    .map(|b| if b > 128 { 1 } else { 0 })
-                    .collect::<Vec<u8>>(),
-                PnmSubtype::Graymap(_) => img.to_luma8().into_raw(),
-                PnmSubtype::Pixmap(_) => img.to_rgb8().into_raw(),
-                PnmSubtype::ArbitraryMap => img.to_rgba8().into_raw(),
-            };
+                let buffer = match subtype {
+                    PnmSubtype::Bitmap(_) => img
+                        .to_luma8()
+                        .into_raw()
+                        .into_iter()
+                        .map(|b| if b > 128 { 1 } else { 0 })
+                        .collect::<Vec<u8>>(),
+                    PnmSubtype::Graymap(_) => img.to_luma8().into_raw(),
+                    PnmSubtype::Pixmap(_) => img.to_rgb8().into_raw(),
+                    PnmSubtype::ArbitraryMap => img.to_rgba8().into_raw(),
+                };
 
-            encoder
-                .encode(
-                    buffer.as_slice(),
-                    img.width(),
-                    img.height(),
-                    color_type.into(),
-                )
-                .context("Failed to encode PNM")?;
+                encoder
+                    .encode(
+                        buffer.as_slice(),
+                        img.width(),
+                        img.height(),
+                        color_type.into(),
+                    )
+                    .context("Failed to encode PNM")?;
 
-            bytes
+                bytes
+            }
         }
         imf => {
             let mut buf = Vec::new();
@@ -1199,6 +2025,17 @@ fn save_image(
 
 fn main() -> Result<()> {
     let raw_args: Vec<String> = env::args().collect();
+    // Read ahead of clap because --version and --list-cameras load libraw before parsing.
+    let libraw_path = raw_args
+        .iter()
+        .enumerate()
+        .find_map(|(i, a)| match a.as_str() {
+            "--libraw" => raw_args.get(i + 1).cloned(),
+            _ => a.strip_prefix("--libraw=").map(str::to_string),
+        });
+    if let Some(path) = libraw_path {
+        init_libraw::set_library_path(PathBuf::from(path));
+    }
     if raw_args.iter().any(|a| a == "-h" || a == "--help") {
         let mut buf: Vec<u8> = Vec::new();
         let mut cmd = Args::command();
@@ -1218,19 +2055,20 @@ fn main() -> Result<()> {
                     continue;
                 }
 
-                if !prog_colored && line.contains(prog_name) {
-                    if let Some(pos) = line.find(prog_name) {
-                        let (before, rest) = line.split_at(pos);
-                        let rest = &rest[prog_name.len()..];
-                        out.push_str(&format!(
-                            "{}{}{}\n",
-                            white(before),
-                            pink(prog_name),
-                            blue(rest)
-                        ));
-                        prog_colored = true;
-                        continue;
-                    }
+                if !prog_colored
+                    && line.contains(prog_name)
+                    && let Some(pos) = line.find(prog_name)
+                {
+                    let (before, rest) = line.split_at(pos);
+                    let rest = &rest[prog_name.len()..];
+                    out.push_str(&format!(
+                        "{}{}{}\n",
+                        white(before),
+                        pink(prog_name),
+                        blue(rest)
+                    ));
+                    prog_colored = true;
+                    continue;
                 }
                 if let Some(pos) = line.find("    ") {
                     let (left, right) = line.split_at(pos);
@@ -1238,7 +2076,7 @@ fn main() -> Result<()> {
                     out.push_str(&format!("{}    {}\n", pink(left), dark(right)));
                 } else {
                     if trimmed.is_empty() {
-                        out.push_str("\n");
+                        out.push('\n');
                     } else {
                         out.push_str(&format!("{}\n", white(line)));
                     }
@@ -1253,6 +2091,25 @@ fn main() -> Result<()> {
     if raw_args.iter().any(|a| a == "--version" || a == "-v") {
         let prog_name = Args::command().get_name().to_string();
         println!("{} version {}", pink(prog_name), VERSION);
+        match libraw::version() {
+            Ok(v) => {
+                let caps = libraw::capabilities().unwrap_or_default();
+                let caps = if caps.is_empty() {
+                    String::new()
+                } else {
+                    format!(" ({})", caps.join(", "))
+                };
+                println!("{} {}{}", blue("libraw version:"), white(v), caps);
+                if let Some(path) = init_libraw::loaded_path() {
+                    println!("{} {}", blue("libraw path:"), white(path.display()));
+                }
+            }
+            Err(e) => println!(
+                "{} {}",
+                blue("libraw version:"),
+                pink(format!("error loading libraw: {:#}", e))
+            ),
+        }
         #[cfg(feature = "include_exiftool")]
         {
             match exiftool::get_exiftool_version() {
@@ -1267,10 +2124,18 @@ fn main() -> Result<()> {
         return Ok(());
     }
 
+    if let Some(i) = raw_args.iter().position(|a| a == "--list-cameras") {
+        let filter = raw_args
+            .get(i + 1)
+            .filter(|a| !a.starts_with('-'))
+            .map(|a| a.as_str());
+        return camera_support::print_camera_list(filter);
+    }
+
     let args = Args::parse();
 
     let mut out_formats: Vec<String> = Vec::new();
-    for tok in args.format.split(|c| c == '+' || c == ',') {
+    for tok in args.format.split(['+', ',']) {
         let t = tok.trim().to_ascii_lowercase();
         if t.is_empty() {
             continue;
@@ -1295,12 +2160,155 @@ fn main() -> Result<()> {
     if !(args.ratio > 0.0 && args.ratio <= 1.0) {
         anyhow::bail!("Resize ratio must be between 0 and 1");
     }
+    if !(0.0..=1.0).contains(&args.preserve_highlights) {
+        anyhow::bail!(
+            "--preserve-highlights must be between 0 and 1, got {}",
+            red(args.preserve_highlights)
+        );
+    }
+    if args.bits != 8 && args.bits != 16 {
+        anyhow::bail!("Bit depth must be 8 or 16, got {}", red(args.bits));
+    }
+    // Float formats get scene-linear data straight from libraw; integer formats written in
+    // the same run are encoded from it with the --gamma curve.
+    let linear = out_formats.iter().any(|f| {
+        matches!(
+            normalize_format(f),
+            Some((_, ImageFormat::Hdr | ImageFormat::OpenExr))
+        )
+    });
+    let cfa_mode = cfa::parse_cfa_mode(&args.cfa)?;
+    if cfa_mode.is_some() {
+        if let Some(f) = out_formats
+            .iter()
+            .find(|f| !cfa::CFA_FORMATS.contains(&f.as_str()))
+        {
+            anyhow::bail!(
+                "--cfa writes {} only, not {}",
+                blue(cfa::CFA_FORMATS.join(" or ")),
+                red(f)
+            );
+        }
+        if args.preview
+            || args.brightness.is_some()
+            || args.rotation.is_some()
+            || args.enhance
+            || args.denoise.is_some()
+        {
+            anyhow::bail!(
+                "--cfa exports sensor data as is and cannot be combined with --preview, --brightness, --rotation, --enhance or --denoise"
+            );
+        }
+    }
+    // A single output named `*.dng` is written as DNG whatever --format says.
+    let wants_dng = out_formats.iter().any(|f| f == "dng")
+        || args
+            .output_dir
+            .as_ref()
+            .and_then(|p| p.extension())
+            .is_some_and(|e| e.eq_ignore_ascii_case("dng"));
+    if args.preview && wants_dng {
+        anyhow::bail!("DNG output is built from the raw data and cannot use --preview");
+    }
+    // Workers take the calibration masters, lens table and output paths from the parent.
+    let mut prepared = args
+        .worker
+        .then(|| isolate::read_state(&mut std::io::stdin().lock()))
+        .transpose()?;
+    let calibration = if let Some(p) = prepared.as_mut() {
+        p.calibration.take()
+    } else if args.info || args.find_hot_pixels.is_some() {
+        None
+    } else {
+        Calibration::load(
+            &args.dark_frame,
+            &args.flat,
+            &args.bias,
+            args.bad_pixels.as_deref(),
+            args.debug,
+        )?
+    };
+    let lens = if let Some(p) = prepared.as_mut() {
+        p.lens.take()
+    } else if args.info || args.find_hot_pixels.is_some() {
+        None
+    } else {
+        LensCorrection::load(
+            args.lens_table.as_deref(),
+            args.ca.as_deref(),
+            args.distortion.as_deref(),
+            args.vignetting.as_deref(),
+        )?
+    };
+    let decoder_choice = decoder::parse_decoder(&args.decoder)?;
+    let decode_opts = DecodeOptions {
+        preview: if args.preview {
+            Some(preview::parse_preview_pick(
+                &args.preview_select,
+                args.ratio.sqrt(),
+            )?)
+        } else {
+            None
+        },
+        exposure: parse_brightness(&args.brightness),
+        preserve_highlights: args.preserve_highlights,
+        denoise: denoise::parse_denoise(&args.denoise)?,
+        calibration: calibration.as_ref(),
+        lens: lens.as_ref(),
+        output_bps: args.bits,
+        linear,
+        white_balance: white_balance::parse_white_balance(&args.wb)?,
+        demosaic: args.demosaic.as_deref().map(parse_demosaic).transpose()?,
+        color_space: ColorSpace::parse(&args.colorspace)?,
+        highlight: parse_highlights(&args.highlights)?,
+        curve: ToneCurve::parse(&args.gamma)?,
+        half_size: parse_decode_size(&args.decode_size, args.ratio)?,
+        cfa: cfa_mode,
+        dng: if wants_dng {
+            Some(dng::parse_dng_kind(&args.dng)?)
+        } else {
+            None
+        },
+    };
+
+    let extensions = match args.extensions.as_deref() {
+        Some(list) => raw_format::parse_extensions(list)?,
+        None => raw_format::DEFAULT_EXTENSIONS
+            .iter()
+            .map(|e| e.to_string())
+            .collect(),
+    };
+    let name_template = args
+        .name
+        .as_deref()
+        .map(raw_metadata::parse_name_template)
+        .transpose()?;
+    let name_template = name_template.as_deref();
+    // With --isolate the parent leaves reading inputs to workers, so that a file that
+    // crashes the parser fails alone.
+    let probe_in_workers = args.isolate && !args.info && args.find_hot_pixels.is_none();
+    let single_stem = |in_path: &Path| {
+        if name_template.is_some() && probe_in_workers {
+            // Without the worker's answer the template expands without metadata.
+            return isolate::probe(&[in_path.to_path_buf()], 1, args.debug)
+                .into_iter()
+                .flatten()
+                .next()
+                .map(|p| p.stem)
+                .unwrap_or_else(|| output_stem(in_path, name_template, None, 1));
+        }
+        let meta = name_template.and_then(|_| RawMetadata::read(in_path).ok());
+        output_stem(in_path, name_template, meta.as_ref(), 1)
+    };
 
     let mut inputs: Vec<PathBuf> = Vec::new();
     let mut out_dirs: Vec<PathBuf> = Vec::new();
     let mut out_files_for_single: Option<Vec<PathBuf>> = None;
 
-    if args.input.len() == 1 && args.input[0].exists() && args.input[0].is_dir() {
+    if let Some(p) = prepared {
+        out_dirs = p.out_dirs;
+        out_files_for_single = p.single_outs;
+    } else if args.input.len() == 1 && args.input[0].exists() && args.input[0].is_dir() {
         let input_dir = &args.input[0];
         let out_arg = match args.output_dir.as_ref() {
             Some(p) => p,
@@ -1313,19 +2321,19 @@ fn main() -> Result<()> {
             out_dirs.push(d);
         }
 
-        let mut nef_files: Vec<PathBuf> = fs::read_dir(input_dir)?
+        let mut raw_files: Vec<PathBuf> = fs::read_dir(input_dir)?
             .filter_map(|e| e.ok())
             .filter(|e| {
                 e.path()
                     .extension()
                     .and_then(|s| s.to_str())
-                    .map(|ext| ext.eq_ignore_ascii_case("nef"))
+                    .map(|ext| extensions.iter().any(|x| ext.eq_ignore_ascii_case(x)))
                     .unwrap_or(false)
             })
             .map(|e| e.path())
             .collect();
-        nef_files.sort();
-        inputs = nef_files;
+        raw_files.sort();
+        inputs = raw_files;
     } else {
         for p in &args.input {
             if p.exists() && p.is_file() {
@@ -1337,7 +2345,7 @@ fn main() -> Result<()> {
             match args.output_dir.as_ref() {
                 Some(out_arg) => {
                     if out_arg.exists() && out_arg.is_dir() {
-                        let in_stem = in_path.file_stem().unwrap().to_string_lossy();
+                        let in_stem = single_stem(in_path);
                         let mut files = Vec::new();
                         for fmt in &out_formats {
                             let d = out_arg.join(format!("{}.{}", in_stem, fmt));
@@ -1374,7 +2382,7 @@ fn main() -> Result<()> {
                         .parent()
                         .map(|p| p.to_path_buf())
                         .unwrap_or_else(|| PathBuf::from("."));
-                    let stem = in_path.file_stem().unwrap().to_string_lossy();
+                    let stem = single_stem(in_path);
                     let mut files = Vec::new();
                     for fmt in &out_formats {
                         files.push(parent.join(format!("{}.{}", stem, fmt)));
@@ -1390,7 +2398,12 @@ fn main() -> Result<()> {
         if inputs.len() == 1 {
             let p = &inputs[0];
             if p.exists() && p.is_file() {
-                if let Err(e) = print_metadata(&p) {
+                let res = if args.json {
+                    print_metadata_json(p)
+                } else {
+                    print_metadata(p)
+                };
+                if let Err(e) = res {
                     eprintln!(
                         "{}",
                         pink(format!("Error reading metadata for {}: {}", p.display(), e))
@@ -1399,16 +2412,70 @@ fn main() -> Result<()> {
             } else {
                 eprintln!("{}", pink(format!("Not a file: {}", p.display())));
             }
-            println!("");
+            if !args.json {
+                println!();
+            }
         } else {
             eprintln!("Info flag takes only one file.")
         }
         return Ok(());
     }
-    if total == 0 {
-        println!("No {} files found.", pink(".NEF"));
+    if let Some(out) = &args.find_hot_pixels {
+        let darks = if args.dark_frame.is_empty() {
+            &inputs
+        } else {
+            &args.dark_frame
+        };
+        if darks.is_empty() {
+            anyhow::bail!("--find-hot-pixels needs dark frames, as inputs or with --dark-frame");
+        }
+        let master = calibration::master_dark(darks, args.debug)?;
+        let hot = calibration::find_hot_pixels(&master);
+        calibration::write_bad_pixels(out, &hot)?;
+        println!(
+            "Found {} hot pixels in {} dark frame{}, map written to {}",
+            pink(hot.len()),
+            pink(darks.len()),
+            if darks.len() == 1 { "" } else { "s" },
+            pink(out.display())
+        );
         return Ok(());
     }
+    let worker = args.worker;
+    if let Some(template) = name_template
+        && total > 1
+        && !raw_metadata::names_each_file(template)
+    {
+        anyhow::bail!(
+            "Name template {} would give several of the {} inputs the same name and overwrite them; add {} or {}",
+            red(template),
+            total,
+            blue("{stem}"),
+            blue("{seq}")
+        );
+    }
+    if total == 0 && !worker {
+        println!(
+            "No raw files found (looked for {}).",
+            pink(extensions.join(", "))
+        );
+        return Ok(());
+    }
+    let threads = args.threads.unwrap_or_else(num_cpus::get);
+    // Workers decode one file at a time, each with its own copy of libraw.
+    let concurrent = if args.isolate || worker {
+        1
+    } else {
+        threads.min(total)
+    };
+    let decoder = decoder::select(decoder_choice, concurrent, args.debug)?;
+    decoder::check_inputs(decoder, &inputs)?;
+    // The camera list is libraw's, so other decoders are not checked against it.
+    let cameras = if decoder.name() == "libraw" && !worker {
+        CameraCheck::new(args.debug)
+    } else {
+        None
+    };
 
     if cfg!(debug_assertions) && args.debug {
         eprintln!(
@@ -1418,11 +2485,24 @@ fn main() -> Result<()> {
         );
     }
 
-    if let Some(method) = args.sort.as_ref() {
+    if !worker && let Some(method) = args.sort.as_ref() {
         if args.debug {
             eprintln!("Sorting {} inputs by {} method", inputs.len(), blue(method));
         }
-        sort_inputs(&mut inputs, method.as_str(), args.debug);
+        let workers = threads.min(total);
+        sort_inputs(&mut inputs, method.as_str(), args.debug, |paths| {
+            if probe_in_workers {
+                isolate::probe(paths, workers, args.debug)
+                    .into_iter()
+                    .map(|p| p.and_then(|p| p.timestamp))
+                    .collect()
+            } else {
+                paths
+                    .iter()
+                    .map(|p| RawMetadata::read(p).ok().and_then(|m| m.timestamp))
+                    .collect()
+            }
+        });
     }
 
     let quality: u8 = match args.quality {
@@ -1443,7 +2523,7 @@ fn main() -> Result<()> {
         None => 75,
     };
 
-    if total == 1 && out_files_for_single.is_some() {
+    if total == 1 && out_files_for_single.is_some() && !args.isolate {
         let in_path = inputs.remove(0);
         let outs = out_files_for_single.take().unwrap();
         let out_desc = outs
@@ -1452,12 +2532,23 @@ fn main() -> Result<()> {
             .collect::<Vec<_>>()
             .join(", ");
         let out_desc_cl = out_desc.clone();
+        let Some(raw_fmt) = raw_format::detect_file(&in_path) else {
+            return Err(anyhow::anyhow!(pink(format!(
+                "{}: {}",
+                red("Not a supported raw format"),
+                in_path.display()
+            ))));
+        };
         let spinner_run = Arc::new(AtomicBool::new(true));
         let spinner_flag = spinner_run.clone();
-        let spinner_in = in_path
-            .file_name()
-            .map(|s| s.to_string_lossy().to_string())
-            .unwrap_or_else(|| in_path.to_string_lossy().to_string());
+        let spinner_in = format!(
+            "{} ({})",
+            in_path
+                .file_name()
+                .map(|s| s.to_string_lossy().to_string())
+                .unwrap_or_else(|| in_path.to_string_lossy().to_string()),
+            raw_fmt
+        );
         let handle = thread::spawn(move || {
             let frames = ["/", "-", "\\", "|"];
             let mut idx = 0usize;
@@ -1476,67 +2567,26 @@ fn main() -> Result<()> {
         });
 
         let t0 = Instant::now();
-        let brightness_mode = parse_brightness(&args.brightness);
-        let auto_bright = matches!(brightness_mode, BrightnessMode::Auto);
-        if !is_nef_file(&in_path) {
-            spinner_run.store(false, Ordering::SeqCst);
-            handle.join().ok();
-            return Err(anyhow::anyhow!(pink(format!(
-                "\n{}: {}",
-                red("Not a NEF format"),
-                in_path.display()
-            ))));
-        }
-        let res = unsafe { load_with_libraw(&in_path, args.preview, args.debug, auto_bright) };
+        let res = decoder.decode(&in_path, &decode_opts, args.debug);
         match res {
-            Ok(img) => {
-                let mut img = resize_image(img, args.ratio);
-                img = apply_brightness(img, brightness_mode);
-                if let Some(rot) = args.rotation.as_ref() {
-                    if rot == "auto" {
-                        if let Ok(buf) = std::fs::read(&in_path) {
-                            if let Ok(exif) = rexif::parse_buffer(&buf) {
-                                for entry in exif.entries.iter() {
-                                    let tag_name = format!("{}", entry.tag).to_lowercase();
-                                    if tag_name.contains("orientation") {
-                                        let sval = format!("{}", entry.value);
-                                        if let Some(tok) = sval.split_whitespace().next() {
-                                            if let Ok(code) = tok.parse::<u32>() {
-                                                img = match code {
-                                                    3 => image::DynamicImage::ImageRgba8(
-                                                        image::imageops::rotate180(&img),
-                                                    ),
-                                                    6 => image::DynamicImage::ImageRgba8(
-                                                        image::imageops::rotate90(&img),
-                                                    ),
-                                                    8 => image::DynamicImage::ImageRgba8(
-                                                        image::imageops::rotate270(&img),
-                                                    ),
-                                                    _ => img,
-                                                };
-                                            }
-                                        }
-                                        break;
-                                    }
-                                }
-                            }
-                        }
-                    } else if let Ok(deg) = rot.parse::<i32>() {
-                        img = match deg.rem_euclid(360) {
-                            90 => image::DynamicImage::ImageRgba8(image::imageops::rotate90(&img)),
-                            180 => {
-                                image::DynamicImage::ImageRgba8(image::imageops::rotate180(&img))
-                            }
-                            270 => {
-                                image::DynamicImage::ImageRgba8(image::imageops::rotate270(&img))
-                            }
-                            _ => img,
-                        };
-                    }
+            Ok(decoded) => {
+                let camera = Seen::decoded(decoded.meta.as_ref());
+                let resize_ratio = decoded.resize_ratio(args.ratio);
+                let oriented = decoded.oriented;
+                let output = OutputData {
+                    profile: decoded.profile,
+                    sidecar: decoded.sidecar,
+                    dng: decoded.dng,
+                };
+                let mut img = resize_image(decoded.image, resize_ratio);
+                if let Some(rot) = args.rotation.as_ref()
+                    && !(oriented && rot == "auto")
+                {
+                    img = apply_rotation(img, rot, &in_path);
                 }
                 if args.enhance {
-                    img = apply_brightness(img, BrightnessMode::Factor(1.05));
-                    img = image::DynamicImage::ImageRgba8(image::imageops::unsharpen(&img, 1.0, 1));
+                    img = scale_brightness(img, 1.05);
+                    img = img.unsharpen(1.0, 1);
                 }
                 for out_path in &outs {
                     let fmt = out_path
@@ -1544,7 +2594,9 @@ fn main() -> Result<()> {
                         .and_then(|s| s.to_str())
                         .unwrap_or("png")
                         .to_string();
-                    if let Err(e) = save_image(&img, out_path, &fmt, quality, args.debug) {
+                    if let Err(e) = save_output(
+                        &img, &output, out_path, &fmt, quality, args.bits, args.debug,
+                    ) {
                         spinner_run.store(false, Ordering::SeqCst);
                         handle.join().ok();
                         eprintln!(
@@ -1584,10 +2636,10 @@ fn main() -> Result<()> {
                         "N/A".to_string()
                     }
                 );
-                println!(
-                    "Total execution time: {}",
-                    blue(format_time(elapsed as f64))
-                );
+                println!("Total execution time: {}", blue(format_time(elapsed)));
+                if let Some(cameras) = &cameras {
+                    cameras.check(&in_path, &camera);
+                }
                 return Ok(());
             }
             Err(e) => {
@@ -1602,24 +2654,124 @@ fn main() -> Result<()> {
                         e
                     ))
                 );
+                if let Some(cameras) = &cameras {
+                    cameras.check(&in_path, &Seen::failed(&in_path, &e));
+                }
                 return Err(e);
             }
         }
     }
 
+    let debug = args.debug;
+    let convert = |seq: usize, in_path: &Path| -> Converted {
+        let fname = in_path.file_name().unwrap().to_string_lossy();
+        let failed = |msg: String, bytes: u64| Converted {
+            outcome: Outcome::Failed(msg),
+            bytes,
+            camera: Seen::Unknown,
+        };
+        let Some(raw_fmt) = raw_format::detect_file(in_path) else {
+            return failed(
+                format!("{}... {}", fname, pink("Skipped (not a raw file)")),
+                0,
+            );
+        };
+        let t0 = Instant::now();
+        let decoded = match decoder.decode(in_path, &decode_opts, debug) {
+            Ok(decoded) => decoded,
+            Err(e) => {
+                return Converted {
+                    camera: Seen::failed(in_path, &e),
+                    ..failed(format!("{}... {}: {}", fname, red("Error"), e), 0)
+                };
+            }
+        };
+        let camera = Seen::decoded(decoded.meta.as_ref());
+        if debug {
+            println!("{} rotating image...", blue("[rot]"));
+        }
+        let stem = output_stem(in_path, name_template, decoded.meta.as_ref(), seq + 1);
+        let resize_ratio = decoded.resize_ratio(args.ratio);
+        let oriented = decoded.oriented;
+        let output = OutputData {
+            profile: decoded.profile,
+            sidecar: decoded.sidecar,
+            dng: decoded.dng,
+        };
+        let mut img = resize_image(decoded.image, resize_ratio);
+        if let Some(rot) = args.rotation.as_ref()
+            && !(oriented && rot == "auto")
+        {
+            img = apply_rotation(img, rot, in_path);
+        }
+        if args.enhance {
+            img = scale_brightness(img, 1.05);
+            img = img.unsharpen(1.0, 1);
+        }
+        let outs: Vec<(PathBuf, &str)> = match &out_files_for_single {
+            Some(single_outs) => single_outs
+                .iter()
+                .cloned()
+                .zip(out_formats.iter().map(String::as_str))
+                .collect(),
+            None if out_dirs.is_empty() => {
+                let parent = in_path
+                    .parent()
+                    .map(|p| p.to_path_buf())
+                    .unwrap_or_else(|| PathBuf::from("."));
+                out_formats
+                    .iter()
+                    .map(|fmt| (parent.join(format!("{}.{}", stem, fmt)), fmt.as_str()))
+                    .collect()
+            }
+            None => out_formats
+                .iter()
+                .zip(out_dirs.iter())
+                .map(|(fmt, dir)| (dir.join(format!("{}.{}", stem, fmt)), fmt.as_str()))
+                .collect(),
+        };
+        let mut bytes = 0u64;
+        for (out_path, fmt) in &outs {
+            if let Err(e) = save_output(&img, &output, out_path, fmt, quality, args.bits, debug) {
+                return Converted {
+                    camera,
+                    ..failed(
+                        format!("{}... {}: {}", fname, red("Error saving"), e),
+                        bytes,
+                    )
+                };
+            }
+            bytes += out_path.metadata().map(|m| m.len()).unwrap_or(0);
+        }
+        Converted {
+            outcome: Outcome::Done {
+                raw_fmt: raw_fmt.to_string(),
+                elapsed: t0.elapsed().as_secs_f64(),
+            },
+            bytes,
+            camera,
+        }
+    };
+    if worker {
+        let probe = |in_path: &Path| {
+            let meta = RawMetadata::read(in_path).ok();
+            isolate::Probed {
+                timestamp: meta.as_ref().and_then(|m| m.timestamp),
+                stem: output_stem(in_path, name_template, meta.as_ref(), 1),
+            }
+        };
+        return isolate::serve(convert, probe);
+    }
+
     println!(
         "{}\n",
-        blue(format!("Found {} NEF files. Starting conversion...", total))
+        blue(format!("Found {} raw files. Starting conversion...", total))
     );
 
-    let threads = args.threads.unwrap_or_else(|| num_cpus::get());
-    let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
-    let debug = args.debug;
-
     let start = Instant::now();
-    let counter = Arc::new(Mutex::new(0usize));
-    let original_size_counter = Arc::new(Mutex::new(0u64));
-    let converted_size_counter = Arc::new(Mutex::new(0u64));
+    let counter = Mutex::new(0usize);
+    let original_size_counter = Mutex::new(0u64);
+    let converted_size_counter = Mutex::new(0u64);
     let stop_flag = Arc::new(AtomicBool::new(false));
 
     {
@@ -1640,187 +2792,70 @@ fn main() -> Result<()> {
         }
     });
 
-    let inputs_owned = inputs;
-    pool.install(|| {
-        inputs_owned.into_par_iter().for_each(|in_path| {
-            if stop_flag.load(Ordering::SeqCst) {
-                return;
-            }
-            let tx = tx.clone();
-            let out_dirs = out_dirs.clone();
-            let out_formats = out_formats.clone();
-            let ratio = args.ratio;
-            let preview = args.preview;
-            let debug = debug;
-            let brightness_mode = parse_brightness(&args.brightness);
-            let rotation_opt = args.rotation.clone();
-            let enhance_flag = args.enhance;
-            let counter = counter.clone();
-            let original_size_counter = original_size_counter.clone();
-            let converted_size_counter = converted_size_counter.clone();
-            let total = total;
-
-            let t0 = Instant::now();
-            let auto_bright = matches!(brightness_mode, BrightnessMode::Auto);
-
-            let original_file_size = in_path.metadata().map(|m| m.len()).unwrap_or(0);
-            if original_file_size > 0 {
-                if let Ok(mut counter) = original_size_counter.lock() {
-                    *counter += original_file_size;
-                }
+    let report = |in_path: &Path, converted: Converted| {
+        if let Some(cameras) = &cameras {
+            cameras.check(in_path, &converted.camera);
+        }
+        let original_file_size = in_path.metadata().map(|m| m.len()).unwrap_or(0);
+        if original_file_size > 0
+            && let Ok(mut counter) = original_size_counter.lock()
+        {
+            *counter += original_file_size;
+        }
+        if converted.bytes > 0
+            && let Ok(mut counter) = converted_size_counter.lock()
+        {
+            *counter += converted.bytes;
+        }
+        let msg = match converted.outcome {
+            Outcome::Done { raw_fmt, elapsed } => {
+                let mut done = counter.lock().unwrap();
+                *done += 1;
+                let avg = start.elapsed().as_secs_f64() / (*done as f64);
+                let remaining = avg * ((total - *done) as f64);
+                format!(
+                    "{} ({}) → {}... Done ({}).\n   ↳ Est. time left: {}",
+                    pink(in_path.file_name().unwrap().to_string_lossy()),
+                    raw_fmt,
+                    blue(out_formats.join("+")),
+                    format_time(elapsed),
+                    format_time(remaining)
+                )
             }
+            Outcome::Failed(msg) => msg,
+        };
+        tx.send(msg).ok();
+    };
 
-            if !is_nef_file(&in_path) {
-                let fname = in_path.file_name().unwrap().to_string_lossy();
-                tx.send(format!("{}... {}", fname, pink("Skipped (not NEF)")))
-                    .ok();
-                return;
-            }
-            let res = unsafe { load_with_libraw(&in_path, preview, debug, auto_bright) };
-            match res {
-                Ok(img) => {
-                    if args.debug {
-                        println!("{} rotating image...", blue("[rot]"));
-                    }
-                    let mut img = resize_image(img, ratio);
-                    img = apply_brightness(img, brightness_mode);
-                    if let Some(rot) = rotation_opt.as_ref() {
-                        if rot == "auto" {
-                            if let Ok(buf) = std::fs::read(&in_path) {
-                                if let Ok(exif) = rexif::parse_buffer(&buf) {
-                                    for entry in exif.entries.iter() {
-                                        let tag_name = format!("{}", entry.tag).to_lowercase();
-                                        if tag_name.contains("orientation") {
-                                            let sval = format!("{}", entry.value);
-                                            if let Some(tok) = sval.split_whitespace().next() {
-                                                if let Ok(code) = tok.parse::<u32>() {
-                                                    img = match code {
-                                                        3 => image::DynamicImage::ImageRgba8(
-                                                            image::imageops::rotate180(&img),
-                                                        ),
-                                                        6 => image::DynamicImage::ImageRgba8(
-                                                            image::imageops::rotate90(&img),
-                                                        ),
-                                                        8 => image::DynamicImage::ImageRgba8(
-                                                            image::imageops::rotate270(&img),
-                                                        ),
-                                                        _ => img,
-                                                    };
-                                                }
-                                            }
-                                            break;
-                                        }
-                                    }
-                                }
-                            }
-                        } else if let Ok(deg) = rot.parse::<i32>() {
-                            img = match deg.rem_euclid(360) {
-                                90 => {
-                                    image::DynamicImage::ImageRgba8(image::imageops::rotate90(&img))
-                                }
-                                180 => image::DynamicImage::ImageRgba8(image::imageops::rotate180(
-                                    &img,
-                                )),
-                                270 => image::DynamicImage::ImageRgba8(image::imageops::rotate270(
-                                    &img,
-                                )),
-                                _ => img,
-                            };
-                        }
-                    }
-                    if enhance_flag {
-                        img = apply_brightness(img, BrightnessMode::Factor(1.05));
-                        img = image::DynamicImage::ImageRgba8(image::imageops::unsharpen(
-                            &img, 1.0, 1,
-                        ));
-                    }
-                    if let Some(ref single_outs) = out_files_for_single {
-                        for (fmt, out_path) in out_formats.iter().zip(single_outs.iter()) {
-                            if let Err(e) = save_image(&img, out_path, fmt, quality, args.debug) {
-                                let fname = in_path.file_name().unwrap().to_string_lossy();
-                                tx.send(format!("{}... {}: {}", fname, red("Error saving"), e))
-                                    .ok();
-                                return;
-                            }
-                            if let Ok(meta) = out_path.metadata() {
-                                if let Ok(mut counter) = converted_size_counter.lock() {
-                                    *counter += meta.len();
-                                }
-                            }
-                        }
-                    } else {
-                        let fname = in_path.file_name().unwrap().to_string_lossy();
-                        if out_dirs.is_empty() {
-                            let parent = in_path
-                                .parent()
-                                .map(|p| p.to_path_buf())
-                                .unwrap_or_else(|| PathBuf::from("."));
-                            for fmt in out_formats.iter() {
-                                let out_name = format!(
-                                    "{}.{}",
-                                    in_path.file_stem().unwrap().to_string_lossy(),
-                                    fmt
-                                );
-                                let out_path = parent.join(out_name);
-                                if let Err(e) =
-                                    save_image(&img, &out_path, fmt, quality, args.debug)
-                                {
-                                    tx.send(format!("{}... {}: {}", fname, red("Error saving"), e))
-                                        .ok();
-                                    return;
-                                }
-                                if let Ok(meta) = out_path.metadata() {
-                                    if let Ok(mut counter) = converted_size_counter.lock() {
-                                        *counter += meta.len();
-                                    }
-                                }
-                            }
-                        } else {
-                            for (fmt, out_dir) in out_formats.iter().zip(out_dirs.iter()) {
-                                let out_name = format!(
-                                    "{}.{}",
-                                    in_path.file_stem().unwrap().to_string_lossy(),
-                                    fmt
-                                );
-                                let out_path = out_dir.join(out_name);
-                                if let Err(e) =
-                                    save_image(&img, &out_path, fmt, quality, args.debug)
-                                {
-                                    tx.send(format!("{}... {}: {}", fname, red("Error saving"), e))
-                                        .ok();
-                                    return;
-                                }
-                                if let Ok(meta) = out_path.metadata() {
-                                    if let Ok(mut counter) = converted_size_counter.lock() {
-                                        *counter += meta.len();
-                                    }
-                                }
-                            }
-                        }
+    if args.isolate {
+        let state = isolate::encode_state(
+            decode_opts.calibration,
+            decode_opts.lens,
+            &out_dirs,
+            out_files_for_single.as_deref(),
+        );
+        isolate::run(
+            inputs,
+            &state,
+            threads.min(total),
+            &stop_flag,
+            debug,
+            report,
+        );
+    } else {
+        let pool = ThreadPoolBuilder::new().num_threads(threads).build()?;
+        pool.install(|| {
+            inputs
+                .into_par_iter()
+                .enumerate()
+                .for_each(|(seq, in_path)| {
+                    if stop_flag.load(Ordering::SeqCst) {
+                        return;
                     }
-                    let elapsed = t0.elapsed().as_secs_f64();
-                    let mut done = counter.lock().unwrap();
-                    *done += 1;
-                    let avg = start.elapsed().as_secs_f64() / (*done as f64);
-                    let remaining = avg * ((total - *done) as f64);
-                    let name_for_msg = in_path.file_name().unwrap().to_string_lossy();
-                    tx.send(format!(
-                        "{} → {}... Done ({}).\n   ↳ Est. time left: {}",
-                        pink(name_for_msg),
-                        blue(out_formats.join("+")),
-                        format_time(elapsed),
-                        format_time(remaining)
-                    ))
-                    .ok();
-                }
-                Err(e) => {
-                    let name_for_msg = in_path.file_name().unwrap().to_string_lossy();
-                    tx.send(format!("{}... {}: {}", name_for_msg, red("Error"), e))
-                        .ok();
-                }
-            }
+                    report(&in_path, convert(seq, &in_path));
+                });
         });
-    });
+    }
 
     drop(tx);
     printer.join().ok();
@@ -1849,3 +2884,55 @@ fn main() -> Result<()> {
 
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decode_size_auto_follows_ratio() {
+        assert!(parse_decode_size("auto", 0.25).unwrap());
+        assert!(parse_decode_size("Auto", 0.1).unwrap());
+        assert!(!parse_decode_size("auto", 0.26).unwrap());
+        assert!(!parse_decode_size("auto", 1.0).unwrap());
+    }
+
+    #[test]
+    fn decode_size_explicit() {
+        assert!(parse_decode_size("half", 1.0).unwrap());
+        assert!(!parse_decode_size(" FULL ", 0.1).unwrap());
+        assert!(parse_decode_size("quarter", 1.0).is_err());
+    }
+
+    fn factor(s: &str) -> f32 {
+        match parse_brightness(&Some(Some(s.to_string()))) {
+            BrightnessMode::Factor(f) => f,
+            other => panic!("{s}: {other:?}"),
+        }
+    }
+
+    #[test]
+    fn brightness_stops_and_percentages() {
+        assert_eq!(factor("1ev"), 2.0);
+        assert_eq!(factor("-2EV"), 0.25);
+        assert_eq!(factor("+0.5ev"), 2f32.sqrt());
+        assert_eq!(factor("-20%"), 0.8);
+        assert_eq!(factor("+50%"), 1.5);
+        assert_eq!(factor("80%"), 0.8);
+        assert_eq!(factor("1.5"), 1.5);
+        assert_eq!(factor("2"), 2.0);
+    }
+
+    #[test]
+    fn brightness_modes() {
+        assert!(matches!(parse_brightness(&None), BrightnessMode::None));
+        assert!(matches!(
+            parse_brightness(&Some(None)),
+            BrightnessMode::Auto
+        ));
+        let mode = |s: &str| parse_brightness(&Some(Some(s.to_string())));
+        assert!(matches!(mode("auto"), BrightnessMode::Auto));
+        assert!(matches!(mode("none"), BrightnessMode::None));
+        assert!(matches!(mode("bright"), BrightnessMode::None));
+    }
+}
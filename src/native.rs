@@ -0,0 +1,1032 @@
+use anyhow::{Context, Result};
+use image::{DynamicImage, Rgb32FImage};
+use rayon::prelude::*;
+use std::collections::HashMap;
+use std::path::Path;
+
+use crate::color_profile::{
+    ColorProfile, ColorSpace, ToneCurve, bradford_to_d50, invert3, mat_mul, mat_vec,
+};
+use crate::raw_metadata::RawMetadata;
+use crate::term_colors::{blue, pink, red};
+use crate::white_balance::WhiteBalance;
+use crate::{BrightnessMode, DecodeOptions, Decoded, denoise, exposure, lens};
+
+const CFA: u32 = 32803;
+const LINEAR_RAW: u32 = 34892;
+/// CalibrationIlluminant value of D65.
+const D65_ILLUMINANT: f64 = 21.0;
+
+#[derive(Clone, Copy, Debug)]
+struct Entry {
+    kind: u16,
+    count: usize,
+    /// Position of the value, inline or at its offset.
+    at: usize,
+}
+
+type Ifd = HashMap<u16, Entry>;
+
+struct Tiff<'a> {
+    buf: &'a [u8],
+    little: bool,
+}
+
+impl Tiff<'_> {
+    fn uint(&self, at: usize, size: usize) -> Option<u64> {
+        let b = self.buf.get(at..at + size)?;
+        Some(if self.little {
+            b.iter().rev().fold(0, |v, &x| v << 8 | x as u64)
+        } else {
+            b.iter().fold(0, |v, &x| v << 8 | x as u64)
+        })
+    }
+
+    fn u16(&self, at: usize) -> Option<u16> {
+        self.uint(at, 2).map(|v| v as u16)
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        self.uint(at, 4).map(|v| v as u32)
+    }
+
+    fn ifd(&self, at: usize) -> Option<(Ifd, usize)> {
+        let count = self.u16(at)? as usize;
+        let mut ifd = Ifd::new();
+        for i in 0..count.min(1024) {
+            let e = at + 2 + i * 12;
+            let kind = self.u16(e + 2)?;
+            let entry_count = self.u32(e + 4)? as usize;
+            let size: usize = match kind {
+                1 | 2 | 6 | 7 => 1,
+                3 | 8 => 2,
+                4 | 9 | 11 | 13 => 4,
+                5 | 10 | 12 => 8,
+                _ => continue,
+            };
+            let total = size.checked_mul(entry_count)?;
+            let pos = if total <= 4 {
+                e + 8
+            } else {
+                self.u32(e + 8)? as usize
+            };
+            if pos.checked_add(total)? > self.buf.len() {
+                continue;
+            }
+            ifd.insert(
+                self.u16(e)?,
+                Entry {
+                    kind,
+                    count: entry_count,
+                    at: pos,
+                },
+            );
+        }
+        let next = self.u32(at + 2 + count * 12).unwrap_or(0) as usize;
+        Some((ifd, next))
+    }
+
+    fn values(&self, ifd: &Ifd, tag: u16) -> Vec<f64> {
+        let Some(e) = ifd.get(&tag) else {
+            return Vec::new();
+        };
+        (0..e.count)
+            .filter_map(|i| {
+                Some(match e.kind {
+                    1 | 7 => self.uint(e.at + i, 1)? as f64,
+                    6 => self.uint(e.at + i, 1)? as u8 as i8 as f64,
+                    3 => self.u16(e.at + 2 * i)? as f64,
+                    8 => self.u16(e.at + 2 * i)? as i16 as f64,
+                    4 | 13 => self.u32(e.at + 4 * i)? as f64,
+                    9 => self.u32(e.at + 4 * i)? as i32 as f64,
+                    5 => {
+                        let d = self.u32(e.at + 8 * i + 4)?;
+                        self.u32(e.at + 8 * i)? as f64 / d.max(1) as f64
+                    }
+                    10 => {
+                        let d = self.u32(e.at + 8 * i + 4)? as i32;
+                        self.u32(e.at + 8 * i)? as i32 as f64 / if d == 0 { 1.0 } else { d as f64 }
+                    }
+                    11 => f32::from_bits(self.u32(e.at + 4 * i)?) as f64,
+                    12 => f64::from_bits(self.uint(e.at + 8 * i, 8)?),
+                    _ => return None,
+                })
+            })
+            .collect()
+    }
+
+    fn value(&self, ifd: &Ifd, tag: u16) -> Option<f64> {
+        self.values(ifd, tag).first().copied()
+    }
+
+    fn string(&self, ifd: &Ifd, tag: u16) -> String {
+        let Some(e) = ifd.get(&tag) else {
+            return String::new();
+        };
+        let bytes = &self.buf[e.at..e.at + e.count];
+        let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+        String::from_utf8_lossy(&bytes[..end]).trim().to_string()
+    }
+
+    /// IFD0 and its chain, with every SubIFD, in file order.
+    fn all_ifds(&self) -> Vec<Ifd> {
+        let mut out = Vec::new();
+        let mut queue = vec![(self.u32(4).unwrap_or(0) as usize, 0)];
+        while let Some((at, depth)) = queue.pop() {
+            if at == 0 || out.len() >= 64 {
+                continue;
+            }
+            let Some((ifd, next)) = self.ifd(at) else {
+                continue;
+            };
+            if depth < 4 {
+                for sub in self.values(&ifd, 0x014a) {
+                    queue.push((sub as usize, depth + 1));
+                }
+            }
+            if depth == 0 {
+                queue.push((next, 0));
+            }
+            out.push(ifd);
+        }
+        out
+    }
+}
+
+/// MSB-first bit reader over JPEG entropy-coded data, with byte stuffing and markers.
+struct BitReader<'a> {
+    data: &'a [u8],
+    pos: usize,
+    bits: u64,
+    count: u32,
+    /// A marker was hit; zeros are fed from here on.
+    marker: bool,
+}
+
+impl<'a> BitReader<'a> {
+    fn new(data: &'a [u8]) -> Self {
+        BitReader {
+            data,
+            pos: 0,
+            bits: 0,
+            count: 0,
+            marker: false,
+        }
+    }
+
+    fn fill(&mut self) {
+        while self.count <= 56 {
+            let mut byte = 0;
+            if !self.marker && self.pos < self.data.len() {
+                byte = self.data[self.pos];
+                if byte != 0xff {
+                    self.pos += 1;
+                } else if self.data.get(self.pos + 1) == Some(&0) {
+                    self.pos += 2;
+                } else {
+                    self.marker = true;
+                    byte = 0;
+                }
+            }
+            self.bits |= (byte as u64) << (56 - self.count);
+            self.count += 8;
+        }
+    }
+
+    fn peek(&mut self, n: u32) -> u32 {
+        self.fill();
+        (self.bits >> (64 - n)) as u32
+    }
+
+    fn skip(&mut self, n: u32) {
+        self.bits <<= n;
+        self.count -= n;
+    }
+
+    fn read(&mut self, n: u32) -> u32 {
+        if n == 0 {
+            return 0;
+        }
+        let v = self.peek(n);
+        self.skip(n);
+        v
+    }
+
+    /// Skips to the next restart marker and past it.
+    fn restart(&mut self) {
+        self.bits = 0;
+        self.count = 0;
+        self.marker = false;
+        while self.pos + 1 < self.data.len()
+            && !(self.data[self.pos] == 0xff && (0xd0..=0xd7).contains(&self.data[self.pos + 1]))
+        {
+            self.pos += 1;
+        }
+        self.pos += 2;
+    }
+}
+
+/// Huffman table as a 16-bit lookup of (code length, symbol).
+struct Huffman {
+    lookup: Vec<(u8, u8)>,
+}
+
+impl Huffman {
+    fn new(counts: &[u8], symbols: &[u8]) -> Result<Huffman> {
+        let mut lookup = vec![(0u8, 0u8); 1 << 16];
+        let mut code = 0u32;
+        let mut k = 0;
+        for (i, &n) in counts.iter().enumerate() {
+            let len = i as u32 + 1;
+            for _ in 0..n {
+                let sym = *symbols.get(k).context("Huffman table is truncated")?;
+                let start = (code << (16 - len)) as usize;
+                let end = ((code + 1) << (16 - len)) as usize;
+                lookup
+                    .get_mut(start..end)
+                    .context("Huffman table is invalid")?
+                    .fill((len as u8, sym));
+                code += 1;
+                k += 1;
+            }
+            code <<= 1;
+        }
+        Ok(Huffman { lookup })
+    }
+
+    fn diff(&self, bits: &mut BitReader) -> i32 {
+        let (len, ssss) = self.lookup[bits.peek(16) as usize];
+        bits.skip(len.max(1) as u32);
+        match ssss {
+            0 => 0,
+            16 => 32768,
+            n => {
+                let n = n.min(15) as u32;
+                let v = bits.read(n) as i32;
+                if v < 1 << (n - 1) {
+                    v - (1 << n) + 1
+                } else {
+                    v
+                }
+            }
+        }
+    }
+}
+
+/// Decodes a lossless JPEG (ITU T.81 process 14), as DNG uses for compressed tiles.
+/// Returns the samples of each row, components interleaved.
+fn decode_ljpeg(data: &[u8]) -> Result<(usize, usize, usize, Vec<u16>)> {
+    let u16_at = |at: usize| -> Result<usize> {
+        let b = data.get(at..at + 2).context("lossless JPEG is truncated")?;
+        Ok(u16::from_be_bytes([b[0], b[1]]) as usize)
+    };
+    if data.get(..2) != Some(&[0xff, 0xd8]) {
+        anyhow::bail!("not a JPEG stream");
+    }
+    let mut tables: HashMap<u8, Huffman> = HashMap::new();
+    let (mut width, mut height, mut precision) = (0, 0, 0);
+    let mut components: Vec<u8> = Vec::new();
+    let mut restart_interval = 0;
+    let mut pos = 2;
+    loop {
+        while data.get(pos) == Some(&0xff) && data.get(pos + 1) == Some(&0xff) {
+            pos += 1;
+        }
+        let marker = u16_at(pos)?;
+        let len = u16_at(pos + 2)?;
+        let body = data
+            .get(pos + 4..pos + 2 + len)
+            .context("lossless JPEG is truncated")?;
+        let byte = |at: usize| -> Result<u8> {
+            body.get(at)
+                .copied()
+                .with_context(|| format!("JPEG marker {:04X} is truncated", marker))
+        };
+        let word = |at: usize| -> Result<usize> {
+            Ok(u16::from_be_bytes([byte(at)?, byte(at + 1)?]) as usize)
+        };
+        match marker {
+            0xffc3 => {
+                precision = byte(0)? as u32;
+                if !(2..=16).contains(&precision) {
+                    anyhow::bail!("unsupported lossless JPEG precision {}", precision);
+                }
+                height = word(1)?;
+                width = word(3)?;
+                components = (0..byte(5)? as usize)
+                    .map(|i| byte(6 + 3 * i))
+                    .collect::<Result<_>>()?;
+            }
+            0xffc4 => {
+                let mut at = 0;
+                while at + 17 <= body.len() {
+                    let id = body[at] & 0x0f;
+                    let counts = &body[at + 1..at + 17];
+                    let n: usize = counts.iter().map(|&c| c as usize).sum();
+                    let symbols = body
+                        .get(at + 17..at + 17 + n)
+                        .context("Huffman table is truncated")?;
+                    tables.insert(id, Huffman::new(counts, symbols)?);
+                    at += 17 + n;
+                }
+            }
+            0xffdd => restart_interval = word(0)?,
+            0xffda => {
+                let ns = byte(0)? as usize;
+                if ns == 0 || ns != components.len() {
+                    anyhow::bail!("lossless JPEG scan does not cover every component");
+                }
+                let predictor = byte(1 + 2 * ns)?;
+                let point_transform = byte(3 + 2 * ns)? & 0x0f;
+                let huff: Vec<&Huffman> = (0..ns)
+                    .map(|i| {
+                        tables
+                            .get(&(byte(2 + 2 * i)? >> 4))
+                            .context("missing Huffman table")
+                    })
+                    .collect::<Result<_>>()?;
+                if width == 0 || height == 0 {
+                    anyhow::bail!("lossless JPEG has no frame header");
+                }
+                let samples = decode_scan(
+                    &data[pos + 2 + len..],
+                    &huff,
+                    width,
+                    height,
+                    predictor,
+                    precision.saturating_sub(point_transform as u32).max(1),
+                    restart_interval,
+                )?;
+                let shifted = if point_transform > 0 {
+                    samples.into_iter().map(|v| v << point_transform).collect()
+                } else {
+                    samples
+                };
+                return Ok((width, height, ns, shifted));
+            }
+            0xffc0..=0xffcf if marker != 0xffc8 && marker != 0xffcc => {
+                anyhow::bail!("JPEG tile is not lossless (marker {:04X})", marker)
+            }
+            0xffd9 => anyhow::bail!("lossless JPEG has no scan"),
+            _ => {}
+        }
+        pos += 2 + len;
+    }
+}
+
+fn decode_scan(
+    data: &[u8],
+    huff: &[&Huffman],
+    width: usize,
+    height: usize,
+    predictor: u8,
+    precision: u32,
+    restart_interval: usize,
+) -> Result<Vec<u16>> {
+    let nc = huff.len();
+    let row_len = width * nc;
+    let mut out = vec![0u16; row_len * height];
+    let mut bits = BitReader::new(data);
+    let initial = 1i32 << (precision - 1);
+    // Restarts are row aligned in DNG, so they reset the prediction like the first row.
+    let restart_rows = (restart_interval / width.max(1)).max(1);
+    for row in 0..height {
+        let first = row == 0 || (restart_interval > 0 && row % restart_rows == 0);
+        if first && row > 0 {
+            bits.restart();
+        }
+        let (done, rest) = out.split_at_mut(row * row_len);
+        let above = done
+            .get(done.len().saturating_sub(row_len)..)
+            .unwrap_or(&[]);
+        let line = &mut rest[..row_len];
+        for x in 0..width {
+            for (c, table) in huff.iter().enumerate() {
+                let i = x * nc + c;
+                let pred = if x == 0 {
+                    if first { initial } else { above[i] as i32 }
+                } else if first {
+                    line[i - nc] as i32
+                } else {
+                    let (ra, rb, rc) = (line[i - nc] as i32, above[i] as i32, above[i - nc] as i32);
+                    match predictor {
+                        1 => ra,
+                        2 => rb,
+                        3 => rc,
+                        4 => ra + rb - rc,
+                        5 => ra + ((rb - rc) >> 1),
+                        6 => rb + ((ra - rc) >> 1),
+                        7 => (ra + rb) >> 1,
+                        _ => ra,
+                    }
+                };
+                // Differences are modulo 2^16.
+                line[i] = (pred + table.diff(&mut bits)) as u16;
+            }
+        }
+    }
+    Ok(out)
+}
+
+/// Bit-packed uncompressed samples, most significant bit first, rows starting on a byte.
+fn unpack(data: &[u8], bits: u32, little: bool, row_samples: usize, rows: usize) -> Vec<u16> {
+    let mut out = Vec::with_capacity(row_samples * rows);
+    match bits {
+        8 => out.extend(data.iter().take(row_samples * rows).map(|&b| b as u16)),
+        16 => out.extend(data.chunks_exact(2).take(row_samples * rows).map(|b| {
+            if little {
+                u16::from_le_bytes([b[0], b[1]])
+            } else {
+                u16::from_be_bytes([b[0], b[1]])
+            }
+        })),
+        _ => {
+            let row_bytes = (row_samples * bits as usize).div_ceil(8);
+            for row in data.chunks(row_bytes).take(rows) {
+                let mut acc = 0u64;
+                let mut have = 0;
+                let mut bytes = row.iter();
+                for _ in 0..row_samples {
+                    while have < bits {
+                        acc = acc << 8 | *bytes.next().unwrap_or(&0) as u64;
+                        have += 8;
+                    }
+                    have -= bits;
+                    out.push(((acc >> have) & ((1 << bits) - 1)) as u16);
+                }
+            }
+        }
+    }
+    out.resize(row_samples * rows, 0);
+    out
+}
+
+/// The raw image of a DNG with its color data.
+struct RawImage {
+    width: usize,
+    height: usize,
+    /// Samples per pixel: 1 for CFA data, 3 for linear raw.
+    spp: usize,
+    data: Vec<u16>,
+    /// 2x2 CFA colors (0 red, 1 green, 2 blue) by row and column.
+    cfa: Option<[[usize; 2]; 2]>,
+    black: Vec<f64>,
+    black_dim: (usize, usize),
+    black_h: Vec<f64>,
+    black_v: Vec<f64>,
+    white: Vec<f64>,
+    /// Crop in raw pixels: left, top, width, height.
+    crop: (usize, usize, usize, usize),
+    color_matrix: Option<[[f64; 3]; 3]>,
+    neutral: Option<[f64; 3]>,
+    orientation: u32,
+}
+
+fn read_raw(buf: &[u8], debug: bool) -> Result<(RawImage, RawMetadata)> {
+    let little = match buf.get(..4) {
+        Some(b"II*\0") => true,
+        Some(b"MM\0*") => false,
+        _ => anyhow::bail!("not a TIFF-based file; the native decoder reads DNG only"),
+    };
+    let t = Tiff { buf, little };
+    let ifds = t.all_ifds();
+    let ifd0 = ifds.first().context("file has no IFD")?;
+    if !ifd0.contains_key(&0xc612) {
+        anyhow::bail!("not a DNG; the native decoder reads DNG only");
+    }
+    let raw = ifds
+        .iter()
+        .filter(|ifd| t.value(ifd, 0x00fe).unwrap_or(0.0) as u32 & 1 == 0)
+        .filter(|ifd| {
+            matches!(
+                t.value(ifd, 0x0106).map(|v| v as u32),
+                Some(CFA | LINEAR_RAW)
+            )
+        })
+        .max_by_key(|ifd| t.value(ifd, 0x0100).unwrap_or(0.0) as u64)
+        .context("DNG has no raw image")?;
+
+    let num = |tag: u16| t.value(raw, tag).map(|v| v as usize);
+    let width = num(0x0100).context("raw image has no width")?;
+    let height = num(0x0101).context("raw image has no height")?;
+    let spp = num(0x0115).unwrap_or(1);
+    let bits = num(0x0102).unwrap_or(16) as u32;
+    let compression = num(0x0103).unwrap_or(1);
+    let photometric = num(0x0106).unwrap_or(0) as u32;
+    if debug {
+        println!(
+            "{} {}x{} {} bits, {} sample(s), compression {}",
+            blue("[native]"),
+            pink(width),
+            pink(height),
+            pink(bits),
+            pink(spp),
+            pink(compression)
+        );
+    }
+    if !(1..=16).contains(&bits) || !matches!(spp, 1 | 3) {
+        anyhow::bail!("unsupported raw layout: {} bits, {} samples", bits, spp);
+    }
+    if width == 0 || height == 0 || width.saturating_mul(height) > 1 << 30 {
+        anyhow::bail!("invalid raw size {}x{}", width, height);
+    }
+
+    // Strips are tiles as wide as the image.
+    let tiled = raw.contains_key(&0x0144);
+    let (tw, th, offsets, counts) = if tiled {
+        (
+            num(0x0142).context("tiled raw without TileWidth")?,
+            num(0x0143).context("tiled raw without TileLength")?,
+            t.values(raw, 0x0144),
+            t.values(raw, 0x0145),
+        )
+    } else {
+        (
+            width,
+            num(0x0116).unwrap_or(height).min(height),
+            t.values(raw, 0x0111),
+            t.values(raw, 0x0117),
+        )
+    };
+    if tw == 0 || th == 0 || offsets.is_empty() {
+        anyhow::bail!("raw image has no data");
+    }
+    let across = width.div_ceil(tw);
+    let tiles: Vec<(usize, &[u8])> = offsets
+        .iter()
+        .zip(&counts)
+        .enumerate()
+        .map(|(i, (&o, &n))| {
+            let (o, n) = (o as usize, n as usize);
+            let end = o.saturating_add(n).min(buf.len());
+            (i, buf.get(o.min(end)..end).unwrap_or(&[]))
+        })
+        .collect();
+    let decoded: Vec<(usize, Vec<u16>)> = tiles
+        .into_par_iter()
+        .map(|(i, bytes)| {
+            let samples = match compression {
+                1 => unpack(bytes, bits, little, tw * spp, th),
+                7 => {
+                    let (w, h, c, s) =
+                        decode_ljpeg(bytes).with_context(|| format!("tile {}", i))?;
+                    if w * h * c < tw * th * spp {
+                        anyhow::bail!("tile {} decodes to {}x{}x{} samples", i, w, h, c);
+                    }
+                    s
+                }
+                n => anyhow::bail!(
+                    "compression {} is not supported natively (uncompressed and lossless JPEG are)",
+                    n
+                ),
+            };
+            Ok((i, samples))
+        })
+        .collect::<Result<_>>()?;
+    let mut data = vec![0u16; width * height * spp];
+    for (i, samples) in decoded {
+        let (x0, y0) = ((i % across) * tw, (i / across) * th);
+        for y in 0..th.min(height.saturating_sub(y0)) {
+            let n = tw.min(width.saturating_sub(x0)) * spp;
+            let src = &samples[y * tw * spp..][..n];
+            data[((y0 + y) * width + x0) * spp..][..n].copy_from_slice(src);
+        }
+    }
+    let linearization = t.values(raw, 0xc618);
+    if !linearization.is_empty() {
+        let last = linearization.len() - 1;
+        for v in data.iter_mut() {
+            *v = linearization[(*v as usize).min(last)] as u16;
+        }
+    }
+
+    let cfa = if photometric == CFA {
+        let dim = t.values(raw, 0x828d);
+        let pattern = t.values(raw, 0x828e);
+        if dim != [2.0, 2.0] || pattern.len() != 4 || pattern.iter().any(|&c| c > 2.0) {
+            anyhow::bail!("only 2x2 RGB CFA patterns are supported natively");
+        }
+        let p = |i: usize| pattern[i] as usize;
+        Some([[p(0), p(1)], [p(2), p(3)]])
+    } else {
+        None
+    };
+    let black_dim = match t.values(raw, 0xc619).as_slice() {
+        [r, c] if *r >= 1.0 && *c >= 1.0 => (*r as usize, *c as usize),
+        _ => (1, 1),
+    };
+    let mut black = t.values(raw, 0xc61a);
+    if black.is_empty() {
+        black.push(0.0);
+    }
+    let mut white = t.values(raw, 0xc61d);
+    if white.is_empty() {
+        white.push(((1u32 << bits) - 1) as f64);
+    }
+
+    // ActiveArea (top, left, bottom, right), then DefaultCrop inside it.
+    let (top, left, bottom, right) = match t.values(raw, 0xc68d).as_slice() {
+        [t, l, b, r] => (*t as usize, *l as usize, *b as usize, *r as usize),
+        _ => (0, 0, height, width),
+    };
+    let (active_w, active_h) = (
+        right.min(width).saturating_sub(left),
+        bottom.min(height).saturating_sub(top),
+    );
+    let crop = match (
+        t.values(raw, 0xc61f).as_slice(),
+        t.values(raw, 0xc620).as_slice(),
+    ) {
+        ([x, y], [w, h]) => (
+            left.saturating_add(*x as usize),
+            top.saturating_add(*y as usize),
+            *w as usize,
+            *h as usize,
+        ),
+        _ => (left, top, active_w, active_h),
+    };
+    let crop = (
+        crop.0.min(width),
+        crop.1.min(height),
+        crop.2.min(width - crop.0.min(width)),
+        crop.3.min(height - crop.1.min(height)),
+    );
+
+    // Prefer the D65 calibration; libraw does the same for DNGs.
+    let matrix = |tag: u16| {
+        let m = t.values(ifd0, tag);
+        (m.len() == 9).then(|| [[m[0], m[1], m[2]], [m[3], m[4], m[5]], [m[6], m[7], m[8]]])
+    };
+    let color_matrix = if t.value(ifd0, 0xc65b) == Some(D65_ILLUMINANT) {
+        matrix(0xc622).or_else(|| matrix(0xc621))
+    } else {
+        matrix(0xc621).or_else(|| matrix(0xc622))
+    };
+    let neutral = match t.values(ifd0, 0xc628).as_slice() {
+        [r, g, b] if *r > 0.0 && *g > 0.0 && *b > 0.0 => Some([*r, *g, *b]),
+        _ => None,
+    };
+
+    let mut meta = RawMetadata {
+        make: t.string(ifd0, 0x010f),
+        model: t.string(ifd0, 0x0110),
+        software: t.string(ifd0, 0x0131),
+        artist: t.string(ifd0, 0x013b),
+        ..RawMetadata::default()
+    };
+    if let Some(exif) = t
+        .value(ifd0, 0x8769)
+        .and_then(|at| t.ifd(at as usize))
+        .map(|(ifd, _)| ifd)
+    {
+        let positive = |tag: u16| t.value(&exif, tag).filter(|v| *v > 0.0).map(|v| v as f32);
+        meta.iso = positive(0x8827);
+        meta.shutter = positive(0x829a);
+        meta.aperture = positive(0x829d);
+        meta.focal_len = positive(0x920a);
+        meta.focal_35mm = positive(0xa405).map(|v| v as u16);
+        meta.lens = t.string(&exif, 0xa434);
+        meta.lens_make = t.string(&exif, 0xa433);
+    }
+    if meta.lens.is_empty() {
+        meta.lens = t.string(ifd0, 0xc65d);
+    }
+
+    Ok((
+        RawImage {
+            width,
+            height,
+            spp,
+            data,
+            cfa,
+            black,
+            black_dim,
+            black_h: t.values(raw, 0xc61b),
+            black_v: t.values(raw, 0xc61c),
+            white,
+            crop,
+            color_matrix,
+            neutral,
+            orientation: t.value(ifd0, 0x0112).unwrap_or(1.0) as u32,
+        },
+        meta,
+    ))
+}
+
+impl RawImage {
+    /// Sample `s` of pixel (x, y) scaled to 0..1 between black and white.
+    fn level(&self, x: usize, y: usize, s: usize) -> f32 {
+        let (br, bc) = self.black_dim;
+        let bi = ((y % br) * bc + x % bc) * self.spp + s;
+        let black = self
+            .black
+            .get(bi)
+            .or(self.black.first())
+            .copied()
+            .unwrap_or(0.0)
+            + self.black_h.get(x).copied().unwrap_or(0.0)
+            + self.black_v.get(y).copied().unwrap_or(0.0);
+        let white = self
+            .white
+            .get(s)
+            .or(self.white.first())
+            .copied()
+            .unwrap_or(65535.0);
+        let v = self.data[(y * self.width + x) * self.spp + s] as f64;
+        ((v - black) / (white - black).max(1.0)) as f32
+    }
+
+    fn color_at(&self, x: usize, y: usize) -> usize {
+        self.cfa.map_or(1, |p| p[y & 1][x & 1])
+    }
+
+    /// Camera RGB of the cropped area: bilinear demosaicing, or 2x2 binning at half size.
+    fn demosaic(&self, half: bool) -> Rgb32FImage {
+        let (cx, cy, cw, ch) = self.crop;
+        if self.cfa.is_none() {
+            let mut out = Rgb32FImage::new(cw as u32, ch as u32);
+            for (x, y, p) in out.enumerate_pixels_mut() {
+                let (x, y) = (cx + x as usize, cy + y as usize);
+                p.0 = [0, 1, 2].map(|s| self.level(x, y, s));
+            }
+            return if half {
+                DynamicImage::ImageRgb32F(out)
+                    .resize_exact(
+                        (cw / 2).max(1) as u32,
+                        (ch / 2).max(1) as u32,
+                        image::imageops::FilterType::Triangle,
+                    )
+                    .to_rgb32f()
+            } else {
+                out
+            };
+        }
+        let (ow, oh) = if half {
+            ((cw / 2).max(1), (ch / 2).max(1))
+        } else {
+            (cw, ch)
+        };
+        let rows: Vec<Vec<f32>> = (0..oh)
+            .into_par_iter()
+            .map(|oy| {
+                let mut row = Vec::with_capacity(ow * 3);
+                for ox in 0..ow {
+                    let mut sum = [0f32; 3];
+                    let mut n = [0u32; 3];
+                    let mut add = |x: usize, y: usize| {
+                        let c = self.color_at(x, y);
+                        sum[c] += self.level(x, y, 0);
+                        n[c] += 1;
+                    };
+                    let (x, y) = if half {
+                        (cx + 2 * ox, cy + 2 * oy)
+                    } else {
+                        (cx + ox, cy + oy)
+                    };
+                    if half {
+                        for (dx, dy) in [(0, 0), (1, 0), (0, 1), (1, 1)] {
+                            add((x + dx).min(self.width - 1), (y + dy).min(self.height - 1));
+                        }
+                    } else {
+                        let own = self.color_at(x, y);
+                        for dy in -1i64..=1 {
+                            for dx in -1i64..=1 {
+                                let (xx, yy) = (x as i64 + dx, y as i64 + dy);
+                                if xx < 0
+                                    || yy < 0
+                                    || xx >= self.width as i64
+                                    || yy >= self.height as i64
+                                {
+                                    continue;
+                                }
+                                let (xx, yy) = (xx as usize, yy as usize);
+                                // The site's own color comes from the site alone.
+                                if (dx, dy) != (0, 0) && self.color_at(xx, yy) == own {
+                                    continue;
+                                }
+                                add(xx, yy);
+                            }
+                        }
+                    }
+                    for c in 0..3 {
+                        row.push(sum[c] / n[c].max(1) as f32);
+                    }
+                }
+                row
+            })
+            .collect();
+        Rgb32FImage::from_raw(ow as u32, oh as u32, rows.concat()).unwrap_or_default()
+    }
+}
+
+/// White balance multipliers for camera RGB, the smallest being 1.
+fn wb_multipliers(raw: &RawImage, img: &Rgb32FImage, wb: WhiteBalance) -> Result<[f32; 3]> {
+    let mul = match wb {
+        WhiteBalance::Camera => match raw.neutral {
+            Some(n) => n.map(|v| (1.0 / v) as f32),
+            None => [1.0; 3],
+        },
+        WhiteBalance::Multipliers(m) => [m[0], m[1], m[2]],
+        WhiteBalance::Auto => {
+            let mut sum = [0f64; 3];
+            for p in img.pixels() {
+                for (s, &v) in sum.iter_mut().zip(&p.0) {
+                    *s += v as f64;
+                }
+            }
+            sum.map(|s| (sum[1] / s.max(1e-9)) as f32)
+        }
+        other => anyhow::bail!(
+            "white balance {} is not supported by the native decoder",
+            red(format!("{:?}", other))
+        ),
+    };
+    let min = mul.iter().copied().fold(f32::MAX, f32::min);
+    if min.is_nan() || min <= 0.0 {
+        anyhow::bail!("invalid white balance multipliers {:?}", mul);
+    }
+    Ok(mul.map(|m| m / min))
+}
+
+/// Camera RGB (white balanced) to linear `space`, the way dcraw derives it from a DNG
+/// ColorMatrix: rows of camera-from-sRGB normalized so that white stays white.
+fn output_matrix(cam_xyz: [[f64; 3]; 3], space: ColorSpace) -> Option<[[f64; 3]; 3]> {
+    let (xyz_srgb, srgb_white) = ColorSpace::Srgb.to_xyz()?;
+    let mut cam_rgb = mat_mul(cam_xyz, xyz_srgb);
+    for row in cam_rgb.iter_mut() {
+        let sum: f64 = row.iter().sum();
+        if sum.abs() < 1e-9 {
+            return None;
+        }
+        for v in row.iter_mut() {
+            *v /= sum;
+        }
+    }
+    let srgb_cam = invert3(cam_rgb)?;
+    let (xyz_out, out_white) = space.to_xyz()?;
+    let adapt = mat_mul(
+        invert3(bradford_to_d50(out_white))?,
+        bradford_to_d50(srgb_white),
+    );
+    Some(mat_mul(
+        invert3(xyz_out)?,
+        mat_mul(adapt, mat_mul(xyz_srgb, srgb_cam)),
+    ))
+}
+
+/// Pure-Rust decoding of DNG files (uncompressed or lossless JPEG, 2x2 CFA or linear),
+/// for systems without libraw.
+pub fn decode(path: &Path, opts: &DecodeOptions, debug: bool) -> Result<Decoded> {
+    if opts.cfa.is_some() || opts.dng.is_some() || opts.calibration.is_some() {
+        anyhow::bail!(
+            "--cfa, DNG output and calibration frames need libraw (--decoder {})",
+            blue("libraw")
+        );
+    }
+    if opts.preview.is_some() && debug {
+        println!(
+            "{} previews need libraw, decoding the raw data",
+            blue("[native]")
+        );
+    }
+    let buf = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+    let (raw, meta) = read_raw(&buf, debug)?;
+
+    let mut img = raw.demosaic(opts.half_size);
+    let mul = wb_multipliers(&raw, &img, opts.white_balance)?;
+    if debug {
+        println!(
+            "{} white balance {}",
+            blue("[native]"),
+            pink(format!("{:?}", mul))
+        );
+    }
+    let matrix = match (raw.color_matrix, opts.color_space) {
+        (_, ColorSpace::Raw) => None,
+        (Some(cm), space) => {
+            let m = output_matrix(cm, space);
+            if m.is_none() {
+                eprintln!("{}", pink("Invalid DNG color matrix, writing camera RGB"));
+            }
+            m
+        }
+        (None, _) => {
+            if debug {
+                println!("{} no ColorMatrix, writing camera RGB", blue("[native]"));
+            }
+            None
+        }
+    };
+    for p in img.pixels_mut() {
+        // Clipping after white balance keeps blown highlights neutral.
+        let cam = [0, 1, 2].map(|c| (p.0[c] * mul[c]).clamp(0.0, 1.0) as f64);
+        p.0 = match matrix {
+            Some(m) => mat_vec(m, cam).map(|v| v.max(0.0) as f32),
+            None => cam.map(|v| v as f32),
+        };
+    }
+
+    let factor = match opts.exposure {
+        BrightnessMode::None => None,
+        BrightnessMode::Factor(f) => Some(f),
+        BrightnessMode::Auto => exposure::linear_auto_factor(&img, opts.preserve_highlights),
+    };
+    if let Some(f) = factor {
+        if debug {
+            println!("{} exposure x{:.3}", blue("[native]"), f);
+        }
+        for v in img.iter_mut() {
+            *v *= f;
+        }
+    }
+    if let Some(d) = opts.denoise.resolve(meta.iso).settings() {
+        denoise::denoise_rgb(&mut img, &d, debug);
+    }
+    if let Some(l) = opts.lens {
+        let profile = l.profile_for(Some(&meta), debug);
+        if profile.ca.is_some() {
+            eprintln!(
+                "{}",
+                pink("Chromatic aberration correction needs libraw, skipping it")
+            );
+        }
+        img = lens::correct_rgb(&img, &profile, &ToneCurve::LINEAR, debug);
+    }
+
+    let image = if opts.linear {
+        DynamicImage::ImageRgb32F(img)
+    } else {
+        crate::linear_to_display(&img, opts.output_bps, &opts.curve)
+    };
+    Ok(Decoded {
+        image: crate::orient_image(image, raw.orientation),
+        profile: ColorProfile {
+            space: opts.color_space,
+            curve: opts.curve,
+        },
+        area: if opts.half_size { 0.25 } else { 1.0 },
+        meta: Some(meta),
+        sidecar: None,
+        dng: None,
+        oriented: true,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dng::{DngSource, write_dng};
+
+    /// 2x2, 8-bit, one component, predictor 1, samples 128 129 / 127 128.
+    /// Codes: `00` for a zero difference, `01` plus one bit for ±1.
+    const LJPEG: &[u8] = &[
+        0xff, 0xd8, // SOI
+        0xff, 0xc3, 0x00, 0x0b, 0x08, 0x00, 0x02, 0x00, 0x02, 0x01, 0x01, 0x11, 0x00, // SOF3
+        0xff, 0xc4, 0x00, 0x16, 0x00, // DHT, class 0 table 0
+        0x00, 0x03, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x01, 0x02, // three codes of length 2
+        0xff, 0xda, 0x00, 0x08, 0x01, 0x01, 0x00, 0x01, 0x00, 0x00, // SOS
+        0x1a, 0x7f, // 00 011 010 011, padded with ones
+        0xff, 0xd9, // EOI
+    ];
+
+    #[test]
+    fn decodes_lossless_jpeg() {
+        let (w, h, c, samples) = decode_ljpeg(LJPEG).unwrap();
+        assert_eq!((w, h, c), (2, 2, 1));
+        assert_eq!(samples, [128, 129, 127, 128]);
+    }
+
+    #[test]
+    fn rejects_bad_lossless_jpeg() {
+        let mut precision = LJPEG.to_vec();
+        precision[6] = 1;
+        assert!(decode_ljpeg(&precision).is_err());
+        precision[6] = 17;
+        assert!(decode_ljpeg(&precision).is_err());
+        for len in [2, 6, 20, 40] {
+            assert!(decode_ljpeg(&LJPEG[..len]).is_err(), "truncated at {}", len);
+        }
+        assert!(decode_ljpeg(&[0xff, 0xd8, 0xff, 0xd9, 0x00, 0x02]).is_err());
+    }
+
+    #[test]
+    fn cfa_dng_round_trip() {
+        let (width, height) = (6, 4);
+        let samples: Vec<u16> = (0..width * height).map(|i| 512 + 37 * i as u16).collect();
+        let src = DngSource::mosaic(width, height, samples.clone(), [1, 2, 0, 1], 512, 15000);
+        let path = std::env::temp_dir().join(format!("fempeg-test-{}.dng", std::process::id()));
+        write_dng(&path, &src, false).unwrap();
+        let buf = std::fs::read(&path).unwrap();
+        std::fs::remove_file(&path).ok();
+
+        let (raw, _) = read_raw(&buf, false).unwrap();
+        assert_eq!((raw.width, raw.height, raw.spp), (6, 4, 1));
+        assert_eq!(raw.data, samples);
+        assert_eq!(raw.cfa, Some([[1, 2], [0, 1]]));
+        assert_eq!(raw.black, [512.0]);
+        assert_eq!(raw.black_dim, (1, 1));
+        assert_eq!(raw.white, [15000.0]);
+        assert_eq!(raw.crop, (0, 0, 6, 4));
+    }
+}
@@ -0,0 +1,186 @@
+use anyhow::{Context, Result};
+use image::DynamicImage;
+use std::os::raw::c_int;
+
+use crate::libraw::{ImageKind, LibRaw};
+use crate::term_colors::{blue, pink, red};
+
+/// `LIBRAW_THUMBNAIL_MAXCOUNT`: libraw lists at most this many previews per file.
+const MAX_THUMBNAILS: c_int = 8;
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PreviewPick {
+    Largest,
+    /// Smallest preview at least as large as the output, given as a fraction of the
+    /// sensor's width and height.
+    Covering(f64),
+}
+
+pub fn parse_preview_pick(s: &str, scale: f64) -> Result<PreviewPick> {
+    match s.trim().to_ascii_lowercase().as_str() {
+        "largest" => Ok(PreviewPick::Largest),
+        "fit" => Ok(PreviewPick::Covering(scale)),
+        _ => anyhow::bail!(
+            "Unknown preview selection {}. Valid: {}, {}",
+            red(s),
+            blue("fit"),
+            blue("largest")
+        ),
+    }
+}
+
+enum PreviewData {
+    Jpeg(Vec<u8>),
+    Bitmap(DynamicImage),
+}
+
+struct Preview {
+    index: c_int,
+    width: u32,
+    height: u32,
+    data: PreviewData,
+}
+
+impl Preview {
+    fn long_short(&self) -> (u32, u32) {
+        (self.width.max(self.height), self.width.min(self.height))
+    }
+}
+
+/// Copies the currently unpacked thumbnail out of libraw.
+fn read_thumbnail(lr: &mut LibRaw, index: c_int) -> Result<Preview> {
+    let pimg = lr
+        .make_mem_thumb()
+        .context("libraw_dcraw_make_mem_thumb failed")?;
+    if pimg.data().is_empty() {
+        anyhow::bail!("thumbnail {} is empty", index);
+    }
+    if pimg.kind() == ImageKind::Jpeg {
+        let bytes = pimg.data().to_vec();
+        // libraw leaves the size fields unset for JPEG thumbnails, so read the header.
+        image::ImageReader::new(std::io::Cursor::new(&bytes))
+            .with_guessed_format()
+            .ok()
+            .and_then(|r| r.into_dimensions().ok())
+            .map(|(width, height)| Preview {
+                index,
+                width,
+                height,
+                data: PreviewData::Jpeg(bytes),
+            })
+            .with_context(|| format!("thumbnail {} is not a readable JPEG", index))
+    } else {
+        crate::processed_bitmap_to_image(&pimg, "thumbnail").map(|img| Preview {
+            index,
+            width: pimg.width(),
+            height: pimg.height(),
+            data: PreviewData::Bitmap(img),
+        })
+    }
+}
+
+/// Every embedded preview libraw can extract, from the thumbnail list where available.
+fn list_previews(lr: &mut LibRaw, debug: bool) -> Vec<Preview> {
+    let mut previews = Vec::new();
+    for index in 0..MAX_THUMBNAILS {
+        let r = match lr.unpack_thumb_ex(index) {
+            Some(r) => r,
+            None if index == 0 => lr.unpack_thumb(),
+            None => break,
+        };
+        if let Err(e) = r {
+            if debug && index == 0 {
+                eprintln!("{} libraw_unpack_thumb: {}", blue("[preview]"), e);
+            }
+            break;
+        }
+        match read_thumbnail(lr, index) {
+            Ok(p) => {
+                if debug {
+                    println!(
+                        "{} thumbnail {} is {}x{} {}",
+                        blue("[preview]"),
+                        index,
+                        p.width,
+                        p.height,
+                        if matches!(p.data, PreviewData::Jpeg(_)) {
+                            "JPEG"
+                        } else {
+                            "bitmap"
+                        }
+                    );
+                }
+                previews.push(p);
+            }
+            Err(e) => {
+                if debug {
+                    eprintln!("{} skipping thumbnail {}: {}", blue("[preview]"), index, e);
+                }
+            }
+        }
+    }
+    previews
+}
+
+fn choose(mut previews: Vec<Preview>, pick: PreviewPick, sensor: (u32, u32)) -> Option<Preview> {
+    previews.sort_by_key(|p| p.width as u64 * p.height as u64);
+    if let PreviewPick::Covering(scale) = pick {
+        let (long, short) = (sensor.0.max(sensor.1), sensor.0.min(sensor.1));
+        let need = (
+            (long as f64 * scale).ceil() as u32,
+            (short as f64 * scale).ceil() as u32,
+        );
+        if let Some(i) = previews.iter().position(|p| {
+            let (pl, ps) = p.long_short();
+            pl >= need.0 && ps >= need.1
+        }) {
+            return Some(previews.swap_remove(i));
+        }
+    }
+    previews.pop()
+}
+
+/// Extracts the embedded preview chosen by `pick`, already oriented for display.
+///
+/// Returns the image and its area relative to the full sensor, or `None` when the file
+/// has no usable preview. Must run after `libraw_open_buffer`; no raw data is unpacked.
+pub fn extract_preview(
+    lr: &mut LibRaw,
+    pick: PreviewPick,
+    debug: bool,
+) -> Result<Option<(DynamicImage, f64)>> {
+    let (w, h) = lr.image_size();
+    let sensor = (w.max(1), h.max(1));
+    let previews = list_previews(lr, debug);
+    let Some(preview) = choose(previews, pick, sensor) else {
+        return Ok(None);
+    };
+    if debug {
+        println!(
+            "{} using thumbnail {} ({}x{}) for {}x{} sensor",
+            blue("[preview]"),
+            preview.index,
+            pink(preview.width),
+            pink(preview.height),
+            sensor.0,
+            sensor.1
+        );
+    }
+    let area = (preview.width as f64 * preview.height as f64) / (sensor.0 as f64 * sensor.1 as f64);
+    // JPEG previews usually carry their own EXIF orientation (libraw adds one when the
+    // camera did not); bitmaps follow the raw file's.
+    let (img, orientation) = match preview.data {
+        PreviewData::Jpeg(bytes) => {
+            let img = image::load_from_memory(&bytes).context("Failed to decode preview JPEG")?;
+            let orientation =
+                crate::exif_orientation(&bytes).or_else(|| crate::exif_orientation(lr.file_data()));
+            (img, orientation)
+        }
+        PreviewData::Bitmap(img) => (img, crate::exif_orientation(lr.file_data())),
+    };
+    let img = match orientation {
+        Some(code) => crate::orient_image(img, code),
+        None => img,
+    };
+    Ok(Some((img, area)))
+}
@@ -0,0 +1,282 @@
+use anyhow::Result;
+use std::fmt;
+use std::io::Read;
+use std::path::Path;
+
+use crate::term_colors::red;
+
+/// Bytes read from the start of a file for detection.
+const PROBE_LEN: u64 = 128 * 1024;
+
+/// Raw extensions scanned in input directories unless `--extensions` overrides them.
+pub const DEFAULT_EXTENSIONS: &[&str] = &[
+    "nef", "nrw", "arw", "srf", "sr2", "cr2", "cr3", "crw", "raf", "dng", "orf", "rw2", "rwl",
+    "pef", "srw", "3fr", "fff", "iiq", "mos", "mef", "mrw", "x3f", "erf", "kdc", "dcr",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Container {
+    /// TIFF/EP based: NEF, ARW, CR2, DNG, PEF and most others.
+    Tiff,
+    /// ISO base media file (CR3).
+    IsoBmff,
+    Raf,
+    Orf,
+    Rw2,
+    Crw,
+    Mrw,
+    X3f,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct RawFormat {
+    /// Conventional name of the format, e.g. `NEF`.
+    pub name: &'static str,
+    pub vendor: &'static str,
+    pub container: Container,
+}
+
+impl fmt::Display for RawFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+const fn raw(name: &'static str, vendor: &'static str, container: Container) -> RawFormat {
+    RawFormat {
+        name,
+        vendor,
+        container,
+    }
+}
+
+/// TIFF `Make` prefixes (upper-cased) and the format each vendor writes.
+const TIFF_MAKES: &[(&str, &str, &str)] = &[
+    ("NIKON", "NEF", "Nikon"),
+    ("SONY", "ARW", "Sony"),
+    ("CANON", "CR2", "Canon"),
+    ("PENTAX", "PEF", "Pentax"),
+    ("RICOH", "PEF", "Ricoh"),
+    ("ASAHI", "PEF", "Pentax"),
+    ("SAMSUNG", "SRW", "Samsung"),
+    ("HASSELBLAD", "3FR", "Hasselblad"),
+    ("PHASE ONE", "IIQ", "Phase One"),
+    ("LEAF", "MOS", "Leaf"),
+    ("MAMIYA", "MEF", "Mamiya"),
+    ("KODAK", "DCR", "Kodak"),
+    ("EASTMAN KODAK", "DCR", "Kodak"),
+    ("SEIKO EPSON", "ERF", "Epson"),
+    ("LEICA", "RWL", "Leica"),
+    ("PANASONIC", "RW2", "Panasonic"),
+    ("OLYMPUS", "ORF", "Olympus"),
+    ("OM DIGITAL", "ORF", "OM System"),
+    ("FUJIFILM", "RAF", "Fujifilm"),
+    ("MINOLTA", "MRW", "Minolta"),
+    ("KONICA MINOLTA", "MRW", "Minolta"),
+];
+
+fn vendor_for_make(make: &str) -> Option<(&'static str, &'static str)> {
+    let make = make.trim().to_ascii_uppercase();
+    TIFF_MAKES
+        .iter()
+        .find(|(prefix, _, _)| make.starts_with(prefix))
+        .map(|(_, name, vendor)| (*name, *vendor))
+}
+
+struct Tiff<'a> {
+    buf: &'a [u8],
+    little: bool,
+}
+
+impl Tiff<'_> {
+    fn u16(&self, at: usize) -> Option<u16> {
+        let b: [u8; 2] = self.buf.get(at..at + 2)?.try_into().ok()?;
+        Some(if self.little {
+            u16::from_le_bytes(b)
+        } else {
+            u16::from_be_bytes(b)
+        })
+    }
+
+    fn u32(&self, at: usize) -> Option<u32> {
+        let b: [u8; 4] = self.buf.get(at..at + 4)?.try_into().ok()?;
+        Some(if self.little {
+            u32::from_le_bytes(b)
+        } else {
+            u32::from_be_bytes(b)
+        })
+    }
+
+    /// Returns (Make, has DNGVersion) from IFD0.
+    fn ifd0(&self) -> Option<(Option<String>, bool)> {
+        let ifd = self.u32(4)? as usize;
+        let count = self.u16(ifd)? as usize;
+        let mut make = None;
+        let mut dng = false;
+        for i in 0..count.min(512) {
+            let entry = ifd + 2 + i * 12;
+            match self.u16(entry)? {
+                0x010f => {
+                    let len = self.u32(entry + 4)? as usize;
+                    let start = if len <= 4 {
+                        entry + 8
+                    } else {
+                        self.u32(entry + 8)? as usize
+                    };
+                    let bytes = self.buf.get(start..start + len)?;
+                    let end = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+                    make = Some(String::from_utf8_lossy(&bytes[..end]).into_owned());
+                }
+                0xc612 => dng = true,
+                _ => {}
+            }
+        }
+        Some((make, dng))
+    }
+}
+
+/// Identifies a raw container and vendor from the first bytes of a file.
+pub fn detect(buf: &[u8]) -> Option<RawFormat> {
+    if buf.len() < 16 {
+        return None;
+    }
+    if buf.starts_with(b"FUJIFILMCCD-RAW") {
+        return Some(raw("RAF", "Fujifilm", Container::Raf));
+    }
+    if &buf[4..12] == b"ftypcrx " {
+        return Some(raw("CR3", "Canon", Container::IsoBmff));
+    }
+    if &buf[6..14] == b"HEAPCCDR" {
+        return Some(raw("CRW", "Canon", Container::Crw));
+    }
+    if buf.starts_with(b"\0MRM") {
+        return Some(raw("MRW", "Minolta", Container::Mrw));
+    }
+    if buf.starts_with(b"FOVb") {
+        return Some(raw("X3F", "Sigma", Container::X3f));
+    }
+    if buf.starts_with(b"IIRO") || buf.starts_with(b"IIRS") || buf.starts_with(b"MMOR") {
+        return Some(raw("ORF", "Olympus", Container::Orf));
+    }
+    if buf.starts_with(b"IIU\0") {
+        return Some(raw("RW2", "Panasonic", Container::Rw2));
+    }
+    let little = match &buf[..4] {
+        b"II*\0" => true,
+        b"MM\0*" => false,
+        _ => return None,
+    };
+    let tiff = Tiff { buf, little };
+    let (make, dng) = tiff.ifd0().unwrap_or((None, false));
+    let vendor = make.as_deref().and_then(vendor_for_make);
+    if dng {
+        return Some(raw(
+            "DNG",
+            vendor.map_or("Adobe", |(_, v)| v),
+            Container::Tiff,
+        ));
+    }
+    if &buf[8..10] == b"CR" {
+        return Some(raw("CR2", "Canon", Container::Tiff));
+    }
+    vendor.map(|(name, vendor)| raw(name, vendor, Container::Tiff))
+}
+
+pub fn detect_file(path: &Path) -> Option<RawFormat> {
+    let f = std::fs::File::open(path).ok()?;
+    let mut buf = Vec::new();
+    f.take(PROBE_LEN).read_to_end(&mut buf).ok()?;
+    detect(&buf)
+}
+
+/// Parses `--extensions`: a comma or `+` separated list, leading dots optional.
+pub fn parse_extensions(s: &str) -> Result<Vec<String>> {
+    let exts: Vec<String> = s
+        .split([',', '+'])
+        .map(|e| e.trim().trim_start_matches('.').to_ascii_lowercase())
+        .filter(|e| !e.is_empty())
+        .collect();
+    if exts.is_empty() {
+        anyhow::bail!("No extensions given in {}", red(s));
+    }
+    Ok(exts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A little-endian TIFF whose IFD0 holds `Make` and, for DNG, `DNGVersion`.
+    fn tiff(make: &str, dng: bool) -> Vec<u8> {
+        let entries: u16 = if dng { 2 } else { 1 };
+        let strings = 8 + 2 + 12 * entries as u32 + 4;
+        let mut buf = b"II*\0".to_vec();
+        buf.extend(8u32.to_le_bytes());
+        buf.extend(entries.to_le_bytes());
+        buf.extend(0x010fu16.to_le_bytes());
+        buf.extend(2u16.to_le_bytes());
+        buf.extend((make.len() as u32 + 1).to_le_bytes());
+        buf.extend(strings.to_le_bytes());
+        if dng {
+            buf.extend(0xc612u16.to_le_bytes());
+            buf.extend(1u16.to_le_bytes());
+            buf.extend(4u32.to_le_bytes());
+            buf.extend([1, 4, 0, 0]);
+        }
+        buf.extend(0u32.to_le_bytes());
+        buf.extend(make.as_bytes());
+        buf.push(0);
+        buf.resize(64, 0);
+        buf
+    }
+
+    fn padded(head: &[u8]) -> Vec<u8> {
+        let mut buf = head.to_vec();
+        buf.resize(64, 0);
+        buf
+    }
+
+    #[test]
+    fn detects_magic_containers() {
+        let cases: &[(&[u8], &str, Container)] = &[
+            (b"FUJIFILMCCD-RAW 0201", "RAF", Container::Raf),
+            (b"\0\0\0\x18ftypcrx ", "CR3", Container::IsoBmff),
+            (b"II\x1a\0\0\0HEAPCCDR", "CRW", Container::Crw),
+            (b"\0MRM", "MRW", Container::Mrw),
+            (b"FOVb", "X3F", Container::X3f),
+            (b"IIRO\x08\0\0\0", "ORF", Container::Orf),
+            (b"IIU\0\x08\0\0\0", "RW2", Container::Rw2),
+        ];
+        for (head, name, container) in cases {
+            let f = detect(&padded(head)).expect(name);
+            assert_eq!((f.name, f.container), (*name, *container));
+        }
+    }
+
+    #[test]
+    fn detects_tiff_vendor_from_make() {
+        let f = detect(&tiff("NIKON CORPORATION", false)).unwrap();
+        assert_eq!((f.name, f.vendor), ("NEF", "Nikon"));
+        let f = detect(&tiff("Konica Minolta", false)).unwrap();
+        assert_eq!((f.name, f.vendor), ("MRW", "Minolta"));
+        assert_eq!(detect(&tiff("Apple", false)), None);
+    }
+
+    #[test]
+    fn dng_wins_over_make() {
+        let f = detect(&tiff("SONY", true)).unwrap();
+        assert_eq!((f.name, f.vendor), ("DNG", "Sony"));
+        let f = detect(&tiff("Apple", true)).unwrap();
+        assert_eq!((f.name, f.vendor), ("DNG", "Adobe"));
+    }
+
+    #[test]
+    fn rejects_short_and_unknown_input() {
+        assert_eq!(detect(b"II*\0"), None);
+        assert_eq!(detect(&padded(b"\x89PNG\r\n\x1a\n")), None);
+        // IFD0 offset past the end of the buffer.
+        let mut buf = padded(b"MM\0*");
+        buf[4..8].copy_from_slice(&0xffffu32.to_be_bytes());
+        assert_eq!(detect(&buf), None);
+    }
+}
@@ -0,0 +1,248 @@
+use anyhow::{Context, Result};
+use serde_json::{Value, json};
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::libraw::{ColorLevels, LibRaw};
+use crate::libraw_ffi::LibRawImageSizesHead;
+
+/// Raw-level facts about a file, for troubleshooting decodes.
+#[derive(Clone, Debug)]
+pub struct RawInfo {
+    pub sizes: LibRawImageSizesHead,
+    /// libraw's packed CFA description: 0 for no CFA, 9 for X-Trans, else a Bayer pattern.
+    pub filters: u32,
+    pub cfa: String,
+    pub colors: i32,
+    /// Channel letters in libraw's channel order, e.g. `RGBG`.
+    pub cdesc: String,
+    /// `None` when the color data could not be located in the loaded libraw.
+    pub levels: Option<ColorLevels>,
+    pub cam_mul: [f32; 4],
+    pub pre_mul: [f32; 4],
+    pub rgb_cam: [[f32; 3]; 3],
+    pub decoder: Option<String>,
+    pub maximum: u32,
+    /// Sample depth implied by the white level.
+    pub bits: u32,
+    /// Set when unpacking failed, in which case levels are those known after open.
+    pub unpack_error: Option<String>,
+}
+
+/// Letters of the CFA cells starting at the visible image's top-left corner.
+fn cfa_pattern(filters: u32, xtrans: &[[c_char; 6]; 6], cdesc: &[u8]) -> String {
+    let letter = |c: usize| cdesc.get(c).map_or('?', |&b| b as char);
+    match filters {
+        0 => "none (full color per pixel)".to_string(),
+        1 => "Leaf CatchLight 16x16".to_string(),
+        9 => xtrans
+            .iter()
+            .map(|row| row.iter().map(|&c| letter(c as usize)).collect::<String>())
+            .collect::<Vec<_>>()
+            .join("/"),
+        f if f < 1000 => format!("unknown ({})", f),
+        f => {
+            let fc = |r: u32, c: u32| (f >> ((((r << 1) & 14) | (c & 1)) << 1) & 3) as usize;
+            let first: String = (0..2)
+                .flat_map(|r| (0..2).map(move |c| (r, c)))
+                .map(|(r, c)| letter(fc(r, c)))
+                .collect();
+            // Most sensors repeat every two rows; others need the full 8x2 period.
+            if (0..8).all(|r| (0..2).all(|c| fc(r, c) == fc(r % 2, c))) {
+                first
+            } else {
+                (0..8)
+                    .map(|r| (0..2).map(|c| letter(fc(r, c))).collect::<String>())
+                    .collect::<Vec<_>>()
+                    .join("/")
+            }
+        }
+    }
+}
+
+fn list<T: std::fmt::Display>(values: &[T]) -> String {
+    values
+        .iter()
+        .map(|v| v.to_string())
+        .collect::<Vec<_>>()
+        .join(", ")
+}
+
+fn matrix<const N: usize>(rows: &[[f32; N]]) -> String {
+    rows.iter()
+        .map(|r| {
+            r.iter()
+                .map(|v| format!("{:.4}", v))
+                .collect::<Vec<_>>()
+                .join(" ")
+        })
+        .collect::<Vec<_>>()
+        .join(" | ")
+}
+
+impl RawInfo {
+    /// Opens and unpacks `path`; unpacking fills levels some formats only know then.
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut lr = LibRaw::new()?;
+        let data = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        lr.open_buffer(data).context("libraw_open_buffer failed")?;
+        let unpack_error = lr.unpack().err().map(|e| e.to_string());
+        let mut info = Self::from_libraw(&lr)?;
+        info.unpack_error = unpack_error;
+        Ok(info)
+    }
+
+    pub fn from_libraw(lr: &LibRaw) -> Result<Self> {
+        let sizes = lr.sizes()?;
+        let ip = lr.iparams();
+        let cdesc: Vec<u8> = ip
+            .cdesc
+            .iter()
+            .take_while(|&&c| c != 0)
+            .map(|&c| c as u8)
+            .collect();
+        let levels = lr.color_levels().ok();
+        let maximum = lr.color_maximum().max(0) as u32;
+        Ok(RawInfo {
+            sizes,
+            filters: ip.filters,
+            cfa: cfa_pattern(ip.filters, &ip.xtrans, &cdesc),
+            colors: ip.colors,
+            cdesc: String::from_utf8_lossy(&cdesc).into_owned(),
+            levels,
+            cam_mul: lr.cam_mul(),
+            pre_mul: lr.pre_mul(),
+            rgb_cam: lr.rgb_cam(),
+            decoder: lr.unpack_function_name(),
+            maximum,
+            bits: 32 - maximum.leading_zeros(),
+            unpack_error: None,
+        })
+    }
+
+    /// Margins as (top, left, bottom, right) around the visible area.
+    pub fn margins(&self) -> (u32, u32, u32, u32) {
+        let s = &self.sizes;
+        let (top, left) = (s.top_margin as u32, s.left_margin as u32);
+        (
+            top,
+            left,
+            (s.raw_height as u32).saturating_sub(s.height as u32 + top),
+            (s.raw_width as u32).saturating_sub(s.width as u32 + left),
+        )
+    }
+
+    /// Labelled values for `--info`.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let s = &self.sizes;
+        let (top, left, bottom, right) = self.margins();
+        let mut out = vec![
+            ("Raw size", format!("{}x{}", s.raw_width, s.raw_height)),
+            ("Visible size", format!("{}x{}", s.width, s.height)),
+            (
+                "Margins",
+                format!(
+                    "top {}, left {}, bottom {}, right {}",
+                    top, left, bottom, right
+                ),
+            ),
+            (
+                "CFA pattern",
+                format!("{} (filters 0x{:08x})", self.cfa, self.filters),
+            ),
+            ("Colors", format!("{} ({})", self.colors, self.cdesc)),
+        ];
+        if s.pixel_aspect != 1.0 && s.pixel_aspect > 0.0 {
+            out.push(("Pixel aspect", format!("{:.4}", s.pixel_aspect)));
+        }
+        if let Some(l) = &self.levels {
+            out.push((
+                "Black level",
+                format!("{} + per channel [{}]", l.black, list(&l.cblack)),
+            ));
+            if let Some((rows, cols, values)) = &l.black_pattern {
+                out.push((
+                    "Black pattern",
+                    format!("{}x{} [{}]", rows, cols, list(values)),
+                ));
+            }
+            out.push(("White level", l.maximum.to_string()));
+            if l.linear_max.iter().any(|&v| v > 0) {
+                out.push(("White per channel", format!("[{}]", list(&l.linear_max))));
+            }
+            if l.data_maximum > 0 {
+                out.push(("Data maximum", l.data_maximum.to_string()));
+            }
+            out.push(("Camera matrix (XYZ)", matrix(&l.cam_xyz)));
+        } else {
+            out.push((
+                "Black level",
+                "unavailable in this libraw build".to_string(),
+            ));
+            out.push(("White level", self.maximum.to_string()));
+        }
+        out.push(("RGB from camera", matrix(&self.rgb_cam)));
+        out.push(("As-shot WB", format!("[{}]", list(&self.cam_mul))));
+        out.push(("Daylight WB", format!("[{}]", list(&self.pre_mul))));
+        if let Some(d) = &self.decoder {
+            out.push(("Compression", d.clone()));
+        }
+        out.push(("Bits per sample", self.bits.to_string()));
+        out.push(("Flip", format!("{} ({})", s.flip, flip_name(s.flip))));
+        if let Some(e) = &self.unpack_error {
+            out.push(("Unpack", format!("failed: {}", e)));
+        }
+        out
+    }
+
+    pub fn to_json(&self) -> Value {
+        let s = &self.sizes;
+        let (top, left, bottom, right) = self.margins();
+        let levels = self.levels.as_ref().map(|l| {
+            json!({
+                "black": l.black,
+                "cblack": l.cblack,
+                "black_pattern": l.black_pattern.as_ref().map(|(rows, cols, values)| json!({
+                    "rows": rows,
+                    "cols": cols,
+                    "values": values,
+                })),
+                "white": l.maximum,
+                "white_per_channel": l.linear_max,
+                "data_maximum": l.data_maximum,
+                "cam_xyz": l.cam_xyz,
+            })
+        });
+        json!({
+            "raw_width": s.raw_width,
+            "raw_height": s.raw_height,
+            "width": s.width,
+            "height": s.height,
+            "margins": { "top": top, "left": left, "bottom": bottom, "right": right },
+            "pixel_aspect": s.pixel_aspect,
+            "filters": self.filters,
+            "cfa_pattern": self.cfa,
+            "colors": self.colors,
+            "cdesc": self.cdesc,
+            "white": self.maximum,
+            "levels": levels,
+            "rgb_cam": self.rgb_cam,
+            "as_shot_wb": self.cam_mul,
+            "daylight_wb": self.pre_mul,
+            "compression": self.decoder,
+            "bits": self.bits,
+            "flip": s.flip,
+            "unpack_error": self.unpack_error,
+        })
+    }
+}
+
+fn flip_name(flip: i32) -> &'static str {
+    match flip {
+        0 => "none",
+        3 => "180°",
+        5 => "90° CCW",
+        6 => "90° CW",
+        _ => "other",
+    }
+}
@@ -0,0 +1,431 @@
+use anyhow::{Context, Result};
+use chrono::{DateTime, Local};
+use serde_json::{Value, json};
+use std::os::raw::c_char;
+use std::path::Path;
+
+use crate::libraw::{self, LibRaw};
+use crate::libraw_ffi::{self, LibRawGpsInfo};
+use crate::term_colors::{blue, red};
+
+/// Placeholders accepted by `--name`.
+pub const TEMPLATE_KEYS: &[&str] = &[
+    "stem", "make", "model", "lens", "iso", "shutter", "aperture", "focal", "date", "time",
+    "datetime", "seq",
+];
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct GpsPosition {
+    /// Decimal degrees, negative south of the equator.
+    pub latitude: f64,
+    /// Decimal degrees, negative west of Greenwich.
+    pub longitude: f64,
+    /// Meters, negative below sea level.
+    pub altitude: f64,
+}
+
+/// Camera, exposure and lens data libraw parsed from a raw file's maker notes and EXIF.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct RawMetadata {
+    pub make: String,
+    pub model: String,
+    pub software: String,
+    pub iso: Option<f32>,
+    /// Seconds.
+    pub shutter: Option<f32>,
+    pub aperture: Option<f32>,
+    /// Millimeters.
+    pub focal_len: Option<f32>,
+    pub focal_35mm: Option<u16>,
+    /// Capture time as libraw reports it: the camera's wall clock read as local time.
+    pub timestamp: Option<i64>,
+    pub lens: String,
+    pub lens_make: String,
+    pub shot_order: Option<u32>,
+    pub artist: String,
+    pub gps: Option<GpsPosition>,
+}
+
+fn c_string(chars: &[c_char]) -> String {
+    let bytes: Vec<u8> = chars
+        .iter()
+        .take_while(|&&c| c != 0)
+        .map(|&c| c as u8)
+        .collect();
+    String::from_utf8_lossy(&bytes).trim().to_string()
+}
+
+fn positive(v: f32) -> Option<f32> {
+    (v.is_finite() && v > 0.0).then_some(v)
+}
+
+fn dms(v: [f32; 3]) -> f64 {
+    v[0] as f64 + v[1] as f64 / 60.0 + v[2] as f64 / 3600.0
+}
+
+fn gps_position(g: &LibRawGpsInfo) -> Option<GpsPosition> {
+    if g.gpsparsed == 0 {
+        return None;
+    }
+    let sign = |r: c_char, neg: u8| if r as u8 == neg { -1.0 } else { 1.0 };
+    Some(GpsPosition {
+        latitude: sign(g.latref, b'S') * dms(g.latitude),
+        longitude: sign(g.longref, b'W') * dms(g.longitude),
+        altitude: if g.altref == 1 {
+            -(g.altitude as f64)
+        } else {
+            g.altitude as f64
+        },
+    })
+}
+
+impl RawMetadata {
+    /// Reads the metadata of `path` without unpacking any raw data.
+    pub fn read(path: &Path) -> Result<Self> {
+        let mut lr = LibRaw::new()?;
+        let data = std::fs::read(path).with_context(|| format!("Failed to read {:?}", path))?;
+        lr.open_buffer(data).context("libraw_open_buffer failed")?;
+        Self::from_libraw(&lr)
+    }
+
+    /// Collects the metadata of a file already opened with `libraw_open_buffer`.
+    pub fn from_libraw(lr: &LibRaw) -> Result<Self> {
+        let have = libraw::version_number()?;
+        if have < libraw_ffi::make_version(0, 20, 0) {
+            anyhow::bail!(
+                "Reading metadata needs libraw 0.20 or newer, loaded libraw is {}",
+                red(libraw_ffi::format_version(have))
+            );
+        }
+        let ip = lr.iparams();
+        let other = lr.imgother();
+        let lens = lr.lensinfo();
+        Ok(RawMetadata {
+            make: c_string(&ip.make),
+            model: c_string(&ip.model),
+            software: c_string(&ip.software),
+            iso: positive(other.iso_speed),
+            shutter: positive(other.shutter),
+            aperture: positive(other.aperture),
+            focal_len: positive(other.focal_len),
+            focal_35mm: (lens.focal_length_in_35mm_format > 0)
+                .then_some(lens.focal_length_in_35mm_format),
+            timestamp: (other.timestamp > 0).then_some(other.timestamp),
+            lens: c_string(&lens.lens),
+            lens_make: c_string(&lens.lens_make),
+            shot_order: (other.shot_order > 0).then_some(other.shot_order),
+            artist: c_string(&other.artist),
+            gps: gps_position(&other.parsed_gps),
+        })
+    }
+
+    /// Capture time on the camera's clock. libraw builds the timestamp with `mktime`, so
+    /// converting back through the local zone restores the original wall-clock fields.
+    pub fn datetime(&self) -> Option<DateTime<Local>> {
+        self.timestamp
+            .and_then(|t| DateTime::from_timestamp(t, 0))
+            .map(|t| t.with_timezone(&Local))
+    }
+
+    /// Camera name without the make repeated in front of the model, as most vendors do.
+    pub fn camera(&self) -> String {
+        if self.make.is_empty()
+            || self
+                .model
+                .to_ascii_lowercase()
+                .starts_with(&self.make.to_ascii_lowercase())
+        {
+            self.model.clone()
+        } else {
+            format!("{} {}", self.make, self.model).trim().to_string()
+        }
+    }
+
+    pub fn shutter_text(&self) -> Option<String> {
+        self.shutter.map(|s| {
+            if s >= 0.3 {
+                format!("{}s", trim_float(s, 1))
+            } else {
+                format!("1/{}s", (1.0 / s).round())
+            }
+        })
+    }
+
+    pub fn aperture_text(&self) -> Option<String> {
+        self.aperture.map(|a| format!("f/{}", trim_float(a, 1)))
+    }
+
+    pub fn focal_text(&self) -> Option<String> {
+        self.focal_len.map(|f| match self.focal_35mm {
+            Some(eq) if eq as f32 != f.round() => format!("{}mm ({}mm eq.)", trim_float(f, 1), eq),
+            _ => format!("{}mm", trim_float(f, 1)),
+        })
+    }
+
+    /// Labelled values for `--info`, skipping whatever the file does not record.
+    pub fn fields(&self) -> Vec<(&'static str, String)> {
+        let mut out = Vec::new();
+        let mut push = |k: &'static str, v: Option<String>| {
+            if let Some(v) = v.filter(|v| !v.is_empty()) {
+                out.push((k, v));
+            }
+        };
+        push("Make", Some(self.make.clone()));
+        push("Model", Some(self.model.clone()));
+        push("Software", Some(self.software.clone()));
+        push("ISO", self.iso.map(|i| format!("{}", i.round())));
+        push("Shutter", self.shutter_text());
+        push("Aperture", self.aperture_text());
+        push("Focal length", self.focal_text());
+        push(
+            "Captured",
+            self.datetime()
+                .map(|t| t.format("%Y-%m-%d %H:%M:%S").to_string()),
+        );
+        let lens = if self.lens_make.is_empty()
+            || self
+                .lens
+                .to_ascii_lowercase()
+                .starts_with(&self.lens_make.to_ascii_lowercase())
+        {
+            self.lens.clone()
+        } else {
+            format!("{} {}", self.lens_make, self.lens)
+        };
+        push("Lens", Some(lens));
+        push("Shot number", self.shot_order.map(|n| n.to_string()));
+        push("Artist", Some(self.artist.clone()));
+        push(
+            "GPS",
+            self.gps
+                .map(|g| format!("{:.6}, {:.6}, {:.1}m", g.latitude, g.longitude, g.altitude)),
+        );
+        out
+    }
+
+    pub fn to_json(&self) -> Value {
+        json!({
+            "make": self.make,
+            "model": self.model,
+            "software": self.software,
+            "iso": self.iso,
+            "shutter": self.shutter,
+            "aperture": self.aperture,
+            "focal_length": self.focal_len,
+            "focal_length_35mm": self.focal_35mm,
+            "timestamp": self.timestamp,
+            "captured": self.datetime().map(|t| t.format("%Y-%m-%dT%H:%M:%S").to_string()),
+            "lens": self.lens,
+            "lens_make": self.lens_make,
+            "shot_order": self.shot_order,
+            "artist": self.artist,
+            "gps": self.gps.map(|g| json!({
+                "latitude": g.latitude,
+                "longitude": g.longitude,
+                "altitude": g.altitude,
+            })),
+        })
+    }
+
+    /// Value substituted for `{key}` in `--name`, or `None` for an unknown key.
+    fn template_value(&self, key: &str, stem: &str, seq: usize) -> Option<String> {
+        let unknown = || "unknown".to_string();
+        let dt = self.datetime();
+        let value = match key {
+            "stem" => stem.to_string(),
+            "make" => Some(self.make.clone())
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(unknown),
+            "model" => Some(self.camera())
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(unknown),
+            "lens" => Some(self.lens.clone())
+                .filter(|s| !s.is_empty())
+                .unwrap_or_else(unknown),
+            "iso" => self
+                .iso
+                .map(|i| format!("ISO{}", i.round()))
+                .unwrap_or_else(unknown),
+            "shutter" => self.shutter_text().unwrap_or_else(unknown),
+            "aperture" => self.aperture_text().unwrap_or_else(unknown),
+            "focal" => self
+                .focal_len
+                .map(|f| format!("{}mm", trim_float(f, 1)))
+                .unwrap_or_else(unknown),
+            "date" => dt
+                .map(|t| t.format("%Y-%m-%d").to_string())
+                .unwrap_or_else(unknown),
+            "time" => dt
+                .map(|t| t.format("%H%M%S").to_string())
+                .unwrap_or_else(unknown),
+            "datetime" => dt
+                .map(|t| t.format("%Y%m%d-%H%M%S").to_string())
+                .unwrap_or_else(unknown),
+            "seq" => format!("{:04}", seq),
+            _ => return None,
+        };
+        Some(value)
+    }
+
+    /// Expands a `--name` template such as `{date}_{model}_{stem}` into a file stem.
+    pub fn expand_template(&self, template: &str, stem: &str, seq: usize) -> String {
+        let mut out = String::new();
+        let mut rest = template;
+        while let Some(open) = rest.find('{') {
+            out.push_str(&rest[..open]);
+            let after = &rest[open + 1..];
+            match after.find('}') {
+                Some(close) => {
+                    let key = after[..close].trim().to_ascii_lowercase();
+                    match self.template_value(&key, stem, seq) {
+                        Some(v) => out.push_str(&sanitize(&v)),
+                        None => out.push_str(&rest[open..open + close + 2]),
+                    }
+                    rest = &after[close + 1..];
+                }
+                None => {
+                    out.push_str(&rest[open..]);
+                    rest = "";
+                }
+            }
+        }
+        out.push_str(rest);
+        out
+    }
+}
+
+/// Checks a `--name` template for unknown placeholders before any file is converted.
+pub fn parse_name_template(s: &str) -> Result<String> {
+    let mut rest = s;
+    while let Some(open) = rest.find('{') {
+        let after = &rest[open + 1..];
+        let Some(close) = after.find('}') else {
+            anyhow::bail!("Unclosed {{ in name template {}", red(s));
+        };
+        let key = after[..close].trim().to_ascii_lowercase();
+        if !TEMPLATE_KEYS.contains(&key.as_str()) {
+            let keys = TEMPLATE_KEYS
+                .iter()
+                .map(|k| blue(format!("{{{}}}", k)).to_string())
+                .collect::<Vec<_>>()
+                .join(", ");
+            anyhow::bail!(
+                "Unknown placeholder {} in name template. Valid: {}",
+                red(format!("{{{}}}", key)),
+                keys
+            );
+        }
+        rest = &after[close + 1..];
+    }
+    if s.contains(['/', '\\']) {
+        anyhow::bail!("Name template {} must not contain path separators", red(s));
+    }
+    Ok(s.to_string())
+}
+
+/// Whether `template` gives every file of a batch its own name. Only `{stem}` and `{seq}`
+/// differ between any two files; everything else repeats, e.g. for a burst shot within
+/// one second, and files without metadata all expand to the same defaults.
+pub fn names_each_file(template: &str) -> bool {
+    let mut rest = template;
+    while let Some(open) = rest.find('{') {
+        let after = &rest[open + 1..];
+        let Some(close) = after.find('}') else {
+            break;
+        };
+        if matches!(
+            after[..close].trim().to_ascii_lowercase().as_str(),
+            "stem" | "seq"
+        ) {
+            return true;
+        }
+        rest = &after[close + 1..];
+    }
+    false
+}
+
+/// Formats with at most `digits` decimals, dropping trailing zeros.
+fn trim_float(v: f32, digits: usize) -> String {
+    let s = format!("{:.*}", digits, v);
+    if s.contains('.') {
+        s.trim_end_matches('0').trim_end_matches('.').to_string()
+    } else {
+        s
+    }
+}
+
+/// Replaces characters that are not allowed in file names on common filesystems.
+fn sanitize(s: &str) -> String {
+    s.chars()
+        .map(|c| match c {
+            '/' | '\\' | ':' | '*' | '?' | '"' | '<' | '>' | '|' => '-',
+            c if c.is_control() => '_',
+            c => c,
+        })
+        .collect::<String>()
+        .trim()
+        .to_string()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn meta() -> RawMetadata {
+        RawMetadata {
+            make: "Canon".into(),
+            model: "EOS R5".into(),
+            iso: Some(400.0),
+            shutter: Some(1.0 / 250.0),
+            aperture: Some(2.8),
+            focal_len: Some(50.0),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn parse_accepts_known_keys() {
+        let t = "{make}_{ Model }_{iso}_{seq}";
+        assert_eq!(parse_name_template(t).unwrap(), t);
+        assert!(parse_name_template("plain").is_ok());
+    }
+
+    #[test]
+    fn parse_rejects_bad_templates() {
+        assert!(parse_name_template("{bogus}").is_err());
+        assert!(parse_name_template("{stem").is_err());
+        assert!(parse_name_template("out/{stem}").is_err());
+        assert!(parse_name_template("out\\{stem}").is_err());
+    }
+
+    #[test]
+    fn batch_templates_need_stem_or_seq() {
+        assert!(names_each_file("{date}_{model}_{stem}"));
+        assert!(names_each_file("shoot_{ SEQ }"));
+        assert!(!names_each_file("{date}_{time}_{model}"));
+        assert!(!names_each_file("plain"));
+        assert!(!names_each_file("stem_seq"));
+    }
+
+    #[test]
+    fn expands_placeholders() {
+        let m = meta();
+        assert_eq!(
+            m.expand_template("{model}_{iso}_{stem}_{seq}", "IMG_0001", 7),
+            "Canon EOS R5_ISO400_IMG_0001_0007"
+        );
+        // Slashes in values are sanitized so the result stays one file name.
+        assert_eq!(
+            m.expand_template("{shutter} {aperture} {focal}", "x", 0),
+            "1-250s f-2.8 50mm"
+        );
+    }
+
+    #[test]
+    fn expand_keeps_unknown_and_unclosed_text() {
+        let m = RawMetadata::default();
+        assert_eq!(m.expand_template("{make}-{nope}", "s", 1), "unknown-{nope}");
+        assert_eq!(m.expand_template("{date}_{stem", "s", 1), "unknown_{stem");
+        assert_eq!(m.expand_template("{STEM}", "s", 1), "s");
+    }
+}
@@ -0,0 +1,320 @@
+use anyhow::{Context, Result};
+
+use crate::color_profile::invert3;
+use crate::libraw::LibRaw;
+use crate::term_colors::{blue, pink, red};
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum WhiteBalance {
+    Camera,
+    Auto,
+    Kelvin {
+        temp: f32,
+        tint: f32,
+    },
+    /// Multipliers in libraw order: R, G, B, G2.
+    Multipliers([f32; 4]),
+    /// Rectangle in full-size sensor pixels (before rotation) to neutralize.
+    Spot {
+        x: u32,
+        y: u32,
+        w: u32,
+        h: u32,
+    },
+}
+
+pub const PRESETS: &[(&str, f32, f32)] = &[
+    ("daylight", 5500.0, 0.0),
+    ("cloudy", 6500.0, 0.0),
+    ("shade", 7500.0, 0.0),
+    ("tungsten", 2850.0, 0.0),
+    ("incandescent", 2850.0, 0.0),
+    ("fluorescent", 3800.0, 21.0),
+    ("flash", 5500.0, 0.0),
+];
+
+fn parse_numbers(s: &str) -> Result<Vec<f32>> {
+    s.split(',')
+        .map(|t| {
+            t.trim()
+                .parse::<f32>()
+                .with_context(|| format!("Invalid number {}", red(t.trim())))
+        })
+        .collect()
+}
+
+pub fn parse_white_balance(s: &str) -> Result<WhiteBalance> {
+    let low = s.trim().to_ascii_lowercase();
+    match low.as_str() {
+        "camera" | "as-shot" | "asshot" => return Ok(WhiteBalance::Camera),
+        "auto" => return Ok(WhiteBalance::Auto),
+        _ => {}
+    }
+    if let Some(&(_, temp, tint)) = PRESETS.iter().find(|(name, _, _)| *name == low) {
+        return Ok(WhiteBalance::Kelvin { temp, tint });
+    }
+    if let Some(rest) = low.strip_prefix("mul:") {
+        let v = parse_numbers(rest)?;
+        // RGGB on the command line, libraw wants R, G, B, G2.
+        let mul = match v.as_slice() {
+            [r, g, b] => [*r, *g, *b, *g],
+            [r, g1, g2, b] => [*r, *g1, *b, *g2],
+            _ => anyhow::bail!("White balance multipliers need 3 (R,G,B) or 4 (R,G,G,B) values"),
+        };
+        if mul.iter().any(|m| m.is_nan() || *m <= 0.0) {
+            anyhow::bail!("White balance multipliers must be positive");
+        }
+        return Ok(WhiteBalance::Multipliers(mul));
+    }
+    if let Some(rest) = low.strip_prefix("spot:") {
+        let v = parse_numbers(rest)?;
+        return match v.as_slice() {
+            [x, y, w, h] if *x >= 0.0 && *y >= 0.0 && *w >= 1.0 && *h >= 1.0 => {
+                Ok(WhiteBalance::Spot {
+                    x: *x as u32,
+                    y: *y as u32,
+                    w: *w as u32,
+                    h: *h as u32,
+                })
+            }
+            _ => anyhow::bail!("Spot white balance needs X,Y,W,H with a non-empty rectangle"),
+        };
+    }
+    let (temp_s, tint_s) = match low.split_once(':') {
+        Some((t, n)) => (t, Some(n)),
+        None => (low.as_str(), None),
+    };
+    if let Ok(temp) = temp_s.trim_end_matches('k').parse::<f32>() {
+        if !(1500.0..=15000.0).contains(&temp) {
+            anyhow::bail!("Color temperature must be between 1500K and 15000K");
+        }
+        let tint = match tint_s {
+            Some(t) => t
+                .parse::<f32>()
+                .with_context(|| format!("Invalid tint {}", red(t)))?,
+            None => 0.0,
+        };
+        return Ok(WhiteBalance::Kelvin { temp, tint });
+    }
+    let presets = PRESETS
+        .iter()
+        .map(|(n, _, _)| blue(n).to_string())
+        .collect::<Vec<_>>()
+        .join(", ");
+    anyhow::bail!(
+        "Unknown white balance {}. Use camera, auto, {}, <K>[:tint], mul:R,G,G,B or spot:X,Y,W,H",
+        red(s),
+        presets
+    )
+}
+
+/// CIE 1960 uv of a Planckian radiator (Krystek's approximation, 1000K-15000K).
+fn planckian_uv(t: f64) -> (f64, f64) {
+    let u = (0.860_117_757 + 1.541_182_54e-4 * t + 1.286_412_12e-7 * t * t)
+        / (1.0 + 8.424_202_35e-4 * t + 7.081_451_63e-7 * t * t);
+    let v = (0.317_398_726 + 4.228_062_45e-5 * t + 4.204_816_91e-8 * t * t)
+        / (1.0 - 2.897_418_16e-5 * t + 1.614_560_53e-7 * t * t);
+    (u, v)
+}
+
+/// Linear sRGB color of an illuminant at `temp` Kelvin. Tint follows the DNG convention:
+/// one unit is 1/3000 Duv, positive values move towards magenta.
+fn illuminant_srgb(temp: f32, tint: f32) -> [f64; 3] {
+    let t = temp as f64;
+    let (u, v) = planckian_uv(t);
+    let (u1, v1) = planckian_uv(t + 1.0);
+    let (du, dv) = (u1 - u, v1 - v);
+    let len = (du * du + dv * dv).sqrt();
+    // Unit normal pointing above the locus (towards green).
+    let (nu, nv) = if du < 0.0 {
+        (dv / len, -du / len)
+    } else {
+        (-dv / len, du / len)
+    };
+    let duv = -(tint as f64) / 3000.0;
+    let (u, v) = (u + duv * nu, v + duv * nv);
+
+    let d = 2.0 * u - 8.0 * v + 4.0;
+    let (x, y) = (3.0 * u / d, 2.0 * v / d);
+    let xyz = [x / y, 1.0, (1.0 - x - y) / y];
+    const XYZ_TO_SRGB: [[f64; 3]; 3] = [
+        [3.240_454_2, -1.537_138_5, -0.498_531_4],
+        [-0.969_266_0, 1.876_010_8, 0.041_556_0],
+        [0.055_643_4, -0.204_025_9, 1.057_225_2],
+    ];
+    let mut rgb = [0.0; 3];
+    for (i, row) in XYZ_TO_SRGB.iter().enumerate() {
+        rgb[i] = row.iter().zip(xyz.iter()).map(|(a, b)| a * b).sum();
+    }
+    rgb
+}
+
+/// Camera multipliers for an illuminant, read from libraw's `rgb_cam` and `pre_mul`.
+fn kelvin_multipliers(lr: &LibRaw, temp: f32, tint: f32) -> Result<[f32; 4]> {
+    let rgb_cam = lr.rgb_cam().map(|row| row.map(|c| c as f64));
+    let pre_mul = lr.pre_mul();
+    let pre_mul = [0, 1, 2].map(|i| pre_mul[i] as f64);
+    multipliers_for(rgb_cam, pre_mul, temp, tint)
+}
+
+/// Multipliers that neutralize an illuminant, relative to green.
+///
+/// `rgb_cam` is the inverse of the row-normalized camera-from-sRGB matrix and `pre_mul`
+/// holds the normalization factors, so the raw response to sRGB color `s` is
+/// `inverse(rgb_cam) * s / pre_mul`. For D65 this yields `pre_mul` itself.
+fn multipliers_for(
+    rgb_cam: [[f64; 3]; 3],
+    pre_mul: [f64; 3],
+    temp: f32,
+    tint: f32,
+) -> Result<[f32; 4]> {
+    let cam_rgb = invert3(rgb_cam).context("Camera color matrix is not invertible")?;
+    let illum = illuminant_srgb(temp, tint);
+    let mut mul = [0.0f32; 4];
+    for i in 0..3 {
+        let pre = pre_mul[i];
+        let response: f64 = (0..3).map(|j| cam_rgb[i][j] * illum[j]).sum::<f64>() / pre;
+        if response.is_nan() || response <= 0.0 || pre <= 0.0 {
+            anyhow::bail!("Cannot derive multipliers for {}K on this camera", temp);
+        }
+        mul[i] = (1.0 / response) as f32;
+    }
+    let g = mul[1];
+    for m in mul.iter_mut() {
+        *m /= g;
+    }
+    mul[3] = mul[1];
+    Ok(mul)
+}
+
+/// Configures white balance on an opened libraw handle, before `libraw_dcraw_process`.
+pub fn apply_white_balance(lr: &mut LibRaw, wb: WhiteBalance, debug: bool) -> Result<()> {
+    match wb {
+        WhiteBalance::Camera => {
+            if lr.set_use_camera_wb(true).is_err() {
+                // Same multipliers use_camera_wb would pick, applied as user multipliers.
+                let mul = lr.cam_mul();
+                if mul[0] > 0.0 && mul[1] > 0.0 {
+                    lr.set_user_mul(mul);
+                } else if debug {
+                    eprintln!("{} no as-shot multipliers in file", blue("[wb]"));
+                }
+            }
+        }
+        WhiteBalance::Auto | WhiteBalance::Spot { .. } => {
+            lr.set_use_auto_wb(true)
+                .context("Auto and spot white balance are unavailable")?;
+            if let WhiteBalance::Spot { x, y, w, h } = wb {
+                lr.set_greybox([x, y, w, h])?;
+            }
+        }
+        WhiteBalance::Kelvin { temp, tint } => {
+            let mul = kelvin_multipliers(lr, temp, tint)?;
+            lr.set_user_mul(mul);
+        }
+        WhiteBalance::Multipliers(mul) => lr.set_user_mul(mul),
+    }
+    if debug {
+        println!(
+            "{} white balance {}",
+            blue("[wb]"),
+            pink(format!("{:?}", wb))
+        );
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const IDENTITY: [[f64; 3]; 3] = [[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]];
+
+    fn near(a: [f32; 4], b: [f32; 4], tolerance: f32) -> bool {
+        a.iter().zip(b).all(|(x, y)| (x - y).abs() <= tolerance)
+    }
+
+    #[test]
+    fn parses_modes() {
+        assert_eq!(
+            parse_white_balance(" Camera ").unwrap(),
+            WhiteBalance::Camera
+        );
+        assert_eq!(parse_white_balance("auto").unwrap(), WhiteBalance::Auto);
+        assert_eq!(
+            parse_white_balance("tungsten").unwrap(),
+            WhiteBalance::Kelvin {
+                temp: 2850.0,
+                tint: 0.0
+            }
+        );
+        assert_eq!(
+            parse_white_balance("5200K:10").unwrap(),
+            WhiteBalance::Kelvin {
+                temp: 5200.0,
+                tint: 10.0
+            }
+        );
+        // R,G,G,B on the command line, R,G,B,G2 for libraw.
+        assert_eq!(
+            parse_white_balance("mul:2,1,1.1,1.5").unwrap(),
+            WhiteBalance::Multipliers([2.0, 1.0, 1.5, 1.1])
+        );
+        assert_eq!(
+            parse_white_balance("mul:2,1,1.5").unwrap(),
+            WhiteBalance::Multipliers([2.0, 1.0, 1.5, 1.0])
+        );
+        assert_eq!(
+            parse_white_balance("spot:10,20,30,40").unwrap(),
+            WhiteBalance::Spot {
+                x: 10,
+                y: 20,
+                w: 30,
+                h: 40
+            }
+        );
+    }
+
+    #[test]
+    fn rejects_bad_values() {
+        for s in [
+            "900K",
+            "20000",
+            "5000K:x",
+            "mul:1,2",
+            "mul:1,0,1",
+            "spot:1,2,0,4",
+            "sunset",
+        ] {
+            assert!(parse_white_balance(s).is_err(), "{}", s);
+        }
+    }
+
+    /// D65 sits about 0.0032 Duv above the Planckian locus at 6504K.
+    #[test]
+    fn d65_gives_pre_mul() {
+        let mul = multipliers_for(IDENTITY, [2.0, 1.0, 1.5], 6504.0, -9.5).unwrap();
+        assert!(near(mul, [2.0, 1.0, 1.5, 1.0], 0.02), "{:?}", mul);
+    }
+
+    #[test]
+    fn kelvin_follows_the_light() {
+        let neutral = [1.0; 3];
+        let tungsten = multipliers_for(IDENTITY, neutral, 2850.0, 0.0).unwrap();
+        assert_eq!((tungsten[1], tungsten[3]), (1.0, 1.0));
+        assert!(tungsten[0] < 1.0 && tungsten[2] > 1.5, "{:?}", tungsten);
+        let shade = multipliers_for(IDENTITY, neutral, 7500.0, 0.0).unwrap();
+        assert!(shade[0] > 1.0 && shade[2] < 1.0, "{:?}", shade);
+        // Magenta light needs more green gain, so red and blue drop relative to it.
+        let plain = multipliers_for(IDENTITY, neutral, 5500.0, 0.0).unwrap();
+        let magenta = multipliers_for(IDENTITY, neutral, 5500.0, 20.0).unwrap();
+        assert!(magenta[0] < plain[0] && magenta[2] < plain[2]);
+    }
+
+    #[test]
+    fn unusable_camera_data_is_an_error() {
+        let singular = [[1.0, 0.0, 0.0], [1.0, 0.0, 0.0], [0.0, 0.0, 1.0]];
+        assert!(multipliers_for(singular, [1.0; 3], 5000.0, 0.0).is_err());
+        assert!(multipliers_for(IDENTITY, [1.0, 0.0, 1.0], 5000.0, 0.0).is_err());
+    }
+}